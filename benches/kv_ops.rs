@@ -0,0 +1,188 @@
+//! Throughput and latency of key-value operations against a local cluster
+//!
+//! A criterion benchmark that starts the provider like the integration tests do, so it needs
+//! NATS, a Couchbase cluster and provider_test_config.toml. Each operation is measured at every
+//! concurrency of CONCURRENCIES: criterion's iterations are spread over that many tasks sending
+//! requests at once, and the time per request, with the matching throughput, is reported per
+//! `<operation>/<concurrency>`. Criterion keeps the results of the previous run and reports the
+//! change from it, so running the benchmark before and after a change shows what it costs:
+//!
+//! ```shell
+//! cargo bench --bench kv_ops                   # every operation
+//! cargo bench --bench kv_ops -- '^(get|set)/'  # only these
+//! ```
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wasmbus_rpc::provider::prelude::*;
+use wasmcloud_interface_keyvalue::*;
+use wasmcloud_test_util::provider_test::{test_provider, Provider};
+
+/// Operations measured, by name
+const BENCHMARKS: [&str; 5] = ["get", "set", "increment", "list_add", "list_range"];
+
+/// Requests in flight at once
+const CONCURRENCIES: [usize; 4] = [1, 8, 32, 128];
+
+/// Values stored by the benchmarks
+const VALUE: &str = "kvcouchbase benchmark value of a few dozen bytes";
+
+/// Elements of the lists read by list_range
+const LIST_LENGTH: usize = 100;
+
+fn kv_ops(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let provider = runtime.block_on(test_provider());
+    for name in BENCHMARKS {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(1));
+        for concurrency in CONCURRENCIES {
+            if let Err(e) = runtime.block_on(prepare(&provider, name, concurrency)) {
+                panic!("{}: preparing failed: {}", name, e);
+            }
+            group.bench_with_input(
+                BenchmarkId::from_parameter(concurrency),
+                &concurrency,
+                |b, &concurrency| {
+                    b.to_async(&runtime)
+                        .iter_custom(|iters| run(&provider, name, concurrency, iters as usize));
+                },
+            );
+        }
+        group.finish();
+        runtime.block_on(cleanup(&provider, name));
+    }
+    let _ = runtime.block_on(provider.shutdown());
+}
+
+criterion_group!(benches, kv_ops);
+criterion_main!(benches);
+
+/// Key used by a task of a benchmark
+fn key(name: &str, task: usize) -> String {
+    format!("kvcouchbase_bench_{}_{}", name, task)
+}
+
+/// Store what the tasks of a benchmark read
+async fn prepare(provider: &Provider, name: &str, concurrency: usize) -> RpcResult<()> {
+    let client = KeyValueSender::via(provider.clone());
+    let ctx = Context::default();
+    for task in 0..concurrency {
+        match name {
+            "get" => {
+                let request = SetRequest {
+                    key: key(name, task),
+                    value: VALUE.to_string(),
+                    expires: 0,
+                };
+                client.set(&ctx, &request).await?;
+            }
+            "list_add" => {
+                client.list_clear(&ctx, &key(name, task)).await?;
+            }
+            "list_range" => {
+                client.list_clear(&ctx, &key(name, task)).await?;
+                for _ in 0..LIST_LENGTH {
+                    let request = ListAddRequest {
+                        list_name: key(name, task),
+                        value: VALUE.to_string(),
+                    };
+                    client.list_add(&ctx, &request).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Send `operations` requests of a benchmark from `concurrency` tasks.
+/// Returns the time they took.
+async fn run(
+    provider: &Provider,
+    name: &'static str,
+    concurrency: usize,
+    operations: usize,
+) -> Duration {
+    let started = Instant::now();
+    let tasks = (0..concurrency).map(|task| {
+        let client = KeyValueSender::via(provider.clone());
+        // the first tasks take the remainder
+        let count = operations / concurrency + usize::from(task < operations % concurrency);
+        tokio::spawn(async move {
+            let ctx = Context::default();
+            let key = key(name, task);
+            for _ in 0..count {
+                operation(&client, &ctx, name, &key).await?;
+            }
+            Ok::<_, RpcError>(())
+        })
+    });
+    for task in futures::future::join_all(tasks).await {
+        match task {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => panic!("{}: {}", name, e),
+            Err(e) => panic!("{}: {}", name, e),
+        }
+    }
+    started.elapsed()
+}
+
+/// Send one request of a benchmark
+async fn operation(
+    client: &KeyValueSender<Provider>,
+    ctx: &Context,
+    name: &str,
+    key: &str,
+) -> RpcResult<()> {
+    match name {
+        "get" => {
+            client.get(ctx, key).await?;
+        }
+        "set" => {
+            let request = SetRequest {
+                key: key.to_string(),
+                value: VALUE.to_string(),
+                expires: 0,
+            };
+            client.set(ctx, &request).await?;
+        }
+        "increment" => {
+            let request = IncrementRequest {
+                key: key.to_string(),
+                value: 1,
+            };
+            client.increment(ctx, &request).await?;
+        }
+        "list_add" => {
+            let request = ListAddRequest {
+                list_name: key.to_string(),
+                value: VALUE.to_string(),
+            };
+            client.list_add(ctx, &request).await?;
+        }
+        "list_range" => {
+            let request = ListRangeRequest {
+                list_name: key.to_string(),
+                start: 0,
+                stop: LIST_LENGTH as i32 - 1,
+            };
+            client.list_range(ctx, &request).await?;
+        }
+        _ => return Err(RpcError::MethodNotHandled(name.to_string())),
+    }
+    Ok(())
+}
+
+/// Remove the keys a benchmark wrote
+async fn cleanup(provider: &Provider, name: &str) {
+    let client = KeyValueSender::via(provider.clone());
+    let ctx = Context::default();
+    for task in 0..CONCURRENCIES[CONCURRENCIES.len() - 1] {
+        let key = key(name, task);
+        let _ = match name {
+            "list_add" | "list_range" => client.list_clear(&ctx, &key).await,
+            _ => client.del(&ctx, &key).await,
+        };
+    }
+}
@@ -0,0 +1,141 @@
+//! CouchbaseAdmin: management of the scopes and collections of a link's bucket
+//!
+//! Only actors named in the provider's `admin_actors` may call these methods, as
+//! `CouchbaseAdmin.<Method>`, so a platform can provision the storage of its tenants
+//! without giving every actor that power.
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use wasmbus_rpc::{
+    common::{Context, Message, MessageDispatch},
+    error::{RpcError, RpcResult},
+};
+
+/// Longest scope or collection name Couchbase accepts
+const MAX_NAME_LENGTH: usize = 251;
+
+/// A collection of the link's bucket
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CollectionName {
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub collection: String,
+}
+
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait CouchbaseAdmin {
+    /// Creates a scope. Returns false if it exists already.
+    async fn create_scope<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool>;
+    /// Drops a scope and its collections. Returns false if it does not exist.
+    async fn drop_scope<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool>;
+    /// Creates a collection in an existing scope. Returns false if it exists already.
+    async fn create_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<bool>;
+    /// Drops a collection and its documents. Returns false if it does not exist.
+    async fn drop_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<bool>;
+    /// Removes every document of a collection, returning how many were removed
+    async fn flush_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<u64>;
+}
+
+/// CouchbaseAdminReceiver receives messages defined in the CouchbaseAdmin service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait CouchbaseAdminReceiver: MessageDispatch + CouchbaseAdmin {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "CreateScope" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseAdmin::create_scope(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DropScope" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseAdmin::drop_scope(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "CreateCollection" => {
+                let value: CollectionName = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'CollectionName': {}", e)))?;
+
+                let resp = CouchbaseAdmin::create_collection(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DropCollection" => {
+                let value: CollectionName = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'CollectionName': {}", e)))?;
+
+                let resp = CouchbaseAdmin::drop_collection(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "FlushCollection" => {
+                let value: CollectionName = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'CollectionName': {}", e)))?;
+
+                let resp = CouchbaseAdmin::flush_collection(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "CouchbaseAdmin::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Check a scope or collection name: letters, digits, `_`, `-` and `%`, not starting with `_`
+/// or `%`, which Couchbase reserves for its own
+pub(crate) fn check_name(kind: &str, name: &str) -> RpcResult<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '%');
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.chars().all(allowed)
+        && !name.starts_with(['_', '%']);
+    if valid {
+        Ok(())
+    } else {
+        Err(RpcError::InvalidParameter(format!(
+            "invalid {} name {:?}: expected up to {} letters, digits, _, - or %, \
+             not starting with _ or %",
+            kind, name, MAX_NAME_LENGTH
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_names() {
+        assert!(check_name("scope", "tenant-42").is_ok());
+        assert!(check_name("collection", "sessions_v2%").is_ok());
+        assert!(check_name("scope", "").is_err());
+        assert!(check_name("scope", "_default").is_err());
+        assert!(check_name("scope", "%tenant").is_err());
+        assert!(check_name("collection", "a.b").is_err());
+        assert!(check_name("collection", "kv` WHERE 1=1 --").is_err());
+        assert!(check_name("collection", &"c".repeat(MAX_NAME_LENGTH + 1)).is_err());
+    }
+}
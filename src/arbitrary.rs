@@ -0,0 +1,37 @@
+//! Strategies generating the inputs of property tests
+//!
+//! Strings mix ASCII, JSON punctuation, control characters, multi-byte characters and the
+//! separators the provider puts in document keys, at lengths up to a few kilobytes. proptest
+//! checks each property against 256 generated inputs and shrinks a failing one to a minimal case.
+use proptest::prelude::*;
+
+/// Characters strings are drawn from, besides random code points
+const ALPHABET: &[char] = &[
+    'a', 'Z', '0', ' ', ':', '/', '%', '"', '\\', '{', '}', '[', ']', ',', '\n', '\t', '\0', 'é',
+    'ß', '€', '𝄞', '\u{feff}',
+];
+
+/// Strings of up to `max_len` characters
+pub(crate) fn string(max_len: usize) -> impl Strategy<Value = String> {
+    let character = prop_oneof![3 => proptest::sample::select(ALPHABET), 1 => any::<char>()];
+    proptest::collection::vec(character, 0..=max_len).prop_map(|chars| chars.into_iter().collect())
+}
+
+/// JSON texts: a scalar, or an array or object of them
+pub(crate) fn json() -> impl Strategy<Value = String> {
+    let scalar = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(serde_json::Value::from),
+        // quarters print and parse back exactly
+        any::<i32>().prop_map(|n| serde_json::Value::from(n as f64 / 4.0)),
+        string(32).prop_map(serde_json::Value::from),
+    ];
+    prop_oneof![
+        scalar.clone(),
+        proptest::collection::vec(scalar.clone(), 0..16).prop_map(serde_json::Value::from),
+        proptest::collection::vec((string(16), scalar), 0..16)
+            .prop_map(|entries| serde_json::Value::Object(entries.into_iter().collect())),
+    ]
+    .prop_map(|value| value.to_string())
+}
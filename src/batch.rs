@@ -0,0 +1,105 @@
+//! Reads and writes of many keys in one request
+//!
+//! `GetMany`, `SetMany` and `DelMany` run the `get`, `set` or `del` of every key, up to
+//! `batch_concurrency` keys at a time, so everything the link does for single keys (codec,
+//! chunks, quota, audit, write-behind) applies to each key of a batch. A key that fails does not
+//! fail the batch: its result carries the error and the other keys go on. Results are returned
+//! in the order of the keys.
+use futures::{Future, StreamExt};
+use wasmcloud_interface_keyvalue::SetRequest;
+
+use crate::interface::{GetManyResult, KeyResult};
+use crate::{del_value, get_value, set_value, CouchbaseLink};
+
+/// Run an operation on every item, batch_concurrency items at a time, returning the results in
+/// the order of the items
+async fn each<I, R, F>(
+    link: &CouchbaseLink,
+    items: I,
+    operation: impl FnMut(I::Item) -> F,
+) -> Vec<R>
+where
+    I: IntoIterator,
+    F: Future<Output = R>,
+{
+    futures::stream::iter(items)
+        .map(operation)
+        .buffered(link.config.batch_concurrency)
+        .collect()
+        .await
+}
+
+/// Get the values of keys
+pub(crate) async fn get_many(
+    link: &CouchbaseLink,
+    op: &str,
+    keys: &[String],
+) -> Vec<GetManyResult> {
+    each(link, keys.to_vec(), |key| async move {
+        match get_value(link, op, &key).await {
+            Ok(r) => GetManyResult {
+                key,
+                exists: r.exists,
+                value: r.value,
+                error: None,
+            },
+            Err(e) => GetManyResult {
+                key,
+                error: Some(e.to_string()),
+                ..Default::default()
+            },
+        }
+    })
+    .await
+}
+
+/// Set the values of keys
+pub(crate) async fn set_many(
+    link: &CouchbaseLink,
+    op: &str,
+    values: &[SetRequest],
+) -> Vec<KeyResult> {
+    each(link, values.to_vec(), |value| async move {
+        key_result(&value.key, set_value(link, op, &value).await.map(|_| true))
+    })
+    .await
+}
+
+/// Delete keys
+pub(crate) async fn del_many(link: &CouchbaseLink, op: &str, keys: &[String]) -> Vec<KeyResult> {
+    each(link, keys.to_vec(), |key| async move {
+        key_result(&key, del_value(link, op, &key).await)
+    })
+    .await
+}
+
+fn key_result(key: &str, result: wasmbus_rpc::error::RpcResult<bool>) -> KeyResult {
+    match result {
+        Ok(success) => KeyResult {
+            key: key.to_string(),
+            success,
+            error: None,
+        },
+        Err(e) => KeyResult {
+            key: key.to_string(),
+            success: false,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmbus_rpc::error::RpcError;
+
+    #[test]
+    fn reports_each_key() {
+        let done = key_result("a", Ok(true));
+        assert!(done.success && done.error.is_none());
+        let failed = key_result("b", Err(RpcError::Timeout("slow".to_string())));
+        assert_eq!(failed.key, "b");
+        assert!(!failed.success);
+        assert!(failed.error.unwrap().contains("slow"));
+    }
+}
@@ -0,0 +1,950 @@
+//! Blobstore containers and objects stored as documents of the link
+//!
+//! A container is a document, `kvcouchbase_blob/<container>`, holding
+//! `{"kvcouchbase_container": {"created_at"}}`, and each of its objects a document,
+//! `kvcouchbase_blob/<container>/<object>`, holding `{"kvcouchbase_object": {...}}`: the object's
+//! length, content type and encoding, and modification time. The content of an object sent in one
+//! chunk of up to the link's blob chunk size is stored in the same document, base64 in
+//! `kvcouchbase_data`. Larger objects are split into chunk documents,
+//! `kvcouchbase_blob_chunk/<container>/<generation>/<n>`, each holding up to a chunk size of bytes,
+//! base64, and the object document lists their lengths, so a range is read from the chunks
+//! covering it only. As with chunked values, the chunks are written before the object document,
+//! and each object gets a new generation, so the chunks of the object it replaces are removed
+//! only once it is written.
+//!
+//! Objects uploaded with `PutChunk` have their chunks written as they arrive. The link keeps the
+//! uploads in progress, so the chunks of an upload must be sent to the provider instance that
+//! started it, and the chunks of an upload that is neither completed nor cancelled stay.
+use std::{collections::HashMap, sync::Mutex};
+
+use couchbase::{
+    CouchbaseError, ExistsOptions, GetOptions, InsertOptions, LookupInOptions, LookupInSpec,
+    RemoveOptions, UpsertOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{warn, Level};
+use wasmbus_rpc::{
+    error::{RpcError, RpcResult},
+    Timestamp,
+};
+
+use crate::blobstore::{
+    Chunk, ContainerMetadata, GetObjectRequest, GetObjectResponse, ItemResult, ListObjectsRequest,
+    ListObjectsResponse, MultiResult, ObjectMetadata, PutChunkRequest, PutObjectRequest,
+    PutObjectResponse,
+};
+use crate::query::{self, N1ql};
+use crate::{dead_letter, errors::to_rpc_err, quota, scan, CouchbaseLink};
+
+/// Field of a container document
+const CONTAINER: &str = "kvcouchbase_container";
+/// Field of an object document
+const OBJECT: &str = "kvcouchbase_object";
+/// Field of an object document holding the content of an object that is not chunked
+const DATA: &str = "kvcouchbase_data";
+/// Prefix of the keys of containers and objects
+const BLOBS: &str = "kvcouchbase_blob/";
+/// Prefix of the keys of object chunks
+const CHUNKS: &str = "kvcouchbase_blob_chunk/";
+
+/// Bytes of an object chunk when the link sets no chunk_size
+const DEFAULT_BLOB_CHUNK_SIZE: usize = 1024 * 1024;
+/// Objects returned by a ListObjects at most
+const MAX_LIST_ITEMS: u32 = 1000;
+
+/// A container as stored
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredContainer {
+    created_at: Timestamp,
+}
+
+/// An object as stored, without its content
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+struct StoredObject {
+    content_length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<String>,
+    last_modified: Timestamp,
+    /// generation of the object's chunks, None if its content is in its document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    generation: Option<String>,
+    /// length of each chunk, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<u64>,
+}
+
+impl StoredObject {
+    fn metadata(self, container_id: &str, object_id: String) -> ObjectMetadata {
+        ObjectMetadata {
+            container_id: container_id.to_string(),
+            object_id,
+            content_length: self.content_length,
+            last_modified: Some(self.last_modified),
+            content_type: self.content_type,
+            content_encoding: self.content_encoding,
+        }
+    }
+}
+
+/// An object being uploaded in chunks
+struct Upload {
+    container_id: String,
+    object_id: String,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    generation: String,
+    /// length of each chunk written
+    chunks: Vec<u64>,
+    /// bytes received
+    length: u64,
+}
+
+/// The uploads in progress of a link, by stream id
+#[derive(Default)]
+pub(crate) struct Uploads(Mutex<HashMap<String, Upload>>);
+
+impl Uploads {
+    fn take(&self, stream_id: &str) -> Option<Upload> {
+        self.0.lock().unwrap().remove(stream_id)
+    }
+
+    fn put(&self, stream_id: String, upload: Upload) {
+        self.0.lock().unwrap().insert(stream_id, upload);
+    }
+}
+
+/// Returns the document key of a container, refusing names that would not make one
+fn container_key(link: &CouchbaseLink, container_id: &str) -> RpcResult<String> {
+    if container_id.is_empty() || container_id.contains('/') {
+        return Err(RpcError::InvalidParameter(format!(
+            "invalid container name '{}': it must be non-empty and without '/'",
+            container_id
+        )));
+    }
+    link.doc_key(&format!("{}{}", BLOBS, container_id))
+}
+
+/// Returns the document key of an object
+fn object_key(link: &CouchbaseLink, container_id: &str, object_id: &str) -> RpcResult<String> {
+    if object_id.is_empty() {
+        return Err(RpcError::InvalidParameter(
+            "object name must be non-empty".to_string(),
+        ));
+    }
+    Ok(format!(
+        "{}/{}",
+        container_key(link, container_id)?,
+        object_id
+    ))
+}
+
+/// Returns the actor's key of a document, as audited
+fn actor_key<'a>(link: &CouchbaseLink, key: &'a str) -> &'a str {
+    // the namespace prefix is the same for every key of the link
+    &key[link.namespace().len()..]
+}
+
+fn chunk_key(link: &CouchbaseLink, container_id: &str, generation: &str, n: usize) -> String {
+    link.config.doc_key(
+        &link.actor_id,
+        &format!("{}{}/{}/{}", CHUNKS, container_id, generation, n),
+    )
+}
+
+/// Returns the bytes held by a chunk: base64 stores three bytes in four of the link's chunk_size
+fn chunk_bytes(link: &CouchbaseLink) -> usize {
+    link.config
+        .chunk_size()
+        .map_or(DEFAULT_BLOB_CHUNK_SIZE, |size| size / 4 * 3)
+}
+
+/// Returns whether the document at `key` exists
+async fn exists(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<bool> {
+    let options = kv_options!(link, ExistsOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move { connection.collection.exists(doc_key, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => Ok(r.exists()),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+pub(crate) async fn container_exists(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+) -> RpcResult<bool> {
+    exists(link, op, &container_key(link, container_id)?).await
+}
+
+/// Create a container, doing nothing if it exists
+pub(crate) async fn create_container(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+) -> RpcResult<()> {
+    let key = container_key(link, container_id)?;
+    let container = json!({ CONTAINER: StoredContainer { created_at: Timestamp::now() } });
+    let size = quota::doc_size(&key, &container);
+    link.check_quota(size)?;
+    let options = kv_options!(link, InsertOptions::default());
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let operation = async move {
+        connection
+            .collection
+            .insert(doc_key, container, options)
+            .await
+    };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            link.audit(op, actor_key(link, &key), Some(r.cas()));
+            Ok(())
+        }
+        Err(CouchbaseError::DocumentExists { .. }) => Ok(()),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            Err(e)
+        }
+    }
+}
+
+pub(crate) async fn container_info(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+) -> RpcResult<ContainerMetadata> {
+    let key = container_key(link, container_id)?;
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.clone(), vec![LookupInSpec::get(CONTAINER)]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) if r.exists(0) => {
+            let container: StoredContainer = r.content(0).map_err(to_rpc_err)?;
+            Ok(ContainerMetadata {
+                container_id: container_id.to_string(),
+                created_at: Some(container.created_at),
+            })
+        }
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Err(RpcError::Other(format!(
+            "container {} not found",
+            container_id
+        ))),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Returns the containers of the link, ordered by name
+pub(crate) async fn list_containers(
+    link: &CouchbaseLink,
+    op: &str,
+) -> RpcResult<Vec<ContainerMetadata>> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: String,
+        container: StoredContainer,
+    }
+    let prefix = link.doc_key(BLOBS)?;
+    let statement = N1ql::on(
+        "SELECT META(d).id AS id, d.kvcouchbase_container AS container FROM {keyspace} AS d \
+         WHERE META(d).id LIKE $pattern AND d.kvcouchbase_container IS VALUED \
+         ORDER BY META(d).id",
+        &link.config,
+    );
+    let options =
+        query::options(link, op).named_parameters(json!({ "pattern": scan::like_prefix(&prefix) }));
+    let rows: Vec<Row> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ContainerMetadata {
+            container_id: row.id[prefix.len()..].to_string(),
+            created_at: Some(row.container.created_at),
+        })
+        .collect())
+}
+
+/// Remove containers with their objects, returning the containers that could not be removed
+pub(crate) async fn remove_containers(
+    link: &CouchbaseLink,
+    op: &str,
+    container_ids: &[String],
+) -> MultiResult {
+    let mut failed = Vec::new();
+    for container_id in container_ids {
+        let error = match remove_container(link, op, container_id).await {
+            Ok(true) => continue,
+            Ok(false) => format!("container {} not found", container_id),
+            Err(e) => e.to_string(),
+        };
+        failed.push(ItemResult {
+            key: container_id.clone(),
+            success: false,
+            error: Some(error),
+        });
+    }
+    failed
+}
+
+/// Remove a container's objects and chunks, with N1QL, then the container. Returns whether the
+/// container existed.
+async fn remove_container(link: &CouchbaseLink, op: &str, container_id: &str) -> RpcResult<bool> {
+    let key = container_key(link, container_id)?;
+    if !exists(link, op, &key).await? {
+        return Ok(false);
+    }
+    scan::delete_by_prefix(link, op, &format!("{}{}/", BLOBS, container_id)).await?;
+    scan::delete_by_prefix(link, op, &format!("{}{}/", CHUNKS, container_id)).await?;
+    let options = kv_options!(link, RemoveOptions::default());
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let operation = async move { connection.collection.remove(doc_key, options).await };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.sub(quota::doc_size(&key, &json!({ CONTAINER: {} })));
+            }
+            link.audit(op, actor_key(link, &key), Some(r.cas()));
+            Ok(true)
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            Err(e)
+        }
+    }
+}
+
+pub(crate) async fn object_exists(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    object_id: &str,
+) -> RpcResult<bool> {
+    exists(link, op, &object_key(link, container_id, object_id)?).await
+}
+
+/// Returns the object stored at `key`, without its content
+async fn lookup(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Option<StoredObject>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![LookupInSpec::get(OBJECT)]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) if r.exists(0) => Ok(Some(r.content(0).map_err(to_rpc_err)?)),
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+pub(crate) async fn object_info(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    object_id: &str,
+) -> RpcResult<ObjectMetadata> {
+    let key = object_key(link, container_id, object_id)?;
+    match lookup(link, op, &key).await? {
+        Some(object) => Ok(object.metadata(container_id, object_id.to_string())),
+        None => Err(RpcError::Other(format!(
+            "object {} not found in container {}",
+            object_id, container_id
+        ))),
+    }
+}
+
+/// Returns the objects of a container in the request's bounds, ordered by name
+pub(crate) async fn list_objects(
+    link: &CouchbaseLink,
+    op: &str,
+    arg: &ListObjectsRequest,
+) -> RpcResult<ListObjectsResponse> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: String,
+        object: StoredObject,
+    }
+    let prefix = format!("{}/", container_key(link, &arg.container_id)?);
+    let bound = |id: &Option<String>| id.as_ref().map(|id| format!("{}{}", prefix, id));
+    let (from, after) = match &arg.continuation {
+        Some(continuation) => (None, bound(&Some(continuation.clone()))),
+        None => (bound(&arg.start_with), None),
+    };
+    let limit = match arg.max_items {
+        Some(max) if max > 0 => max.min(MAX_LIST_ITEMS),
+        _ => MAX_LIST_ITEMS,
+    };
+    let statement = N1ql::on(
+        "SELECT META(d).id AS id, d.kvcouchbase_object AS object FROM {keyspace} AS d \
+         WHERE META(d).id LIKE $pattern AND d.kvcouchbase_object IS VALUED \
+         AND ($from IS NULL OR META(d).id >= $from) AND ($after IS NULL OR META(d).id > $after) \
+         AND ($through IS NULL OR META(d).id <= $through) \
+         AND ($before IS NULL OR META(d).id < $before) \
+         ORDER BY META(d).id LIMIT $limit",
+        &link.config,
+    );
+    // one more object than returned tells whether the list is complete
+    let options = query::options(link, op).named_parameters(json!({
+        "pattern": scan::like_prefix(&prefix),
+        "from": from,
+        "after": after,
+        "through": bound(&arg.end_with),
+        "before": bound(&arg.end_before),
+        "limit": limit + 1,
+    }));
+    let mut rows: Vec<Row> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    let is_last = rows.len() <= limit as usize;
+    rows.truncate(limit as usize);
+    let objects: Vec<ObjectMetadata> = rows
+        .into_iter()
+        .map(|row| {
+            let object_id = row.id[prefix.len()..].to_string();
+            row.object.metadata(&arg.container_id, object_id)
+        })
+        .collect();
+    let continuation = match is_last {
+        true => None,
+        false => objects.last().map(|object| object.object_id.clone()),
+    };
+    Ok(ListObjectsResponse {
+        objects,
+        is_last,
+        continuation,
+    })
+}
+
+/// Remove objects of a container, returning the objects that could not be removed
+pub(crate) async fn remove_objects(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    object_ids: &[String],
+) -> MultiResult {
+    let mut failed = Vec::new();
+    for object_id in object_ids {
+        let error = match remove_object(link, op, container_id, object_id).await {
+            Ok(true) => continue,
+            Ok(false) => format!("object {} not found", object_id),
+            Err(e) => e.to_string(),
+        };
+        failed.push(ItemResult {
+            key: object_id.clone(),
+            success: false,
+            error: Some(error),
+        });
+    }
+    failed
+}
+
+/// Remove an object and its chunks. Returns whether the object existed.
+async fn remove_object(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    object_id: &str,
+) -> RpcResult<bool> {
+    let key = object_key(link, container_id, object_id)?;
+    let Some(object) = lookup(link, op, &key).await? else {
+        return Ok(false);
+    };
+    let options = kv_options!(link, RemoveOptions::default());
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let operation = async move { connection.collection.remove(doc_key, options).await };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.sub(key.len() as u64 + inline_size(&object));
+            }
+            link.audit(op, actor_key(link, &key), Some(r.cas()));
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => return Ok(false),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            return Err(e);
+        }
+    }
+    if let Some(generation) = &object.generation {
+        remove_chunks(link, op, container_id, generation, &object.chunks).await;
+    }
+    Ok(true)
+}
+
+/// Returns the approximate length of an object document's JSON
+fn inline_size(object: &StoredObject) -> u64 {
+    let data = match object.generation {
+        Some(_) => 0,
+        None => base64_len(object.content_length),
+    };
+    json!({ OBJECT: object }).to_string().len() as u64 + data
+}
+
+/// Returns the length of the JSON string holding `len` bytes in base64
+fn base64_len(len: u64) -> u64 {
+    len.div_ceil(3) * 4 + 2
+}
+
+/// Start storing an object. An object sent in one chunk is written at once; otherwise its first
+/// chunk is written and the response holds the stream id of the upload.
+pub(crate) async fn put_object(
+    link: &CouchbaseLink,
+    op: &str,
+    arg: &PutObjectRequest,
+) -> RpcResult<PutObjectResponse> {
+    let chunk = &arg.chunk;
+    let key = object_key(link, &chunk.container_id, &chunk.object_id)?;
+    if !container_exists(link, op, &chunk.container_id).await? {
+        return Err(RpcError::Other(format!(
+            "container {} not found",
+            chunk.container_id
+        )));
+    }
+    if chunk.is_last && chunk.bytes.len() <= chunk_bytes(link) {
+        let object = StoredObject {
+            content_length: chunk.bytes.len() as u64,
+            content_type: arg.content_type.clone(),
+            content_encoding: arg.content_encoding.clone(),
+            last_modified: Timestamp::now(),
+            generation: None,
+            chunks: Vec::new(),
+        };
+        write_object(
+            link,
+            op,
+            &key,
+            &chunk.container_id,
+            object,
+            Some(&chunk.bytes),
+        )
+        .await?;
+        return Ok(PutObjectResponse { stream_id: None });
+    }
+    let mut upload = Upload {
+        container_id: chunk.container_id.clone(),
+        object_id: chunk.object_id.clone(),
+        content_type: arg.content_type.clone(),
+        content_encoding: arg.content_encoding.clone(),
+        generation: format!("{:016x}", rand::random::<u64>()),
+        chunks: Vec::new(),
+        length: 0,
+    };
+    append(link, op, &mut upload, &chunk.bytes).await?;
+    if chunk.is_last {
+        finish(link, op, upload).await?;
+        return Ok(PutObjectResponse { stream_id: None });
+    }
+    let stream_id = wasmbus_rpc::provider::make_uuid();
+    link.uploads.put(stream_id.clone(), upload);
+    Ok(PutObjectResponse {
+        stream_id: Some(stream_id),
+    })
+}
+
+/// Continue an upload started by [put_object], writing the object with its last chunk, or cancel
+/// it and remove the chunks written
+pub(crate) async fn put_chunk(
+    link: &CouchbaseLink,
+    op: &str,
+    arg: &PutChunkRequest,
+) -> RpcResult<()> {
+    let stream_id = arg
+        .stream_id
+        .as_deref()
+        .ok_or_else(|| RpcError::InvalidParameter("streamId is required".to_string()))?;
+    let mut upload = link.uploads.take(stream_id).ok_or_else(|| {
+        RpcError::InvalidParameter(format!("no upload in progress with streamId {}", stream_id))
+    })?;
+    if arg.cancel_and_remove {
+        remove_chunks(
+            link,
+            op,
+            &upload.container_id,
+            &upload.generation,
+            &upload.chunks,
+        )
+        .await;
+        return Ok(());
+    }
+    let chunk = &arg.chunk;
+    let error = if chunk.container_id != upload.container_id || chunk.object_id != upload.object_id
+    {
+        Some(format!("streamId {} uploads another object", stream_id))
+    } else if chunk.offset != upload.length {
+        Some(format!(
+            "chunk at offset {} out of order, expected offset {}",
+            chunk.offset, upload.length
+        ))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        link.uploads.put(stream_id.to_string(), upload);
+        return Err(RpcError::InvalidParameter(error));
+    }
+    // a chunk that could not be written may be sent again
+    if let Err(e) = append(link, op, &mut upload, &chunk.bytes).await {
+        link.uploads.put(stream_id.to_string(), upload);
+        return Err(e);
+    }
+    match chunk.is_last {
+        true => finish(link, op, upload).await,
+        false => {
+            link.uploads.put(stream_id.to_string(), upload);
+            Ok(())
+        }
+    }
+}
+
+/// Write bytes received for an upload as its next chunks. Chunks written before a failure are
+/// removed.
+async fn append(
+    link: &CouchbaseLink,
+    op: &str,
+    upload: &mut Upload,
+    bytes: &[u8],
+) -> RpcResult<()> {
+    let first = upload.chunks.len();
+    let pieces: Vec<&[u8]> = bytes.chunks(chunk_bytes(link)).collect();
+    let writes = pieces.iter().enumerate().map(|(i, piece)| {
+        let key = chunk_key(link, &upload.container_id, &upload.generation, first + i);
+        let content = Value::String(base64::encode(piece));
+        let options = kv_options!(link, UpsertOptions::default());
+        let connection = link.connection();
+        async move {
+            let size = quota::doc_size(&key, &content);
+            link.check_quota(size)?;
+            let doc_key = key.clone();
+            let operation = async move {
+                connection
+                    .collection
+                    .upsert(doc_key, content, options)
+                    .await
+            };
+            link.execute(op, Some(&key), operation)
+                .await
+                .map_err(to_rpc_err)?;
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            Ok(())
+        }
+    });
+    if let Err(e) = futures::future::try_join_all(writes).await {
+        let lengths: Vec<u64> = pieces.iter().map(|piece| piece.len() as u64).collect();
+        let generation = upload.generation.clone();
+        remove_chunks_from(link, op, &upload.container_id, &generation, first, &lengths).await;
+        return Err(e);
+    }
+    upload
+        .chunks
+        .extend(pieces.iter().map(|piece| piece.len() as u64));
+    upload.length += bytes.len() as u64;
+    Ok(())
+}
+
+/// Write the object document of a completed upload
+async fn finish(link: &CouchbaseLink, op: &str, upload: Upload) -> RpcResult<()> {
+    let key = object_key(link, &upload.container_id, &upload.object_id)?;
+    let object = StoredObject {
+        content_length: upload.length,
+        content_type: upload.content_type,
+        content_encoding: upload.content_encoding,
+        last_modified: Timestamp::now(),
+        generation: Some(upload.generation),
+        chunks: upload.chunks,
+    };
+    let written = write_object(link, op, &key, &upload.container_id, object.clone(), None).await;
+    if written.is_err() {
+        if let Some(generation) = &object.generation {
+            remove_chunks(link, op, &upload.container_id, generation, &object.chunks).await;
+        }
+    }
+    written
+}
+
+/// Write an object document, with its content if it is not chunked, then remove the chunks of the
+/// object it replaces
+async fn write_object(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    container_id: &str,
+    object: StoredObject,
+    data: Option<&[u8]>,
+) -> RpcResult<()> {
+    let replaced = lookup(link, op, key).await?;
+    let mut content = json!({ OBJECT: object });
+    if let Some(data) = data {
+        content[DATA] = Value::String(base64::encode(data));
+    }
+    let size = quota::doc_size(key, &content);
+    link.check_quota(size)?;
+    let options = kv_options!(link, UpsertOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move {
+        connection
+            .collection
+            .upsert(doc_key, content, options)
+            .await
+    };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            link.audit(op, actor_key(link, key), Some(r.cas()));
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, key, &e);
+            return Err(e);
+        }
+    }
+    if let Some(replaced) = replaced {
+        if let Some(quota) = &link.quota {
+            quota.sub(key.len() as u64 + inline_size(&replaced));
+        }
+        if let Some(generation) = &replaced.generation {
+            remove_chunks(link, op, container_id, generation, &replaced.chunks).await;
+        }
+    }
+    Ok(())
+}
+
+/// Read an object, or the range of it the request asks for
+pub(crate) async fn get_object(
+    link: &CouchbaseLink,
+    op: &str,
+    arg: &GetObjectRequest,
+) -> RpcResult<GetObjectResponse> {
+    let key = object_key(link, &arg.container_id, &arg.object_id)?;
+    let Some(object) = lookup(link, op, &key).await? else {
+        return Ok(GetObjectResponse {
+            success: false,
+            error: Some(format!(
+                "object {} not found in container {}",
+                arg.object_id, arg.container_id
+            )),
+            ..Default::default()
+        });
+    };
+    let (start, end) = range(arg, object.content_length);
+    let bytes = match &object.generation {
+        _ if start >= end => Vec::new(),
+        None => read_data(link, op, &key).await?[start as usize..end as usize].to_vec(),
+        Some(generation) => {
+            read_chunks(
+                link,
+                op,
+                &arg.container_id,
+                generation,
+                &object.chunks,
+                start,
+                end,
+            )
+            .await?
+        }
+    };
+    Ok(GetObjectResponse {
+        success: true,
+        error: None,
+        content_length: bytes.len() as u64,
+        initial_chunk: Some(Chunk {
+            object_id: arg.object_id.clone(),
+            container_id: arg.container_id.clone(),
+            bytes,
+            offset: start,
+            is_last: true,
+        }),
+        content_type: object.content_type,
+        content_encoding: object.content_encoding,
+    })
+}
+
+/// Returns the bytes of an object a request asks for, from `start` included to `end` excluded
+fn range(arg: &GetObjectRequest, length: u64) -> (u64, u64) {
+    let start = arg.range_start.unwrap_or(0).min(length);
+    let end = arg
+        .range_end
+        .map_or(length, |end| end.saturating_add(1))
+        .min(length);
+    (start, end.max(start))
+}
+
+/// Read the content stored in an object document
+async fn read_data(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Vec<u8>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![LookupInSpec::get(DATA)]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    let data: String = match link.execute(op, Some(key), operation).await {
+        Ok(r) if r.exists(0) => r.content(0).map_err(to_rpc_err)?,
+        Ok(_) => String::new(),
+        Err(CouchbaseError::DocumentNotFound { .. }) => {
+            return Err(RpcError::Other(
+                "object removed while it was read, retry".to_string(),
+            ))
+        }
+        Err(e) => return Err(to_rpc_err(e)),
+    };
+    base64::decode(data).map_err(|e| RpcError::Other(format!("object is corrupt: {}", e)))
+}
+
+/// Read the chunks holding the bytes of an object from `start` included to `end` excluded
+async fn read_chunks(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    generation: &str,
+    chunks: &[u64],
+    start: u64,
+    end: u64,
+) -> RpcResult<Vec<u8>> {
+    let mut offset = 0;
+    let mut covering = Vec::new();
+    for (n, len) in chunks.iter().enumerate() {
+        if offset < end && offset + len > start {
+            covering.push((n, offset));
+        }
+        offset += len;
+    }
+    let first = covering.first().map_or(0, |(_, offset)| *offset);
+    let reads = covering.into_iter().map(|(n, _)| {
+        let key = chunk_key(link, container_id, generation, n);
+        let options = kv_options!(link, GetOptions::default());
+        let connection = link.connection();
+        async move {
+            let doc_key = key.clone();
+            let operation = async move { connection.collection.get(doc_key, options).await };
+            let data = match link.execute(op, Some(&key), operation).await {
+                Ok(r) => r.content::<String>().map_err(to_rpc_err)?,
+                // removed by a put or remove of the object since it was looked up
+                Err(CouchbaseError::DocumentNotFound { .. }) => {
+                    return Err(RpcError::Other(
+                        "object changed while it was read, retry".to_string(),
+                    ))
+                }
+                Err(e) => return Err(to_rpc_err(e)),
+            };
+            base64::decode(data).map_err(|e| RpcError::Other(format!("object is corrupt: {}", e)))
+        }
+    });
+    let bytes = futures::future::try_join_all(reads).await?.concat();
+    let from = (start - first) as usize;
+    let to = ((end - first) as usize).min(bytes.len());
+    Ok(bytes[from.min(to)..to].to_vec())
+}
+
+/// Remove the chunks of an object
+async fn remove_chunks(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    generation: &str,
+    chunks: &[u64],
+) {
+    remove_chunks_from(link, op, container_id, generation, 0, chunks).await
+}
+
+/// Remove the chunks of a generation from the `first`, given their lengths. Chunks that cannot be
+/// removed are logged and left.
+async fn remove_chunks_from(
+    link: &CouchbaseLink,
+    op: &str,
+    container_id: &str,
+    generation: &str,
+    first: usize,
+    chunks: &[u64],
+) {
+    let removals = chunks.iter().enumerate().map(|(i, len)| {
+        let key = chunk_key(link, container_id, generation, first + i);
+        let options = kv_options!(link, RemoveOptions::default());
+        let connection = link.connection();
+        async move {
+            let doc_key = key.clone();
+            let operation = async move { connection.collection.remove(doc_key, options).await };
+            match link.execute(op, Some(&key), operation).await {
+                Ok(_) => {
+                    if let Some(quota) = &link.quota {
+                        quota.sub(key.len() as u64 + base64_len(*len));
+                    }
+                    None
+                }
+                Err(CouchbaseError::DocumentNotFound { .. }) => None,
+                Err(e) => Some(e),
+            }
+        }
+    });
+    let failures: Vec<_> = futures::future::join_all(removals)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    if let Some(e) = failures.first() {
+        if link.logs(Level::WARN) {
+            warn!(
+                actor_id = %link.actor_id,
+                generation,
+                failed = failures.len(),
+                "couchbase object chunks could not be removed: {}",
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_requested_ranges() {
+        let request = |range_start, range_end| GetObjectRequest {
+            range_start,
+            range_end,
+            ..Default::default()
+        };
+        assert_eq!(range(&request(None, None), 10), (0, 10));
+        assert_eq!(range(&request(Some(2), Some(4)), 10), (2, 5));
+        assert_eq!(range(&request(Some(8), Some(100)), 10), (8, 10));
+        assert_eq!(range(&request(Some(12), None), 10), (10, 10));
+        assert_eq!(range(&request(Some(5), Some(3)), 10), (5, 5));
+        assert_eq!(range(&request(None, Some(u64::MAX)), 10), (0, 10));
+        assert_eq!(base64_len(4), "\"AAAAAA==\"".len() as u64);
+    }
+}
@@ -0,0 +1,205 @@
+//! In-process bloom filters of set members, so that checking a member a set does not hold needs
+//! no round trip
+//!
+//! With `set_filters` set, `SetContains` keeps a bloom filter of the members of the last
+//! `set_filters` sets it checked. A filter is built from the set the first time it is checked,
+//! and rebuilt lazily, by the first check after `set_filter_refresh`. Members the filter does not
+//! hold are reported absent without reading the set; members it may hold are confirmed with the
+//! cluster, so a false positive costs a round trip but never a wrong answer. `set_add` adds its
+//! member to the filter before writing it, so the link's own additions are never missed; a
+//! filter built from a read that raced with one of them is dropped rather than kept.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Bits per member a filter is sized for, for a false positive rate of about 1%
+const BITS_PER_MEMBER: usize = 10;
+/// Bit positions set per member, optimal for BITS_PER_MEMBER
+const HASHES: u32 = 7;
+/// Members a filter is sized for beyond twice those of the set it is built from, so sets can
+/// grow before their filter is rebuilt
+const SPARE_MEMBERS: usize = 64;
+
+/// Bloom filter of the members of a set
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    /// members the filter holds, counting repeated insertions
+    len: usize,
+    /// members the filter is sized for, beyond which its false positive rate grows
+    capacity: usize,
+}
+
+impl BloomFilter {
+    pub(crate) fn with_members(members: &[String]) -> Self {
+        let capacity = members.len() * 2 + SPARE_MEMBERS;
+        let mut filter = BloomFilter {
+            bits: vec![0; (capacity * BITS_PER_MEMBER).div_ceil(64)],
+            len: 0,
+            capacity,
+        };
+        for member in members {
+            filter.insert(member);
+        }
+        filter
+    }
+
+    pub(crate) fn insert(&mut self, member: &str) {
+        for bit in self.positions(member).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Returns false if the member was never inserted, true if it may have been
+    pub(crate) fn may_contain(&self, member: &str) -> bool {
+        self.positions(member)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether the filter holds more members than it is sized for
+    fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    /// Bit positions of a member, by double hashing the halves of its hash
+    fn positions(&self, member: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        member.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as u32 as u64, hash >> 32);
+        let bits = (self.bits.len() * 64) as u64;
+        (0..HASHES as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+/// Filter of a set and when it was built and last used
+#[derive(Debug)]
+struct Filter {
+    members: BloomFilter,
+    built: Instant,
+    used: Instant,
+}
+
+/// Bloom filters of the sets a link checks most recently
+#[derive(Debug)]
+pub(crate) struct SetFilters {
+    /// sets a filter is kept for
+    capacity: usize,
+    /// age beyond which a filter is rebuilt
+    refresh: Duration,
+    /// filters by set document key
+    filters: Mutex<HashMap<String, Filter>>,
+    /// insertions so far, to tell builds racing with them
+    insertions: AtomicU64,
+}
+
+impl SetFilters {
+    pub(crate) fn new(capacity: usize, refresh: Duration) -> Self {
+        SetFilters {
+            capacity,
+            refresh,
+            filters: Mutex::new(HashMap::new()),
+            insertions: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `member` may be in the set at `key`, or None if the set has no filter to tell,
+    /// because it was never built, is out of date or holds too many members
+    pub(crate) fn may_contain(&self, key: &str, member: &str) -> Option<bool> {
+        let mut filters = self.filters.lock().unwrap();
+        let filter = filters.get_mut(key)?;
+        if filter.built.elapsed() >= self.refresh || filter.members.is_full() {
+            filters.remove(key);
+            return None;
+        }
+        filter.used = Instant::now();
+        Some(filter.members.may_contain(member))
+    }
+
+    /// Returns the value to pass to [build] with members read after this call
+    pub(crate) fn epoch(&self) -> u64 {
+        self.insertions.load(Ordering::SeqCst)
+    }
+
+    /// Keep a filter of `members`, read from the set at `key` after [epoch] returned `epoch`,
+    /// unless a member was inserted since, which the read may have missed. The filter of the
+    /// least recently used set is dropped to make room.
+    pub(crate) fn build(&self, key: &str, members: &[String], epoch: u64) {
+        let filter = BloomFilter::with_members(members);
+        let mut filters = self.filters.lock().unwrap();
+        if self.epoch() != epoch {
+            return;
+        }
+        if filters.len() >= self.capacity && !filters.contains_key(key) {
+            let oldest = filters
+                .iter()
+                .min_by_key(|(_, filter)| filter.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                filters.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        filters.insert(
+            key.to_string(),
+            Filter {
+                members: filter,
+                built: now,
+                used: now,
+            },
+        );
+    }
+
+    /// Add `member` to the filter of the set at `key`, if it has one, before it is written
+    pub(crate) fn insert(&self, key: &str, member: &str) {
+        let mut filters = self.filters.lock().unwrap();
+        self.insertions.fetch_add(1, Ordering::SeqCst);
+        if let Some(filter) = filters.get_mut(key) {
+            filter.members.insert(member);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_members() {
+        let members: Vec<String> = (0..1000).map(|i| format!("member-{}", i)).collect();
+        let filter = BloomFilter::with_members(&members);
+        assert!(members.iter().all(|member| filter.may_contain(member)));
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("absent-{}", i)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        let filters = SetFilters::new(2, Duration::from_secs(60));
+        assert_eq!(filters.may_contain("a", "x"), None);
+        filters.build("a", &["x".to_string()], filters.epoch());
+        assert_eq!(filters.may_contain("a", "x"), Some(true));
+        assert_eq!(filters.may_contain("a", "y"), Some(false));
+        filters.insert("a", "y");
+        assert_eq!(filters.may_contain("a", "y"), Some(true));
+
+        // a build that raced with an insertion is dropped
+        let epoch = filters.epoch();
+        filters.insert("b", "x");
+        filters.build("b", &[], epoch);
+        assert_eq!(filters.may_contain("b", "x"), None);
+
+        // the least recently used filter makes room
+        filters.build("b", &[], filters.epoch());
+        filters.may_contain("a", "x");
+        filters.build("c", &[], filters.epoch());
+        assert_eq!(filters.may_contain("b", "x"), None);
+        assert_eq!(filters.may_contain("a", "x"), Some(true));
+    }
+}
@@ -0,0 +1,63 @@
+//! Bounded retries of the optimistic writes that read a document and write it back with its cas
+//!
+//! A writer that lost the race to another one waits before reading the document again, a random
+//! time whose ceiling doubles with each conflict, so writers contending for the same document
+//! spread out instead of conflicting again in lockstep.
+use std::time::Duration;
+
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+/// Attempts of an optimistic write before giving up on a document that keeps changing
+pub(crate) const MAX_ATTEMPTS: u32 = 10;
+
+/// Longest wait after the first conflict
+const BASE_BACKOFF: Duration = Duration::from_millis(2);
+/// Longest wait after any conflict
+const MAX_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The conflicts of an optimistic write so far
+#[derive(Debug, Default)]
+pub(crate) struct CasRetry {
+    conflicts: u32,
+}
+
+impl CasRetry {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that an attempt to write `key` lost the race to another writer, and wait before
+    /// the next attempt. Fails once MAX_ATTEMPTS attempts conflicted.
+    pub(crate) async fn conflicted(&mut self, key: &str) -> RpcResult<()> {
+        self.conflicts += 1;
+        if self.conflicts >= MAX_ATTEMPTS {
+            return Err(RpcError::Other(format!(
+                "couchbase cas_mismatch (retryable): {} changed concurrently {} times",
+                key, self.conflicts
+            )));
+        }
+        tokio::time::sleep(ceiling(self.conflicts).mul_f64(rand::random::<f64>())).await;
+        Ok(())
+    }
+}
+
+/// Returns the longest wait after `conflicts` conflicts
+fn ceiling(conflicts: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(1 << conflicts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(ceiling(1), BASE_BACKOFF);
+        assert_eq!(ceiling(2), BASE_BACKOFF * 2);
+        assert_eq!(ceiling(4), BASE_BACKOFF * 8);
+        assert_eq!(ceiling(MAX_ATTEMPTS), MAX_BACKOFF);
+        assert_eq!(ceiling(u32::MAX), MAX_BACKOFF);
+    }
+}
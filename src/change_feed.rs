@@ -0,0 +1,375 @@
+//! Change feed: documents changed by anyone, reported to the linked actor as messages
+//!
+//! The Couchbase SDK has no DCP client, so the feed polls N1QL for the documents whose CAS,
+//! a hybrid logical clock in nanoseconds, is newer than the last change reported.
+//! Expirations are detected by tracking the expiration time of the documents seen,
+//! and checking that they are gone once it has passed.
+//! Events are delivered to the actor's wasmcloud:messaging `MessageSubscriber`, and with
+//! `change_feed_delivery` set to `lattice` or `both` published on the lattice's NATS, where the
+//! messaging providers of other actors subscribed to the subject pick them up.
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    sync::Weak,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use couchbase::ExistsOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{warn, Level};
+use wasmbus_rpc::{
+    common::{Context, Message, Transport},
+    core::LinkDefinition,
+    error::{RpcError, RpcResult},
+    provider::ProviderTransport,
+};
+
+use crate::query::{self, N1ql};
+use crate::{errors::to_rpc_err, lattice, scan, CouchbaseLink};
+
+/// Operation name of the feed's queries, in metrics
+const OP: &str = "change_feed";
+
+/// Changes read from Couchbase by one query
+const BATCH_SIZE: u32 = 1000;
+
+/// Documents whose expiration is tracked at most, to bound the feed's memory
+const MAX_TRACKED_EXPIRIES: usize = 100_000;
+
+/// A change reported to the actor, as the JSON body of a message
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ChangeEvent {
+    /// `mutation` for a created or updated document, `expiration` for an expired one
+    pub(crate) event: &'static str,
+    /// the key name, without the link's key prefix
+    pub(crate) key: String,
+    /// cas of the document after the change, 0 for an expiration
+    pub(crate) cas: u64,
+}
+
+/// wasmcloud:messaging message, as received by `MessageSubscriber.HandleMessage`
+#[derive(Debug, Serialize)]
+struct SubMessage {
+    subject: String,
+    #[serde(rename = "replyTo")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<String>,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+/// A document changed, as read by a poll
+#[derive(Deserialize)]
+pub(crate) struct Changed {
+    pub(crate) id: String,
+    pub(crate) cas: u64,
+    /// unix time in seconds at which the document expires, 0 if it does not
+    #[serde(default)]
+    expiration: u64,
+}
+
+/// Expiration times of the documents seen by the feed, to report them once expired
+#[derive(Default)]
+struct Expiries {
+    /// expiration and key, soonest first
+    due: BTreeSet<(u64, String)>,
+    by_key: HashMap<String, u64>,
+}
+
+impl Expiries {
+    /// Track the latest expiration of a key, 0 if it no longer expires.
+    /// Returns false if the key could not be tracked because too many are.
+    fn track(&mut self, key: &str, expiration: u64) -> bool {
+        if let Some(previous) = self.by_key.remove(key) {
+            self.due.remove(&(previous, key.to_string()));
+        }
+        if expiration == 0 {
+            return true;
+        }
+        if self.by_key.len() >= MAX_TRACKED_EXPIRIES {
+            return false;
+        }
+        self.by_key.insert(key.to_string(), expiration);
+        self.due.insert((expiration, key.to_string()));
+        true
+    }
+
+    /// Stop tracking and return the keys whose expiration is before `now`
+    fn take_due(&mut self, now: u64) -> Vec<String> {
+        let mut keys = Vec::new();
+        while let Some((expiration, _)) = self.due.first() {
+            if *expiration > now {
+                break;
+            }
+            let (_, key) = self.due.pop_first().unwrap();
+            self.by_key.remove(&key);
+            keys.push(key);
+        }
+        keys
+    }
+}
+
+/// Periodically report the documents changed since the previous poll to the actor,
+/// and those expired if the link asks for it. Stops when the link is dropped.
+pub(crate) fn spawn_feed(link: Weak<CouchbaseLink>, ld: LinkDefinition, interval: Duration) {
+    tokio::spawn(async move {
+        // only changes made after the link was put are reported
+        let mut since = unix_time().as_nanos() as u64;
+        let mut expiries: Option<Expiries> = None;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let subject = match &link.config.change_feed_subject {
+                Some(subject) => subject.clone(),
+                None => break,
+            };
+            // documents that already expire when the feed starts are tracked too
+            if link.config.change_feed_expirations && expiries.is_none() {
+                match poll(&link, OP, None, &link.config.change_feed_prefix).await {
+                    Ok(expiring) => {
+                        let mut tracked = Expiries::default();
+                        track(&link, &mut tracked, &expiring);
+                        expiries = Some(tracked);
+                    }
+                    Err(e) => {
+                        log_failure(&link, "couchbase change feed query failed", &e);
+                        continue;
+                    }
+                }
+            }
+            let prefix = &link.config.change_feed_prefix;
+            let changes = match poll(&link, OP, Some(since), prefix).await {
+                Ok(changes) => changes,
+                Err(e) => {
+                    log_failure(&link, "couchbase change feed query failed", &e);
+                    continue;
+                }
+            };
+            if let Some(expiries) = expiries.as_mut() {
+                track(&link, expiries, &changes);
+            }
+            for change in changes {
+                since = since.max(change.cas);
+                let event = ChangeEvent {
+                    event: "mutation",
+                    key: change.id,
+                    cas: change.cas,
+                };
+                deliver(&link, &ld, &subject, &event).await;
+            }
+            let due = match expiries.as_mut() {
+                Some(expiries) => expiries.take_due(unix_time().as_secs()),
+                None => continue,
+            };
+            for key in due {
+                match exists(&link, &key).await {
+                    // a document touched meanwhile is reported as a mutation
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let event = ChangeEvent {
+                            event: "expiration",
+                            key,
+                            cas: 0,
+                        };
+                        deliver(&link, &ld, &subject, &event).await;
+                    }
+                    Err(e) => log_failure(&link, "couchbase change feed expiry check failed", &e),
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+pub(crate) fn log_failure(link: &CouchbaseLink, message: &str, e: &RpcError) {
+    if link.logs(Level::WARN) {
+        warn!(actor_id = %link.actor_id, "{}: {}", message, e);
+    }
+}
+
+/// Track the expiration of changed documents
+fn track(link: &CouchbaseLink, expiries: &mut Expiries, changes: &[Changed]) {
+    let mut untracked = 0;
+    for change in changes {
+        if !expiries.track(&change.id, change.expiration) {
+            untracked += 1;
+        }
+    }
+    if untracked > 0 && link.logs(Level::WARN) {
+        warn!(
+            actor_id = %link.actor_id,
+            untracked,
+            "couchbase change feed tracks {} expiring documents, expirations of others are not reported",
+            MAX_TRACKED_EXPIRIES
+        );
+    }
+}
+
+/// Send an event to the actor and/or the lattice, logging failures.
+/// An event the actor fails to handle, or that cannot be published, is not delivered again.
+async fn deliver(link: &CouchbaseLink, ld: &LinkDefinition, subject: &str, event: &ChangeEvent) {
+    let delivery = link.config.change_feed_delivery;
+    if delivery.reaches_actor() {
+        if let Err(e) = publish(ld, subject, event).await {
+            log_undelivered(link, event, "delivered", &e);
+        }
+    }
+    if delivery.reaches_lattice() {
+        if let Err(e) = broadcast(subject, event).await {
+            log_undelivered(link, event, "published", &e);
+        }
+    }
+}
+
+fn log_undelivered(link: &CouchbaseLink, event: &ChangeEvent, action: &str, e: &RpcError) {
+    if link.logs(Level::WARN) {
+        warn!(
+            actor_id = %link.actor_id,
+            key = %link.log_key(&event.key),
+            event = event.event,
+            "couchbase change event could not be {}: {}",
+            action,
+            e
+        );
+    }
+}
+
+/// Returns true if the document of a key, without the link's key prefix, exists
+async fn exists(link: &CouchbaseLink, key: &str) -> RpcResult<bool> {
+    let doc_key = link.doc_key(key)?;
+    let options = kv_options!(link, ExistsOptions::default());
+    let connection = link.connection();
+    let id = doc_key.clone();
+    let operation = async move { connection.collection.exists(id, options).await };
+    let result = link
+        .execute(OP, Some(&doc_key), operation)
+        .await
+        .map_err(to_rpc_err)?;
+    Ok(result.exists())
+}
+
+/// Returns the link's documents starting with `prefix` changed after the cas `since`, oldest
+/// first, or without `since` all those that expire, keyed without the link's key prefix
+pub(crate) async fn poll(
+    link: &CouchbaseLink,
+    op: &str,
+    since: Option<u64>,
+    prefix: &str,
+) -> RpcResult<Vec<Changed>> {
+    let (template, limit) = match since {
+        Some(_) => (
+            "SELECT META(d).id AS id, META(d).cas AS cas, META(d).expiration AS expiration \
+             FROM {keyspace} AS d WHERE META(d).id LIKE $pattern AND META(d).id NOT LIKE $derived \
+             AND META(d).cas > $since ORDER BY META(d).cas LIMIT $limit",
+            BATCH_SIZE as usize,
+        ),
+        None => (
+            "SELECT META(d).id AS id, META(d).cas AS cas, META(d).expiration AS expiration \
+             FROM {keyspace} AS d WHERE META(d).id LIKE $pattern AND META(d).id NOT LIKE $derived \
+             AND META(d).expiration > 0 ORDER BY META(d).cas LIMIT $limit",
+            MAX_TRACKED_EXPIRIES,
+        ),
+    };
+    let statement = N1ql::on(template, &link.config);
+    let pattern = scan::like_prefix(&link.doc_key(prefix)?);
+    let options = query::options(link, op).named_parameters(json!({
+        "pattern": pattern,
+        "derived": scan::like_derived(&link.namespace()),
+        "since": since.unwrap_or_default(),
+        "limit": limit,
+    }));
+    let changed: Vec<Changed> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    // the namespace prefix is the same for every key of the link
+    let namespace = link.namespace().len();
+    Ok(changed
+        .into_iter()
+        .map(|changed| Changed {
+            id: changed.id[namespace..].to_string(),
+            ..changed
+        })
+        .collect())
+}
+
+/// Send a change event to the linked actor as a message on `subject`
+pub(crate) async fn publish(
+    ld: &LinkDefinition,
+    subject: &str,
+    event: &ChangeEvent,
+) -> RpcResult<()> {
+    let arg = wasmbus_rpc::common::serialize(&message(subject, event)?)?;
+    ProviderTransport::new(ld, None)
+        .send(
+            &Context::default(),
+            Message {
+                method: "MessageSubscriber.HandleMessage",
+                arg: Cow::Owned(arg),
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Publish a change event on `subject` of the lattice's NATS, with the same JSON body the actor
+/// receives, for the subscribers of wasmcloud:messaging providers connected to it
+pub(crate) async fn broadcast(subject: &str, event: &ChangeEvent) -> RpcResult<()> {
+    let body = serde_json::to_vec(event).map_err(|e| RpcError::Ser(e.to_string()))?;
+    lattice::publish(subject, body).await
+}
+
+fn message(subject: &str, event: &ChangeEvent) -> RpcResult<SubMessage> {
+    Ok(SubMessage {
+        subject: subject.to_string(),
+        reply_to: None,
+        body: serde_json::to_vec(event).map_err(|e| RpcError::Ser(e.to_string()))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_expiries() {
+        let mut expiries = Expiries::default();
+        expiries.track("a", 200);
+        expiries.track("b", 100);
+        expiries.track("c", 300);
+        // touched to expire later, or no longer expiring
+        expiries.track("a", 400);
+        expiries.track("c", 0);
+        assert_eq!(expiries.take_due(250), ["b"]);
+        assert!(expiries.take_due(250).is_empty());
+        assert_eq!(expiries.take_due(400), ["a"]);
+        assert!(expiries.by_key.is_empty());
+    }
+
+    #[test]
+    fn encodes_events() {
+        let event = ChangeEvent {
+            event: "mutation",
+            key: "greeting".to_string(),
+            cas: 1667383200123456789,
+        };
+        let message = message("changes", &event).unwrap();
+        assert_eq!(message.subject, "changes");
+        let body: serde_json::Value = serde_json::from_slice(&message.body).unwrap();
+        assert_eq!(
+            body,
+            json!({"event": "mutation", "key": "greeting", "cas": 1667383200123456789u64})
+        );
+    }
+}
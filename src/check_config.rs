@@ -0,0 +1,187 @@
+//! `--check-config`: validate link values against a cluster before deploying them
+//!
+//! The link values are read from a JSON or TOML file of `"name": "value"` entries, if one is
+//! given, and from `KVCOUCHBASE_<NAME>` environment variables, which take precedence. The check
+//! parses them like a link would, connects to the cluster, pings the bucket, then upserts, reads
+//! back and removes a test document in the link's collection. Each step is printed with its
+//! outcome, and the check stops at the first failure.
+use std::{collections::HashMap, future::Future, path::Path, time::Duration};
+
+use couchbase::{GetOptions, PingOptions, PingState, RemoveOptions, ServiceType, UpsertOptions};
+use serde_json::json;
+use wasmbus_rpc::core::LinkDefinition;
+
+use crate::config;
+
+/// Prefix of the environment variables holding link values
+const ENV_PREFIX: &str = "KVCOUCHBASE_";
+
+/// How long each step may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Expiry of the test document, in case the check cannot remove it
+const TEST_DOCUMENT_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Run the checks on the link values of `file` and the environment, printing a report.
+/// Returns whether every check passed.
+pub(crate) fn run(file: Option<&str>) -> bool {
+    let ld = match link_definition(file) {
+        Ok(ld) => ld,
+        Err(e) => return report("read", Err(e)),
+    };
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => return report("runtime", Err(e.to_string())),
+    };
+    runtime.block_on(check(&ld))
+}
+
+async fn check(ld: &LinkDefinition) -> bool {
+    let config = match config::load_config(ld) {
+        Ok(config) => config,
+        Err(e) => return report("config", Err(e.to_string())),
+    };
+    report("config", Ok(config.keyspace()));
+    let connection = match step(config::create_collection_conection(&config)).await {
+        Ok(connection) => connection,
+        Err(e) => return report("connect", Err(e)),
+    };
+    report("connect", Ok(String::new()));
+
+    let ping = step(connection.bucket.ping(PingOptions::default()))
+        .await
+        .and_then(|result| {
+            let endpoints = result
+                .endpoints()
+                .get(&ServiceType::KeyValue)
+                .filter(|endpoints| !endpoints.is_empty())
+                .ok_or_else(|| "no key-value endpoints".to_string())?;
+            match endpoints.iter().find(|e| e.state() != PingState::OK) {
+                Some(failed) => Err(format!(
+                    "{} {}: {}",
+                    failed.remote().unwrap_or_default(),
+                    failed.state(),
+                    failed.error().unwrap_or_default()
+                )),
+                None => Ok(format!("{} key-value endpoints", endpoints.len())),
+            }
+        });
+    if !report("ping", ping) {
+        return false;
+    }
+
+    let key = format!("kvcouchbase_check::{}", wasmbus_rpc::provider::make_uuid());
+    let content = json!({ "kvcouchbase_check": key });
+    let options = UpsertOptions::default().expiry(TEST_DOCUMENT_EXPIRY);
+    let upsert = step(connection.collection.upsert(&key, &content, options)).await;
+    if !report("upsert", upsert.map(|_| key.clone())) {
+        return false;
+    }
+    let get = step(connection.collection.get(&key, GetOptions::default()))
+        .await
+        .and_then(|r| r.content::<serde_json::Value>().map_err(|e| e.to_string()))
+        .and_then(|read| match read == content {
+            true => Ok(String::new()),
+            false => Err(format!("read back {} instead of {}", read, content)),
+        });
+    if !report("get", get) {
+        return false;
+    }
+    let remove = step(connection.collection.remove(&key, RemoveOptions::default())).await;
+    report("remove", remove.map(|_| String::new()))
+}
+
+/// Run a step of the check, failing it if it takes longer than STEP_TIMEOUT
+async fn step<T, E: std::fmt::Display>(
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match tokio::time::timeout(STEP_TIMEOUT, future).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", STEP_TIMEOUT)),
+    }
+}
+
+/// Print the outcome of a step. Returns whether it succeeded.
+fn report(step: &str, outcome: Result<String, String>) -> bool {
+    let passed = outcome.is_ok();
+    match outcome {
+        Ok(detail) if detail.is_empty() => println!("{:<8} ok", step),
+        Ok(detail) => println!("{:<8} ok: {}", step, detail),
+        Err(e) => println!("{:<8} FAILED: {}", step, e),
+    }
+    std::io::Write::flush(&mut std::io::stdout()).ok();
+    passed
+}
+
+/// Returns a link definition with the link values of `file`, if any, overridden by those
+/// of the environment
+pub(crate) fn link_definition(file: Option<&str>) -> Result<LinkDefinition, String> {
+    let mut values = file.map(read_file).transpose()?.unwrap_or_default();
+    values.extend(env_values(std::env::vars()));
+    // non-exhaustive, so built field by field
+    let mut ld = LinkDefinition::default();
+    ld.values = values;
+    Ok(ld)
+}
+
+/// Read link values from a JSON file, or a TOML file if its extension is `.toml`
+fn read_file(path: &str) -> Result<HashMap<String, String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let toml = Path::new(path).extension().is_some_and(|e| e == "toml");
+    parse_values(&text, toml).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Parse a JSON object or TOML table of link values. Values that are not strings, such as
+/// numbers and booleans, are taken as written.
+fn parse_values(text: &str, toml: bool) -> Result<HashMap<String, String>, String> {
+    let table: serde_json::Map<String, serde_json::Value> = match toml {
+        true => toml::from_str(text).map_err(|e| e.to_string())?,
+        false => serde_json::from_str(text).map_err(|e| e.to_string())?,
+    };
+    Ok(table
+        .into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(value) => (name, value),
+            value => (name, value.to_string()),
+        })
+        .collect())
+}
+
+/// Link values of `KVCOUCHBASE_<NAME>` variables, named in lower case except `URL`
+fn env_values(vars: impl Iterator<Item = (String, String)>) -> HashMap<String, String> {
+    vars.filter_map(|(name, value)| {
+        let name = name.strip_prefix(ENV_PREFIX)?;
+        match name {
+            "URL" => Some((name.to_string(), value)),
+            _ => Some((name.to_ascii_lowercase(), value)),
+        }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values() {
+        let json = r#"{"URL": "couchbase://db", "max_ops_per_sec": 100}"#;
+        let json = parse_values(json, false).unwrap();
+        assert_eq!(json["URL"], "couchbase://db");
+        assert_eq!(json["max_ops_per_sec"], "100");
+        let toml = parse_values("bucket = \"cache\"\nensure_indexes = true\n", true).unwrap();
+        assert_eq!(toml["bucket"], "cache");
+        assert_eq!(toml["ensure_indexes"], "true");
+        assert!(parse_values("[1, 2]", false).is_err());
+
+        let vars = [
+            ("KVCOUCHBASE_URL", "couchbase://env"),
+            ("KVCOUCHBASE_BUCKET", "sessions"),
+            ("HOME", "/root"),
+        ];
+        let env = env_values(vars.iter().map(|(n, v)| (n.to_string(), v.to_string())));
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["URL"], "couchbase://env");
+        assert_eq!(env["bucket"], "sessions");
+    }
+}
@@ -0,0 +1,237 @@
+//! Values split across several documents, for values larger than Couchbase's document limit
+//!
+//! When the JSON storing a value is longer than the link's chunk_size, its text is split into
+//! chunk documents, `<key>::chunk::<generation>::<n>`, each holding a JSON string, and the key
+//! holds a manifest, `{"kvcouchbase_chunks": {"generation", "count", "size"}}`.
+//! The manifest is written after its chunks, so a manifest never names missing chunks, and each
+//! value gets a new generation, so the chunks of the previous value are removed only once the
+//! new manifest replaced it.
+use std::time::Duration;
+
+use couchbase::{
+    CouchbaseError, GetOptions, LookupInOptions, LookupInSpec, RemoveOptions, UpsertOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{warn, Level};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::{errors::to_rpc_err, CouchbaseLink};
+
+/// Field of a manifest document
+pub(crate) const MANIFEST: &str = "kvcouchbase_chunks";
+
+/// Time chunks outlive their manifest, so they never expire before it
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// The chunks of a value
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub(crate) struct Manifest {
+    generation: String,
+    count: usize,
+    /// length of the JSON storing the value
+    pub(crate) size: usize,
+}
+
+impl Manifest {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({ MANIFEST: self })
+    }
+
+    fn chunk_key(&self, doc_key: &str, n: usize) -> String {
+        format!("{}::chunk::{}::{}", doc_key, self.generation, n)
+    }
+}
+
+/// Returns the manifest a document holds, or None if it holds a value
+pub(crate) fn manifest(content: &serde_json::Value) -> Option<Manifest> {
+    match content.as_object() {
+        Some(object) if object.len() == 1 => {
+            serde_json::from_value(object.get(MANIFEST)?.clone()).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Returns the pieces of the JSON storing a value, at most chunk_size bytes each,
+/// or None if the JSON is not longer than chunk_size
+pub(crate) fn split(content: &serde_json::Value, chunk_size: usize) -> Option<Vec<String>> {
+    let text = content.to_string();
+    if text.len() <= chunk_size {
+        return None;
+    }
+    let mut pieces = Vec::new();
+    let mut rest = text.as_str();
+    while !rest.is_empty() {
+        // pieces end on a character boundary
+        let mut end = chunk_size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece.to_string());
+        rest = tail;
+    }
+    Some(pieces)
+}
+
+/// Write the pieces of a value as chunks of the key and return their manifest,
+/// which the caller writes at the key. Chunks written before a failure are removed.
+pub(crate) async fn write(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+    pieces: Vec<String>,
+    expiry: Option<Duration>,
+) -> RpcResult<Manifest> {
+    let manifest = Manifest {
+        generation: format!("{:016x}", rand::random::<u64>()),
+        count: pieces.len(),
+        size: pieces.iter().map(String::len).sum(),
+    };
+    let writes = pieces.into_iter().enumerate().map(|(n, piece)| {
+        let chunk_key = manifest.chunk_key(doc_key, n);
+        let mut options = kv_options!(link, UpsertOptions::default());
+        if let Some(expiry) = expiry {
+            options = options.expiry(expiry + EXPIRY_MARGIN);
+        }
+        let connection = link.connection();
+        async move {
+            let key = chunk_key.clone();
+            let operation = async move { connection.collection.upsert(key, piece, options).await };
+            link.execute(op, Some(&chunk_key), operation).await
+        }
+    });
+    if let Err(e) = futures::future::try_join_all(writes).await {
+        remove(link, op, doc_key, &manifest).await;
+        return Err(to_rpc_err(e));
+    }
+    Ok(manifest)
+}
+
+/// Read the chunks of a manifest and return the JSON storing the value
+pub(crate) async fn read(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+    manifest: &Manifest,
+) -> RpcResult<serde_json::Value> {
+    let reads = (0..manifest.count).map(|n| {
+        let chunk_key = manifest.chunk_key(doc_key, n);
+        let options = kv_options!(link, GetOptions::default());
+        let connection = link.connection();
+        async move {
+            let key = chunk_key.clone();
+            let operation = async move { connection.collection.get(key, options).await };
+            match link.execute(op, Some(&chunk_key), operation).await {
+                Ok(r) => r.content::<String>().map_err(to_rpc_err),
+                // removed by a set or del of the key since its manifest was read
+                Err(CouchbaseError::DocumentNotFound { .. }) => Err(RpcError::Other(
+                    "chunked value changed while it was read, retry".to_string(),
+                )),
+                Err(e) => Err(to_rpc_err(e)),
+            }
+        }
+    });
+    let text = futures::future::try_join_all(reads).await?.concat();
+    serde_json::from_str(&text)
+        .map_err(|e| RpcError::Other(format!("chunked value is corrupt: {}", e)))
+}
+
+/// Returns the manifest stored at the key, without reading the value it replaces
+pub(crate) async fn lookup(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+) -> RpcResult<Option<Manifest>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (key, specs) = (doc_key.to_string(), vec![LookupInSpec::get(MANIFEST)]);
+    let operation = async move { connection.collection.lookup_in(key, specs, options).await };
+    match link.execute(op, Some(doc_key), operation).await {
+        Ok(r) if r.exists(0) => {
+            let manifest: serde_json::Value = r.content(0).map_err(to_rpc_err)?;
+            Ok(serde_json::from_value(manifest).ok())
+        }
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Remove the chunks of a manifest. Chunks that cannot be removed are logged and left to
+/// expire with their value, if it expires.
+pub(crate) async fn remove(link: &CouchbaseLink, op: &str, doc_key: &str, manifest: &Manifest) {
+    let removals = (0..manifest.count).map(|n| {
+        let chunk_key = manifest.chunk_key(doc_key, n);
+        let options = kv_options!(link, RemoveOptions::default());
+        let connection = link.connection();
+        async move {
+            let key = chunk_key.clone();
+            let operation = async move { connection.collection.remove(key, options).await };
+            match link.execute(op, Some(&chunk_key), operation).await {
+                Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => None,
+                Err(e) => Some(e),
+            }
+        }
+    });
+    let failures: Vec<_> = futures::future::join_all(removals)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    if let Some(e) = failures.first() {
+        if link.logs(Level::WARN) {
+            warn!(
+                actor_id = %link.actor_id,
+                key = %link.log_key(doc_key),
+                failed = failures.len(),
+                "couchbase chunks could not be removed: {}",
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::string;
+    use proptest::prelude::*;
+
+    #[test]
+    fn splits_values() {
+        let content = json!("héllo wörld");
+        assert_eq!(split(&content, 64), None);
+        let pieces = split(&content, 4).unwrap();
+        assert!(pieces.iter().all(|p| p.len() <= 4));
+        assert_eq!(pieces.concat(), content.to_string());
+
+        let manifest = Manifest {
+            generation: "g".to_string(),
+            count: pieces.len(),
+            size: content.to_string().len(),
+        };
+        assert_eq!(super::manifest(&manifest.to_json()), Some(manifest.clone()));
+        assert_eq!(super::manifest(&json!({ "count": 1 })), None);
+        assert_eq!(manifest.chunk_key("k", 2), "k::chunk::g::2");
+    }
+
+    proptest! {
+        // a chunk holds at least one character of at most 4 bytes
+        #[test]
+        fn splits_any_value(value in string(2048), chunk_size in 4..=1024usize) {
+            let content = serde_json::Value::String(value);
+            let text = content.to_string();
+            match split(&content, chunk_size) {
+                Some(pieces) => {
+                    prop_assert!(text.len() > chunk_size);
+                    prop_assert!(pieces
+                        .iter()
+                        .all(|p| !p.is_empty() && p.len() <= chunk_size));
+                    prop_assert_eq!(pieces.concat(), text);
+                }
+                None => prop_assert!(text.len() <= chunk_size),
+            }
+        }
+    }
+}
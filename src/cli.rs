@@ -0,0 +1,78 @@
+//! Command line modes of the provider binary, run instead of the provider
+//!
+//! - `--check-config [file]` checks link values against the cluster, see [crate::check_config]
+//! - `migrate --from-redis <url>` copies a Redis database into a link's collection, see
+//!   [crate::redis_migration]
+//! - `export --prefix <p> --out <file>` dumps a link's documents to a file, see [crate::export]
+//! - `import --in <file> [--overwrite]` restores the documents of an export, see [crate::import]
+//! - `--debug-http <addr>` serves a link's keys over HTTP for development, see [crate::debug_http]
+//!
+//! Modes that write to Couchbase take the link values from `--config <file>` and the
+//! environment like `--check-config`, and the actor the keys belong to from `--actor <id>`,
+//! which matters when the link sets `namespace_by_actor`.
+use tracing::level_filters::LevelFilter;
+
+use crate::{check_config, config, debug_http, export, import, redis_migration, CouchbaseLink};
+
+/// Run the mode the arguments name. Returns None if they name no mode, else whether it
+/// succeeded.
+pub(crate) fn run(args: &[String]) -> Option<bool> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let passed = match *args.first()? {
+        "--check-config" => check_config::run(args.get(1).copied()),
+        "--debug-http" => runtime().block_on(debug_http::run(args.get(1).copied(), &args[1..])),
+        "migrate" => runtime().block_on(redis_migration::run(&args[1..])),
+        "export" => runtime().block_on(export::run(&args[1..])),
+        "import" => runtime().block_on(import::run(&args[1..])),
+        _ => return None,
+    };
+    Some(passed)
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().expect("failed to start the tokio runtime")
+}
+
+/// Returns the value following `--name` in the arguments
+pub(crate) fn option<'a>(args: &[&'a str], name: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|arg| *arg == name) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) if !value.starts_with("--") => Ok(Some(value)),
+            _ => Err(format!("{} needs a value", name)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Returns true if `--name` is among the arguments
+pub(crate) fn flag(args: &[&str], name: &str) -> bool {
+    args.contains(&name)
+}
+
+/// Connect to the link of `--config` and `--actor`, without the link's background tasks
+pub(crate) async fn open_link(args: &[&str]) -> Result<CouchbaseLink, String> {
+    let ld = check_config::link_definition(option(args, "--config")?)?;
+    let config = config::load_config(&ld).map_err(|e| e.to_string())?;
+    let actor_id = option(args, "--actor")?.unwrap_or_default();
+    CouchbaseLink::connect(actor_id, config, Some(LevelFilter::OFF))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_options() {
+        let args = ["--from-redis", "redis://cache", "--match"];
+        assert_eq!(
+            option(&args, "--from-redis").unwrap(),
+            Some("redis://cache")
+        );
+        assert_eq!(option(&args, "--actor").unwrap(), None);
+        assert!(option(&args, "--match").is_err());
+        assert!(flag(&["--in", "a.ndjson", "--overwrite"], "--overwrite"));
+        assert!(!flag(&args, "--overwrite"));
+    }
+}
@@ -0,0 +1,484 @@
+//! Conversion of the actor's string values to and from the JSON stored in documents
+//!
+//! Every value a link reads or writes goes through its codec: single values, list and set
+//! elements, transactions and search results. The codec serializes values with the serializer
+//! chosen by the link's value_format; documents must be JSON, so formats that are not store
+//! their bytes, base64-encoded, in an envelope such as `{"kvcouchbase_msgpack": "..."}`.
+//! If the link compresses values, large ones are compressed
+//! into an envelope, `{"kvcouchbase_compressed": {"alg", "data"}}`, and if the link has an
+//! encryption key, the result is encrypted into another,
+//! `{"kvcouchbase_encrypted": {"alg", "iv", "ciphertext"}}`, authenticated with the key of the
+//! document the value belongs to, so a value copied to another key does not decrypt.
+//! Reads unwrap whichever envelopes a value has, so values stored before compression or
+//! encryption was enabled are still read as they are.
+use miniz_oxide::{deflate::compress_to_vec_zlib, inflate::decompress_to_vec_zlib_with_limit};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde_json::json;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::config::{parse_encryption_key, Compression, Config, ValueFormat};
+use crate::{chunks, tombstones};
+
+/// Converts values between the actor's strings and JSON
+pub(crate) trait Serializer: Send + Sync {
+    /// Returns the JSON storing a value
+    fn serialize(&self, value: &str) -> RpcResult<serde_json::Value>;
+    /// Returns the value stored as JSON, which may have been written by another application
+    fn deserialize(&self, content: serde_json::Value) -> RpcResult<String>;
+    /// Whether values are stored in an envelope, so their fields cannot be read or written
+    fn wraps(&self) -> bool {
+        false
+    }
+}
+
+/// Stores values as JSON strings
+struct StringSerializer;
+
+impl Serializer for StringSerializer {
+    fn serialize(&self, value: &str) -> RpcResult<serde_json::Value> {
+        Ok(serde_json::Value::from(value))
+    }
+
+    /// Returns the content of a JSON string, or the JSON text of any other document
+    fn deserialize(&self, content: serde_json::Value) -> RpcResult<String> {
+        match content {
+            serde_json::Value::String(value) => Ok(value),
+            content => Ok(content.to_string()),
+        }
+    }
+}
+
+/// Stores the JSON a value holds, so other applications read it as JSON
+struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn serialize(&self, value: &str) -> RpcResult<serde_json::Value> {
+        serde_json::from_str(value)
+            .map_err(|e| RpcError::InvalidParameter(format!("value is not JSON: {}", e)))
+    }
+
+    fn deserialize(&self, content: serde_json::Value) -> RpcResult<String> {
+        Ok(content.to_string())
+    }
+}
+
+/// Stores the bytes of base64 values in an envelope, `{"kvcouchbase_raw": "<base64>"}`
+struct RawBase64Serializer;
+
+impl Serializer for RawBase64Serializer {
+    fn serialize(&self, value: &str) -> RpcResult<serde_json::Value> {
+        let bytes = base64::decode(value.trim())
+            .map_err(|e| RpcError::InvalidParameter(format!("value is not base64: {}", e)))?;
+        // encoded again, so the same bytes are always stored alike
+        Ok(json!({ RAW: base64::encode(bytes) }))
+    }
+
+    /// Returns the bytes of an envelope, or the JSON text of any other document, in base64
+    fn deserialize(&self, content: serde_json::Value) -> RpcResult<String> {
+        match envelope(&content, RAW).and_then(|data| data.as_str()) {
+            Some(data) => Ok(data.to_string()),
+            None => Ok(base64::encode(content.to_string())),
+        }
+    }
+
+    fn wraps(&self) -> bool {
+        true
+    }
+}
+
+/// Stores the JSON a value holds as MessagePack, in an envelope,
+/// `{"kvcouchbase_msgpack": "<base64>"}`
+struct MsgpackSerializer;
+
+impl Serializer for MsgpackSerializer {
+    fn serialize(&self, value: &str) -> RpcResult<serde_json::Value> {
+        let value: serde_json::Value = serde_json::from_str(value)
+            .map_err(|e| RpcError::InvalidParameter(format!("value is not JSON: {}", e)))?;
+        let bytes = rmp_serde::to_vec(&value)
+            .map_err(|e| RpcError::Ser(format!("value could not be written as msgpack: {}", e)))?;
+        Ok(json!({ MSGPACK: base64::encode(bytes) }))
+    }
+
+    /// Returns the JSON text of an envelope's value, or of any other document
+    fn deserialize(&self, content: serde_json::Value) -> RpcResult<String> {
+        let corrupt = || RpcError::Other("msgpack value is corrupt".to_string());
+        let data = match envelope(&content, MSGPACK) {
+            Some(data) => data,
+            None => return Ok(content.to_string()),
+        };
+        let bytes = data
+            .as_str()
+            .and_then(|data| base64::decode(data).ok())
+            .ok_or_else(corrupt)?;
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).map_err(|_| corrupt())?;
+        Ok(value.to_string())
+    }
+
+    fn wraps(&self) -> bool {
+        true
+    }
+}
+
+/// Field of the envelope of a value stored as raw bytes
+const RAW: &str = "kvcouchbase_raw";
+/// Field of the envelope of a value stored as MessagePack
+const MSGPACK: &str = "kvcouchbase_msgpack";
+
+/// Field of the envelope of an encrypted value
+const ENCRYPTED: &str = "kvcouchbase_encrypted";
+const ALGORITHM: &str = "AES-256-GCM";
+/// Field of the envelope of a compressed value
+const COMPRESSED: &str = "kvcouchbase_compressed";
+const DEFLATE: &str = "deflate";
+/// Compression level of deflate, from 1 (fastest) to 9 (smallest)
+const DEFLATE_LEVEL: u8 = 6;
+/// Largest decompressed value, Couchbase's document size limit
+const MAX_DECOMPRESSED_SIZE: usize = 20 * 1024 * 1024;
+
+/// Encrypts the JSON storing values with AES-256-GCM and a random nonce per value
+struct Cipher {
+    key: LessSafeKey,
+    random: SystemRandom,
+}
+
+impl Cipher {
+    fn new(key: &str) -> RpcResult<Self> {
+        let key = parse_encryption_key(key).map_err(RpcError::InvalidParameter)?;
+        let key = UnboundKey::new(&AES_256_GCM, &key)
+            .map_err(|_| RpcError::InvalidParameter("invalid encryption key".to_string()))?;
+        Ok(Cipher {
+            key: LessSafeKey::new(key),
+            random: SystemRandom::new(),
+        })
+    }
+
+    fn seal(&self, doc_key: &str, content: &serde_json::Value) -> RpcResult<serde_json::Value> {
+        let mut iv = [0u8; NONCE_LEN];
+        self.random
+            .fill(&mut iv)
+            .map_err(|_| RpcError::Other("no random nonce for encryption".to_string()))?;
+        let mut sealed = content.to_string().into_bytes();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(doc_key.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| RpcError::Other("value could not be encrypted".to_string()))?;
+        Ok(json!({ ENCRYPTED: {
+            "alg": ALGORITHM,
+            "iv": base64::encode(iv),
+            "ciphertext": base64::encode(sealed),
+        }}))
+    }
+
+    fn open(&self, doc_key: &str, envelope: &serde_json::Value) -> RpcResult<serde_json::Value> {
+        let undecryptable = || RpcError::Other("value could not be decrypted".to_string());
+        if envelope["alg"] != ALGORITHM {
+            return Err(RpcError::Other(format!(
+                "value is encrypted with unknown algorithm {}",
+                envelope["alg"]
+            )));
+        }
+        let field = |name: &str| envelope[name].as_str().and_then(|f| base64::decode(f).ok());
+        let iv = field("iv").ok_or_else(undecryptable)?;
+        let mut sealed = field("ciphertext").ok_or_else(undecryptable)?;
+        let nonce = Nonce::try_assume_unique_for_key(&iv).map_err(|_| undecryptable())?;
+        // fails as well if the value was encrypted with another key, or for another document
+        let content = self
+            .key
+            .open_in_place(nonce, Aad::from(doc_key.as_bytes()), &mut sealed)
+            .map_err(|_| undecryptable())?;
+        serde_json::from_slice(content).map_err(|_| undecryptable())
+    }
+}
+
+/// Returns the envelope of a value wrapped under `field`, or None if the value is not wrapped
+fn envelope<'a>(content: &'a serde_json::Value, field: &str) -> Option<&'a serde_json::Value> {
+    match content.as_object() {
+        Some(object) if object.len() == 1 => object.get(field),
+        _ => None,
+    }
+}
+
+/// Returns the compressed envelope of a value longer than the threshold once serialized,
+/// or the value itself if compression would not make it smaller
+fn compress(content: serde_json::Value, threshold: usize) -> serde_json::Value {
+    let text = content.to_string();
+    if text.len() <= threshold {
+        return content;
+    }
+    let data = base64::encode(compress_to_vec_zlib(text.as_bytes(), DEFLATE_LEVEL));
+    if data.len() >= text.len() {
+        return content;
+    }
+    json!({ COMPRESSED: { "alg": DEFLATE, "data": data } })
+}
+
+fn decompress(envelope: &serde_json::Value) -> RpcResult<serde_json::Value> {
+    let corrupt = || RpcError::Other("compressed value is corrupt".to_string());
+    if envelope["alg"] != DEFLATE {
+        return Err(RpcError::Other(format!(
+            "value is compressed with unknown algorithm {}",
+            envelope["alg"]
+        )));
+    }
+    let data = envelope["data"]
+        .as_str()
+        .and_then(|data| base64::decode(data).ok())
+        .ok_or_else(corrupt)?;
+    let text =
+        decompress_to_vec_zlib_with_limit(&data, MAX_DECOMPRESSED_SIZE).map_err(|_| corrupt())?;
+    serde_json::from_slice(&text).map_err(|_| corrupt())
+}
+
+/// A link's conversion of values
+pub(crate) struct Codec {
+    serializer: Box<dyn Serializer>,
+    cipher: Option<Cipher>,
+    /// size of the serialized values above which they are compressed, if they are
+    compression_threshold: Option<usize>,
+}
+
+impl Codec {
+    pub(crate) fn new(config: &Config) -> RpcResult<Self> {
+        let serializer: Box<dyn Serializer> = match config.value_format {
+            ValueFormat::String => Box::new(StringSerializer),
+            ValueFormat::Json => Box::new(JsonSerializer),
+            ValueFormat::RawBase64 => Box::new(RawBase64Serializer),
+            ValueFormat::Msgpack => Box::new(MsgpackSerializer),
+        };
+        let cipher = config
+            .encryption_key
+            .as_deref()
+            .map(Cipher::new)
+            .transpose()?;
+        let compression_threshold = match config.compression {
+            Compression::None => None,
+            Compression::Deflate => Some(config.compression_threshold),
+        };
+        Ok(Codec {
+            serializer,
+            cipher,
+            compression_threshold,
+        })
+    }
+
+    /// Whether stored values may be wrapped in an envelope, so their fields cannot be read
+    /// or written
+    pub(crate) fn wraps(&self) -> bool {
+        self.serializer.wraps() || self.cipher.is_some() || self.compression_threshold.is_some()
+    }
+
+    /// Returns the JSON storing a value of the document `doc_key`
+    pub(crate) fn encode(&self, doc_key: &str, value: &str) -> RpcResult<serde_json::Value> {
+        let mut content = self.serializer.serialize(value)?;
+        if let Some(threshold) = self.compression_threshold {
+            content = compress(content, threshold);
+        }
+        match &self.cipher {
+            Some(cipher) => cipher.seal(doc_key, &content),
+            None => Ok(content),
+        }
+    }
+
+    /// Returns the value stored as JSON in the document `doc_key`
+    pub(crate) fn decode(&self, doc_key: &str, content: serde_json::Value) -> RpcResult<String> {
+        if tombstones::is_tombstone(&content) {
+            return Err(RpcError::Other("key was deleted".to_string()));
+        }
+        if envelope(&content, chunks::MANIFEST).is_some() {
+            return Err(RpcError::Other(
+                "value is split across documents, only get can read it".to_string(),
+            ));
+        }
+        let content = match (envelope(&content, ENCRYPTED), &self.cipher) {
+            (Some(envelope), Some(cipher)) => cipher.open(doc_key, envelope)?,
+            (Some(_), None) => {
+                return Err(RpcError::Other(
+                    "value is encrypted and the link has no encryption_key".to_string(),
+                ))
+            }
+            (None, _) => content,
+        };
+        let content = match envelope(&content, COMPRESSED) {
+            Some(envelope) => decompress(envelope)?,
+            None => content,
+        };
+        self.serializer.deserialize(content)
+    }
+
+    /// Returns the JSON storing each of the values of a document, such as the elements of a list
+    pub(crate) fn encode_all(
+        &self,
+        doc_key: &str,
+        values: &[String],
+    ) -> RpcResult<Vec<serde_json::Value>> {
+        values
+            .iter()
+            .map(|value| self.encode(doc_key, value))
+            .collect()
+    }
+
+    /// Returns the values stored as JSON in a document, such as the elements of a list
+    pub(crate) fn decode_all(
+        &self,
+        doc_key: &str,
+        contents: Vec<serde_json::Value>,
+    ) -> RpcResult<Vec<String>> {
+        contents
+            .into_iter()
+            .map(|content| self.decode(doc_key, content))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{json, string};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    fn codec(format: ValueFormat) -> Codec {
+        let mut config = Config::default();
+        config.value_format = format;
+        Codec::new(&config).unwrap()
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let codec = codec(ValueFormat::String);
+        let stored = codec.encode("k", "{\"a\":1}").unwrap();
+        assert_eq!(stored, json!("{\"a\":1}"));
+        assert_eq!(codec.decode("k", stored).unwrap(), "{\"a\":1}");
+        // documents written by other applications
+        assert_eq!(codec.decode("k", json!(42)).unwrap(), "42");
+
+        let codec = self::codec(ValueFormat::Json);
+        let stored = codec.encode("k", "{\"a\":1}").unwrap();
+        assert_eq!(stored, json!({"a": 1}));
+        assert_eq!(codec.decode("k", stored).unwrap(), "{\"a\":1}");
+        assert_eq!(codec.decode("k", json!("hi")).unwrap(), "\"hi\"");
+        assert!(codec.encode("k", "hello").is_err());
+        let stored = codec
+            .encode_all("k", &["1".to_string(), "[true]".to_string()])
+            .unwrap();
+        assert_eq!(stored, vec![json!(1), json!([true])]);
+        assert_eq!(codec.decode_all("k", stored).unwrap(), ["1", "[true]"]);
+
+        let codec = self::codec(ValueFormat::RawBase64);
+        let stored = codec.encode("k", "AAEC/w==").unwrap();
+        assert_eq!(stored, json!({ RAW: "AAEC/w==" }));
+        assert_eq!(codec.decode("k", stored).unwrap(), "AAEC/w==");
+        assert_eq!(
+            codec.decode("k", json!("hi")).unwrap(),
+            base64::encode("\"hi\"")
+        );
+        assert!(codec.encode("k", "not base64!").is_err());
+
+        let codec = self::codec(ValueFormat::Msgpack);
+        let stored = codec.encode("k", "{\"a\":[1,true]}").unwrap();
+        // fixmap of 1, "a", fixarray of 2, 1, true
+        assert_eq!(
+            stored,
+            json!({ MSGPACK: base64::encode([0x81, 0xa1, b'a', 0x92, 1, 0xc3]) })
+        );
+        assert_eq!(codec.decode("k", stored).unwrap(), "{\"a\":[1,true]}");
+        assert_eq!(codec.decode("k", json!({"b": 2})).unwrap(), "{\"b\":2}");
+        assert!(codec.encode("k", "hello").is_err());
+        // 0xc1 is never used by MessagePack
+        assert!(codec.decode("k", json!({ MSGPACK: "wQ==" })).is_err());
+        assert!(codec.wraps());
+    }
+
+    #[test]
+    fn encrypts_values() {
+        let mut config = Config::default();
+        config.encryption_key = Some(base64::encode([7u8; 32]));
+        let codec = Codec::new(&config).unwrap();
+        let stored = codec.encode("k", "secret").unwrap();
+        assert!(!stored.to_string().contains("secret"));
+        assert_ne!(stored, codec.encode("k", "secret").unwrap());
+        assert_eq!(codec.decode("k", stored.clone()).unwrap(), "secret");
+        // a value copied to another document does not decrypt
+        assert!(codec.decode("other", stored.clone()).is_err());
+        // values stored before the key was set
+        assert_eq!(codec.decode("k", json!("plain")).unwrap(), "plain");
+        assert!(self::codec(ValueFormat::String)
+            .decode("k", stored.clone())
+            .is_err());
+
+        config.encryption_key = Some(base64::encode([8u8; 32]));
+        assert!(Codec::new(&config).unwrap().decode("k", stored).is_err());
+        config.encryption_key = Some(base64::encode([8u8; 16]));
+        assert!(Codec::new(&config).is_err());
+    }
+
+    #[test]
+    fn compresses_large_values() {
+        let mut config = Config::default();
+        config.compression = Compression::Deflate;
+        config.compression_threshold = 64;
+        config.encryption_key = Some(base64::encode([7u8; 32]));
+        let codec = Codec::new(&config).unwrap();
+        let large = "abc".repeat(100);
+        let stored = codec.encode("k", &large).unwrap();
+        assert!(stored.to_string().len() < large.len());
+        assert_eq!(codec.decode("k", stored).unwrap(), large);
+
+        config.encryption_key = None;
+        let codec = Codec::new(&config).unwrap();
+        assert_eq!(codec.encode("k", "small").unwrap(), json!("small"));
+        let stored = codec.encode("k", &large).unwrap();
+        assert_eq!(stored[COMPRESSED]["alg"], DEFLATE);
+        // links that stopped compressing still read compressed values
+        assert_eq!(
+            self::codec(ValueFormat::String)
+                .decode("k", stored)
+                .unwrap(),
+            large
+        );
+        let corrupt = json!({ COMPRESSED: { "alg": DEFLATE, "data": "AAAA" } });
+        assert!(codec.decode("k", corrupt).is_err());
+    }
+
+    /// A value format with a value it stores
+    fn formatted_value() -> impl Strategy<Value = (ValueFormat, String)> {
+        prop_oneof![
+            string(4096).prop_map(|value| (ValueFormat::String, value)),
+            string(4096).prop_map(|value| (ValueFormat::RawBase64, base64::encode(value))),
+            json().prop_map(|value| (ValueFormat::Json, value)),
+            json().prop_map(|value| (ValueFormat::Msgpack, value)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_any_value(
+            (value_format, value) in formatted_value(),
+            key_byte in proptest::option::of(any::<u8>()),
+            compression_threshold in proptest::option::of(0..=256usize),
+            chunk_size in 4..=512usize,
+        ) {
+            let mut config = Config::default();
+            config.value_format = value_format;
+            config.encryption_key = key_byte.map(|byte| base64::encode([byte; 32]));
+            if let Some(threshold) = compression_threshold {
+                config.compression = Compression::Deflate;
+                config.compression_threshold = threshold;
+            }
+            let codec = Codec::new(&config).unwrap();
+            let stored = codec.encode("k", &value).unwrap();
+            // stored as set writes it, whole or in chunks
+            let stored = match chunks::split(&stored, chunk_size) {
+                Some(pieces) => serde_json::from_str(&pieces.concat()).unwrap(),
+                None => stored,
+            };
+            prop_assert_eq!(codec.decode("k", stored).unwrap(), value);
+        }
+    }
+}
@@ -1,31 +1,719 @@
 //! Configuration for sqldb-postgres capability provider
 //!
-use std::{str::FromStr, time::Duration};
-use couchbase::{Cluster, Collection, Bucket};
+use std::collections::HashMap;
+use std::time::Duration;
+use couchbase::Cluster;
 
-use serde::Deserialize;
-use wasmbus_rpc::{core::LinkDefinition, error::RpcError};
+use serde::{Deserialize, Deserializer};
+use tracing::level_filters::LevelFilter;
+use wasmbus_rpc::{
+    core::{HostData, LinkDefinition},
+    error::RpcError,
+};
+
+use crate::contracts;
 
 
 const COUCHBASE_URL_KEY: &str = "URL";
 const COUCHBASE_BUCKET_KEY: &str = "bucket";
+const COUCHBASE_SCOPE_KEY: &str = "scope";
 const COUCHBASE_COLLECTION_KEY: &str = "collection";
 const COUCHBASE_USERNAME_KEY: &str = "username";
 const COUCHBASE_PASSWORD_KEY: &str = "password";
+const DEFAULT_TTL_KEY: &str = "default_ttl";
+const MAX_TTL_KEY: &str = "max_ttl";
+const KV_TIMEOUT_KEY: &str = "kv_timeout";
+const KEY_PREFIX_KEY: &str = "key_prefix";
+const NAMESPACE_BY_ACTOR_KEY: &str = "namespace_by_actor";
+const ALLOWED_OPS_KEY: &str = "allowed_ops";
+const DENIED_OPS_KEY: &str = "denied_ops";
+const MAX_OPS_PER_SEC_KEY: &str = "max_ops_per_sec";
+const BURST_KEY: &str = "burst";
+const STORAGE_QUOTA_KEY: &str = "storage_quota";
+const QUOTA_RECONCILE_INTERVAL_KEY: &str = "quota_reconcile_interval";
+const CREATE_BUCKET_IF_MISSING_KEY: &str = "create_bucket_if_missing";
+const BUCKET_RAM_QUOTA_MB_KEY: &str = "bucket_ram_quota_mb";
+const CREATE_COLLECTION_IF_MISSING_KEY: &str = "create_collection_if_missing";
+const MONITOR_INTERVAL_KEY: &str = "monitor_interval";
+const RECONNECT_AFTER_FAILURES_KEY: &str = "reconnect_after_failures";
+const SLOW_OP_THRESHOLD_KEY: &str = "slow_op_threshold";
+const REDACT_KEYS_KEY: &str = "redact_keys";
+const ORPHAN_WINDOW_KEY: &str = "orphan_window";
+const ORPHAN_REPORT_INTERVAL_KEY: &str = "orphan_report_interval";
+const STATS_INTERVAL_KEY: &str = "stats_interval";
+const AUDIT_COLLECTION_KEY: &str = "audit_collection";
+const LOG_LEVEL_KEY: &str = "log_level";
+const LOG_SAMPLE_RATE_KEY: &str = "log_sample_rate";
+const SLOW_QUERY_THRESHOLD_KEY: &str = "slow_query_threshold";
+const DEAD_LETTER_COLLECTION_KEY: &str = "dead_letter_collection";
+const DEAD_LETTER_FILE_KEY: &str = "dead_letter_file";
+const WRITE_BEHIND_KEY: &str = "write_behind";
+const WRITE_BEHIND_QUEUE_SIZE_KEY: &str = "write_behind_queue_size";
+const WRITE_BEHIND_RETRY_INTERVAL_KEY: &str = "write_behind_retry_interval";
+const WRITE_BEHIND_MAX_ATTEMPTS_KEY: &str = "write_behind_max_attempts";
+const WRITE_BEHIND_SPILL_FILE_KEY: &str = "write_behind_spill_file";
+const FAULT_INJECTION_KEY: &str = "fault_injection";
+const FAULT_INJECTION_LATENCY_KEY: &str = "fault_injection_latency";
+const CHANGE_FEED_SUBJECT_KEY: &str = "change_feed_subject";
+const CHANGE_FEED_PREFIX_KEY: &str = "change_feed_prefix";
+const CHANGE_FEED_INTERVAL_KEY: &str = "change_feed_interval";
+const CHANGE_FEED_EXPIRATIONS_KEY: &str = "change_feed_expirations";
+const CHANGE_FEED_DELIVERY_KEY: &str = "change_feed_delivery";
+const ENSURE_INDEXES_KEY: &str = "ensure_indexes";
+const SQLDB_KEY: &str = "sqldb";
+const SCAN_CONSISTENCY_KEY: &str = "scan_consistency";
+const VALUE_FORMAT_KEY: &str = "value_format";
+const ENCRYPTION_KEY_KEY: &str = "encryption_key";
+const ENCRYPTION_KEY_ENV_KEY: &str = "encryption_key_env";
+const COMPRESSION_KEY: &str = "compression";
+const COMPRESSION_THRESHOLD_KEY: &str = "compression_threshold";
+const CHUNK_SIZE_KEY: &str = "chunk_size";
+const METADATA_COLLECTION_KEY: &str = "metadata_collection";
+const CONTENT_TYPE_KEY: &str = "content_type";
+const SOFT_DELETE_KEY: &str = "soft_delete";
+const HISTORY_LENGTH_KEY: &str = "history_length";
+const COUNTER_OVERFLOW_KEY: &str = "counter_overflow";
+const LIST_STORAGE_KEY: &str = "list_storage";
+const LIST_MAX_LENGTH_KEY: &str = "list_max_length";
+const SET_MAX_MEMBERS_KEY: &str = "set_max_members";
+const SET_EVICTION_KEY: &str = "set_eviction";
+const CONTAINER_TTL_KEY: &str = "container_ttl";
+const SET_FILTERS_KEY: &str = "set_filters";
+const SET_FILTER_REFRESH_KEY: &str = "set_filter_refresh";
+const CONTAINER_GC_INTERVAL_KEY: &str = "container_gc_interval";
+const KEYVALUE_BUCKETS_KEY: &str = "keyvalue_buckets";
+const BATCH_CONCURRENCY_KEY: &str = "batch_concurrency";
+
+/// Operation names that may be used in allowed_ops and denied_ops
+const OPERATIONS: &[&str] = &[
+    "increment",
+    "contains",
+    "del",
+    "get",
+    "list_add",
+    "list_clear",
+    "list_del",
+    "list_range",
+    "set",
+    "set_add",
+    "set_del",
+    "set_clear",
+    "set_intersection",
+    "set_query",
+    "set_union",
+    "get_with_cas",
+    "set_if_cas",
+    "lookup_path",
+    "mutate_path",
+    "touch",
+    "get_and_touch",
+    "get_expiry",
+    "lock",
+    "unlock",
+    "transact",
+    "sql_execute",
+    "sql_query",
+    "search",
+    "scan_keys",
+    "del_by_prefix",
+    "get_all_replicas",
+    "get_metadata",
+    "set_content_type",
+    "undelete",
+    "get_history",
+    "set_with_mode",
+    "get_with_meta",
+    "set_with_options",
+    "get_set",
+    "append",
+    "prepend",
+    "increment64",
+    "get64",
+    "migrate_counters",
+    "zadd",
+    "zincr_by",
+    "zrem",
+    "zscore",
+    "zrank",
+    "zrange",
+    "list_add_capped",
+    "list_range_page",
+    "queue_push",
+    "queue_pop",
+    "deque_push",
+    "deque_pop",
+    "list_pop_n",
+    "expire_container",
+    "set_contains",
+    "set_query_page",
+    "set_add_many",
+    "set_diff",
+    "zrange_by_score",
+    "ztop",
+    "approx_add",
+    "approx_count",
+    "hset",
+    "hget",
+    "hdel",
+    "hgetall",
+    "get_many",
+    "set_many",
+    "del_many",
+    "watch",
+    "unwatch",
+    "blob_container_exists",
+    "blob_create_container",
+    "blob_get_container_info",
+    "blob_list_containers",
+    "blob_remove_containers",
+    "blob_object_exists",
+    "blob_get_object_info",
+    "blob_list_objects",
+    "blob_remove_objects",
+    "blob_put_object",
+    "blob_get_object",
+    "blob_put_chunk",
+    "wasi_get",
+    "wasi_set",
+    "wasi_delete",
+    "wasi_exists",
+    "wasi_list_keys",
+    "wasi_increment",
+    "wasi_compare_and_swap",
+    "wasi_get_many",
+    "wasi_set_many",
+    "wasi_delete_many",
+    "create_scope",
+    "drop_scope",
+    "create_collection",
+    "drop_collection",
+    "flush_collection",
+];
 
 const DEFAULT_CONNECT_URL: &str = "couchbase://0.0.0.0";
 const DEFAULT_BUCKET: &str = "default";
+const DEFAULT_SCOPE: &str = "_default";
 const DEFAULT_COLLECTION: &str = "_default";
 const DEFAULT_USERNAME: &str = "Administrator";
 const DEFAULT_PASSWORD: &str = "password";
+const DEFAULT_QUOTA_RECONCILE_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_BUCKET_RAM_QUOTA_MB: u32 = 100;
+const DEFAULT_MONITOR_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_RECONNECT_AFTER_FAILURES: u32 = 3;
+/// Slow operation thresholds of the Couchbase SDK's threshold logging tracer
+const DEFAULT_KV_SLOW_THRESHOLD: Duration = Duration::from_millis(500);
+const DEFAULT_QUERY_SLOW_THRESHOLD: Duration = Duration::from_secs(1);
+const DEFAULT_ORPHAN_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_SET_FILTER_REFRESH: Duration = Duration::from_secs(60);
+const DEFAULT_METRICS_PUBLISH_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const DEFAULT_WRITE_BEHIND_QUEUE_SIZE: usize = 1000;
+const DEFAULT_WRITE_BEHIND_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_CHANGE_FEED_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_WRITE_BEHIND_MAX_ATTEMPTS: u32 = 60;
+const DEFAULT_FAULT_INJECTION_LATENCY: Duration = Duration::from_millis(500);
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+const DEFAULT_BATCH_CONCURRENCY: usize = 16;
+/// Bounds of chunk_size; Couchbase documents may not exceed 20 MiB
+const MIN_CHUNK_SIZE: usize = 1024;
+const MAX_CHUNK_SIZE: usize = 20 * 1024 * 1024 - 1024;
+
+/// Operations run with N1QL rather than the key-value service
+const QUERY_OPERATIONS: &[&str] = &[
+    "get_expiry",
+    "get_with_meta",
+    "transact",
+    "sql_execute",
+    "sql_query",
+    "search",
+    "scan_keys",
+    "del_by_prefix",
+    "quota_reconcile",
+    "change_feed",
+    "container_gc",
+    "blob_list_containers",
+    "blob_list_objects",
+    "blob_remove_containers",
+    "wasi_list_keys",
+    "flush_collection",
+];
+
+/// Operations run by the provider on its own behalf
+const INTERNAL_OPERATIONS: &[&str] = &["ping", "quota_lookup", "index_lookup"];
 
 #[derive(Debug, Default, Deserialize)]
 pub(crate) struct Config {
     url: String,
-    bucket: String,
-    collection : String,
+    pub(crate) bucket: String,
+    #[serde(default = "default_scope")]
+    pub(crate) scope: String,
+    pub(crate) collection : String,
     username: String,
-    password: String
+    password: String,
+    /// expiry in seconds applied to `set` when the request has none (0 = never expire)
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    pub(crate) default_ttl: u32,
+    /// upper bound in seconds for any expiry written by this link (0 = no cap)
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    pub(crate) max_ttl: u32,
+    /// timeout for key-value operations, SDK default if not set
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) kv_timeout: Option<Duration>,
+    /// prefix prepended to every document key
+    #[serde(default)]
+    pub(crate) key_prefix: String,
+    /// prefix every document key with the linked actor's public key
+    #[serde(default)]
+    pub(crate) namespace_by_actor: bool,
+    /// if set, the only operations this link may perform
+    #[serde(default)]
+    allowed_ops: Option<Vec<String>>,
+    /// operations this link may not perform
+    #[serde(default)]
+    denied_ops: Vec<String>,
+    /// average operations per second allowed for this link (0 = unlimited)
+    #[serde(default)]
+    pub(crate) max_ops_per_sec: u32,
+    /// operations allowed in a burst above max_ops_per_sec (0 = same as max_ops_per_sec)
+    #[serde(default)]
+    pub(crate) burst: u32,
+    /// approximate number of bytes this link may store (0 = unlimited)
+    #[serde(default)]
+    pub(crate) storage_quota: u64,
+    /// how often storage usage is recomputed with N1QL
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) quota_reconcile_interval: Option<Duration>,
+    /// create the bucket at link time if it does not exist (for development clusters)
+    #[serde(default)]
+    pub(crate) create_bucket_if_missing: bool,
+    /// RAM quota of a bucket created by create_bucket_if_missing
+    #[serde(default = "default_bucket_ram_quota_mb")]
+    pub(crate) bucket_ram_quota_mb: u32,
+    /// create the scope and collection at link time if they do not exist
+    #[serde(default)]
+    pub(crate) create_collection_if_missing: bool,
+    /// how often the connection monitor pings the cluster (0 = no monitor)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    monitor_interval: Option<Duration>,
+    /// consecutive failed pings after which the connection is rebuilt
+    #[serde(default = "default_reconnect_after_failures")]
+    pub(crate) reconnect_after_failures: u32,
+    /// duration above which an operation is logged as slow, by operation name
+    /// or by service (`kv` or `query`); 0 disables logging for that entry
+    #[serde(default, deserialize_with = "deserialize_thresholds")]
+    slow_op_threshold: HashMap<String, Duration>,
+    /// log a hash of document keys instead of the keys themselves
+    #[serde(default)]
+    pub(crate) redact_keys: bool,
+    /// how long a key-value request timed out by the provider stays outstanding
+    /// to detect late responses (0 = no orphan reporting)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    orphan_window: Option<Duration>,
+    /// how often the orphan report is logged
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    orphan_report_interval: Option<Duration>,
+    /// how often the link's operation statistics are logged (0 = never)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    stats_interval: Option<Duration>,
+    /// collection (`collection` or `scope.collection`) receiving an audit record of every mutation
+    #[serde(default)]
+    audit_collection: Option<String>,
+    /// verbosity of the provider's logs about this link, the provider default if not set
+    #[serde(default, deserialize_with = "deserialize_level")]
+    pub(crate) log_level: Option<LevelFilter>,
+    /// log one in N successful operations of the link, errors are always logged
+    #[serde(default, deserialize_with = "deserialize_sample_rates")]
+    pub(crate) log_sample_rate: SampleRates,
+    /// duration, rows included, above which a N1QL query is logged (0 = never)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    slow_query_threshold: Option<Duration>,
+    /// collection (`collection` or `scope.collection`) receiving a record of every failed write
+    #[serde(default)]
+    dead_letter_collection: Option<String>,
+    /// local file to which a JSON line is appended for every failed write
+    #[serde(default)]
+    pub(crate) dead_letter_file: Option<String>,
+    /// acknowledge `set` and `del` calls that failed with a transient error and retry them
+    /// in the background
+    #[serde(default)]
+    pub(crate) write_behind: bool,
+    /// writes held in memory for retry before they are spilled or rejected
+    #[serde(default = "default_write_behind_queue_size")]
+    pub(crate) write_behind_queue_size: usize,
+    /// how often queued writes are retried
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    write_behind_retry_interval: Option<Duration>,
+    /// attempts after which a queued write is dead-lettered (0 = retry until shutdown)
+    #[serde(default = "default_write_behind_max_attempts")]
+    pub(crate) write_behind_max_attempts: u32,
+    /// local file receiving queued writes when the queue is full or the provider shuts down
+    #[serde(default)]
+    pub(crate) write_behind_spill_file: Option<String>,
+    /// fraction of the actor's operations failed or delayed on purpose, by fault
+    #[serde(default, deserialize_with = "deserialize_fault_rates")]
+    pub(crate) fault_injection: FaultRates,
+    /// delay added by an injected latency fault
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    fault_injection_latency: Option<Duration>,
+    /// subject of the change events sent to the actor, which enables the change feed
+    #[serde(default)]
+    pub(crate) change_feed_subject: Option<String>,
+    /// only keys starting with this prefix are reported by the change feed
+    #[serde(default)]
+    pub(crate) change_feed_prefix: String,
+    /// how often the change feed looks for changed documents
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    change_feed_interval: Option<Duration>,
+    /// also report the documents that expired to the actor
+    #[serde(default)]
+    pub(crate) change_feed_expirations: bool,
+    /// who receives the change events: the linked actor, the lattice, or both
+    #[serde(default)]
+    pub(crate) change_feed_delivery: ChangeFeedDelivery,
+    /// create the indexes needed by N1QL-backed features at link time if they do not exist
+    #[serde(default)]
+    pub(crate) ensure_indexes: bool,
+    /// serve SqlDb, whose statements may read and write any document of the bucket
+    #[serde(default)]
+    pub(crate) sqldb: bool,
+    /// scan consistency of N1QL queries, by operation
+    #[serde(default, deserialize_with = "deserialize_scan_consistencies")]
+    scan_consistency: ScanConsistencies,
+    /// how values are stored in documents
+    #[serde(default)]
+    pub(crate) value_format: ValueFormat,
+    /// base64 AES-256 key encrypting the stored values, which are then opaque to the cluster
+    #[serde(default)]
+    pub(crate) encryption_key: Option<String>,
+    /// compression of the stored values
+    #[serde(default)]
+    pub(crate) compression: Compression,
+    /// size in bytes of the serialized values above which they are compressed
+    #[serde(default = "default_compression_threshold")]
+    pub(crate) compression_threshold: usize,
+    /// size in bytes of the stored values above which they are split across documents
+    /// (0 = never split)
+    #[serde(default)]
+    chunk_size: usize,
+    /// collection (`collection` or `scope.collection`) holding the metadata of the link's keys
+    #[serde(default)]
+    metadata_collection: Option<String>,
+    /// content type recorded in the metadata of the keys the link writes
+    #[serde(default)]
+    pub(crate) content_type: Option<String>,
+    /// how long `del` keeps a deleted value in a tombstone before it expires (0 = remove it)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    soft_delete: Option<Duration>,
+    /// previous values kept of each key the link sets (0 = none)
+    #[serde(default)]
+    pub(crate) history_length: u32,
+    /// what counter increments beyond the counter's range do
+    #[serde(default)]
+    pub(crate) counter_overflow: CounterOverflow,
+    /// how the lists the link creates are stored
+    #[serde(default)]
+    pub(crate) list_storage: ListStorage,
+    /// length beyond which list_add removes the oldest values of a list (0 = no limit)
+    #[serde(default)]
+    pub(crate) list_max_length: u32,
+    /// number of members beyond which set_add does not grow a set (0 = no limit)
+    #[serde(default)]
+    pub(crate) set_max_members: u32,
+    /// what set_add does when a set has set_max_members members
+    #[serde(default)]
+    pub(crate) set_eviction: SetEviction,
+    /// seconds after their last mutation lists and sets expire, unless set per container
+    /// (0 = never)
+    #[serde(default)]
+    pub(crate) container_ttl: u32,
+    /// number of sets set_contains keeps a bloom filter of (0 = none)
+    #[serde(default)]
+    pub(crate) set_filters: u32,
+    /// age beyond which a set's bloom filter is rebuilt
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    set_filter_refresh: Option<Duration>,
+    /// how often empty lists and sets are removed (0 = never)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    container_gc_interval: Option<Duration>,
+    /// collections (`collection` or `scope.collection`) of the Store, Atomics and Batch buckets
+    /// other than `default`, by identifier
+    #[serde(default)]
+    keyvalue_buckets: HashMap<String, String>,
+    /// keys get_many, set_many and del_many send to Couchbase at a time
+    #[serde(default = "default_batch_concurrency")]
+    pub(crate) batch_concurrency: usize,
+}
+
+/// Receivers of the change feed's events
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeFeedDelivery {
+    /// the linked actor, through `MessageSubscriber.HandleMessage`
+    #[default]
+    Actor,
+    /// whoever subscribes to the subject on the lattice's NATS
+    Lattice,
+    /// both the linked actor and the lattice
+    Both,
+}
+
+impl ChangeFeedDelivery {
+    pub(crate) fn reaches_actor(self) -> bool {
+        matches!(self, ChangeFeedDelivery::Actor | ChangeFeedDelivery::Both)
+    }
+
+    pub(crate) fn reaches_lattice(self) -> bool {
+        matches!(self, ChangeFeedDelivery::Lattice | ChangeFeedDelivery::Both)
+    }
+}
+
+impl std::str::FromStr for ChangeFeedDelivery {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "actor" => Ok(ChangeFeedDelivery::Actor),
+            "lattice" => Ok(ChangeFeedDelivery::Lattice),
+            "both" => Ok(ChangeFeedDelivery::Both),
+            _ => Err(format!(
+                "unknown delivery '{}', expected actor, lattice or both",
+                value
+            )),
+        }
+    }
+}
+
+/// Serializer of the values stored in documents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ValueFormat {
+    /// as a JSON string
+    #[default]
+    String,
+    /// as the JSON document the value holds
+    Json,
+    /// as the bytes of a base64 value, in an envelope
+    RawBase64,
+    /// as the MessagePack of the JSON the value holds, in an envelope
+    Msgpack,
+}
+
+impl std::str::FromStr for ValueFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "string" => Ok(ValueFormat::String),
+            "json" => Ok(ValueFormat::Json),
+            "raw_base64" => Ok(ValueFormat::RawBase64),
+            "msgpack" => Ok(ValueFormat::Msgpack),
+            // the Couchbase SDK the provider is built with can neither write nor read documents
+            // that are not JSON
+            "raw" => Err(
+                "raw is not supported by this provider's Couchbase SDK, use raw_base64".to_string(),
+            ),
+            _ => Err(format!(
+                "unknown value format '{}', expected string, json, raw_base64 or msgpack",
+                value
+            )),
+        }
+    }
+}
+
+/// Compression of the values stored in documents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    /// zlib-wrapped deflate, the algorithm of gzip
+    Deflate,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "gzip" => Err("gzip is not supported, deflate compresses with the same algorithm"
+                .to_string()),
+            "snappy" => Err("snappy is not supported, only deflate is".to_string()),
+            _ => Err(format!(
+                "unknown compression '{}', expected none or deflate",
+                value
+            )),
+        }
+    }
+}
+
+/// What a counter increment beyond the counter's range does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CounterOverflow {
+    /// fail, leaving the counter unchanged
+    #[default]
+    Error,
+    /// stop at the smallest or largest value of the range
+    Saturate,
+}
+
+impl std::str::FromStr for CounterOverflow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(CounterOverflow::Error),
+            "saturate" => Ok(CounterOverflow::Saturate),
+            _ => Err(format!(
+                "unknown counter overflow '{}', expected error or saturate",
+                value
+            )),
+        }
+    }
+}
+
+/// How lists are stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ListStorage {
+    /// as one document, a JSON array of the elements
+    #[default]
+    Document,
+    /// one document per element, with an index document
+    Elements,
+}
+
+impl std::str::FromStr for ListStorage {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "document" => Ok(ListStorage::Document),
+            "elements" => Ok(ListStorage::Elements),
+            _ => Err(format!(
+                "unknown list storage '{}', expected document or elements",
+                value
+            )),
+        }
+    }
+}
+
+/// What adding a member to a full set does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SetEviction {
+    /// fail, leaving the set unchanged
+    #[default]
+    Reject,
+    /// remove the members added first to make room
+    Oldest,
+}
+
+impl std::str::FromStr for SetEviction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(SetEviction::Reject),
+            "oldest" => Ok(SetEviction::Oldest),
+            _ => Err(format!(
+                "unknown set eviction '{}', expected reject or oldest",
+                value
+            )),
+        }
+    }
+}
+
+/// Index consistency required by a N1QL query
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScanConsistency {
+    /// read the indexes as they are, which may miss the latest mutations
+    #[default]
+    NotBounded,
+    /// wait for the indexes to include every mutation made before the query
+    RequestPlus,
+    /// wait for the indexes to include the link's own recent mutations
+    AtPlus,
+}
+
+impl std::str::FromStr for ScanConsistency {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "not_bounded" => Ok(ScanConsistency::NotBounded),
+            "request_plus" => Ok(ScanConsistency::RequestPlus),
+            "at_plus" => Ok(ScanConsistency::AtPlus),
+            _ => Err(format!(
+                "unknown scan consistency '{}', expected not_bounded, request_plus or at_plus",
+                value
+            )),
+        }
+    }
+}
+
+/// Scan consistency of the link's queries
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ScanConsistencies {
+    /// consistency of operations not listed in `operations`
+    pub(crate) default: ScanConsistency,
+    pub(crate) operations: HashMap<String, ScanConsistency>,
+}
+
+/// Sampling rates of successful operation logs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct SampleRates {
+    /// rate of operations not listed in `operations` (0 or 1 = log all)
+    pub(crate) default: u64,
+    pub(crate) operations: HashMap<String, u64>,
+}
+
+fn default_reconnect_after_failures() -> u32 {
+    DEFAULT_RECONNECT_AFTER_FAILURES
+}
+
+/// Rates, between 0 and 1, of the faults injected into a link's operations
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct FaultRates {
+    /// operations delayed by fault_injection_latency before they run
+    pub(crate) latency: f64,
+    /// operations failed with a timeout without being sent
+    pub(crate) timeout: f64,
+    /// reads answered as if the document did not exist
+    pub(crate) not_found: f64,
+}
+
+impl FaultRates {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.latency > 0.0 || self.timeout > 0.0 || self.not_found > 0.0
+    }
+}
+
+fn default_write_behind_queue_size() -> usize {
+    DEFAULT_WRITE_BEHIND_QUEUE_SIZE
+}
+
+fn default_write_behind_max_attempts() -> u32 {
+    DEFAULT_WRITE_BEHIND_MAX_ATTEMPTS
+}
+
+fn default_compression_threshold() -> usize {
+    DEFAULT_COMPRESSION_THRESHOLD
+}
+
+fn default_batch_concurrency() -> usize {
+    DEFAULT_BATCH_CONCURRENCY
+}
+
+fn default_scope() -> String {
+    DEFAULT_SCOPE.to_string()
+}
+
+fn default_bucket_ram_quota_mb() -> u32 {
+    DEFAULT_BUCKET_RAM_QUOTA_MB
 }
 
 impl Config {
@@ -33,11 +721,384 @@ impl Config {
         Config {
             url: DEFAULT_CONNECT_URL.to_string(),
             bucket: DEFAULT_BUCKET.to_string(),
+            scope: DEFAULT_SCOPE.to_string(),
             collection: DEFAULT_COLLECTION.to_string(),
             username: DEFAULT_USERNAME.to_string(),
-            password: DEFAULT_PASSWORD.to_string()
+            password: DEFAULT_PASSWORD.to_string(),
+            default_ttl: 0,
+            max_ttl: 0,
+            kv_timeout: None,
+            key_prefix: String::new(),
+            namespace_by_actor: false,
+            allowed_ops: None,
+            denied_ops: Vec::new(),
+            max_ops_per_sec: 0,
+            burst: 0,
+            storage_quota: 0,
+            quota_reconcile_interval: None,
+            create_bucket_if_missing: false,
+            bucket_ram_quota_mb: DEFAULT_BUCKET_RAM_QUOTA_MB,
+            create_collection_if_missing: false,
+            monitor_interval: None,
+            reconnect_after_failures: DEFAULT_RECONNECT_AFTER_FAILURES,
+            slow_op_threshold: HashMap::new(),
+            redact_keys: false,
+            orphan_window: None,
+            orphan_report_interval: None,
+            stats_interval: None,
+            audit_collection: None,
+            log_level: None,
+            log_sample_rate: SampleRates::default(),
+            slow_query_threshold: None,
+            dead_letter_collection: None,
+            dead_letter_file: None,
+            write_behind: false,
+            write_behind_queue_size: DEFAULT_WRITE_BEHIND_QUEUE_SIZE,
+            write_behind_retry_interval: None,
+            write_behind_max_attempts: DEFAULT_WRITE_BEHIND_MAX_ATTEMPTS,
+            write_behind_spill_file: None,
+            fault_injection: FaultRates::default(),
+            fault_injection_latency: None,
+            change_feed_subject: None,
+            change_feed_prefix: String::new(),
+            change_feed_interval: None,
+            change_feed_expirations: false,
+            change_feed_delivery: ChangeFeedDelivery::Actor,
+            ensure_indexes: false,
+            sqldb: false,
+            scan_consistency: ScanConsistencies::default(),
+            value_format: ValueFormat::String,
+            encryption_key: None,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            chunk_size: 0,
+            metadata_collection: None,
+            content_type: None,
+            soft_delete: None,
+            history_length: 0,
+            counter_overflow: CounterOverflow::Error,
+            list_storage: ListStorage::Document,
+            list_max_length: 0,
+            set_max_members: 0,
+            set_eviction: SetEviction::Reject,
+            container_ttl: 0,
+            set_filters: 0,
+            set_filter_refresh: None,
+            container_gc_interval: None,
+            keyvalue_buckets: HashMap::new(),
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
         }
     }
+
+    /// Duration above which a N1QL query is logged, or None if queries are never logged
+    pub(crate) fn slow_query_threshold(&self) -> Option<Duration> {
+        let threshold = self
+            .slow_query_threshold
+            .unwrap_or(DEFAULT_QUERY_SLOW_THRESHOLD);
+        (!threshold.is_zero()).then_some(threshold)
+    }
+
+    /// Size of the stored values above which they are split across documents,
+    /// or None if values are never split
+    pub(crate) fn chunk_size(&self) -> Option<usize> {
+        (self.chunk_size > 0).then_some(self.chunk_size)
+    }
+
+    /// Scope and collection of the audit log, if mutations are audited.
+    /// The scope defaults to the link's scope.
+    pub(crate) fn audit_collection(&self) -> Option<(&str, &str)> {
+        let audit = self.audit_collection.as_deref()?;
+        Some(match audit.split_once('.') {
+            Some((scope, collection)) => (scope, collection),
+            None => (self.scope.as_str(), audit),
+        })
+    }
+
+    /// Scope and collection of the dead-letter records, if failed writes are recorded there.
+    /// The scope defaults to the link's scope.
+    pub(crate) fn dead_letter_collection(&self) -> Option<(&str, &str)> {
+        let dead_letter = self.dead_letter_collection.as_deref()?;
+        Some(match dead_letter.split_once('.') {
+            Some((scope, collection)) => (scope, collection),
+            None => (self.scope.as_str(), dead_letter),
+        })
+    }
+
+    /// How long deleted values are kept in tombstones, or None if `del` removes them
+    pub(crate) fn soft_delete(&self) -> Option<Duration> {
+        self.soft_delete.filter(|period| !period.is_zero())
+    }
+
+    /// Scope and collection of the keys' metadata, if the link keeps them.
+    /// The scope defaults to the link's scope.
+    pub(crate) fn metadata_collection(&self) -> Option<(&str, &str)> {
+        let metadata = self.metadata_collection.as_deref()?;
+        Some(match metadata.split_once('.') {
+            Some((scope, collection)) => (scope, collection),
+            None => (self.scope.as_str(), metadata),
+        })
+    }
+
+    /// Scope and collection of every collection the link writes to: its own, and those of its
+    /// audit, dead-letter and metadata records
+    pub(crate) fn collections(&self) -> Vec<(&str, &str)> {
+        let own = (self.scope.as_str(), self.collection.as_str());
+        std::iter::once(own)
+            .chain(self.audit_collection())
+            .chain(self.dead_letter_collection())
+            .chain(self.metadata_collection())
+            .collect()
+    }
+
+    pub(crate) fn fault_injection_latency(&self) -> Duration {
+        self.fault_injection_latency
+            .unwrap_or(DEFAULT_FAULT_INJECTION_LATENCY)
+    }
+
+    pub(crate) fn change_feed_interval(&self) -> Duration {
+        self.change_feed_interval
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(DEFAULT_CHANGE_FEED_INTERVAL)
+    }
+
+    pub(crate) fn write_behind_retry_interval(&self) -> Duration {
+        self.write_behind_retry_interval
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(DEFAULT_WRITE_BEHIND_RETRY_INTERVAL)
+    }
+
+    pub(crate) fn set_filter_refresh(&self) -> Duration {
+        self.set_filter_refresh
+            .filter(|refresh| !refresh.is_zero())
+            .unwrap_or(DEFAULT_SET_FILTER_REFRESH)
+    }
+
+    /// Interval of the removal of empty containers, or None if they are kept
+    pub(crate) fn container_gc_interval(&self) -> Option<Duration> {
+        self.container_gc_interval.filter(|interval| !interval.is_zero())
+    }
+
+    /// Scope and collection of the Store, Atomics and Batch bucket opened with `identifier`, or
+    /// None if the link maps none to it. `default` and the empty identifier are the link's
+    /// collection unless mapped; the scope defaults to the link's scope.
+    pub(crate) fn keyvalue_bucket(&self, identifier: &str) -> Option<(&str, &str)> {
+        let Some(bucket) = self.keyvalue_buckets.get(identifier) else {
+            return match identifier {
+                "" | "default" => Some((self.scope.as_str(), self.collection.as_str())),
+                _ => None,
+            };
+        };
+        Some(match bucket.split_once('.') {
+            Some((scope, collection)) => (scope, collection),
+            None => (self.scope.as_str(), bucket),
+        })
+    }
+
+    /// Interval of the statistics log, or None if it is disabled
+    pub(crate) fn stats_interval(&self) -> Option<Duration> {
+        match self.stats_interval {
+            Some(interval) if interval.is_zero() => None,
+            Some(interval) => Some(interval),
+            None => Some(DEFAULT_STATS_INTERVAL),
+        }
+    }
+
+    /// Orphaned responses are reported when the link has a kv_timeout and an orphan_window
+    pub(crate) fn reports_orphans(&self) -> bool {
+        self.kv_timeout.is_some() && !self.orphan_window.unwrap_or_default().is_zero()
+    }
+
+    /// Timeout given to the SDK for key-value operations. When orphans are reported
+    /// the provider times requests out after kv_timeout, and the SDK keeps them
+    /// outstanding for the orphan_window after that.
+    pub(crate) fn sdk_kv_timeout(&self) -> Option<Duration> {
+        self.kv_timeout
+            .map(|timeout| timeout + self.orphan_window.unwrap_or_default())
+    }
+
+    pub(crate) fn orphan_report_interval(&self) -> Duration {
+        self.orphan_report_interval
+            .unwrap_or(DEFAULT_ORPHAN_REPORT_INTERVAL)
+    }
+
+    /// Returns true for the operations of the keyvalue contract
+    pub(crate) fn is_kv_operation(op: &str) -> bool {
+        OPERATIONS.contains(&op)
+    }
+
+    /// Scan consistency of the operation's N1QL queries
+    pub(crate) fn scan_consistency(&self, op: &str) -> ScanConsistency {
+        // the change feed and watches move past the highest cas they read, so a mutation missing
+        // from the index when they poll would never be reported
+        if matches!(op, "change_feed" | "watch") {
+            return ScanConsistency::RequestPlus;
+        }
+        self.scan_consistency
+            .operations
+            .get(op)
+            .copied()
+            .unwrap_or(self.scan_consistency.default)
+    }
+
+    /// Returns true if a query operation reads at_plus, so the link tracks its mutation tokens
+    pub(crate) fn uses_at_plus(&self) -> bool {
+        self.scan_consistency.default == ScanConsistency::AtPlus
+            || self.scan_consistency.operations.values().any(|c| *c == ScanConsistency::AtPlus)
+    }
+
+    /// Duration above which the operation is logged as slow, or None if it is never logged
+    pub(crate) fn slow_op_threshold(&self, op: &str) -> Option<Duration> {
+        let (service, default) = if QUERY_OPERATIONS.contains(&op) {
+            ("query", DEFAULT_QUERY_SLOW_THRESHOLD)
+        } else {
+            ("kv", DEFAULT_KV_SLOW_THRESHOLD)
+        };
+        let threshold = self
+            .slow_op_threshold
+            .get(op)
+            .or_else(|| self.slow_op_threshold.get(service))
+            .copied()
+            .unwrap_or(default);
+        (!threshold.is_zero()).then_some(threshold)
+    }
+
+    /// Interval of the connection monitor, or None if it is disabled
+    pub(crate) fn monitor_interval(&self) -> Option<Duration> {
+        match self.monitor_interval {
+            Some(interval) if interval.is_zero() => None,
+            Some(interval) => Some(interval),
+            None => Some(DEFAULT_MONITOR_INTERVAL),
+        }
+    }
+
+    /// Returns true if the connection string requires TLS
+    pub(crate) fn uses_tls(&self) -> bool {
+        self.url.starts_with("couchbases://")
+    }
+
+    /// Connection string of the link's cluster
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// N1QL keyspace of the link's collection
+    pub(crate) fn keyspace(&self) -> String {
+        format!("`{}`.`{}`.`{}`", self.bucket, self.scope, self.collection)
+    }
+
+    /// Returns the document key for a key of an actor
+    pub(crate) fn doc_key(&self, actor_id: &str, key: &str) -> String {
+        if self.namespace_by_actor {
+            format!("{}{}:{}", self.key_prefix, actor_id, key)
+        } else {
+            format!("{}{}", self.key_prefix, key)
+        }
+    }
+
+    /// Returns the document key as it may appear in logs: a stable hash if the link
+    /// redacts keys, the key itself otherwise
+    pub(crate) fn log_key(&self, key: &str) -> String {
+        if !self.redact_keys {
+            return key.to_string();
+        }
+        format!("#{:016x}", stable_hash(key))
+    }
+
+    pub(crate) fn quota_reconcile_interval(&self) -> Duration {
+        self.quota_reconcile_interval
+            .unwrap_or(DEFAULT_QUOTA_RECONCILE_INTERVAL)
+    }
+
+    /// Returns true if the link policy allows the operation
+    pub(crate) fn is_op_allowed(&self, op: &str) -> bool {
+        let allowed = match &self.allowed_ops {
+            Some(ops) => ops.iter().any(|o| o == op),
+            None => true,
+        };
+        allowed && !self.denied_ops.iter().any(|o| o == op)
+    }
+}
+
+/// Provider-wide settings from the host data's `config_json`
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ProviderConfig {
+    /// port of the Prometheus metrics endpoint; no endpoint if not set
+    #[serde(default)]
+    pub(crate) metrics_port: Option<u16>,
+    /// NATS subject on which metrics snapshots are published; none published if not set
+    #[serde(default)]
+    pub(crate) metrics_subject: Option<String>,
+    /// how often a metrics snapshot is published
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    metrics_publish_interval: Option<Duration>,
+    /// how long shutdown waits for in-flight Couchbase calls to complete
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    shutdown_grace_period: Option<Duration>,
+    /// default verbosity of the provider's logs about each link
+    #[serde(default, deserialize_with = "deserialize_level")]
+    pub(crate) log_level: Option<LevelFilter>,
+    /// public keys of the actors allowed to call CouchbaseAdmin
+    #[serde(default)]
+    pub(crate) admin_actors: Vec<String>,
+    /// contracts on which links are accepted
+    #[serde(default)]
+    contracts: Option<Vec<String>>,
+}
+
+impl ProviderConfig {
+    pub(crate) fn metrics_publish_interval(&self) -> Duration {
+        match self.metrics_publish_interval {
+            Some(interval) if !interval.is_zero() => interval,
+            _ => DEFAULT_METRICS_PUBLISH_INTERVAL,
+        }
+    }
+
+    pub(crate) fn shutdown_grace_period(&self) -> Duration {
+        self.shutdown_grace_period
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD)
+    }
+
+    pub(crate) fn contracts(&self) -> Vec<String> {
+        match &self.contracts {
+            Some(contracts) => contracts.iter().map(|c| c.trim().to_string()).collect(),
+            None => contracts::DEFAULT_CONTRACTS
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Load provider configuration from the host data
+pub(crate) fn load_provider_config(hd: &HostData) -> Result<ProviderConfig, RpcError> {
+    let config: ProviderConfig = match &hd.config_json {
+        Some(cj) if !cj.trim().is_empty() => serde_json::from_str(cj)
+            .map_err(|e| RpcError::ProviderInit(format!("invalid provider config_json: {}", e)))?,
+        _ => ProviderConfig::default(),
+    };
+    if config.contracts().iter().any(|c| c == contracts::WASI_KEYVALUE) {
+        return Err(RpcError::ProviderInit(format!(
+            "contract {} is served over wRPC, which this provider does not speak",
+            contracts::WASI_KEYVALUE
+        )));
+    }
+    Ok(config)
+}
+
+/// Check that a change feed subject can be published to: NATS tokens, without wildcards
+fn check_subject(subject: &str) -> Result<(), RpcError> {
+    let valid = !subject.split('.').any(|token| {
+        token.is_empty() || token == "*" || token == ">" || token.contains(char::is_whitespace)
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(RpcError::ProviderInit(format!(
+            "invalid {} value: {:?} cannot be published to, expected dot-separated tokens \
+             without whitespace or wildcards",
+            CHANGE_FEED_SUBJECT_KEY, subject
+        )))
+    }
 }
 
 /// Load configuration from 'values' field of LinkDefinition.
@@ -62,6 +1123,9 @@ pub(crate) fn load_config(ld: &LinkDefinition) -> Result<Config, RpcError> {
     if let Some(url) = ld.values.get(COUCHBASE_URL_KEY) {
         config.url = url.to_string();
     }
+    if let Some(scope) = ld.values.get(COUCHBASE_SCOPE_KEY) {
+        config.scope = scope.to_string();
+    }
     if let Some(collection) = ld.values.get(COUCHBASE_COLLECTION_KEY) {
         config.collection = collection.to_string();
     }
@@ -74,14 +1138,968 @@ pub(crate) fn load_config(ld: &LinkDefinition) -> Result<Config, RpcError> {
     if let Some(password) = ld.values.get(COUCHBASE_PASSWORD_KEY) {
         config.password = password.to_string();
     }
+    if let Some(ttl) = ld.values.get(DEFAULT_TTL_KEY) {
+        config.default_ttl = parse_ttl(ttl)
+            .map_err(|e| RpcError::ProviderInit(format!("invalid {} value: {}", DEFAULT_TTL_KEY, e)))?;
+    }
+    if let Some(ttl) = ld.values.get(MAX_TTL_KEY) {
+        config.max_ttl = parse_ttl(ttl)
+            .map_err(|e| RpcError::ProviderInit(format!("invalid {} value: {}", MAX_TTL_KEY, e)))?;
+    }
+    if let Some(timeout) = ld.values.get(KV_TIMEOUT_KEY) {
+        config.kv_timeout = Some(parse_duration(timeout).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", KV_TIMEOUT_KEY, e))
+        })?);
+    }
+    if let Some(prefix) = ld.values.get(KEY_PREFIX_KEY) {
+        config.key_prefix = prefix.to_string();
+    }
+    if let Some(namespace) = ld.values.get(NAMESPACE_BY_ACTOR_KEY) {
+        config.namespace_by_actor = parse_bool(NAMESPACE_BY_ACTOR_KEY, namespace)?;
+    }
+    if let Some(ops) = ld.values.get(ALLOWED_OPS_KEY) {
+        config.allowed_ops = Some(parse_list(ops));
+    }
+    if let Some(ops) = ld.values.get(DENIED_OPS_KEY) {
+        config.denied_ops = parse_list(ops);
+    }
+    if let Some(rate) = ld.values.get(MAX_OPS_PER_SEC_KEY) {
+        config.max_ops_per_sec = parse_number(MAX_OPS_PER_SEC_KEY, rate)?;
+    }
+    if let Some(burst) = ld.values.get(BURST_KEY) {
+        config.burst = parse_number(BURST_KEY, burst)?;
+    }
+    if let Some(quota) = ld.values.get(STORAGE_QUOTA_KEY) {
+        config.storage_quota = parse_number(STORAGE_QUOTA_KEY, quota)?;
+    }
+    if let Some(interval) = ld.values.get(QUOTA_RECONCILE_INTERVAL_KEY) {
+        config.quota_reconcile_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", QUOTA_RECONCILE_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(create) = ld.values.get(CREATE_BUCKET_IF_MISSING_KEY) {
+        config.create_bucket_if_missing = parse_bool(CREATE_BUCKET_IF_MISSING_KEY, create)?;
+    }
+    if let Some(quota) = ld.values.get(BUCKET_RAM_QUOTA_MB_KEY) {
+        config.bucket_ram_quota_mb = parse_number(BUCKET_RAM_QUOTA_MB_KEY, quota)?;
+    }
+    if let Some(create) = ld.values.get(CREATE_COLLECTION_IF_MISSING_KEY) {
+        config.create_collection_if_missing = parse_bool(CREATE_COLLECTION_IF_MISSING_KEY, create)?;
+    }
+    if let Some(interval) = ld.values.get(MONITOR_INTERVAL_KEY) {
+        config.monitor_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", MONITOR_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(failures) = ld.values.get(RECONNECT_AFTER_FAILURES_KEY) {
+        config.reconnect_after_failures = parse_number(RECONNECT_AFTER_FAILURES_KEY, failures)?;
+    }
+    if let Some(thresholds) = ld.values.get(SLOW_OP_THRESHOLD_KEY) {
+        config.slow_op_threshold = parse_thresholds(thresholds).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SLOW_OP_THRESHOLD_KEY, e))
+        })?;
+    }
+    if let Some(redact) = ld.values.get(REDACT_KEYS_KEY) {
+        config.redact_keys = parse_bool(REDACT_KEYS_KEY, redact)?;
+    }
+    if let Some(window) = ld.values.get(ORPHAN_WINDOW_KEY) {
+        config.orphan_window = Some(parse_duration(window).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", ORPHAN_WINDOW_KEY, e))
+        })?);
+    }
+    if let Some(interval) = ld.values.get(ORPHAN_REPORT_INTERVAL_KEY) {
+        config.orphan_report_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", ORPHAN_REPORT_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(interval) = ld.values.get(STATS_INTERVAL_KEY) {
+        config.stats_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", STATS_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(audit) = ld.values.get(AUDIT_COLLECTION_KEY) {
+        config.audit_collection = Some(audit.trim().to_string()).filter(|a| !a.is_empty());
+    }
+    if let Some(metadata) = ld.values.get(METADATA_COLLECTION_KEY) {
+        config.metadata_collection = Some(metadata.trim().to_string()).filter(|m| !m.is_empty());
+    }
+    if let Some(period) = ld.values.get(SOFT_DELETE_KEY) {
+        config.soft_delete = Some(parse_duration(period).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SOFT_DELETE_KEY, e))
+        })?);
+    }
+    if let Some(overflow) = ld.values.get(COUNTER_OVERFLOW_KEY) {
+        config.counter_overflow = overflow.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", COUNTER_OVERFLOW_KEY, e))
+        })?;
+    }
+    if let Some(storage) = ld.values.get(LIST_STORAGE_KEY) {
+        config.list_storage = storage.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", LIST_STORAGE_KEY, e))
+        })?;
+    }
+    if let Some(length) = ld.values.get(LIST_MAX_LENGTH_KEY) {
+        config.list_max_length = parse_number(LIST_MAX_LENGTH_KEY, length)?;
+    }
+    if let Some(ttl) = ld.values.get(CONTAINER_TTL_KEY) {
+        config.container_ttl = parse_ttl(ttl).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", CONTAINER_TTL_KEY, e))
+        })?;
+    }
+    if let Some(members) = ld.values.get(SET_MAX_MEMBERS_KEY) {
+        config.set_max_members = parse_number(SET_MAX_MEMBERS_KEY, members)?;
+    }
+    if let Some(eviction) = ld.values.get(SET_EVICTION_KEY) {
+        config.set_eviction = eviction.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SET_EVICTION_KEY, e))
+        })?;
+    }
+    if let Some(filters) = ld.values.get(SET_FILTERS_KEY) {
+        config.set_filters = parse_number(SET_FILTERS_KEY, filters)?;
+    }
+    if let Some(interval) = ld.values.get(CONTAINER_GC_INTERVAL_KEY) {
+        config.container_gc_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", CONTAINER_GC_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(buckets) = ld.values.get(KEYVALUE_BUCKETS_KEY) {
+        config.keyvalue_buckets = parse_keyvalue_buckets(buckets).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", KEYVALUE_BUCKETS_KEY, e))
+        })?;
+    }
+    for bucket in config.keyvalue_buckets.values() {
+        for name in bucket.split('.') {
+            check_name(KEYVALUE_BUCKETS_KEY, name)?;
+        }
+    }
+    if let Some(concurrency) = ld.values.get(BATCH_CONCURRENCY_KEY) {
+        config.batch_concurrency = parse_number(BATCH_CONCURRENCY_KEY, concurrency)?;
+    }
+    if config.batch_concurrency == 0 {
+        return Err(RpcError::ProviderInit(format!(
+            "invalid {} value: 0, expected at least 1",
+            BATCH_CONCURRENCY_KEY
+        )));
+    }
+    if let Some(refresh) = ld.values.get(SET_FILTER_REFRESH_KEY) {
+        config.set_filter_refresh = Some(parse_duration(refresh).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SET_FILTER_REFRESH_KEY, e))
+        })?);
+    }
+    if let Some(length) = ld.values.get(HISTORY_LENGTH_KEY) {
+        config.history_length = parse_number(HISTORY_LENGTH_KEY, length)?;
+    }
+    if let Some(content_type) = ld.values.get(CONTENT_TYPE_KEY) {
+        config.content_type = Some(content_type.trim().to_string()).filter(|c| !c.is_empty());
+    }
+    if let Some(content_type) = &config.content_type {
+        parse_content_type(content_type).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", CONTENT_TYPE_KEY, e))
+        })?;
+    }
+    if let Some(level) = ld.values.get(LOG_LEVEL_KEY) {
+        config.log_level = Some(parse_level(level).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", LOG_LEVEL_KEY, e))
+        })?);
+    }
+    if let Some(rates) = ld.values.get(LOG_SAMPLE_RATE_KEY) {
+        config.log_sample_rate = parse_sample_rates(rates).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", LOG_SAMPLE_RATE_KEY, e))
+        })?;
+    }
+    if let Some(threshold) = ld.values.get(SLOW_QUERY_THRESHOLD_KEY) {
+        config.slow_query_threshold = Some(parse_duration(threshold).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SLOW_QUERY_THRESHOLD_KEY, e))
+        })?);
+    }
+    if let Some(dead_letter) = ld.values.get(DEAD_LETTER_COLLECTION_KEY) {
+        config.dead_letter_collection =
+            Some(dead_letter.trim().to_string()).filter(|d| !d.is_empty());
+    }
+    if let Some(path) = ld.values.get(DEAD_LETTER_FILE_KEY) {
+        config.dead_letter_file = Some(path.trim().to_string()).filter(|p| !p.is_empty());
+    }
+    if let Some(write_behind) = ld.values.get(WRITE_BEHIND_KEY) {
+        config.write_behind = parse_bool(WRITE_BEHIND_KEY, write_behind)?;
+    }
+    if let Some(size) = ld.values.get(WRITE_BEHIND_QUEUE_SIZE_KEY) {
+        config.write_behind_queue_size = parse_number(WRITE_BEHIND_QUEUE_SIZE_KEY, size)?;
+    }
+    if let Some(interval) = ld.values.get(WRITE_BEHIND_RETRY_INTERVAL_KEY) {
+        config.write_behind_retry_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!(
+                "invalid {} value: {}",
+                WRITE_BEHIND_RETRY_INTERVAL_KEY, e
+            ))
+        })?);
+    }
+    if let Some(attempts) = ld.values.get(WRITE_BEHIND_MAX_ATTEMPTS_KEY) {
+        config.write_behind_max_attempts = parse_number(WRITE_BEHIND_MAX_ATTEMPTS_KEY, attempts)?;
+    }
+    if let Some(path) = ld.values.get(WRITE_BEHIND_SPILL_FILE_KEY) {
+        config.write_behind_spill_file = Some(path.trim().to_string()).filter(|p| !p.is_empty());
+    }
+    if let Some(rates) = ld.values.get(FAULT_INJECTION_KEY) {
+        config.fault_injection = parse_fault_rates(rates).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", FAULT_INJECTION_KEY, e))
+        })?;
+    }
+    if let Some(latency) = ld.values.get(FAULT_INJECTION_LATENCY_KEY) {
+        config.fault_injection_latency = Some(parse_duration(latency).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", FAULT_INJECTION_LATENCY_KEY, e))
+        })?);
+    }
+    if let Some(format) = ld.values.get(VALUE_FORMAT_KEY) {
+        config.value_format = format.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", VALUE_FORMAT_KEY, e))
+        })?;
+    }
+    if let Some(compression) = ld.values.get(COMPRESSION_KEY) {
+        config.compression = compression.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", COMPRESSION_KEY, e))
+        })?;
+    }
+    if let Some(threshold) = ld.values.get(COMPRESSION_THRESHOLD_KEY) {
+        config.compression_threshold = parse_number(COMPRESSION_THRESHOLD_KEY, threshold)?;
+    }
+    if let Some(size) = ld.values.get(CHUNK_SIZE_KEY) {
+        config.chunk_size = parse_number(CHUNK_SIZE_KEY, size)?;
+    }
+    if config.chunk_size > 0 && !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&config.chunk_size) {
+        return Err(RpcError::ProviderInit(format!(
+            "invalid {} value: {}, expected 0 or between {} and {}",
+            CHUNK_SIZE_KEY, config.chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+        )));
+    }
+    if let Some(subject) = ld.values.get(CHANGE_FEED_SUBJECT_KEY) {
+        config.change_feed_subject = Some(subject.trim().to_string()).filter(|s| !s.is_empty());
+    }
+    if let Some(prefix) = ld.values.get(CHANGE_FEED_PREFIX_KEY) {
+        config.change_feed_prefix = prefix.to_string();
+    }
+    if let Some(interval) = ld.values.get(CHANGE_FEED_INTERVAL_KEY) {
+        config.change_feed_interval = Some(parse_duration(interval).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", CHANGE_FEED_INTERVAL_KEY, e))
+        })?);
+    }
+    if let Some(expirations) = ld.values.get(CHANGE_FEED_EXPIRATIONS_KEY) {
+        config.change_feed_expirations = parse_bool(CHANGE_FEED_EXPIRATIONS_KEY, expirations)?;
+    }
+    if let Some(delivery) = ld.values.get(CHANGE_FEED_DELIVERY_KEY) {
+        config.change_feed_delivery = delivery.parse().map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", CHANGE_FEED_DELIVERY_KEY, e))
+        })?;
+    }
+    match &config.change_feed_subject {
+        Some(subject) if config.change_feed_delivery.reaches_lattice() => check_subject(subject)?,
+        _ => {}
+    }
+    if let Some(ensure) = ld.values.get(ENSURE_INDEXES_KEY) {
+        config.ensure_indexes = parse_bool(ENSURE_INDEXES_KEY, ensure)?;
+    }
+    if let Some(sqldb) = ld.values.get(SQLDB_KEY) {
+        config.sqldb = parse_bool(SQLDB_KEY, sqldb)?;
+    }
+    if let Some(key) = ld.values.get(ENCRYPTION_KEY_KEY) {
+        config.encryption_key = Some(key.trim().to_string());
+    }
+    // keeps the key out of the link definition, which the lattice shares
+    if let Some(var) = ld.values.get(ENCRYPTION_KEY_ENV_KEY) {
+        let key = std::env::var(var.trim()).map_err(|_| {
+            RpcError::ProviderInit(format!(
+                "invalid {} value: environment variable {} is not set",
+                ENCRYPTION_KEY_ENV_KEY, var
+            ))
+        })?;
+        config.encryption_key = Some(key.trim().to_string());
+    }
+    if let Some(key) = &config.encryption_key {
+        parse_encryption_key(key).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {}: {}", ENCRYPTION_KEY_KEY, e))
+        })?;
+    }
+    if let Some(consistency) = ld.values.get(SCAN_CONSISTENCY_KEY) {
+        config.scan_consistency = parse_scan_consistencies(consistency).map_err(|e| {
+            RpcError::ProviderInit(format!("invalid {} value: {}", SCAN_CONSISTENCY_KEY, e))
+        })?;
+    }
+    // the keyspace is the only part of the provider's N1QL statements not passed as a parameter
+    check_name(COUCHBASE_BUCKET_KEY, &config.bucket)?;
+    check_name(COUCHBASE_SCOPE_KEY, &config.scope)?;
+    check_name(COUCHBASE_COLLECTION_KEY, &config.collection)?;
+    for op in config.allowed_ops.iter().flatten().chain(config.denied_ops.iter()) {
+        if !OPERATIONS.contains(&op.as_str()) {
+            return Err(RpcError::ProviderInit(format!("unknown operation in link policy: {}", op)));
+        }
+    }
+    check_sqldb(&config)?;
     Ok(config)
 }
 
-// Create Couchbase collection connection
-pub(crate) async fn create_collection_conection(config: Config) -> Result<crate::Collection, RpcError> {
-    let cluster = Cluster::connect(config.url, config.username, config.password);
+/// Refuse SqlDb on links that keep their actor within some keys: statements are not confined
+/// to them, so they would read and write the keys of other prefixes and actors
+fn check_sqldb(config: &Config) -> Result<(), RpcError> {
+    if config.sqldb && (!config.key_prefix.is_empty() || config.namespace_by_actor) {
+        return Err(RpcError::ProviderInit(format!(
+            "{} cannot be enabled on a link with {} or {}: statements reach every key of the \
+             bucket",
+            SQLDB_KEY, KEY_PREFIX_KEY, NAMESPACE_BY_ACTOR_KEY
+        )));
+    }
+    Ok(())
+}
+
+/// Parse a comma-separated list, ignoring blank entries
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, RpcError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| RpcError::ProviderInit(format!("invalid {} value: {}", key, value)))
+}
+
+/// Check a content type such as `application/json` or `text/plain; charset=utf-8`
+pub(crate) fn parse_content_type(content_type: &str) -> Result<(), String> {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let token = |t: &str| {
+        !t.is_empty()
+            && t.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    match essence.split_once('/') {
+        Some((kind, subtype)) if token(kind) && token(subtype) && content_type.len() <= 255 => {
+            Ok(())
+        }
+        _ => Err(format!(
+            "'{}' is not a content type such as application/json",
+            content_type
+        )),
+    }
+}
+
+/// Decode a base64 AES-256 key
+pub(crate) fn parse_encryption_key(key: &str) -> Result<Vec<u8>, String> {
+    let key = base64::decode(key.trim()).map_err(|_| "the key is not base64".to_string())?;
+    if key.len() != 32 {
+        return Err(format!("the key has {} bytes, AES-256 needs 32", key.len()));
+    }
+    Ok(key)
+}
+
+/// Refuse bucket, scope and collection names with characters Couchbase does not allow
+fn check_name(key: &str, value: &str) -> Result<(), RpcError> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '%' | '.');
+    if value.chars().all(allowed) {
+        Ok(())
+    } else {
+        Err(RpcError::ProviderInit(format!(
+            "invalid {} value: {}, expected letters, digits, _, -, % or .",
+            key, value
+        )))
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, RpcError> {
+    value.trim().to_ascii_lowercase().parse().map_err(|_| {
+        RpcError::ProviderInit(format!("invalid {} value: {}, expected true or false", key, value))
+    })
+}
+
+/// Parse a duration such as `250ms`, `30s`, `5m`, `12h` or `7d`.
+/// A bare number is interpreted as seconds.
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (num, unit) = value.split_at(split);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let secs_per_unit = match unit.trim() {
+        "ms" => return Ok(Duration::from_millis(num)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit in '{}'", value)),
+    };
+    num.checked_mul(secs_per_unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", value))
+}
+
+/// Separator of the documents the provider derives from a key: `<key>::ttl`, `<key>::history`,
+/// `<key>::element::<n>` and `<key>::chunk::<generation>::<n>`
+pub(crate) const KEY_SEPARATOR: &str = "::";
+
+/// Refuse actor keys containing the separator of derived documents, which could name one
+pub(crate) fn check_key(key: &str) -> Result<(), RpcError> {
+    if key.contains(KEY_SEPARATOR) {
+        return Err(RpcError::InvalidParameter(format!(
+            "key '{}' contains '{}', which is reserved for the provider's own documents",
+            key, KEY_SEPARATOR
+        )));
+    }
+    Ok(())
+}
+
+/// Longest expiry Couchbase reads as seconds from now: it reads longer ones as a unix time
+const MAX_RELATIVE_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+/// Returns the expiry to send Couchbase for a document to expire `secs` seconds after the unix
+/// time `now`: the seconds up to 30 days, the unix time to expire at beyond
+pub(crate) fn server_expiry(secs: u64, now: u64) -> Duration {
+    match secs {
+        secs if secs <= MAX_RELATIVE_EXPIRY => Duration::from_secs(secs),
+        secs => Duration::from_secs(now.saturating_add(secs)),
+    }
+}
 
-    let bucket = cluster.bucket(config.bucket);
-    let collection = bucket.default_collection();
-    Ok(collection)
+/// Parse a TTL, which must be a whole number of seconds
+pub(crate) fn parse_ttl(value: &str) -> Result<u32, String> {
+    let duration = parse_duration(value)?;
+    if duration.subsec_nanos() != 0 {
+        return Err(format!("ttl '{}' must be a whole number of seconds", value));
+    }
+    u32::try_from(duration.as_secs()).map_err(|_| format!("ttl '{}' is too large", value))
+}
+
+/// Parse slow operation thresholds such as `kv=250ms,query=2s,get=50ms`
+fn parse_thresholds(value: &str) -> Result<HashMap<String, Duration>, String> {
+    parse_list(value)
+        .iter()
+        .map(|entry| {
+            let (name, duration) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=duration, got '{}'", entry))?;
+            threshold_entry(name.trim(), duration)
+        })
+        .collect()
+}
+
+fn threshold_entry(name: &str, duration: &str) -> Result<(String, Duration), String> {
+    if !matches!(name, "kv" | "query")
+        && !OPERATIONS.contains(&name)
+        && !QUERY_OPERATIONS.contains(&name)
+        && !INTERNAL_OPERATIONS.contains(&name)
+    {
+        return Err(format!("unknown operation '{}'", name));
+    }
+    Ok((name.to_string(), parse_duration(duration)?))
+}
+
+/// Parse sampling rates such as `10` or `10,get=100,set=1`:
+/// a bare number is the default rate, `op=N` entries set the rate of one operation
+fn parse_sample_rates(value: &str) -> Result<SampleRates, String> {
+    let mut rates = SampleRates::default();
+    for entry in parse_list(value) {
+        let parse_rate = |rate: &str| {
+            rate.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid sample rate '{}'", entry))
+        };
+        match entry.split_once('=') {
+            Some((op, rate)) => {
+                let op = op.trim();
+                if !OPERATIONS.contains(&op) {
+                    return Err(format!("unknown operation '{}'", op));
+                }
+                rates.operations.insert(op.to_string(), parse_rate(rate)?);
+            }
+            None => rates.default = parse_rate(&entry)?,
+        }
+    }
+    Ok(rates)
+}
+
+/// Parse bucket mappings of the Store, Atomics and Batch services, `identifier=collection` or
+/// `identifier=scope.collection` entries such as `sessions=cache.sessions`
+fn parse_keyvalue_buckets(value: &str) -> Result<HashMap<String, String>, String> {
+    let mut buckets = HashMap::new();
+    for entry in parse_list(value) {
+        match entry.split_once('=') {
+            Some((identifier, bucket)) if !bucket.trim().is_empty() => {
+                buckets.insert(identifier.trim().to_string(), bucket.trim().to_string());
+            }
+            _ => return Err(format!("expected identifier=collection, got '{}'", entry)),
+        }
+    }
+    Ok(buckets)
+}
+
+/// Parse a scan consistency, with `op=consistency` entries for single query operations,
+/// such as `not_bounded,scan_keys=request_plus`
+fn parse_scan_consistencies(value: &str) -> Result<ScanConsistencies, String> {
+    let mut consistencies = ScanConsistencies::default();
+    for entry in parse_list(value) {
+        match entry.split_once('=') {
+            Some((op, consistency)) => {
+                let op = op.trim();
+                // search runs on the full-text search service, which has no scan consistency,
+                // and N1QL transactions and the change feed always read at request_plus
+                if !QUERY_OPERATIONS.contains(&op)
+                    || matches!(op, "search" | "transact" | "change_feed")
+                {
+                    return Err(format!("unknown query operation '{}'", op));
+                }
+                consistencies.operations.insert(op.to_string(), consistency.parse()?);
+            }
+            None => consistencies.default = entry.parse()?,
+        }
+    }
+    Ok(consistencies)
+}
+
+fn deserialize_scan_consistencies<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<ScanConsistencies, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_scan_consistencies(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_sample_rates<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SampleRates, D::Error> {
+    let value = NumberOrText::deserialize(deserializer)?.into_text();
+    parse_sample_rates(&value).map_err(serde::de::Error::custom)
+}
+
+/// Parse fault rates such as `timeout=0.01,not_found=0.05,latency=0.1`.
+/// Each rate is between 0 and 1, and together they may not exceed 1.
+fn parse_fault_rates(value: &str) -> Result<FaultRates, String> {
+    let mut rates = FaultRates::default();
+    for entry in parse_list(value) {
+        let (fault, rate) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected fault=rate, got '{}'", entry))?;
+        let rate = rate
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| (0.0..=1.0).contains(rate))
+            .ok_or_else(|| format!("invalid fault rate '{}'", entry))?;
+        match fault.trim() {
+            "latency" => rates.latency = rate,
+            "timeout" => rates.timeout = rate,
+            "not_found" => rates.not_found = rate,
+            fault => return Err(format!("unknown fault '{}'", fault)),
+        }
+    }
+    if rates.latency + rates.timeout + rates.not_found > 1.0 {
+        return Err("fault rates add up to more than 1".to_string());
+    }
+    Ok(rates)
+}
+
+fn deserialize_fault_rates<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<FaultRates, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_fault_rates(&value).map_err(serde::de::Error::custom)
+}
+
+/// Parse a log level: `off`, `error`, `warn`, `info`, `debug` or `trace`
+fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("unknown log level '{}'", value))
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<LevelFilter>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_level(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Durations and rates in json config may be a number or a string,
+/// for example a number of seconds or a duration string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u64),
+    Text(String),
+}
+
+impl NumberOrText {
+    fn into_text(self) -> String {
+        match self {
+            NumberOrText::Number(n) => n.to_string(),
+            NumberOrText::Text(text) => text,
+        }
+    }
+}
+
+fn deserialize_ttl<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let value = NumberOrText::deserialize(deserializer)?.into_text();
+    parse_ttl(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = NumberOrText::deserialize(deserializer)?.into_text();
+    parse_duration(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Thresholds in json config may be a map of names to durations or a threshold string
+fn deserialize_thresholds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Thresholds {
+        Map(HashMap<String, NumberOrText>),
+        Text(String),
+    }
+    match Thresholds::deserialize(deserializer)? {
+        Thresholds::Map(map) => map
+            .into_iter()
+            .map(|(name, value)| threshold_entry(&name, &value.into_text()))
+            .collect(),
+        Thresholds::Text(text) => parse_thresholds(&text),
+    }
+    .map_err(serde::de::Error::custom)
+}
+
+// Create Couchbase cluster, bucket and collection connection
+pub(crate) async fn create_collection_conection(
+    config: &Config,
+) -> Result<crate::Connection, RpcError> {
+    let cluster = Cluster::connect(&config.url, &config.username, &config.password);
+    if config.create_bucket_if_missing {
+        crate::management::ensure_bucket(&cluster, config).await?;
+    }
+
+    let bucket = cluster.bucket(&config.bucket);
+    let collection = bucket.scope(&config.scope).collection(&config.collection);
+    if config.create_collection_if_missing {
+        crate::management::ensure_collection(
+            &cluster,
+            &collection,
+            &config.bucket,
+            &config.scope,
+            &config.collection,
+        )
+        .await?;
+    }
+    if config.ensure_indexes {
+        crate::management::ensure_indexes(&cluster, config).await?;
+    }
+    let mut audit = None;
+    if let Some((scope, name)) = config.audit_collection() {
+        let audit_collection = bucket.scope(scope).collection(name);
+        if config.create_collection_if_missing {
+            crate::management::ensure_collection(
+                &cluster,
+                &audit_collection,
+                &config.bucket,
+                scope,
+                name,
+            )
+            .await?;
+        }
+        audit = Some(audit_collection);
+    }
+    let mut dead_letter = None;
+    if let Some((scope, name)) = config.dead_letter_collection() {
+        let dead_letter_collection = bucket.scope(scope).collection(name);
+        if config.create_collection_if_missing {
+            crate::management::ensure_collection(
+                &cluster,
+                &dead_letter_collection,
+                &config.bucket,
+                scope,
+                name,
+            )
+            .await?;
+        }
+        dead_letter = Some(dead_letter_collection);
+    }
+    let mut metadata = None;
+    if let Some((scope, name)) = config.metadata_collection() {
+        let metadata_collection = bucket.scope(scope).collection(name);
+        if config.create_collection_if_missing {
+            crate::management::ensure_collection(
+                &cluster,
+                &metadata_collection,
+                &config.bucket,
+                scope,
+                name,
+            )
+            .await?;
+        }
+        metadata = Some(metadata_collection);
+    }
+    Ok(crate::Connection {
+        cluster,
+        bucket,
+        collection,
+        audit,
+        dead_letter,
+        metadata,
+    })
+}
+
+/// FNV-1a hash of the text, unlike std's hashers the same in every process and release
+pub(crate) fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::string;
+    use proptest::prelude::*;
+
+    #[test]
+    fn keyspace_names() {
+        assert!(check_name(COUCHBASE_BUCKET_KEY, "travel-sample").is_ok());
+        assert!(check_name(COUCHBASE_SCOPE_KEY, "_default").is_ok());
+        assert!(check_name(COUCHBASE_COLLECTION_KEY, "kv` WHERE 1=1 --").is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(43_200)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604_800)));
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn sqldb_isolation() {
+        let mut config = Config::default();
+        assert!(check_sqldb(&config).is_ok());
+        config.sqldb = true;
+        assert!(check_sqldb(&config).is_ok());
+        config.key_prefix = "tenant:".to_string();
+        assert!(check_sqldb(&config).is_err());
+        config.key_prefix.clear();
+        config.namespace_by_actor = true;
+        assert!(check_sqldb(&config).is_err());
+    }
+
+    #[test]
+    fn change_feed_deliveries() {
+        assert_eq!(" Lattice".parse(), Ok(ChangeFeedDelivery::Lattice));
+        assert!(ChangeFeedDelivery::Both.reaches_actor());
+        assert!(ChangeFeedDelivery::Both.reaches_lattice());
+        assert!(!ChangeFeedDelivery::Actor.reaches_lattice());
+        assert!(!ChangeFeedDelivery::Lattice.reaches_actor());
+        assert!("nats".parse::<ChangeFeedDelivery>().is_err());
+        assert!(check_subject("couchbase.changes.sessions").is_ok());
+        assert!(check_subject("couchbase.changes.*").is_err());
+        assert!(check_subject("couchbase.>").is_err());
+        assert!(check_subject("couchbase..changes").is_err());
+        assert!(check_subject("couchbase changes").is_err());
+    }
+
+    #[test]
+    fn value_formats() {
+        assert_eq!("json".parse(), Ok(ValueFormat::Json));
+        assert_eq!("raw_base64".parse(), Ok(ValueFormat::RawBase64));
+        assert_eq!("MsgPack".parse(), Ok(ValueFormat::Msgpack));
+        assert!("raw".parse::<ValueFormat>().is_err());
+        assert!("binary".parse::<ValueFormat>().is_err());
+        assert_eq!("Deflate".parse(), Ok(Compression::Deflate));
+        assert!("snappy".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn counter_overflows() {
+        assert_eq!(" Saturate".parse(), Ok(CounterOverflow::Saturate));
+        assert_eq!("error".parse(), Ok(CounterOverflow::Error));
+        assert!("wrap".parse::<CounterOverflow>().is_err());
+        assert_eq!("elements".parse(), Ok(ListStorage::Elements));
+        assert!("documents".parse::<ListStorage>().is_err());
+        assert_eq!("Oldest".parse(), Ok(SetEviction::Oldest));
+        assert!("lru".parse::<SetEviction>().is_err());
+    }
+
+    #[test]
+    fn content_types() {
+        assert!(parse_content_type("application/json").is_ok());
+        assert!(parse_content_type("text/plain; charset=utf-8").is_ok());
+        assert!(parse_content_type("application/vnd.api+json").is_ok());
+        assert!(parse_content_type("json").is_err());
+        assert!(parse_content_type("text/").is_err());
+        assert!(parse_content_type("a b/c").is_err());
+    }
+
+    #[test]
+    fn refuses_wasi_keyvalue_contract() {
+        // non-exhaustive, so built field by field
+        let mut hd = HostData::default();
+        assert_eq!(
+            load_provider_config(&hd).unwrap().contracts(),
+            vec!["wasmcloud:keyvalue"]
+        );
+        hd.config_json = Some(r#"{"contracts": ["wasmcloud:keyvalue", "wasi:keyvalue"]}"#.into());
+        assert!(load_provider_config(&hd).is_err());
+    }
+
+    #[test]
+    fn lists_link_collections() {
+        let config = Config {
+            scope: "app".to_string(),
+            collection: "kv".to_string(),
+            audit_collection: Some("audit".to_string()),
+            metadata_collection: Some("meta.keys".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.collections(),
+            vec![("app", "kv"), ("app", "audit"), ("meta", "keys")]
+        );
+    }
+
+    #[test]
+    fn reserves_key_separator() {
+        assert!(check_key("user:42").is_ok());
+        assert!(check_key("user::42").is_err());
+        assert!(check_key("list::element::3").is_err());
+    }
+
+    #[test]
+    fn parse_ttls() {
+        assert_eq!(parse_ttl("1h"), Ok(3600));
+        assert_eq!(parse_ttl("0"), Ok(0));
+        assert!(parse_ttl("1500ms").is_err());
+        assert!(parse_ttl("100000d").is_err());
+        // longer than the 30 days Couchbase takes as relative: sent as a unix time
+        let now = 1_700_000_000;
+        assert_eq!(parse_ttl("60d"), Ok(5_184_000));
+        assert_eq!(server_expiry(5_184_000, now), Duration::from_secs(now + 5_184_000));
+        assert_eq!(server_expiry(2_592_000, now), Duration::from_secs(2_592_000));
+        assert_eq!(server_expiry(60, now), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn scan_consistencies() {
+        let mut config = Config::new();
+        assert_eq!(config.scan_consistency("scan_keys"), ScanConsistency::NotBounded);
+        config.scan_consistency =
+            parse_scan_consistencies("not_bounded, scan_keys=request_plus").unwrap();
+        assert_eq!(config.scan_consistency("scan_keys"), ScanConsistency::RequestPlus);
+        assert_eq!(config.scan_consistency("sql_query"), ScanConsistency::NotBounded);
+        assert!(!config.uses_at_plus());
+        config.scan_consistency = parse_scan_consistencies("sql_query=at_plus").unwrap();
+        assert!(config.uses_at_plus());
+        assert!(parse_scan_consistencies("get=request_plus").is_err());
+        assert!(parse_scan_consistencies("transact=at_plus").is_err());
+        assert!(parse_scan_consistencies("at_least").is_err());
+    }
+
+    #[test]
+    fn sample_rates() {
+        let rates = parse_sample_rates("10, get=100").unwrap();
+        assert_eq!(rates.default, 10);
+        assert_eq!(rates.operations["get"], 100);
+        assert!(parse_sample_rates("fetch=2").is_err());
+        assert!(parse_sample_rates("often").is_err());
+    }
+
+    #[test]
+    fn fault_rates() {
+        let rates = parse_fault_rates("timeout=0.01, not_found=0.05,latency=0.1").unwrap();
+        assert_eq!(
+            rates,
+            FaultRates {
+                latency: 0.1,
+                timeout: 0.01,
+                not_found: 0.05,
+            }
+        );
+        assert!(!parse_fault_rates("").unwrap().is_enabled());
+        assert!(parse_fault_rates("timeout=2").is_err());
+        assert!(parse_fault_rates("crash=0.1").is_err());
+        assert!(parse_fault_rates("timeout=0.6,latency=0.6").is_err());
+    }
+
+    #[test]
+    fn slow_op_thresholds() {
+        let mut config = Config::new();
+        assert_eq!(config.slow_op_threshold("get"), Some(DEFAULT_KV_SLOW_THRESHOLD));
+        assert_eq!(
+            config.slow_op_threshold("quota_reconcile"),
+            Some(DEFAULT_QUERY_SLOW_THRESHOLD)
+        );
+        config.slow_op_threshold = parse_thresholds("kv=250ms, get=50ms, query=0").unwrap();
+        assert_eq!(config.slow_op_threshold("get"), Some(Duration::from_millis(50)));
+        assert_eq!(config.slow_op_threshold("set"), Some(Duration::from_millis(250)));
+        assert_eq!(config.slow_op_threshold("quota_reconcile"), None);
+        assert!(parse_thresholds("get").is_err());
+        assert!(parse_thresholds("fetch=1s").is_err());
+    }
+
+    #[test]
+    fn keyvalue_buckets() {
+        let mut config = Config::new();
+        config.keyvalue_buckets =
+            parse_keyvalue_buckets("sessions=cache.sessions, logs=logs").unwrap();
+        assert_eq!(config.keyvalue_bucket("default"), Some((DEFAULT_SCOPE, DEFAULT_COLLECTION)));
+        assert_eq!(config.keyvalue_bucket("sessions"), Some(("cache", "sessions")));
+        assert_eq!(config.keyvalue_bucket("logs"), Some((DEFAULT_SCOPE, "logs")));
+        assert_eq!(config.keyvalue_bucket("other"), None);
+        assert!(parse_keyvalue_buckets("sessions").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn namespaces_any_key(
+            key_prefix in string(8),
+            namespace_by_actor in any::<bool>(),
+            actor_id in string(16),
+            key in string(64),
+            other in string(64),
+        ) {
+            let config = Config {
+                key_prefix,
+                namespace_by_actor,
+                redact_keys: true,
+                ..Config::new()
+            };
+            let doc_key = config.doc_key(&actor_id, &key);
+            // export strips the namespace of the empty key to recover keys
+            let namespace = config.doc_key(&actor_id, "");
+            prop_assert_eq!(doc_key.strip_prefix(&namespace), Some(key.as_str()));
+            prop_assert_eq!(doc_key == config.doc_key(&actor_id, &other), key == other);
+
+            let logged = config.log_key(&key);
+            prop_assert_eq!(&logged, &config.log_key(&key));
+            prop_assert_eq!(logged.len(), 17);
+            prop_assert!(logged.starts_with('#') && u64::from_str_radix(&logged[1..], 16).is_ok());
+        }
+    }
+
+    #[test]
+    fn logs_keys() {
+        let mut config = Config::new();
+        assert_eq!(config.log_key("session:42"), "session:42");
+        config.redact_keys = true;
+        assert_eq!(config.log_key("session:42"), config.log_key("session:42"));
+        assert_ne!(config.log_key("session:42"), config.log_key("session:43"));
+    }
 }
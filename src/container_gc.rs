@@ -0,0 +1,102 @@
+//! Background removal of the empty lists and sets of a link, for long-running deployments
+//!
+//! With `container_gc_interval` set, the link periodically finds, with N1QL, the containers that
+//! only take room, and removes them with the cas they were found with, so a container written
+//! meanwhile is kept:
+//! - lists and sets stored as an empty array, unless the link's `value_format` is `json`, with
+//!   which `set` may have stored `[]` as a value
+//! - indexes of lists stored by elements that no longer hold any element
+//! - `<name>::ttl` documents of containers that expired after their TTL or were deleted
+//!
+//! A missing container reads like an empty one, so removing them changes no result.
+use std::{sync::Weak, time::Duration};
+
+use couchbase::{CouchbaseError, RemoveOptions};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn, Level};
+
+use crate::config::ValueFormat;
+use crate::query::{self, N1ql};
+use crate::{scan, CouchbaseLink};
+
+const OP: &str = "container_gc";
+
+/// Containers removed by a run at most, the others are left to the next runs
+const GC_BATCH: u32 = 1000;
+
+/// A container to remove
+#[derive(Deserialize)]
+struct Garbage {
+    id: String,
+    cas: u64,
+    /// length of the document's JSON
+    size: u64,
+}
+
+/// Periodically remove the link's empty containers. Stops when the link is dropped.
+pub(crate) fn spawn_collector(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately: leave the link time to settle
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            match collect(&link).await {
+                Ok(0) => {}
+                Ok(removed) if link.logs(Level::INFO) => {
+                    info!(actor_id = %link.actor_id, removed, "empty containers removed")
+                }
+                Ok(_) => {}
+                Err(e) if link.logs(Level::WARN) => {
+                    warn!(actor_id = %link.actor_id, "empty container removal failed: {}", e)
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+/// Remove up to GC_BATCH empty containers of the link and return how many were removed
+async fn collect(link: &CouchbaseLink) -> Result<usize, CouchbaseError> {
+    let statement = N1ql::on(
+        "SELECT META(d).id AS id, META(d).cas AS cas, LENGTH(ENCODE_JSON(d)) AS size \
+         FROM {keyspace} AS d WHERE META(d).id LIKE $pattern \
+         AND (($arrays AND d = []) OR d.kvcouchbase_list.count = 0 \
+         OR (META(d).id LIKE '%::ttl' AND NOT EXISTS (SELECT RAW 1 FROM {keyspace} AS c \
+         USE KEYS SUBSTR(META(d).id, 0, LENGTH(META(d).id) - 5)))) \
+         LIMIT $limit",
+        &link.config,
+    );
+    let options = query::options(link, OP).named_parameters(json!({
+        "pattern": scan::like_prefix(&link.namespace()),
+        "arrays": link.config.value_format != ValueFormat::Json,
+        "limit": GC_BATCH,
+    }));
+    let garbage: Vec<Garbage> = query::query(link, OP, statement, options).await?;
+    let mut removed = 0;
+    for Garbage { id, cas, size } in garbage {
+        let options = kv_options!(link, RemoveOptions::default()).cas(cas);
+        let connection = link.connection();
+        let doc_key = id.clone();
+        let operation = async move { connection.collection.remove(doc_key, options).await };
+        match link.execute(OP, Some(&id), operation).await {
+            Ok(r) => {
+                link.written(&r);
+                if let Some(quota) = &link.quota {
+                    quota.sub(id.len() as u64 + size);
+                }
+                removed += 1;
+            }
+            // written or removed meanwhile: no longer garbage
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,495 @@
+//! Counters, lists and sets stored as JSON documents
+//!
+//! A counter is a JSON number, lists and sets are JSON arrays of their elements,
+//! stored like single values by the link's codec.
+//! Mutations read the document and write it back with its CAS, retrying with [CasRetry]
+//! when another writer changed it in between.
+use std::{ops::Range, time::Duration};
+
+use couchbase::{
+    CouchbaseError, GetAndTouchOptions, GetOptions, InsertOptions, MutateInOptions, RemoveOptions,
+    ReplaceOptions,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::cas::CasRetry;
+use crate::config::{Config, CounterOverflow, SetEviction};
+use crate::query::{self, N1ql};
+use crate::{dead_letter, errors::to_rpc_err, quota, tombstones, CouchbaseLink};
+
+/// Outcome of a container mutation
+pub(crate) struct Updated<R> {
+    pub(crate) result: R,
+    /// cas of the written document, None if nothing was written
+    pub(crate) cas: Option<u64>,
+}
+
+/// Read a container document and its cas, or None if it does not exist or was deleted
+pub(crate) async fn read<V: DeserializeOwned>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Option<(V, u64)>> {
+    match read_current(link, op, key).await? {
+        (Some(value), Some(cas)) => Ok(Some((value, cas))),
+        _ => Ok(None),
+    }
+}
+
+/// Read a container document and the cas to replace it with. A deleted document has no
+/// value and the cas of its tombstone.
+async fn read_current<V: DeserializeOwned>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<(Option<V>, Option<u64>)> {
+    let options = kv_options!(link, GetOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move { connection.collection.get(doc_key, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => {
+            let content: serde_json::Value = r.content().map_err(to_rpc_err)?;
+            if tombstones::is_tombstone(&content) {
+                return Ok((None, Some(r.cas())));
+            }
+            // the same error as when the SDK decodes the content
+            let value = serde_json::from_value(content).map_err(|e| {
+                to_rpc_err(CouchbaseError::DecodingFailure {
+                    ctx: Default::default(),
+                    source: e.into(),
+                })
+            })?;
+            Ok((Some(value), Some(r.cas())))
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok((None, None)),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Read a list or set and its cas, its elements decoded by the link's codec
+pub(crate) async fn read_strings(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Option<(Vec<String>, u64)>> {
+    match read::<Vec<serde_json::Value>>(link, op, key).await? {
+        Some((elements, cas)) => Ok(Some((link.codec.decode_all(key, elements)?, cas))),
+        None => Ok(None),
+    }
+}
+
+/// Expiry an update writes its document with
+#[derive(Clone, Copy)]
+enum Expiry {
+    /// the expiry the document has, none for a new document
+    Keep,
+    Set(Option<Duration>),
+}
+
+/// Apply `apply` to the current value of a container, None if it does not exist,
+/// and write the new value back with the document's expiry. `apply` returns the new value,
+/// or None to leave the document unchanged, and the result of the operation.
+/// Writes that fail in Couchbase or keep conflicting are recorded as dead letters.
+pub(crate) async fn update<V, R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    apply: impl FnMut(Option<V>) -> RpcResult<(Option<V>, R)>,
+) -> RpcResult<Updated<R>>
+where
+    V: Serialize + DeserializeOwned,
+{
+    update_document(link, op, key, Expiry::Keep, apply).await
+}
+
+/// Update a container like [update], writing it with the given expiry rather than its own
+pub(crate) async fn update_with_expiry<V, R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    expiry: Option<Duration>,
+    apply: impl FnMut(Option<V>) -> RpcResult<(Option<V>, R)>,
+) -> RpcResult<Updated<R>>
+where
+    V: Serialize + DeserializeOwned,
+{
+    update_document(link, op, key, Expiry::Set(expiry), apply).await
+}
+
+async fn update_document<V, R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    expiry: Expiry,
+    mut apply: impl FnMut(Option<V>) -> RpcResult<(Option<V>, R)>,
+) -> RpcResult<Updated<R>>
+where
+    V: Serialize + DeserializeOwned,
+{
+    let mut retry = CasRetry::new();
+    loop {
+        let (current, cas) = read_current::<V>(link, op, key)
+            .await
+            .inspect_err(|e| dead_letter::record(link, op, key, e))?;
+        // read after the value: a touch in between changes the cas, and the write is retried
+        let expiry = match expiry {
+            Expiry::Keep if current.is_some() => current_expiry(link, op, key)
+                .await
+                .inspect_err(|e| dead_letter::record(link, op, key, e))?,
+            Expiry::Keep => None,
+            Expiry::Set(expiry) => expiry,
+        };
+        let old_size = match &current {
+            Some(value) => quota::doc_size(key, &to_json(value)?),
+            None => 0,
+        };
+        let (value, result) = match apply(current)? {
+            (Some(value), result) => (to_json(&value)?, result),
+            (None, result) => return Ok(Updated { result, cas: None }),
+        };
+        let new_size = quota::doc_size(key, &value);
+        link.check_quota(new_size.saturating_sub(old_size))?;
+        link.record_sizes(op, key, new_size as usize - key.len());
+
+        let connection = link.connection();
+        let doc_key = key.to_string();
+        let written = match cas {
+            Some(cas) => {
+                let mut options = kv_options!(link, ReplaceOptions::default()).cas(cas);
+                if let Some(expiry) = expiry {
+                    options = options.expiry(expiry);
+                }
+                let operation =
+                    async move { connection.collection.replace(doc_key, value, options).await };
+                link.execute(op, Some(key), operation).await
+            }
+            None => {
+                let mut options = kv_options!(link, InsertOptions::default());
+                if let Some(expiry) = expiry {
+                    options = options.expiry(expiry);
+                }
+                let operation =
+                    async move { connection.collection.insert(doc_key, value, options).await };
+                link.execute(op, Some(key), operation).await
+            }
+        };
+        match written {
+            Ok(r) => {
+                link.written(&r);
+                if let Some(quota) = &link.quota {
+                    quota.add(new_size);
+                    quota.sub(old_size);
+                }
+                return Ok(Updated {
+                    result,
+                    cas: Some(r.cas()),
+                });
+            }
+            // another writer created, changed or removed the document: start over
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentExists { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => {}
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(link, op, key, &e);
+                return Err(e);
+            }
+        }
+        retry
+            .conflicted(key)
+            .await
+            .inspect_err(|e| dead_letter::record(link, op, key, e))?;
+    }
+}
+
+/// Update a list or set like [update], its elements encoded and decoded by the link's codec,
+/// writing it with its [expiry]
+pub(crate) async fn update_strings<R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    mut apply: impl FnMut(Option<Vec<String>>) -> RpcResult<(Option<Vec<String>>, R)>,
+) -> RpcResult<Updated<R>> {
+    let expiry = expiry(link, op, key).await?;
+    update_with_expiry(link, op, key, expiry, |elements: Option<Vec<serde_json::Value>>| {
+        let elements = elements.map(|e| link.codec.decode_all(key, e)).transpose()?;
+        let (elements, result) = apply(elements)?;
+        let elements = elements.map(|e| link.codec.encode_all(key, &e)).transpose()?;
+        Ok((elements, result))
+    })
+    .await
+}
+
+/// Returns the expiry a document has, to write it again with: a replace without one
+/// removes it. The SDK cannot read the `$document.exptime` extended attribute, so the
+/// expiration time is read with N1QL, like `get_expiry`.
+pub(crate) async fn current_expiry(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Option<Duration>> {
+    let statement = N1ql::on(
+        "SELECT RAW META(d).expiration FROM {keyspace} AS d USE KEYS $key",
+        &link.config,
+    );
+    let options = query::options(link, op).named_parameters(serde_json::json!({ "key": key }));
+    let rows = query::query::<u64>(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    Ok(kept_expiry(rows.first().copied().unwrap_or_default()))
+}
+
+/// Returns the expiry to write a document whose expiration time is `expiration` with:
+/// the unix time itself, which Couchbase reads as such, or None if it does not expire
+fn kept_expiry(expiration: u64) -> Option<Duration> {
+    match expiration {
+        0 => None,
+        expiration => Some(Duration::from_secs(expiration)),
+    }
+}
+
+/// Key of the document holding the TTL `ExpireContainer` set on the list or set at `key`
+pub(crate) fn ttl_key(key: &str) -> String {
+    format!("{}::ttl", key)
+}
+
+/// Returns the expiry list and set mutations write the container at `key` with: the TTL
+/// `ExpireContainer` set on it, else the link's container_ttl, clamped to the link's max_ttl
+pub(crate) async fn expiry(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Option<Duration>> {
+    let secs = match read::<u32>(link, op, &ttl_key(key)).await? {
+        Some((secs, _)) => secs,
+        None => link.config.container_ttl,
+    };
+    match secs {
+        0 => Ok(None),
+        secs => Ok(link.expiry(key, secs)),
+    }
+}
+
+/// Set the expiry of a sub-document mutation. The SDK passes it on in microseconds, like the
+/// expiry of get-and-touch, so it goes through [crate::sdk_seconds].
+pub(crate) fn mutate_in_expiry(
+    options: MutateInOptions,
+    expiry: Option<Duration>,
+) -> MutateInOptions {
+    match expiry {
+        Some(expiry) => options.expiry(crate::sdk_seconds(expiry.as_secs())),
+        None => options,
+    }
+}
+
+/// Set the expiry of a container document, removing it with None.
+/// Returns the cas of the document, or None if it does not exist.
+pub(crate) async fn touch(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    expiry: Option<Duration>,
+) -> RpcResult<Option<u64>> {
+    let expiry = crate::sdk_seconds(expiry.map_or(0, |expiry| expiry.as_secs()));
+    let options = kv_options!(link, GetAndTouchOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation =
+        async move { connection.collection.get_and_touch(doc_key, expiry, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => Ok(Some(r.cas())),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Delete a container document. Returns the cas of the removal,
+/// or None if the document did not exist. Failed removals are recorded as dead letters.
+pub(crate) async fn remove(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Option<u64>> {
+    // the stored size is only needed to release it from the quota
+    let size = match &link.quota {
+        Some(_) => match read::<serde_json::Value>(link, op, key)
+            .await
+            .inspect_err(|e| dead_letter::record(link, op, key, e))?
+        {
+            Some((value, _)) => quota::doc_size(key, &value),
+            None => return Ok(None),
+        },
+        None => 0,
+    };
+    let options = kv_options!(link, RemoveOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move { connection.collection.remove(doc_key, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.sub(size);
+            }
+            Ok(Some(r.cas()))
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Returns the value of a counter document, which may also be a numeric string written by `set`
+pub(crate) fn counter_value(key: &str, value: Option<serde_json::Value>) -> RpcResult<i64> {
+    let number = match &value {
+        None => return Ok(0),
+        Some(serde_json::Value::Number(n)) => n.as_i64(),
+        Some(serde_json::Value::String(s)) => s.trim().parse().ok(),
+        Some(_) => None,
+    };
+    number.ok_or_else(|| {
+        RpcError::InvalidParameter(format!("value of {} is not an integer", key))
+    })
+}
+
+/// Returns a counter document's value plus `delta`. Totals beyond `min..=max` are clamped to it
+/// or fail, as `overflow` says.
+pub(crate) fn add_to_counter(
+    key: &str,
+    value: Option<serde_json::Value>,
+    delta: i64,
+    (min, max): (i64, i64),
+    overflow: CounterOverflow,
+) -> RpcResult<i64> {
+    let total = counter_value(key, value)?.checked_add(delta);
+    match (total, overflow) {
+        (Some(total), _) if (min..=max).contains(&total) => Ok(total),
+        (Some(total), CounterOverflow::Saturate) => Ok(total.clamp(min, max)),
+        (None, CounterOverflow::Saturate) if delta < 0 => Ok(min),
+        (None, CounterOverflow::Saturate) => Ok(max),
+        (_, CounterOverflow::Error) => Err(RpcError::InvalidParameter(format!(
+            "increment of {} overflows",
+            key
+        ))),
+    }
+}
+
+/// Make room in a set for `adding` new members within the link's set_max_members, evicting
+/// the members added first or failing according to its set_eviction
+pub(crate) fn make_room(
+    config: &Config,
+    set_name: &str,
+    set: &mut Vec<String>,
+    adding: usize,
+) -> RpcResult<()> {
+    let max = config.set_max_members as usize;
+    if max == 0 || set.len() + adding <= max {
+        return Ok(());
+    }
+    match config.set_eviction {
+        SetEviction::Oldest if adding <= max => {
+            set.drain(..set.len() + adding - max);
+            Ok(())
+        }
+        _ => Err(RpcError::Other(format!(
+            "couchbase set_full: {} cannot have more than {} members",
+            set_name, max
+        ))),
+    }
+}
+
+/// Returns the positions from `start` to `stop` included of a container of `len` elements,
+/// or None if there are none. Negative positions count from the end, -1 being the last element.
+pub(crate) fn index_range(len: usize, start: i32, stop: i32) -> Option<Range<usize>> {
+    let position = |index: i32| match index {
+        0.. => index as i64,
+        _ => len as i64 + index as i64,
+    };
+    let start = position(start).max(0);
+    let stop = position(stop).min(len as i64 - 1);
+    (start <= stop).then(|| start as usize..stop as usize + 1)
+}
+
+fn to_json<V: Serialize>(value: &V) -> RpcResult<serde_json::Value> {
+    serde_json::to_value(value).map_err(|e| RpcError::Ser(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counter_values() {
+        assert_eq!(counter_value("k", None).unwrap(), 0);
+        assert_eq!(counter_value("k", Some(json!(41))).unwrap(), 41);
+        assert_eq!(counter_value("k", Some(json!(" -7 "))).unwrap(), -7);
+        assert!(counter_value("k", Some(json!("seven"))).is_err());
+        assert!(counter_value("k", Some(json!([1]))).is_err());
+    }
+
+    #[test]
+    fn index_ranges() {
+        assert_eq!(index_range(5, 1, 3), Some(1..4));
+        assert_eq!(index_range(5, 0, -1), Some(0..5));
+        assert_eq!(index_range(5, -2, 10), Some(3..5));
+        assert_eq!(index_range(5, -10, 0), Some(0..1));
+        assert_eq!(index_range(5, 3, 1), None);
+        assert_eq!(index_range(5, 5, 9), None);
+        assert_eq!(index_range(0, 0, -1), None);
+    }
+
+    #[test]
+    fn set_limits() {
+        let mut config = Config::default();
+        config.set_max_members = 3;
+        let mut set: Vec<String> = vec!["a".into(), "b".into(), "c".into()];
+        assert!(make_room(&config, "s", &mut set, 1).is_err());
+        config.set_eviction = SetEviction::Oldest;
+        make_room(&config, "s", &mut set, 2).unwrap();
+        assert_eq!(set, ["c"]);
+        assert!(make_room(&config, "s", &mut set, 4).is_err());
+        config.set_max_members = 0;
+        make_room(&config, "s", &mut set, 100).unwrap();
+        assert_eq!(set, ["c"]);
+    }
+
+    #[test]
+    fn keeps_expiries() {
+        assert_eq!(kept_expiry(0), None);
+        // an absolute time, whatever is left of it
+        assert_eq!(kept_expiry(1_700_000_000), Some(Duration::from_secs(1_700_000_000)));
+    }
+
+    #[test]
+    fn subdoc_expiries() {
+        let options = mutate_in_expiry(MutateInOptions::default(), Some(Duration::from_secs(60)));
+        // what the SDK sends the server as microseconds is the number of seconds
+        assert!(format!("{:?}", options).contains("expiry: Some(60µs)"));
+        let options = mutate_in_expiry(MutateInOptions::default(), None);
+        assert!(format!("{:?}", options).contains("expiry: None"));
+    }
+
+    #[test]
+    fn counter_overflows() {
+        let i32_range = (i32::MIN as i64, i32::MAX as i64);
+        let i64_range = (i64::MIN, i64::MAX);
+        let (error, saturate) = (CounterOverflow::Error, CounterOverflow::Saturate);
+        let max = Some(json!(i32::MAX));
+        assert!(add_to_counter("k", max.clone(), 1, i32_range, error).is_err());
+        let saturated = add_to_counter("k", max.clone(), 1, i32_range, saturate);
+        assert_eq!(saturated.unwrap(), i32::MAX as i64);
+        assert_eq!(add_to_counter("k", max, 1, i64_range, error).unwrap(), 1 << 31);
+        let min = Some(json!(i64::MIN));
+        assert!(add_to_counter("k", min.clone(), -1, i64_range, error).is_err());
+        assert_eq!(add_to_counter("k", min, -1, i64_range, saturate).unwrap(), i64::MIN);
+        // a 64-bit counter incremented through the 32-bit interface
+        let big = Some(json!(5_000_000_000i64));
+        assert_eq!(add_to_counter("k", big, -1, i32_range, saturate).unwrap(), i32::MAX as i64);
+    }
+}
@@ -0,0 +1,87 @@
+//! Contracts the provider accepts links on
+//!
+//! The provider accepts links on the contracts of its configuration, wasmcloud:keyvalue by
+//! default: links on any of them go into the same registry, keyed by actor, and every interface
+//! is served on every link. wasi:keyvalue is not one of them: its interfaces are served over
+//! wRPC, which the provider does not speak.
+//!
+//! wasmbus-rpc keeps one link per actor: a second link of the same actor, on another contract,
+//! is ignored, and deleting either removes the actor. An actor linked on two contracts would then
+//! lose the provider when one of its links is deleted, so on a deletion the provider asks the
+//! hosts for the actor's remaining links and relinks it with one of them.
+use wasmbus_rpc::core::LinkDefinition;
+
+/// Contract of the wasmcloud:keyvalue interface
+pub(crate) const KEYVALUE: &str = "wasmcloud:keyvalue";
+
+/// Contract of the wasi:keyvalue interfaces, served over wRPC and so refused
+pub(crate) const WASI_KEYVALUE: &str = "wasi:keyvalue";
+
+/// Contracts accepted when the provider configuration does not list them
+pub(crate) const DEFAULT_CONTRACTS: [&str; 1] = [KEYVALUE];
+
+/// Returns the link, among `links`, that still ties an actor to this provider after one of its
+/// links was deleted: same actor, provider and link name, on an accepted contract.
+/// A wasmcloud:keyvalue link is preferred, as it was the one in use if several remain.
+pub(crate) fn remaining_link(
+    links: Vec<LinkDefinition>,
+    actor_id: &str,
+    provider_id: &str,
+    link_name: &str,
+    contracts: &[String],
+) -> Option<LinkDefinition> {
+    links
+        .into_iter()
+        .filter(|ld| {
+            ld.actor_id == actor_id
+                && ld.provider_id == provider_id
+                && ld.link_name == link_name
+                && contracts.contains(&ld.contract_id)
+        })
+        .min_by_key(|ld| ld.contract_id != KEYVALUE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOBSTORE: &str = "wasmcloud:blobstore";
+
+    fn link(actor_id: &str, link_name: &str, contract_id: &str) -> LinkDefinition {
+        // non-exhaustive, so built field by field
+        let mut ld = LinkDefinition::default();
+        ld.actor_id = actor_id.to_string();
+        ld.provider_id = "VPROVIDER".to_string();
+        ld.link_name = link_name.to_string();
+        ld.contract_id = contract_id.to_string();
+        ld
+    }
+
+    #[test]
+    fn finds_remaining_links() {
+        let contracts = vec![KEYVALUE.to_string(), BLOBSTORE.to_string()];
+        let links = vec![
+            link("MACTOR", "other", BLOBSTORE),
+            link("MOTHER", "default", BLOBSTORE),
+            link("MACTOR", "default", "wasmcloud:messaging"),
+            link("MACTOR", "default", BLOBSTORE),
+        ];
+        let remaining = remaining_link(links.clone(), "MACTOR", "VPROVIDER", "default", &contracts);
+        assert_eq!(
+            remaining.map(|ld| ld.contract_id).as_deref(),
+            Some(BLOBSTORE)
+        );
+
+        let mut both = links.clone();
+        both.push(link("MACTOR", "default", KEYVALUE));
+        let remaining = remaining_link(both, "MACTOR", "VPROVIDER", "default", &contracts);
+        assert_eq!(
+            remaining.map(|ld| ld.contract_id).as_deref(),
+            Some(KEYVALUE)
+        );
+
+        assert!(remaining_link(links.clone(), "MACTOR", "VOTHER", "default", &contracts).is_none());
+        let legacy_only = vec![KEYVALUE.to_string()];
+        assert!(remaining_link(links, "MACTOR", "VPROVIDER", "default", &legacy_only).is_none());
+    }
+}
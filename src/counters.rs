@@ -0,0 +1,110 @@
+//! Conversion of counters stored in other formats into the JSON integers `increment` writes
+//!
+//! Other keyvalue providers, and `set`, store counters as numeric strings, and some clients
+//! write them as doubles such as `42.0`. `increment` reads those, but other applications
+//! and Couchbase's own binary counters only count on integers, so `MigrateCounters` rewrites
+//! them in place.
+use futures::StreamExt;
+use serde_json::json;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::query::{self, N1ql};
+use crate::scan::{like_derived, like_prefix};
+use crate::{containers, errors::to_rpc_err, CouchbaseLink};
+
+/// Counters converted at the same time
+const CONCURRENCY: usize = 16;
+
+/// Numeric strings a counter may be stored as, for the N1QL REGEXP_LIKE
+const NUMERIC_STRING: &str = r"\s*[-+]?[0-9]+(\.0*)?\s*";
+
+/// Convert the link's counters whose key starts with `prefix` into JSON integers,
+/// returning the number converted
+pub(crate) async fn migrate(link: &CouchbaseLink, op: &str, prefix: &str) -> RpcResult<u64> {
+    let doc_prefix = link.doc_key(prefix)?;
+    if doc_prefix.is_empty() {
+        return Err(RpcError::InvalidParameter(
+            "an empty prefix would convert every document of the collection".to_string(),
+        ));
+    }
+    let statement = N1ql::on(
+        "SELECT RAW META(d).id FROM {keyspace} AS d WHERE META(d).id LIKE $pattern \
+         AND META(d).id NOT LIKE $derived \
+         AND ((IS_STRING(d) AND REGEXP_LIKE(d, $numeric)) OR (IS_NUMBER(d) AND d = TRUNC(d)))",
+        &link.config,
+    );
+    let options = query::options(link, op).named_parameters(json!({
+        "pattern": like_prefix(&doc_prefix),
+        "derived": like_derived(&link.namespace()),
+        "numeric": NUMERIC_STRING,
+    }));
+    let ids: Vec<String> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    // the namespace prefix is the same for every key of the link
+    let namespace = link.namespace().len();
+    let mut conversions = futures::stream::iter(ids)
+        .map(|id| async move {
+            let updated = containers::update(link, op, &id, |value| {
+                match value.as_ref().and_then(to_integer) {
+                    Some(counter) => Ok((Some(serde_json::Value::from(counter)), true)),
+                    // an integer already, or changed since it was selected
+                    None => Ok((None, false)),
+                }
+            })
+            .await?;
+            if updated.result {
+                link.audit(op, &id[namespace..], updated.cas);
+            }
+            Ok::<_, RpcError>(updated.result)
+        })
+        .buffer_unordered(CONCURRENCY);
+    let mut converted = 0;
+    while let Some(result) = conversions.next().await {
+        if result? {
+            converted += 1;
+        }
+    }
+    Ok(converted)
+}
+
+/// Returns the integer a counter stored in another format holds, or None if the value is
+/// a JSON integer already or not a counter
+fn to_integer(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::String(s) => {
+            let s = s.trim();
+            let integer = match s.split_once('.') {
+                Some((integer, zeros)) if zeros.chars().all(|c| c == '0') => integer,
+                Some(_) => return None,
+                None => s,
+            };
+            integer.parse().ok()
+        }
+        serde_json::Value::Number(n) if !n.is_i64() => {
+            let f = n.as_f64()?;
+            // integral and within i64, whose bounds are exact doubles
+            (f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64).then_some(f as i64)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_counters() {
+        assert_eq!(to_integer(&json!(" 42 ")), Some(42));
+        assert_eq!(to_integer(&json!("+7")), Some(7));
+        assert_eq!(to_integer(&json!("-3.00")), Some(-3));
+        assert_eq!(to_integer(&json!(42.0)), Some(42));
+        assert_eq!(to_integer(&json!(42)), None);
+        assert_eq!(to_integer(&json!(42.5)), None);
+        assert_eq!(to_integer(&json!("4.2")), None);
+        assert_eq!(to_integer(&json!("forty-two")), None);
+        assert_eq!(to_integer(&json!(1e300)), None);
+        assert_eq!(to_integer(&json!([1])), None);
+    }
+}
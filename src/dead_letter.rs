@@ -0,0 +1,115 @@
+//! Dead-letter records of writes that failed for good, so operators can reconcile them later
+//!
+use std::path::Path;
+
+use couchbase::InsertOptions;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tracing::{warn, Level};
+use wasmbus_rpc::error::RpcError;
+
+use crate::CouchbaseLink;
+
+/// A write the provider could not complete
+#[derive(Debug, Serialize)]
+pub(crate) struct DeadLetter {
+    actor: String,
+    op: String,
+    /// document key, including the link's key prefix
+    key: String,
+    error: String,
+    timestamp: String,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(actor: &str, op: &str, key: &str, error: &RpcError) -> Self {
+        DeadLetter {
+            actor: actor.to_string(),
+            op: op.to_string(),
+            key: key.to_string(),
+            error: error.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Record a failed write to the link's dead-letter collection and file, if any.
+/// The record is written in the background so the caller gets its error without waiting.
+pub(crate) fn record(link: &CouchbaseLink, op: &str, key: &str, error: &RpcError) {
+    let connection = link.connection();
+    let path = link.config.dead_letter_file.clone();
+    if connection.dead_letter.is_none() && path.is_none() {
+        return;
+    }
+    let record = DeadLetter::new(&link.actor_id, op, key, error);
+    let id = format!("{}::{}", link.actor_id, wasmbus_rpc::provider::make_uuid());
+    let actor_id = link.actor_id.clone();
+    let log_failure = link.logs(Level::WARN);
+    tokio::spawn(async move {
+        if let Some(path) = path {
+            if let Err(e) = append(Path::new(&path), &record).await {
+                if log_failure {
+                    warn!(%actor_id, %path, "couchbase dead-letter record could not be written: {}", e);
+                }
+            }
+        }
+        if let Some(dead_letter) = &connection.dead_letter {
+            if let Err(e) = dead_letter
+                .insert(id, record, InsertOptions::default())
+                .await
+            {
+                if log_failure {
+                    warn!(%actor_id, "couchbase dead-letter record could not be written: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Append the record to the file as a line of JSON
+async fn append(path: &Path, record: &DeadLetter) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    // a single write keeps concurrent records on separate lines
+    file.write_all(&line).await?;
+    // tokio completes writes in the background until flushed
+    file.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn appends_json_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "kvcouchbase-dead-letter-{}.jsonl",
+            std::process::id()
+        ));
+        let error = RpcError::Other("couchbase timeout (retryable): timeout".to_string());
+        append(&path, &DeadLetter::new("actor", "set", "greeting", &error))
+            .await
+            .unwrap();
+        append(
+            &path,
+            &DeadLetter::new("actor", "list_add", "orders", &error),
+        )
+        .await
+        .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["op"], "set");
+        assert_eq!(records[1]["key"], "orders");
+        assert_eq!(records[1]["error"], error.to_string());
+    }
+}
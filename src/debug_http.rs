@@ -0,0 +1,204 @@
+//! `--debug-http <addr>`: serve a link's keys over plain HTTP, for development only
+//!
+//! Lets developers read and write what actors stored without Couchbase tooling:
+//!
+//! - `GET /keys/<key>` returns the value of a key, 404 if it does not exist
+//! - `PUT /keys/<key>[?expires=<secs>]` sets a key to the request body
+//! - `DELETE /keys/<key>` deletes a key, 404 if it does not exist
+//!
+//! Keys are percent-decoded and go through the link's `get`, `set` and `del`, so key prefixes,
+//! namespaces, encryption, compression and chunking apply as they do for the actor. The server
+//! has no authentication or TLS: it is meant for a developer's machine, and warns when it listens
+//! on anything but a loopback address. It runs until interrupted.
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use wasmbus_rpc::error::RpcError;
+use wasmcloud_interface_keyvalue::SetRequest;
+
+use crate::{cli, del_value, get_value, set_value, CouchbaseLink};
+
+/// Path under which the keys are served
+const KEYS_PATH: &str = "/keys/";
+
+/// Run the server. Returns whether it shut down cleanly.
+pub(crate) async fn run(addr: Option<&str>, args: &[&str]) -> bool {
+    match serve(addr, args).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("debug server failed: {}", e);
+            false
+        }
+    }
+}
+
+async fn serve(addr: Option<&str>, args: &[&str]) -> Result<(), String> {
+    let addr = addr.ok_or_else(|| "missing --debug-http <addr>".to_string())?;
+    let addr: SocketAddr = addr.parse().map_err(|e| format!("{}: {}", addr, e))?;
+    let link = Arc::new(cli::open_link(args).await?);
+    let make_svc = make_service_fn(move |_| {
+        let link = link.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let link = link.clone();
+                async move { Ok::<_, Infallible>(handle(&link, req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("{}: {}", addr, e))?
+        .serve(make_svc);
+    eprintln!(
+        "DEVELOPMENT ONLY: serving the link's keys on http://{}{}<key> without authentication",
+        server.local_addr(),
+        KEYS_PATH
+    );
+    if !addr.ip().is_loopback() {
+        eprintln!("warning: {} is reachable from other machines", addr);
+    }
+    server
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn handle(link: &CouchbaseLink, req: Request<Body>) -> Response<Body> {
+    let key = match key(req.uri().path()) {
+        Some(key) => key,
+        None => {
+            let usage = format!("not found; keys are served under {}<key>", KEYS_PATH);
+            return respond(StatusCode::NOT_FOUND, usage);
+        }
+    };
+    match *req.method() {
+        Method::GET => match get_value(link, "debug_get", &key).await {
+            Ok(r) if r.exists => respond(StatusCode::OK, r.value),
+            Ok(_) => respond(StatusCode::NOT_FOUND, format!("{} does not exist", key)),
+            Err(e) => error(e),
+        },
+        Method::PUT => {
+            let expires = match expires(req.uri().query()) {
+                Ok(expires) => expires,
+                Err(e) => return respond(StatusCode::BAD_REQUEST, e),
+            };
+            let value = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => match String::from_utf8(body.to_vec()) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        return respond(StatusCode::BAD_REQUEST, "value is not UTF-8".to_string())
+                    }
+                },
+                Err(e) => return respond(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            let request = SetRequest {
+                key,
+                value,
+                expires,
+            };
+            match set_value(link, "debug_set", &request).await {
+                Ok(()) => respond(StatusCode::NO_CONTENT, String::new()),
+                Err(e) => error(e),
+            }
+        }
+        Method::DELETE => match del_value(link, "debug_del", &key).await {
+            Ok(_) => respond(StatusCode::NO_CONTENT, String::new()),
+            Err(e) if e.to_string().contains("document_not_found") => {
+                respond(StatusCode::NOT_FOUND, format!("{} does not exist", key))
+            }
+            Err(e) => error(e),
+        },
+        _ => respond(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "use GET, PUT or DELETE".to_string(),
+        ),
+    }
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error(e: RpcError) -> Response<Body> {
+    let status = match e {
+        RpcError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+        RpcError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    respond(status, e.to_string())
+}
+
+/// Returns the key of a `/keys/<key>` path, percent-decoded
+fn key(path: &str) -> Option<String> {
+    let key = percent_decode(path.strip_prefix(KEYS_PATH)?)?;
+    Some(key).filter(|k| !k.is_empty())
+}
+
+/// Returns the `expires` seconds of a query string, 0 if it has none
+fn expires(query: Option<&str>) -> Result<u32, String> {
+    let value = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("expires="));
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid expires {}", value)),
+        None => Ok(0),
+    }
+}
+
+/// Decode the `%XX` escapes of a path segment. Returns None if an escape is invalid or the
+/// result is not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            byte => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_keys() {
+        assert_eq!(key("/keys/greeting").as_deref(), Some("greeting"));
+        assert_eq!(
+            key("/keys/session%3A42%2Fa%20b").as_deref(),
+            Some("session:42/a b")
+        );
+        assert_eq!(key("/keys/"), None);
+        assert_eq!(key("/metrics"), None);
+        assert_eq!(key("/keys/bad%zz"), None);
+        assert_eq!(key("/keys/cut%4"), None);
+        assert_eq!(key("/keys/sign%+1"), None);
+        assert_eq!(expires(None), Ok(0));
+        assert_eq!(expires(Some("a=b&expires=60")), Ok(60));
+        assert!(expires(Some("expires=soon")).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Diagnostics summary logged when a link is established
+//!
+use std::collections::{BTreeMap, BTreeSet};
+
+use couchbase::{CouchbaseResult, PingOptions, PingState, ServiceType};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{info, warn, Level};
+
+use crate::query::{self, N1ql};
+use crate::{management, CouchbaseLink};
+
+/// What the link's cluster and bucket look like, to make misconfiguration obvious early
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct LinkDiagnostics {
+    actor_id: String,
+    tls: bool,
+    nodes: usize,
+    /// distinct Couchbase Server versions of the nodes
+    cluster_versions: BTreeSet<String>,
+    /// services running on at least one node
+    services: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bucket_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replicas: Option<u64>,
+    /// ping outcome by service, as `<ok endpoints>/<endpoints>`
+    ping: BTreeMap<String, String>,
+    /// likely misconfigurations
+    warnings: Vec<String>,
+}
+
+/// Gather the link's diagnostics from the cluster and bucket management APIs and a ping
+pub(crate) async fn collect(link: &CouchbaseLink) -> LinkDiagnostics {
+    let mut diagnostics = LinkDiagnostics {
+        actor_id: link.actor_id.clone(),
+        tls: link.config.uses_tls(),
+        ..Default::default()
+    };
+    let connection = link.connection();
+
+    match management::get_json(&connection.cluster, "/pools/default".to_string()).await {
+        Ok(pool) => {
+            let nodes = pool["nodes"].as_array().cloned().unwrap_or_default();
+            diagnostics.nodes = nodes.len();
+            for node in &nodes {
+                if let Some(version) = node["version"].as_str() {
+                    diagnostics.cluster_versions.insert(version.to_string());
+                }
+                for service in node["services"].as_array().into_iter().flatten() {
+                    if let Some(service) = service.as_str() {
+                        diagnostics.services.insert(service.to_string());
+                    }
+                }
+            }
+        }
+        Err(e) => diagnostics.warnings.push(format!("cluster details unavailable: {}", e)),
+    }
+
+    let bucket_path = format!("/pools/default/buckets/{}", link.config.bucket);
+    match management::get_json(&connection.cluster, bucket_path).await {
+        Ok(bucket) => {
+            diagnostics.bucket_type = bucket["bucketType"].as_str().map(|t| t.to_string());
+            diagnostics.replicas = bucket["replicaNumber"].as_u64();
+        }
+        Err(e) => diagnostics.warnings.push(format!("bucket details unavailable: {}", e)),
+    }
+
+    let ping = link.execute("ping", None, async move {
+        connection.bucket.ping(PingOptions::default()).await
+    });
+    match ping.await {
+        Ok(result) => {
+            for (service, endpoints) in result.endpoints() {
+                let ok = endpoints.iter().filter(|e| e.state() == PingState::OK).count();
+                diagnostics
+                    .ping
+                    .insert(service.to_string(), format!("{}/{}", ok, endpoints.len()));
+            }
+            if !result.endpoints().contains_key(&ServiceType::KeyValue) {
+                diagnostics.warnings.push("no key-value endpoints".to_string());
+            }
+        }
+        Err(e) => diagnostics.warnings.push(format!("ping failed: {}", e)),
+    }
+
+    if link.config.fault_injection.is_enabled() {
+        diagnostics
+            .warnings
+            .push("fault injection is enabled".to_string());
+    }
+    if diagnostics.bucket_type.as_deref() == Some("memcached") {
+        diagnostics
+            .warnings
+            .push("memcached buckets do not support collections".to_string());
+    }
+    if link.config.storage_quota > 0
+        && !diagnostics.services.is_empty()
+        && !diagnostics.services.contains("n1ql")
+    {
+        diagnostics
+            .warnings
+            .push("storage_quota reconciliation requires the query service".to_string());
+    }
+    let uses_query = link.config.storage_quota > 0 || link.config.change_feed_subject.is_some();
+    if uses_query && !link.config.ensure_indexes && diagnostics.services.contains("n1ql") {
+        match has_primary_index(link).await {
+            Ok(true) => {}
+            Ok(false) => diagnostics.warnings.push(format!(
+                "{} has no primary index for query-backed operations, see ensure_indexes",
+                link.config.keyspace()
+            )),
+            Err(e) => diagnostics
+                .warnings
+                .push(format!("index details unavailable: {}", e)),
+        }
+    }
+    diagnostics
+}
+
+/// Returns true if the link's collection has a primary index
+async fn has_primary_index(link: &CouchbaseLink) -> CouchbaseResult<bool> {
+    // system:indexes names the default collection of a bucket by the bucket alone
+    let default_collection =
+        link.config.scope == "_default" && link.config.collection == "_default";
+    let (statement, parameters) = if default_collection {
+        (
+            "SELECT RAW COUNT(*) FROM system:indexes \
+             WHERE is_primary AND keyspace_id = $bucket AND bucket_id IS MISSING",
+            json!({ "bucket": link.config.bucket }),
+        )
+    } else {
+        (
+            "SELECT RAW COUNT(*) FROM system:indexes \
+             WHERE is_primary AND bucket_id = $bucket AND scope_id = $scope \
+             AND keyspace_id = $collection",
+            json!({
+                "bucket": link.config.bucket,
+                "scope": link.config.scope,
+                "collection": link.config.collection,
+            }),
+        )
+    };
+    let options = query::options(link, "index_lookup").named_parameters(parameters);
+    let counts: Vec<u64> =
+        query::query(link, "index_lookup", N1ql::fixed(statement), options).await?;
+    Ok(counts.first().is_some_and(|count| *count > 0))
+}
+
+/// Log the link's diagnostics, at WARN if anything looks misconfigured
+pub(crate) async fn report(link: &CouchbaseLink) {
+    let diagnostics = collect(link).await;
+    let summary = serde_json::to_string(&diagnostics).unwrap_or_default();
+    if diagnostics.warnings.is_empty() {
+        if link.logs(Level::INFO) {
+            info!(actor_id = %link.actor_id, diagnostics = %summary, "couchbase link diagnostics");
+        }
+    } else if link.logs(Level::WARN) {
+        warn!(actor_id = %link.actor_id, diagnostics = %summary, "couchbase link diagnostics");
+    }
+}
@@ -0,0 +1,111 @@
+//! Mapping of Couchbase errors to rpc errors actors can react to
+//!
+use couchbase::CouchbaseError;
+use wasmbus_rpc::error::RpcError;
+
+/// Category of a Couchbase error, used in error messages and metric labels
+pub(crate) fn category(e: &CouchbaseError) -> &'static str {
+    match e {
+        CouchbaseError::DocumentNotFound { .. } => "document_not_found",
+        CouchbaseError::DocumentExists { .. } => "document_exists",
+        CouchbaseError::DocumentLocked { .. } => "document_locked",
+        CouchbaseError::CasMismatch { .. } => "cas_mismatch",
+        CouchbaseError::Timeout { .. } => "timeout",
+        CouchbaseError::RequestCanceled { .. } => "request_canceled",
+        CouchbaseError::AuthenticationFailure { .. } => "authentication_failure",
+        CouchbaseError::TemporaryFailure { .. } => "temporary_failure",
+        CouchbaseError::ValueTooLarge { .. } => "value_too_large",
+        CouchbaseError::ServiceNotAvailable { .. } => "service_not_available",
+        CouchbaseError::BucketNotFound { .. }
+        | CouchbaseError::ScopeNotFound { .. }
+        | CouchbaseError::CollectionNotFound { .. } => "keyspace_not_found",
+        CouchbaseError::DurabilityLevelNotAvailable { .. }
+        | CouchbaseError::DurabilityImpossible { .. }
+        | CouchbaseError::DurabilityAmbiguous { .. }
+        | CouchbaseError::DurableWriteInProgress { .. }
+        | CouchbaseError::DurableWriteReCommitInProgress { .. } => "durability",
+        CouchbaseError::DecodingFailure { .. } | CouchbaseError::EncodingFailure { .. } => {
+            "transcoding"
+        }
+        CouchbaseError::InvalidArgument { .. } => "invalid_argument",
+        CouchbaseError::PathNotFound { .. }
+        | CouchbaseError::PathMismatch { .. }
+        | CouchbaseError::PathInvalid { .. }
+        | CouchbaseError::PathTooBig { .. }
+        | CouchbaseError::PathTooDeep { .. }
+        | CouchbaseError::PathExists { .. }
+        | CouchbaseError::ValueTooDeep { .. }
+        | CouchbaseError::ValueInvalid { .. }
+        | CouchbaseError::DocumentNotJson { .. } => "subdoc",
+        _ => "other",
+    }
+}
+
+/// Returns true if the same request may succeed when retried later
+pub(crate) fn is_retryable(e: &CouchbaseError) -> bool {
+    matches!(
+        e,
+        CouchbaseError::TemporaryFailure { .. }
+            | CouchbaseError::DocumentLocked { .. }
+            | CouchbaseError::ServiceNotAvailable { .. }
+            | CouchbaseError::DurableWriteInProgress { .. }
+            | CouchbaseError::DurableWriteReCommitInProgress { .. }
+            | CouchbaseError::Timeout {
+                ambiguous: false,
+                ..
+            }
+    )
+}
+
+/// Convert a Couchbase error to an rpc error.
+/// The message starts with `couchbase <category>:`, followed by `(retryable)` for
+/// errors that may succeed when retried, so actors can match on it.
+pub(crate) fn to_rpc_err(e: CouchbaseError) -> RpcError {
+    let category = category(&e);
+    let message = if is_retryable(&e) {
+        format!("couchbase {} (retryable): {}", category, e)
+    } else {
+        format!("couchbase {}: {}", category, e)
+    };
+    match e {
+        CouchbaseError::Timeout { .. } => RpcError::Timeout(message),
+        _ if category == "subdoc" => RpcError::InvalidParameter(message),
+        CouchbaseError::ValueTooLarge { .. } | CouchbaseError::InvalidArgument { .. } => {
+            RpcError::InvalidParameter(message)
+        }
+        CouchbaseError::DecodingFailure { .. } => RpcError::Deser(message),
+        CouchbaseError::EncodingFailure { .. } => RpcError::Ser(message),
+        _ => RpcError::Other(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use couchbase::ErrorContext;
+
+    #[test]
+    fn maps_categories() {
+        let ctx = ErrorContext::default;
+        match to_rpc_err(CouchbaseError::DocumentNotFound { ctx: ctx() }) {
+            RpcError::Other(message) => assert!(message.starts_with("couchbase document_not_found:")),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match to_rpc_err(CouchbaseError::Timeout {
+            ambiguous: false,
+            ctx: ctx(),
+        }) {
+            RpcError::Timeout(message) => assert!(message.starts_with("couchbase timeout (retryable):")),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(matches!(
+            to_rpc_err(CouchbaseError::ValueTooLarge { ctx: ctx() }),
+            RpcError::InvalidParameter(_)
+        ));
+        assert!(!is_retryable(&CouchbaseError::Timeout {
+            ambiguous: true,
+            ctx: ctx()
+        }));
+        assert!(is_retryable(&CouchbaseError::TemporaryFailure { ctx: ctx() }));
+    }
+}
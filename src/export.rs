@@ -0,0 +1,133 @@
+//! `export --prefix <p> --out <file>`: dump a link's documents to newline-delimited JSON
+//!
+//! Every document of the link whose key starts with the prefix is written as one record,
+//! `{"key": ..., "value": ..., "expiry": ...}`: the key without the link's key prefix, the document
+//! as stored, and the unix time in seconds at which it expires, 0 if it does not. Documents are
+//! exported as stored rather than decoded, so the records of encrypted or compressed values,
+//! lists, sets, chunks and TTLs restore exactly with `import`; a prefix takes the chunks and TTL
+//! documents of the keys it matches along. Documents are read with N1QL, in key order, a page at
+//! a time, so the export needs a primary index on the link's collection.
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::query::{self, N1ql};
+use crate::{cli, scan, CouchbaseLink};
+
+/// Operation name of the export's queries, in metrics
+const OP: &str = "export";
+
+/// Documents read by one query
+const PAGE_SIZE: u32 = 1000;
+
+/// A document of an export file
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Record {
+    /// key without the link's key prefix
+    pub(crate) key: String,
+    /// the document as stored
+    pub(crate) value: serde_json::Value,
+    /// unix time in seconds at which the document expires, 0 if it does not
+    #[serde(default)]
+    pub(crate) expiry: u64,
+}
+
+#[derive(Deserialize)]
+struct Row {
+    id: String,
+    #[serde(default)]
+    expiration: u64,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+/// Run the export. Returns whether it completed.
+pub(crate) async fn run(args: &[&str]) -> bool {
+    match export(args).await {
+        Ok((exported, out)) => {
+            eprintln!("exported {} documents to {}", exported, out);
+            true
+        }
+        Err(e) => {
+            eprintln!("export failed: {}", e);
+            false
+        }
+    }
+}
+
+async fn export(args: &[&str]) -> Result<(u64, String), String> {
+    let prefix = cli::option(args, "--prefix")?.unwrap_or_default();
+    let out = cli::option(args, "--out")?.ok_or_else(|| "missing --out <file>".to_string())?;
+    let link = cli::open_link(args).await?;
+    let file = std::fs::File::create(out).map_err(|e| format!("{}: {}", out, e))?;
+    let mut writer = std::io::BufWriter::new(file);
+    let mut exported = 0;
+    let mut after = String::new();
+    loop {
+        let rows = page(&link, prefix, &after).await?;
+        for row in &rows {
+            let record = record(&link, row);
+            serde_json::to_writer(&mut writer, &record).map_err(|e| e.to_string())?;
+            writer
+                .write_all(b"\n")
+                .map_err(|e| format!("{}: {}", out, e))?;
+            exported += 1;
+        }
+        match rows.last() {
+            Some(last) if rows.len() == PAGE_SIZE as usize => after = last.id.clone(),
+            _ => break,
+        }
+    }
+    writer.flush().map_err(|e| format!("{}: {}", out, e))?;
+    Ok((exported, out.to_string()))
+}
+
+/// Returns the next documents of the link starting with `prefix`, after the document `after`
+async fn page(link: &CouchbaseLink, prefix: &str, after: &str) -> Result<Vec<Row>, String> {
+    let statement = N1ql::on(
+        "SELECT META(d).id AS id, META(d).expiration AS expiration, d AS `value` \
+         FROM {keyspace} AS d WHERE META(d).id LIKE $pattern AND META(d).id > $after \
+         ORDER BY META(d).id LIMIT $limit",
+        &link.config,
+    );
+    let options = query::options(link, OP).named_parameters(json!({
+        "pattern": scan::like_prefix(&link.config.doc_key(&link.actor_id, prefix)),
+        "after": after,
+        "limit": PAGE_SIZE,
+    }));
+    query::query(link, OP, statement, options)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn record(link: &CouchbaseLink, row: &Row) -> Record {
+    // the namespace prefix is the same for every key of the link
+    let namespace = link.namespace().len();
+    Record {
+        key: row.id[namespace..].to_string(),
+        value: row.value.clone(),
+        expiry: row.expiration,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_records() {
+        let record = Record {
+            key: "greeting".to_string(),
+            value: json!({"kvcouchbase_value": "hello"}),
+            expiry: 1700000000,
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"key":"greeting","value":{"kvcouchbase_value":"hello"},"expiry":1700000000}"#
+        );
+        let read: Record = serde_json::from_str(r#"{"key":"k","value":"v"}"#).unwrap();
+        assert_eq!(read.expiry, 0);
+    }
+}
@@ -0,0 +1,115 @@
+//! Fault injection, so actor developers can test their error handling against a healthy cluster
+//!
+use std::{future::Future, time::Duration};
+
+use couchbase::{CouchbaseError, CouchbaseResult, ErrorContext};
+
+use crate::config::{Config, FaultRates};
+
+/// Operations that read a document, and may be answered as if it did not exist
+const NOT_FOUND_OPERATIONS: &[&str] = &[
+    "get",
+    "del",
+    "list_range",
+    "set_query",
+    "set_intersection",
+    "set_union",
+    "get_with_cas",
+    "lookup_path",
+    "touch",
+    "get_and_touch",
+    "get_expiry",
+    "unlock",
+];
+
+/// A fault injected into one operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Fault {
+    Latency(Duration),
+    Timeout,
+    NotFound,
+}
+
+/// Picks the faults injected into a link's operations
+pub(crate) struct FaultInjector {
+    rates: FaultRates,
+    latency: Duration,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(rates: FaultRates, latency: Duration) -> Self {
+        FaultInjector { rates, latency }
+    }
+
+    /// Returns the fault to inject into this operation, if any.
+    /// Operations the provider runs on its own behalf are left alone.
+    pub(crate) fn pick(&self, op: &str) -> Option<Fault> {
+        if !Config::is_kv_operation(op) {
+            return None;
+        }
+        self.pick_with(op, rand::random())
+    }
+
+    /// Pick a fault from a uniform random number in [0, 1)
+    fn pick_with(&self, op: &str, roll: f64) -> Option<Fault> {
+        let mut threshold = self.rates.timeout;
+        if roll < threshold {
+            return Some(Fault::Timeout);
+        }
+        if NOT_FOUND_OPERATIONS.contains(&op) {
+            threshold += self.rates.not_found;
+            if roll < threshold {
+                return Some(Fault::NotFound);
+            }
+        }
+        threshold += self.rates.latency;
+        (roll < threshold).then_some(Fault::Latency(self.latency))
+    }
+}
+
+/// Run the operation with the fault injected: delayed, or replaced by an error
+pub(crate) async fn inject<T>(
+    fault: Option<Fault>,
+    operation: impl Future<Output = CouchbaseResult<T>>,
+) -> CouchbaseResult<T> {
+    match fault {
+        Some(Fault::Latency(latency)) => {
+            tokio::time::sleep(latency).await;
+            operation.await
+        }
+        Some(Fault::Timeout) => Err(CouchbaseError::Timeout {
+            ambiguous: false,
+            ctx: ErrorContext::default(),
+        }),
+        Some(Fault::NotFound) => Err(CouchbaseError::DocumentNotFound {
+            ctx: ErrorContext::default(),
+        }),
+        None => operation.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_faults_by_rate() {
+        let latency = Duration::from_millis(100);
+        let faults = FaultInjector::new(
+            FaultRates {
+                latency: 0.2,
+                timeout: 0.1,
+                not_found: 0.3,
+            },
+            latency,
+        );
+        assert_eq!(faults.pick_with("get", 0.05), Some(Fault::Timeout));
+        assert_eq!(faults.pick_with("get", 0.35), Some(Fault::NotFound));
+        assert_eq!(faults.pick_with("get", 0.45), Some(Fault::Latency(latency)));
+        assert_eq!(faults.pick_with("get", 0.65), None);
+        // writes are never answered with not found
+        assert_eq!(faults.pick_with("set", 0.15), Some(Fault::Latency(latency)));
+        assert_eq!(faults.pick_with("set", 0.35), None);
+        assert_eq!(faults.pick("ping"), None);
+    }
+}
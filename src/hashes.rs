@@ -0,0 +1,205 @@
+//! Hashes, maps of fields to values under one key, for actors ported from Redis
+//!
+//! A hash is a JSON object stored under the hash name, `{"<field>": <value>}`, its values stored
+//! by the link's codec like list elements. `HSet`, `HGet` and `HDel` read and write one field
+//! with sub-document operations, so only that field is transferred; `HSet` looks the field up
+//! with the hash's cas first, to tell new fields and deleted hashes, and writes it with that cas.
+//! A hash that does not exist yet, or was deleted with `soft_delete`, is written whole.
+use couchbase::{CouchbaseError, LookupInOptions, LookupInSpec, MutateInOptions, MutateInSpec};
+use serde_json::{Map, Value};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::cas::CasRetry;
+use crate::containers::{self, Updated};
+use crate::interface::HashField;
+use crate::tombstones::TOMBSTONE;
+use crate::{dead_letter, errors::to_rpc_err, CouchbaseLink};
+
+/// Returns the sub-document path of a field, refusing the field naming tombstones
+fn path(field: &str) -> RpcResult<String> {
+    match field {
+        TOMBSTONE => Err(RpcError::InvalidParameter(format!(
+            "hash field name {} is reserved",
+            field
+        ))),
+        field => Ok(format!("`{}`", field.replace('`', "``"))),
+    }
+}
+
+/// Set a field of the hash at `key`, creating the hash if it does not exist. Returns whether the
+/// field is new.
+pub(crate) async fn set(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    field: &str,
+    value: &str,
+) -> RpcResult<Updated<bool>> {
+    let path = path(field)?;
+    let stored = link.codec.encode(key, value)?;
+    let size = (field.len() + stored.to_string().len()) as u64;
+    link.check_quota(size)?;
+    let expiry = containers::expiry(link, op, key).await?;
+    let mut retry = CasRetry::new();
+    loop {
+        let options = kv_options!(link, LookupInOptions::default());
+        let connection = link.connection();
+        let doc_key = key.to_string();
+        let specs = vec![LookupInSpec::exists(TOMBSTONE), LookupInSpec::exists(&path)];
+        let operation = async move {
+            connection
+                .collection
+                .lookup_in(doc_key, specs, options)
+                .await
+        };
+        let (cas, exists) = match link.execute(op, Some(key), operation).await {
+            Ok(r) if !r.exists(0) => (r.cas(), r.exists(1)),
+            // deleted or missing: write the whole hash
+            Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => {
+                return create(link, op, key, field, stored, expiry).await
+            }
+            Err(e) => return Err(to_rpc_err(e)),
+        };
+        let options = kv_options!(link, MutateInOptions::default()).cas(cas);
+        let options = containers::mutate_in_expiry(options, expiry);
+        let connection = link.connection();
+        let doc_key = key.to_string();
+        let specs = vec![MutateInSpec::upsert(&path, stored.clone())];
+        let operation = async move {
+            connection
+                .collection
+                .mutate_in(doc_key, specs, options)
+                .await
+        };
+        match link.execute(op, Some(key), operation).await {
+            Ok(r) => {
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                return Ok(Updated {
+                    result: !exists,
+                    cas: Some(r.cas()),
+                });
+            }
+            // changed by another writer meanwhile: look the field up again
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => retry.conflicted(key).await?,
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(link, op, key, &e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Set a field of a hash that does not exist or was deleted, writing the whole hash
+async fn create(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    field: &str,
+    stored: Value,
+    expiry: Option<std::time::Duration>,
+) -> RpcResult<Updated<bool>> {
+    containers::update_with_expiry(link, op, key, expiry, |hash: Option<Map<String, Value>>| {
+        let mut hash = hash.unwrap_or_default();
+        let new = hash.insert(field.to_string(), stored.clone()).is_none();
+        Ok((Some(hash), new))
+    })
+    .await
+}
+
+/// Returns the value of a field of the hash at `key`, or None if the field or the hash does not
+/// exist
+pub(crate) async fn get(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    field: &str,
+) -> RpcResult<Option<String>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![LookupInSpec::get(path(field)?)]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) if r.exists(0) => Ok(Some(
+            link.codec.decode(key, r.content(0).map_err(to_rpc_err)?)?,
+        )),
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Remove a field of the hash at `key`. Returns the cas of the hash if the field existed.
+pub(crate) async fn remove(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    field: &str,
+) -> RpcResult<Option<u64>> {
+    let expiry = containers::expiry(link, op, key).await?;
+    let options = kv_options!(link, MutateInOptions::default());
+    let options = containers::mutate_in_expiry(options, expiry);
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![MutateInSpec::remove(path(field)?)]);
+    let operation = async move {
+        connection
+            .collection
+            .mutate_in(doc_key, specs, options)
+            .await
+    };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => Ok(Some(r.cas())),
+        Err(CouchbaseError::DocumentNotFound { .. }) | Err(CouchbaseError::PathNotFound { .. }) => {
+            Ok(None)
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Returns the fields of the hash at `key` and their values, ordered by field, none if it does
+/// not exist
+pub(crate) async fn get_all(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Vec<HashField>> {
+    let hash = match containers::read::<Map<String, Value>>(link, op, key).await? {
+        Some((hash, _)) => hash,
+        None => return Ok(Vec::new()),
+    };
+    let mut fields = hash
+        .into_iter()
+        .map(|(field, value)| {
+            Ok(HashField {
+                field,
+                value: link.codec.decode(key, value)?,
+            })
+        })
+        .collect::<RpcResult<Vec<HashField>>>()?;
+    fields.sort_by(|a, b| a.field.cmp(&b.field));
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_field_paths() {
+        assert_eq!(path("name").unwrap(), "`name`");
+        assert_eq!(path("a.b[0]").unwrap(), "`a.b[0]`");
+        assert_eq!(path("x`y").unwrap(), "`x``y`");
+        assert!(path(TOMBSTONE).is_err());
+    }
+}
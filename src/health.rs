@@ -0,0 +1,71 @@
+//! Link health checks using the cluster ping API
+//!
+use std::{sync::atomic::Ordering, time::Duration};
+
+use couchbase::{PingOptions, PingState, ServiceType};
+use serde::Serialize;
+
+use crate::CouchbaseLink;
+
+/// how long a health check waits for a ping before reporting the link unhealthy
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Health of one link's key-value connection
+#[derive(Debug, Serialize)]
+pub(crate) struct LinkHealth {
+    pub(crate) actor_id: String,
+    pub(crate) healthy: bool,
+    /// the connection monitor has seen repeated ping failures
+    pub(crate) degraded: bool,
+    /// slowest key-value endpoint latency, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) kv_latency_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Ping the key-value endpoints of the link's bucket
+pub(crate) async fn ping_link(link: &CouchbaseLink) -> LinkHealth {
+    let mut health = LinkHealth {
+        actor_id: link.actor_id.clone(),
+        healthy: false,
+        degraded: link.degraded.load(Ordering::Relaxed),
+        kv_latency_ms: None,
+        error: None,
+    };
+    let connection = link.connection();
+    let ping = link.execute("ping", None, async move {
+        connection.bucket.ping(PingOptions::default()).await
+    });
+    let result = match tokio::time::timeout(PING_TIMEOUT, ping).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            health.error = Some(e.to_string());
+            return health;
+        }
+        Err(_) => {
+            health.error = Some(format!("ping timed out after {:?}", PING_TIMEOUT));
+            return health;
+        }
+    };
+    let endpoints = match result.endpoints().get(&ServiceType::KeyValue) {
+        Some(endpoints) if !endpoints.is_empty() => endpoints,
+        _ => {
+            health.error = Some("no key-value endpoints".to_string());
+            return health;
+        }
+    };
+    health.kv_latency_ms = endpoints.iter().map(|e| e.latency().as_millis()).max();
+    match endpoints.iter().find(|e| e.state() != PingState::OK) {
+        Some(failed) => {
+            health.error = Some(format!(
+                "{} {}: {}",
+                failed.remote().unwrap_or_default(),
+                failed.state(),
+                failed.error().unwrap_or_default()
+            ));
+        }
+        None => health.healthy = true,
+    }
+    health
+}
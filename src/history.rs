@@ -0,0 +1,108 @@
+//! Previous values of keys, for actors that need simple change tracking
+//!
+//! On links with a history_length, each `set` that replaces a value appends the replaced value,
+//! as stored, to the key's history document, `<key>::history`, a JSON array of at most
+//! history_length entries, newest first.
+use couchbase::{CouchbaseError, GetOptions};
+use serde::{Deserialize, Serialize};
+use tracing::{warn, Level};
+use wasmbus_rpc::error::RpcResult;
+
+use crate::interface::HistoryEntry;
+use crate::{chunks, containers, errors::to_rpc_err, tombstones, CouchbaseLink};
+
+/// A replaced value
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Previous {
+    /// the value as stored, encoded by the link's codec
+    content: serde_json::Value,
+    cas: u64,
+    replaced_at: String,
+}
+
+fn history_key(doc_key: &str) -> String {
+    format!("{}::history", doc_key)
+}
+
+/// Read the value a `set` is about to replace, or None if there is none to keep:
+/// the key does not exist, is deleted or holds a chunked value
+pub(crate) async fn previous(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+) -> RpcResult<Option<Previous>> {
+    let options = kv_options!(link, GetOptions::default());
+    let connection = link.connection();
+    let key = doc_key.to_string();
+    let operation = async move { connection.collection.get(key, options).await };
+    match link.execute(op, Some(doc_key), operation).await {
+        Ok(r) => {
+            let content: serde_json::Value = r.content().map_err(to_rpc_err)?;
+            if tombstones::is_tombstone(&content) || chunks::manifest(&content).is_some() {
+                return Ok(None);
+            }
+            Ok(Some(Previous {
+                content,
+                cas: r.cas(),
+                replaced_at: String::new(),
+            }))
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Append a replaced value to the key's history, dropping the oldest entries beyond
+/// history_length. A history that cannot be written is logged, the `set` still succeeded.
+pub(crate) async fn append(link: &CouchbaseLink, op: &str, doc_key: &str, previous: Previous) {
+    let length = link.config.history_length as usize;
+    let previous = Previous {
+        replaced_at: chrono::Utc::now().to_rfc3339(),
+        ..previous
+    };
+    let appended = containers::update(link, op, &history_key(doc_key), |history| {
+        let mut history: Vec<Previous> = history.unwrap_or_default();
+        history.insert(0, previous.clone());
+        history.truncate(length);
+        Ok((Some(history), ()))
+    })
+    .await;
+    if let Err(e) = appended {
+        if link.logs(Level::WARN) {
+            warn!(
+                actor_id = %link.actor_id,
+                key = %link.log_key(doc_key),
+                "couchbase value history could not be written: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Read the latest `count` entries of the key's history, all of them if count is 0
+pub(crate) async fn get(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+    count: u32,
+) -> RpcResult<Vec<HistoryEntry>> {
+    let history: Vec<Previous> = match containers::read(link, op, &history_key(doc_key)).await? {
+        Some((history, _)) => history,
+        None => return Ok(Vec::new()),
+    };
+    let count = match count {
+        0 => history.len(),
+        count => count as usize,
+    };
+    history
+        .into_iter()
+        .take(count)
+        .map(|previous| {
+            Ok(HistoryEntry {
+                value: link.codec.decode(doc_key, previous.content)?,
+                cas: previous.cas,
+                replaced_at: previous.replaced_at,
+            })
+        })
+        .collect()
+}
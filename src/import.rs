@@ -0,0 +1,177 @@
+//! `import --in <file> [--overwrite]`: restore the documents of an export file into a link
+//!
+//! Each record of the file is written back as the document it holds, under the link's key for
+//! the record's key, so an export can be restored into another link, bucket or cluster. Records
+//! are written batch_concurrency at a time: inserted, leaving documents that exist alone, or
+//! upserted with `--overwrite`. A record keeps its expiry, and records that have expired since
+//! the export are skipped. Progress is printed every PROGRESS_INTERVAL records, and a summary at
+//! the end; each record that fails is printed with its line number and error.
+use std::{
+    io::BufRead,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use couchbase::{CouchbaseError, InsertOptions, UpsertOptions};
+use futures::StreamExt;
+
+use crate::export::Record;
+use crate::{cli, config, CouchbaseLink};
+
+/// Operation name of the import's writes, in metrics and the audit log
+const OP: &str = "import";
+
+/// Records between two progress reports
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// What became of a record
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Imported,
+    /// the document exists and the import does not overwrite
+    Exists,
+    Expired,
+}
+
+/// Records imported, skipped and failed
+#[derive(Default)]
+struct Summary {
+    imported: u64,
+    existing: u64,
+    expired: u64,
+    failed: u64,
+}
+
+impl Summary {
+    fn total(&self) -> u64 {
+        self.imported + self.existing + self.expired + self.failed
+    }
+}
+
+/// Run the import. Returns whether every record was imported or skipped.
+pub(crate) async fn run(args: &[&str]) -> bool {
+    match import(args).await {
+        Ok(summary) => {
+            eprintln!(
+                "imported {} documents; skipped {} existing and {} expired; {} failed",
+                summary.imported, summary.existing, summary.expired, summary.failed
+            );
+            summary.failed == 0
+        }
+        Err(e) => {
+            eprintln!("import failed: {}", e);
+            false
+        }
+    }
+}
+
+async fn import(args: &[&str]) -> Result<Summary, String> {
+    let input = cli::option(args, "--in")?.ok_or_else(|| "missing --in <file>".to_string())?;
+    let overwrite = cli::flag(args, "--overwrite");
+    let file = std::fs::File::open(input).map_err(|e| format!("{}: {}", input, e))?;
+    let link = cli::open_link(args).await?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let lines = std::io::BufReader::new(file).lines().enumerate();
+    let mut writes = futures::stream::iter(lines)
+        .map(|(n, line)| {
+            let link = &link;
+            async move {
+                let record = line.map_err(|e| e.to_string()).and_then(|line| {
+                    serde_json::from_str::<Record>(&line).map_err(|e| e.to_string())
+                });
+                let outcome = match record {
+                    Ok(record) => write(link, &record, now, overwrite).await,
+                    Err(e) => Err(e),
+                };
+                (n + 1, outcome)
+            }
+        })
+        .buffer_unordered(link.config.batch_concurrency);
+    let mut summary = Summary::default();
+    while let Some((line, outcome)) = writes.next().await {
+        match outcome {
+            Ok(Outcome::Imported) => summary.imported += 1,
+            Ok(Outcome::Exists) => summary.existing += 1,
+            Ok(Outcome::Expired) => summary.expired += 1,
+            Err(e) => {
+                eprintln!("{}:{}: {}", input, line, e);
+                summary.failed += 1;
+            }
+        }
+        if summary.total() % PROGRESS_INTERVAL == 0 {
+            eprintln!("{} records read", summary.total());
+        }
+    }
+    Ok(summary)
+}
+
+/// Returns the expiry to write a record with the unix expiry time `expiry` with at `now`, as
+/// Couchbase reads it: None if it does not expire, Some(Duration::ZERO) if it has expired
+fn remaining(expiry: u64, now: u64) -> Option<Duration> {
+    match expiry {
+        0 => None,
+        expiry if expiry <= now => Some(Duration::ZERO),
+        expiry => Some(config::server_expiry(expiry - now, now)),
+    }
+}
+
+/// Write a record's document
+async fn write(
+    link: &CouchbaseLink,
+    record: &Record,
+    now: u64,
+    overwrite: bool,
+) -> Result<Outcome, String> {
+    let expiry = remaining(record.expiry, now);
+    if expiry == Some(Duration::ZERO) {
+        return Ok(Outcome::Expired);
+    }
+    // records restore the documents derived from keys as well, so their keys are not checked
+    let key = link.config.doc_key(&link.actor_id, &record.key);
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let value = record.value.clone();
+    let result = if overwrite {
+        let mut options = kv_options!(link, UpsertOptions::default());
+        if let Some(expiry) = expiry {
+            options = options.expiry(expiry);
+        }
+        let operation = async move { connection.collection.upsert(doc_key, value, options).await };
+        link.execute(OP, Some(&key), operation).await
+    } else {
+        let mut options = kv_options!(link, InsertOptions::default());
+        if let Some(expiry) = expiry {
+            options = options.expiry(expiry);
+        }
+        let operation = async move { connection.collection.insert(doc_key, value, options).await };
+        link.execute(OP, Some(&key), operation).await
+    };
+    match result {
+        Ok(r) => {
+            link.written(&r);
+            link.audit(OP, &record.key, Some(r.cas()));
+            Ok(Outcome::Imported)
+        }
+        Err(CouchbaseError::DocumentExists { .. }) => Ok(Outcome::Exists),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_expiries() {
+        assert_eq!(remaining(0, 1000), None);
+        assert_eq!(remaining(1060, 1000), Some(Duration::from_secs(60)));
+        assert_eq!(remaining(1000, 1000), Some(Duration::ZERO));
+        assert_eq!(remaining(900, 1000), Some(Duration::ZERO));
+        // more than 30 days left: written as the unix time it expires at
+        let now = 1_700_000_000;
+        let later = now + 60 * 24 * 60 * 60;
+        assert_eq!(remaining(later, now), Some(Duration::from_secs(later)));
+    }
+}
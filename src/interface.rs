@@ -0,0 +1,1473 @@
+//! CouchbaseKeyValue: Couchbase features beyond wasmcloud:keyvalue
+//!
+//! Actors linked on wasmcloud:keyvalue call these methods as `CouchbaseKeyValue.<Method>`,
+//! with MessagePack-encoded requests and responses like the generated KeyValue interface.
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use wasmbus_rpc::{
+    common::{Context, Message, MessageDispatch},
+    error::{RpcError, RpcResult},
+};
+use wasmcloud_interface_keyvalue::{GetResponse, ListAddRequest, SetRequest, StringList};
+
+/// Response to getWithCas
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetWithCasResponse {
+    /// the value, if it existed
+    #[serde(default)]
+    pub value: String,
+    /// whether or not the value existed
+    #[serde(default)]
+    pub exists: bool,
+    /// cas of the document, to pass to setIfCas (0 if it did not exist)
+    #[serde(default)]
+    pub cas: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetIfCasRequest {
+    /// the key name to change (or create)
+    #[serde(default)]
+    pub key: String,
+    /// the new value
+    #[serde(default)]
+    pub value: String,
+    /// cas returned by getWithCas, or 0 to create the key only if it does not exist
+    #[serde(default)]
+    pub cas: u64,
+    /// expiration time in seconds 0 for no expiration
+    #[serde(default)]
+    pub expires: u32,
+}
+
+/// Response to setIfCas
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetIfCasResponse {
+    /// false if the key changed, was removed or, for cas 0, already existed
+    #[serde(default)]
+    pub updated: bool,
+    /// new cas of the document if it was updated, 0 otherwise
+    #[serde(default)]
+    pub cas: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LookupPathRequest {
+    /// key of a document holding a JSON object
+    #[serde(default)]
+    pub key: String,
+    /// sub-document path of the field, such as `address.city` or `items[0]`
+    #[serde(default)]
+    pub path: String,
+}
+
+/// Response to lookupPath
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LookupPathResponse {
+    /// the field as JSON text, if it existed
+    #[serde(default)]
+    pub value: String,
+    /// whether or not the document and the field existed
+    #[serde(default)]
+    pub exists: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MutatePathRequest {
+    /// key of a document holding a JSON object
+    #[serde(default)]
+    pub key: String,
+    /// sub-document path of the field, such as `address.city` or `items[0]`
+    #[serde(default)]
+    pub path: String,
+    /// the new value of the field, as JSON text
+    #[serde(default)]
+    pub value: String,
+    /// create the document and any missing objects along the path
+    #[serde(default)]
+    pub create_parents: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TouchRequest {
+    /// the key name to touch
+    #[serde(default)]
+    pub key: String,
+    /// new expiration time in seconds 0 for no expiration
+    #[serde(default)]
+    pub expires: u32,
+}
+
+/// Response to getExpiry
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetExpiryResponse {
+    /// seconds until the key expires, 0 if it never expires
+    #[serde(default)]
+    pub expires: u32,
+    /// whether or not the key existed
+    #[serde(default)]
+    pub exists: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LockRequest {
+    /// the key name to lock (created with an empty value if it does not exist)
+    #[serde(default)]
+    pub key: String,
+    /// seconds until the lock is released if not unlocked, at most 30 (0 for the server default of 15)
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+/// Response to lock
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LockResponse {
+    /// false if the key is already locked
+    #[serde(default)]
+    pub locked: bool,
+    /// token to pass to unlock, 0 if the key was not locked
+    #[serde(default)]
+    pub token: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UnlockRequest {
+    /// the key name to unlock
+    #[serde(default)]
+    pub key: String,
+    /// token returned by lock
+    #[serde(default)]
+    pub token: u64,
+}
+
+/// One mutation of a transaction
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TransactMutation {
+    /// insert (only if the key does not exist), replace (only if it does), upsert or remove
+    #[serde(default)]
+    pub op: String,
+    /// the key name to change
+    #[serde(default)]
+    pub key: String,
+    /// the new value, unused by remove
+    #[serde(default)]
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TransactRequest {
+    /// the mutations to apply together
+    #[serde(default)]
+    pub mutations: Vec<TransactMutation>,
+    /// milliseconds before the transaction is abandoned, 0 for the server default
+    #[serde(default)]
+    pub timeout_ms: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SearchRequest {
+    /// name of the full-text search index
+    #[serde(default)]
+    pub index: String,
+    /// query in the query string syntax, such as `+city:paris wifi`
+    #[serde(default)]
+    pub query: String,
+    /// maximum number of hits, 0 for the server default of 10
+    #[serde(default)]
+    pub limit: u32,
+    /// also return the value of each hit
+    #[serde(default)]
+    pub include_values: bool,
+}
+
+/// A document matching a search
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SearchHit {
+    /// the key name of the document
+    #[serde(default)]
+    pub key: String,
+    /// relevance of the document to the query, higher is better
+    #[serde(default)]
+    pub score: f64,
+    /// the value, if requested and the document still exists, as a string
+    /// or, for documents that do not hold a string, as JSON text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ScanKeysRequest {
+    /// return only keys starting with this prefix, empty for all keys
+    #[serde(default)]
+    pub prefix: String,
+    /// maximum number of keys to return, 0 for 100
+    #[serde(default)]
+    pub limit: u32,
+    /// cursor returned by the previous scan, empty for the first page
+    #[serde(default)]
+    pub cursor: String,
+}
+
+/// Response to scanKeys
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ScanKeysResponse {
+    /// the keys, in order
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// cursor to pass to the next scan, empty if there are no more keys
+    #[serde(default)]
+    pub cursor: String,
+}
+
+/// One copy of a document, on the node holding its active or a replica vBucket
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ReplicaCopy {
+    /// the node, as host:port
+    #[serde(default)]
+    pub node: String,
+    /// true for the active copy, false for a replica
+    #[serde(default)]
+    pub active: bool,
+    /// whether the node has the document
+    #[serde(default)]
+    pub exists: bool,
+    /// cas of the node's copy, 0 if it does not have the document
+    #[serde(default)]
+    pub cas: u64,
+}
+
+/// Response to getAllReplicas
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetAllReplicasResponse {
+    /// the copies of the document, active first
+    #[serde(default)]
+    pub copies: Vec<ReplicaCopy>,
+    /// true if every copy exists with the same cas, or none exists
+    #[serde(default)]
+    pub consistent: bool,
+}
+
+/// Response to getMetadata
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetMetadataResponse {
+    /// whether the key has metadata
+    #[serde(default)]
+    pub exists: bool,
+    /// when the key was created, RFC 3339
+    #[serde(default)]
+    pub created_at: String,
+    /// when the key was last changed, RFC 3339
+    #[serde(default)]
+    pub updated_at: String,
+    /// public key of the actor that last changed the key
+    #[serde(default)]
+    pub writer_actor: String,
+    /// content type of the value, such as `application/json`, empty if none was declared
+    #[serde(default)]
+    pub content_type: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetContentTypeRequest {
+    /// the key name
+    #[serde(default)]
+    pub key: String,
+    /// content type of its value, as `type/subtype`
+    #[serde(default)]
+    pub content_type: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetHistoryRequest {
+    /// the key name
+    #[serde(default)]
+    pub key: String,
+    /// how many of the latest entries to return, 0 for all of them
+    #[serde(default)]
+    pub count: u32,
+}
+
+/// A value replaced by a set
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// the replaced value
+    #[serde(default)]
+    pub value: String,
+    /// cas of the replaced value
+    #[serde(default)]
+    pub cas: u64,
+    /// when the value was replaced, RFC 3339
+    #[serde(default)]
+    pub replaced_at: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetWithModeRequest {
+    /// the key name to change (or create)
+    #[serde(default)]
+    pub key: String,
+    /// the new value
+    #[serde(default)]
+    pub value: String,
+    /// expiration time in seconds 0 for no expiration
+    #[serde(default)]
+    pub expires: u32,
+    /// `insert` to create the key only if it does not exist, `replace` to change it only if it
+    /// does, or `upsert` (the default) to set it either way
+    #[serde(default)]
+    pub mode: String,
+}
+
+/// Response to setWithMode
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetWithModeResponse {
+    /// false if the key existed for an insert, or did not for a replace
+    #[serde(default)]
+    pub written: bool,
+    /// new cas of the document if it was written, 0 otherwise
+    #[serde(default)]
+    pub cas: u64,
+}
+
+/// Response to getWithMeta
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetWithMetaResponse {
+    /// the value, if it existed
+    #[serde(default)]
+    pub value: String,
+    /// whether or not the value existed
+    #[serde(default)]
+    pub exists: bool,
+    /// cas of the document, to pass to setWithOptions or setIfCas (0 if it did not exist)
+    #[serde(default)]
+    pub cas: u64,
+    /// seconds left before the key expires, 0 if it does not expire
+    #[serde(default)]
+    pub expires: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetWithOptionsRequest {
+    /// the key name to change (or create)
+    #[serde(default)]
+    pub key: String,
+    /// the new value
+    #[serde(default)]
+    pub value: String,
+    /// expiration time in seconds 0 for no expiration
+    #[serde(default)]
+    pub expires: u32,
+    /// cas the document must still have, from getWithMeta or getWithCas, or 0 to set it
+    /// whatever its cas
+    #[serde(default)]
+    pub cas: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AppendRequest {
+    /// the key name
+    #[serde(default)]
+    pub key: String,
+    /// text to add at the end (append) or start (prepend) of the value
+    #[serde(default)]
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Increment64Request {
+    /// name of the counter
+    #[serde(default)]
+    pub key: String,
+    /// amount to add to the counter, negative to subtract
+    #[serde(default)]
+    pub value: i64,
+}
+
+/// A member of a sorted set and its score
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScoredMember {
+    #[serde(default)]
+    pub member: String,
+    #[serde(default)]
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ZAddRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    /// members to add, or whose score to change
+    #[serde(default)]
+    pub members: Vec<ScoredMember>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ZIncrByRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub member: String,
+    /// amount to add to the member's score, negative to subtract
+    #[serde(default)]
+    pub increment: f64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZRemRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZScoreRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub member: String,
+}
+
+/// Response to zScore
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ZScoreResponse {
+    /// the member's score, 0 if it is not a member
+    #[serde(default)]
+    pub score: f64,
+    /// whether the member is in the sorted set
+    #[serde(default)]
+    pub exists: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZRankRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub member: String,
+    /// rank from the highest score rather than the lowest
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+/// Response to zRank
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZRankResponse {
+    /// 0-based rank of the member, 0 if it is not a member
+    #[serde(default)]
+    pub rank: u32,
+    /// whether the member is in the sorted set
+    #[serde(default)]
+    pub exists: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZRangeRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    /// rank of the first member returned, negative to count from the end
+    #[serde(default)]
+    pub start: i32,
+    /// rank of the last member returned, included, negative to count from the end
+    #[serde(default)]
+    pub stop: i32,
+    /// rank from the highest score rather than the lowest
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListAddCappedRequest {
+    /// name of the list to modify
+    #[serde(default)]
+    pub list_name: String,
+    /// value to append to the list
+    #[serde(default)]
+    pub value: String,
+    /// length beyond which the oldest values are removed, 0 for the link's list_max_length
+    #[serde(default)]
+    pub max_length: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListRangePageRequest {
+    /// name of the list to read
+    #[serde(default)]
+    pub list_name: String,
+    /// position of the first value of the range, negative from the end of the list
+    #[serde(default)]
+    pub start: i32,
+    /// position of the last value of the range included, negative from the end of the list
+    #[serde(default)]
+    pub stop: i32,
+    /// maximum number of values to return, 0 for 100
+    #[serde(default)]
+    pub limit: u32,
+    /// cursor returned by the previous page, empty for the first page
+    #[serde(default)]
+    pub cursor: String,
+}
+
+/// Response to listRangePage
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListRangePageResponse {
+    /// the values, in list order
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// cursor to pass to the next page, empty if there are no more values
+    #[serde(default)]
+    pub cursor: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DequePushRequest {
+    /// name of the list to modify
+    #[serde(default)]
+    pub list_name: String,
+    /// value to add to the list
+    #[serde(default)]
+    pub value: String,
+    /// add the value at the front of the list rather than at its back
+    #[serde(default)]
+    pub front: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DequePopRequest {
+    /// name of the list to modify
+    #[serde(default)]
+    pub list_name: String,
+    /// remove the value at the back of the list rather than at its front
+    #[serde(default)]
+    pub back: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListPopNRequest {
+    /// name of the list to modify
+    #[serde(default)]
+    pub list_name: String,
+    /// maximum number of values to remove
+    #[serde(default)]
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ExpireContainerRequest {
+    /// name of the list or set
+    #[serde(default)]
+    pub name: String,
+    /// seconds after each mutation the container expires, 0 for the link's container_ttl
+    #[serde(default)]
+    pub expires: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetContainsRequest {
+    /// name of the set
+    #[serde(default)]
+    pub set_name: String,
+    /// value to look for in the set
+    #[serde(default)]
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetQueryPageRequest {
+    /// name of the set to read
+    #[serde(default)]
+    pub set_name: String,
+    /// number of members to skip, 0 for the first page
+    #[serde(default)]
+    pub offset: u32,
+    /// maximum number of members to return, 0 for 100
+    #[serde(default)]
+    pub limit: u32,
+}
+
+/// Response to setQueryPage
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetQueryPageResponse {
+    /// the members, in the order they were added
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// offset to pass to the next page, 0 if there are no more members
+    #[serde(default)]
+    pub next_offset: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetAddManyRequest {
+    /// name of the set
+    #[serde(default)]
+    pub set_name: String,
+    /// values to add to the set
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ZRangeByScoreRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    /// lowest score of the members returned, included
+    #[serde(default)]
+    pub min: f64,
+    /// highest score of the members returned, included
+    #[serde(default)]
+    pub max: f64,
+    /// return the members from the highest score rather than the lowest
+    #[serde(default)]
+    pub reverse: bool,
+    /// number of members in the range to skip
+    #[serde(default)]
+    pub offset: u32,
+    /// maximum number of members to return, 0 for all
+    #[serde(default)]
+    pub limit: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZTopRequest {
+    /// name of the sorted set
+    #[serde(default)]
+    pub key: String,
+    /// number of members to return, 0 for 10
+    #[serde(default)]
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ApproxAddRequest {
+    /// name of the sketch
+    #[serde(default)]
+    pub name: String,
+    /// member to count
+    #[serde(default)]
+    pub member: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HSetRequest {
+    /// name of the hash
+    #[serde(default)]
+    pub key: String,
+    /// field to set
+    #[serde(default)]
+    pub field: String,
+    /// value of the field
+    #[serde(default)]
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HFieldRequest {
+    /// name of the hash
+    #[serde(default)]
+    pub key: String,
+    /// field to read or remove
+    #[serde(default)]
+    pub field: String,
+}
+
+/// A field of a hash and its value
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HashField {
+    #[serde(default)]
+    pub field: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+/// Result of GetMany for one key
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetManyResult {
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub exists: bool,
+    #[serde(default)]
+    pub value: String,
+    /// why the key could not be read, none if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Parameter to Watch and Unwatch
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct WatchRequest {
+    /// key watched, or prefix of the keys watched
+    #[serde(default)]
+    pub key: String,
+    /// whether every key starting with `key` is watched
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+/// Keys and values to set with SetMany
+pub type SetRequests = Vec<SetRequest>;
+
+/// Result of SetMany or DelMany for one key
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyResult {
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub success: bool,
+    /// why the key could not be written, none if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// wasmbus.contractId: wasmcloud:keyvalue
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait CouchbaseKeyValue {
+    /// Gets a value and its cas
+    async fn get_with_cas<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetWithCasResponse>;
+    /// Sets a value only if its cas still matches
+    async fn set_if_cas(&self, ctx: &Context, arg: &SetIfCasRequest)
+        -> RpcResult<SetIfCasResponse>;
+    /// Gets one field of a JSON document
+    async fn lookup_path(
+        &self,
+        ctx: &Context,
+        arg: &LookupPathRequest,
+    ) -> RpcResult<LookupPathResponse>;
+    /// Sets one field of a JSON document. Returns the new cas of the document
+    async fn mutate_path(&self, ctx: &Context, arg: &MutatePathRequest) -> RpcResult<u64>;
+    /// Sets the expiration time of a key. Returns false if the key did not exist
+    async fn touch(&self, ctx: &Context, arg: &TouchRequest) -> RpcResult<bool>;
+    /// Gets a value and sets its expiration time
+    async fn get_and_touch(&self, ctx: &Context, arg: &TouchRequest) -> RpcResult<GetResponse>;
+    /// Gets the time left before a key expires
+    async fn get_expiry<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetExpiryResponse>;
+    /// Locks a key so that only the lock holder can change it until unlocked or the lock expires
+    async fn lock(&self, ctx: &Context, arg: &LockRequest) -> RpcResult<LockResponse>;
+    /// Releases a lock. Returns false if the token no longer holds the lock
+    async fn unlock(&self, ctx: &Context, arg: &UnlockRequest) -> RpcResult<bool>;
+    /// Applies several mutations atomically. Returns false if none were applied
+    /// because a key did not match its mutation
+    async fn transact(&self, ctx: &Context, arg: &TransactRequest) -> RpcResult<bool>;
+    /// Searches documents with a full-text search index. Returns the hits, best first
+    async fn search(&self, ctx: &Context, arg: &SearchRequest) -> RpcResult<Vec<SearchHit>>;
+    /// Lists keys in order, a page at a time
+    async fn scan_keys(&self, ctx: &Context, arg: &ScanKeysRequest)
+        -> RpcResult<ScanKeysResponse>;
+    /// Removes every key starting with a prefix. Returns the number of keys removed
+    async fn del_by_prefix<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64>;
+    /// Reports whether the active and replica copies of a key agree
+    async fn get_all_replicas<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetAllReplicasResponse>;
+    /// Gets when a key was created and last changed, and by which actor
+    async fn get_metadata<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetMetadataResponse>;
+    /// Declares the content type of a key's value. Returns false if the key has no metadata
+    async fn set_content_type(&self, ctx: &Context, arg: &SetContentTypeRequest)
+        -> RpcResult<bool>;
+    /// Restores a deleted key from its tombstone. Returns false if the key is not deleted
+    async fn undelete<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool>;
+    /// Gets the values a key held before, newest first
+    async fn get_history(
+        &self,
+        ctx: &Context,
+        arg: &GetHistoryRequest,
+    ) -> RpcResult<Vec<HistoryEntry>>;
+    /// Sets the value of a key with insert, replace or upsert semantics
+    async fn set_with_mode(
+        &self,
+        ctx: &Context,
+        arg: &SetWithModeRequest,
+    ) -> RpcResult<SetWithModeResponse>;
+    /// Gets a value with its cas and the seconds left before it expires
+    async fn get_with_meta<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetWithMetaResponse>;
+    /// Sets the value of a key with a cas to match and an expiry
+    async fn set_with_options(
+        &self,
+        ctx: &Context,
+        arg: &SetWithOptionsRequest,
+    ) -> RpcResult<SetWithModeResponse>;
+    /// Sets the value of a key and returns the value it replaced
+    async fn get_set(&self, ctx: &Context, arg: &SetRequest) -> RpcResult<GetResponse>;
+    /// Adds text at the end of the value of a key. Returns false if the key does not exist.
+    async fn append(&self, ctx: &Context, arg: &AppendRequest) -> RpcResult<bool>;
+    /// Adds text at the start of the value of a key. Returns false if the key does not exist.
+    async fn prepend(&self, ctx: &Context, arg: &AppendRequest) -> RpcResult<bool>;
+    /// Increments a 64-bit counter and returns its new value
+    async fn increment64(&self, ctx: &Context, arg: &Increment64Request) -> RpcResult<i64>;
+    /// Gets the value of a 64-bit counter, 0 if it does not exist
+    async fn get64<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<i64>;
+    /// Converts counters stored as numeric strings or doubles into JSON integers.
+    /// Returns the number of counters converted.
+    async fn migrate_counters<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64>;
+    /// Adds members to a sorted set, or changes their scores. Returns the number of members added.
+    async fn zadd(&self, ctx: &Context, arg: &ZAddRequest) -> RpcResult<u32>;
+    /// Adds to the score of a member of a sorted set and returns its new score
+    async fn zincr_by(&self, ctx: &Context, arg: &ZIncrByRequest) -> RpcResult<f64>;
+    /// Removes members from a sorted set. Returns the number of members removed.
+    async fn zrem(&self, ctx: &Context, arg: &ZRemRequest) -> RpcResult<u32>;
+    /// Gets the score of a member of a sorted set
+    async fn zscore(&self, ctx: &Context, arg: &ZScoreRequest) -> RpcResult<ZScoreResponse>;
+    /// Gets the rank of a member of a sorted set
+    async fn zrank(&self, ctx: &Context, arg: &ZRankRequest) -> RpcResult<ZRankResponse>;
+    /// Gets the members of a sorted set between two ranks, with their scores
+    async fn zrange(&self, ctx: &Context, arg: &ZRangeRequest) -> RpcResult<Vec<ScoredMember>>;
+    /// Appends a value to a list, removing its oldest values beyond a maximum length.
+    /// Returns the new list size.
+    async fn list_add_capped(&self, ctx: &Context, arg: &ListAddCappedRequest) -> RpcResult<u32>;
+    /// Gets a range of values from a list a page at a time
+    async fn list_range_page(
+        &self,
+        ctx: &Context,
+        arg: &ListRangePageRequest,
+    ) -> RpcResult<ListRangePageResponse>;
+    /// Appends a value to the end of a queue. Returns the new queue size.
+    async fn queue_push(&self, ctx: &Context, arg: &ListAddRequest) -> RpcResult<u32>;
+    /// Removes the value at the front of a queue and returns it
+    async fn queue_pop<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetResponse>;
+    /// Adds a value at the front or the back of a list. Returns the new list size.
+    async fn deque_push(&self, ctx: &Context, arg: &DequePushRequest) -> RpcResult<u32>;
+    /// Removes the value at the front or the back of a list and returns it
+    async fn deque_pop(&self, ctx: &Context, arg: &DequePopRequest) -> RpcResult<GetResponse>;
+    /// Removes up to count values at the front of a list and returns them
+    async fn list_pop_n(&self, ctx: &Context, arg: &ListPopNRequest) -> RpcResult<Vec<String>>;
+    /// Sets the time to live of a list or set, renewed by each of its mutations.
+    /// Returns whether the container exists.
+    async fn expire_container(
+        &self,
+        ctx: &Context,
+        arg: &ExpireContainerRequest,
+    ) -> RpcResult<bool>;
+    /// Returns whether the set holds the value
+    async fn set_contains(&self, ctx: &Context, arg: &SetContainsRequest) -> RpcResult<bool>;
+    /// Returns a page of the members of a set
+    async fn set_query_page(
+        &self,
+        ctx: &Context,
+        arg: &SetQueryPageRequest,
+    ) -> RpcResult<SetQueryPageResponse>;
+    /// Adds values to a set. Returns the number of values the set did not hold.
+    async fn set_add_many(&self, ctx: &Context, arg: &SetAddManyRequest) -> RpcResult<u32>;
+    /// Returns the members of the first set found in none of the others
+    async fn set_diff(&self, ctx: &Context, arg: &StringList) -> RpcResult<StringList>;
+    /// Returns the members of a sorted set within a range of scores
+    async fn zrange_by_score(
+        &self,
+        ctx: &Context,
+        arg: &ZRangeByScoreRequest,
+    ) -> RpcResult<Vec<ScoredMember>>;
+    /// Returns the members of a sorted set with the highest scores, highest first
+    async fn ztop(&self, ctx: &Context, arg: &ZTopRequest) -> RpcResult<Vec<ScoredMember>>;
+    /// Adds a member to an approximate distinct count. Returns whether the estimate changed.
+    async fn approx_add(&self, ctx: &Context, arg: &ApproxAddRequest) -> RpcResult<bool>;
+    /// Returns the estimated number of distinct members of an approximate distinct count
+    async fn approx_count<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64>;
+    /// Sets a field of a hash. Returns whether the field is new.
+    async fn hset(&self, ctx: &Context, arg: &HSetRequest) -> RpcResult<bool>;
+    /// Gets the value of a field of a hash
+    async fn hget(&self, ctx: &Context, arg: &HFieldRequest) -> RpcResult<GetResponse>;
+    /// Removes a field of a hash. Returns whether the field existed.
+    async fn hdel(&self, ctx: &Context, arg: &HFieldRequest) -> RpcResult<bool>;
+    /// Returns the fields of a hash and their values
+    async fn hgetall<TS: ToString + ?Sized + std::marker::Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<Vec<HashField>>;
+    /// Gets the values of keys, with a result for each key in order
+    async fn get_many(&self, ctx: &Context, arg: &StringList) -> RpcResult<Vec<GetManyResult>>;
+    /// Sets the values of keys, with a result for each key in order
+    async fn set_many(&self, ctx: &Context, arg: &SetRequests) -> RpcResult<Vec<KeyResult>>;
+    /// Deletes keys, with a result for each key in order
+    async fn del_many(&self, ctx: &Context, arg: &StringList) -> RpcResult<Vec<KeyResult>>;
+    /// Reports changes to a key, or to the keys starting with a prefix, to the actor.
+    /// Returns false if it was watched already.
+    async fn watch(&self, ctx: &Context, arg: &WatchRequest) -> RpcResult<bool>;
+    /// Stops reporting changes to a key or prefix. Returns false if it was not watched.
+    async fn unwatch(&self, ctx: &Context, arg: &WatchRequest) -> RpcResult<bool>;
+}
+
+/// CouchbaseKeyValueReceiver receives messages defined in the CouchbaseKeyValue service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait CouchbaseKeyValueReceiver: MessageDispatch + CouchbaseKeyValue {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "GetWithCas" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_with_cas(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetIfCas" => {
+                let value: SetIfCasRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetIfCasRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_if_cas(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "LookupPath" => {
+                let value: LookupPathRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'LookupPathRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::lookup_path(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "MutatePath" => {
+                let value: MutatePathRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'MutatePathRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::mutate_path(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Touch" => {
+                let value: TouchRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'TouchRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::touch(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetAndTouch" => {
+                let value: TouchRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'TouchRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_and_touch(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetExpiry" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_expiry(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Lock" => {
+                let value: LockRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'LockRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::lock(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Unlock" => {
+                let value: UnlockRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'UnlockRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::unlock(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Transact" => {
+                let value: TransactRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'TransactRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::transact(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Search" => {
+                let value: SearchRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SearchRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::search(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ScanKeys" => {
+                let value: ScanKeysRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ScanKeysRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::scan_keys(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DelByPrefix" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::del_by_prefix(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetAllReplicas" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_all_replicas(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetMetadata" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_metadata(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetContentType" => {
+                let value: SetContentTypeRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetContentTypeRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_content_type(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Undelete" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::undelete(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetHistory" => {
+                let value: GetHistoryRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'GetHistoryRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_history(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetWithMode" => {
+                let value: SetWithModeRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetWithModeRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_with_mode(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetWithMeta" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_with_meta(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetWithOptions" => {
+                let value: SetWithOptionsRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetWithOptionsRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_with_options(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetSet" => {
+                let value: SetRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_set(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Append" => {
+                let value: AppendRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'AppendRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::append(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Prepend" => {
+                let value: AppendRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'AppendRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::prepend(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Increment64" => {
+                let value: Increment64Request = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'Increment64Request': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::increment64(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Get64" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get64(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "MigrateCounters" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::migrate_counters(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZAdd" => {
+                let value: ZAddRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZAddRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zadd(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZIncrBy" => {
+                let value: ZIncrByRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZIncrByRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zincr_by(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZRem" => {
+                let value: ZRemRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZRemRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zrem(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZScore" => {
+                let value: ZScoreRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZScoreRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zscore(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZRank" => {
+                let value: ZRankRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZRankRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zrank(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZRange" => {
+                let value: ZRangeRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZRangeRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zrange(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ListAddCapped" => {
+                let value: ListAddCappedRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListAddCappedRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::list_add_capped(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ListRangePage" => {
+                let value: ListRangePageRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListRangePageRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::list_range_page(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "QueuePush" => {
+                let value: ListAddRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListAddRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::queue_push(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "QueuePop" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::queue_pop(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DequePush" => {
+                let value: DequePushRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'DequePushRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::deque_push(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DequePop" => {
+                let value: DequePopRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'DequePopRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::deque_pop(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ListPopN" => {
+                let value: ListPopNRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListPopNRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::list_pop_n(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ExpireContainer" => {
+                let value: ExpireContainerRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ExpireContainerRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::expire_container(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetContains" => {
+                let value: SetContainsRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetContainsRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_contains(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetQueryPage" => {
+                let value: SetQueryPageRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetQueryPageRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_query_page(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetAddMany" => {
+                let value: SetAddManyRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetAddManyRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_add_many(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetDiff" => {
+                let value: StringList = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'StringList': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_diff(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZRangeByScore" => {
+                let value: ZRangeByScoreRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZRangeByScoreRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::zrange_by_score(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ZTop" => {
+                let value: ZTopRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ZTopRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::ztop(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ApproxAdd" => {
+                let value: ApproxAddRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ApproxAddRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::approx_add(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ApproxCount" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::approx_count(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "HSet" => {
+                let value: HSetRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'HSetRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::hset(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "HGet" => {
+                let value: HFieldRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'HFieldRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::hget(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "HDel" => {
+                let value: HFieldRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'HFieldRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::hdel(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "HGetAll" => {
+                let value: String = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'String': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::hgetall(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "GetMany" => {
+                let value: StringList = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'StringList': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::get_many(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetMany" => {
+                let value: SetRequests = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetRequests': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::set_many(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "DelMany" => {
+                let value: StringList = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'StringList': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::del_many(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Watch" => {
+                let value: WatchRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'WatchRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::watch(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Unwatch" => {
+                let value: WatchRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'WatchRequest': {}", e)))?;
+
+                let resp = CouchbaseKeyValue::unwatch(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "CouchbaseKeyValue::{}",
+                message.method
+            ))),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Connection to the lattice's NATS, for the features that talk to the lattice directly
+//!
+//! Requests from actors and link definitions reach the provider through wasmbus-rpc; this
+//! connection serves what it does not cover: publishing change events for other actors, and
+//! asking the hosts which links remain when one is deleted.
+use std::time::Duration;
+
+use serde::Deserialize;
+use wasmbus_rpc::{
+    core::LinkDefinition,
+    error::{RpcError, RpcResult},
+    provider::prelude::load_host_data,
+};
+
+/// How long the hosts are given to answer a control request
+const CTL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connection to the lattice's NATS, opened by its first use
+static CLIENT: tokio::sync::OnceCell<async_nats::Client> = tokio::sync::OnceCell::const_new();
+
+/// Reply of the hosts to a `get.links` control request
+#[derive(Deserialize)]
+struct LinkDefinitionList {
+    #[serde(default)]
+    links: Vec<LinkDefinition>,
+}
+
+/// Returns the connection to the lattice's NATS, connecting if needed
+pub(crate) async fn client() -> RpcResult<&'static async_nats::Client> {
+    CLIENT
+        .get_or_try_init(|| async { load_host_data()?.nats_connect().await })
+        .await
+}
+
+/// Publish a message on the lattice's NATS and flush it, since the callers publish rarely
+pub(crate) async fn publish(subject: &str, body: Vec<u8>) -> RpcResult<()> {
+    let client = client().await?;
+    client
+        .publish(subject.to_string(), body.into())
+        .await
+        .map_err(|e| RpcError::Nats(e.to_string()))?;
+    client
+        .flush()
+        .await
+        .map_err(|e| RpcError::Nats(e.to_string()))
+}
+
+/// Returns the link definitions of the lattice, as the control interface reports them
+pub(crate) async fn links() -> RpcResult<Vec<LinkDefinition>> {
+    let subject = format!(
+        "wasmbus.ctl.{}.get.links",
+        load_host_data()?.lattice_rpc_prefix
+    );
+    let request = client().await?.request(subject, Vec::new().into());
+    let reply = tokio::time::timeout(CTL_TIMEOUT, request)
+        .await
+        .map_err(|_| RpcError::Timeout(format!("no reply to get.links in {:?}", CTL_TIMEOUT)))?
+        .map_err(|e| RpcError::Nats(e.to_string()))?;
+    let list: LinkDefinitionList = serde_json::from_slice(&reply.payload)
+        .map_err(|e| RpcError::Deser(format!("get.links reply: {}", e)))?;
+    Ok(list.links)
+}
@@ -0,0 +1,614 @@
+//! Lists stored one document per element, for lists too large or too busy for one document
+//!
+//! With `list_storage` set to `elements`, a list is an index document at the list name,
+//! `{"kvcouchbase_list": {"head", "next", "count"}}`, and each element is a document of its own,
+//! `<list>::element::<slot>`, slots numbered in the order the elements were added. Adding an
+//! element reserves the next slot in the small index document with its cas, then writes the
+//! element, so writers never rewrite the whole list. Deleting an element leaves its slot empty,
+//! and reads skip empty slots.
+use std::{ops::Range, time::Duration};
+
+use couchbase::{CouchbaseError, GetOptions, RemoveOptions, UpsertOptions};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{warn, Level};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::cas::CasRetry;
+use crate::config::ListStorage;
+use crate::containers::{self, Updated};
+use crate::list_pages::Cursor;
+use crate::{dead_letter, errors::to_rpc_err, quota, CouchbaseLink};
+
+/// Field of a list index document
+pub(crate) const INDEX: &str = "kvcouchbase_list";
+
+/// Slots read at the same time
+const BATCH: u64 = 64;
+
+/// The slots of a list
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub(crate) struct Index {
+    /// slot of the first element, the slots before it are empty
+    head: u64,
+    /// slot of the next element added
+    next: u64,
+    /// number of elements
+    count: u32,
+}
+
+impl Index {
+    fn to_json(&self) -> serde_json::Value {
+        json!({ INDEX: self })
+    }
+}
+
+/// How a list is stored
+pub(crate) enum Storage {
+    /// as one document, a JSON array
+    Document,
+    /// one document per element, with its index if the list exists
+    Elements(Option<Index>),
+}
+
+/// An element and its slot
+struct Element {
+    slot: u64,
+    value: String,
+    cas: u64,
+    /// size of the element document
+    size: u64,
+}
+
+fn element_key(key: &str, slot: u64) -> String {
+    format!("{}::element::{}", key, slot)
+}
+
+/// Returns the index a list document holds, or None if it holds a list stored as one document
+fn parse(key: &str, content: serde_json::Value) -> RpcResult<Option<Index>> {
+    if content.is_array() {
+        return Ok(None);
+    }
+    match content.get(INDEX) {
+        Some(index) => serde_json::from_value(index.clone())
+            .map(Some)
+            .map_err(|e| RpcError::Other(format!("list index of {} is corrupt: {}", key, e))),
+        None => Err(RpcError::InvalidParameter(format!("{} is not a list", key))),
+    }
+}
+
+/// Returns how the list at `key` is stored. Links with `elements` storage keep using the lists
+/// they stored as one document before.
+pub(crate) async fn storage(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Storage> {
+    if link.config.list_storage != ListStorage::Elements {
+        return Ok(Storage::Document);
+    }
+    match containers::read::<serde_json::Value>(link, op, key).await? {
+        Some((content, _)) => match parse(key, content)? {
+            Some(index) => Ok(Storage::Elements(Some(index))),
+            None => Ok(Storage::Document),
+        },
+        None => Ok(Storage::Elements(None)),
+    }
+}
+
+/// Append an element to a list, removing the oldest elements beyond `max_length` unless it is 0,
+/// and return the new list size, or None if the list is stored as one document
+pub(crate) async fn add(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    value: &str,
+    max_length: u32,
+) -> RpcResult<Option<Updated<u32>>> {
+    let element = link.codec.encode(key, value)?;
+    let expiry = containers::expiry(link, op, key).await?;
+    let reserved = containers::update_with_expiry(link, op, key, expiry, |content| {
+        let mut index = match content {
+            Some(content) => match parse(key, content)? {
+                Some(index) => index,
+                None => return Ok((None, None)),
+            },
+            None => Index::default(),
+        };
+        let slot = index.next;
+        index.next += 1;
+        index.count += 1;
+        Ok((Some(index.to_json()), Some((slot, index.count))))
+    })
+    .await?;
+    let Some((slot, count)) = reserved.result else {
+        return Ok(None);
+    };
+    let element_key = element_key(key, slot);
+    if let Err(e) = write(link, op, &element_key, element, expiry).await {
+        // give the slot back, it stays empty
+        release(link, op, key, &[slot]).await;
+        return Err(e);
+    }
+    if max_length > 0 && count > max_length {
+        let cas = trim(link, op, key, count - max_length).await?;
+        return Ok(Some(Updated {
+            result: max_length,
+            cas: cas.or(reserved.cas),
+        }));
+    }
+    Ok(Some(Updated {
+        result: count,
+        cas: reserved.cas,
+    }))
+}
+
+/// Remove the `excess` oldest elements of a list. Returns the cas of the index if any was removed.
+async fn trim(link: &CouchbaseLink, op: &str, key: &str, excess: u32) -> RpcResult<Option<u64>> {
+    let Storage::Elements(Some(index)) = storage(link, op, key).await? else {
+        return Ok(None);
+    };
+    let oldest = read_from(link, op, key, &index, index.head, excess as usize).await?;
+    let removals = oldest.iter().map(|element| async move {
+        let removed = remove(link, op, key, element).await?;
+        Ok::<_, RpcError>(removed.then_some(element.slot))
+    });
+    let removed: Vec<u64> = futures::future::try_join_all(removals)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+    if removed.is_empty() {
+        return Ok(None);
+    }
+    Ok(release(link, op, key, &removed).await)
+}
+
+/// Remove an element document, unless it changed since it was read.
+/// Returns whether it was removed.
+async fn remove(link: &CouchbaseLink, op: &str, key: &str, element: &Element) -> RpcResult<bool> {
+    let element_key = element_key(key, element.slot);
+    let options = kv_options!(link, RemoveOptions::default()).cas(element.cas);
+    let connection = link.connection();
+    let doc_key = element_key.clone();
+    let operation = async move { connection.collection.remove(doc_key, options).await };
+    match link.execute(op, Some(&element_key), operation).await {
+        Ok(_) => {
+            if let Some(quota) = &link.quota {
+                quota.sub(element.size);
+            }
+            Ok(true)
+        }
+        // removed by another writer meanwhile
+        Err(CouchbaseError::CasMismatch { .. }) | Err(CouchbaseError::DocumentNotFound { .. }) => {
+            Ok(false)
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &element_key, &e);
+            Err(e)
+        }
+    }
+}
+
+async fn write(
+    link: &CouchbaseLink,
+    op: &str,
+    element_key: &str,
+    value: serde_json::Value,
+    expiry: Option<Duration>,
+) -> RpcResult<()> {
+    let size = quota::doc_size(element_key, &value);
+    link.check_quota(size)?;
+    link.record_sizes(op, element_key, size as usize - element_key.len());
+    let mut options = kv_options!(link, UpsertOptions::default());
+    if let Some(expiry) = expiry {
+        options = options.expiry(expiry);
+    }
+    let connection = link.connection();
+    let doc_key = element_key.to_string();
+    let operation = async move { connection.collection.upsert(doc_key, value, options).await };
+    match link.execute(op, Some(element_key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, element_key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Update the index of a list like [containers::update], writing it with the list's expiry
+async fn update_index<R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    apply: impl FnMut(Option<serde_json::Value>) -> RpcResult<(Option<serde_json::Value>, R)>,
+) -> RpcResult<Updated<R>> {
+    let expiry = containers::expiry(link, op, key).await?;
+    containers::update_with_expiry(link, op, key, expiry, apply).await
+}
+
+/// Remove elements from the count of a list after their slots were emptied.
+/// Returns the cas of the index.
+async fn release(link: &CouchbaseLink, op: &str, key: &str, slots: &[u64]) -> Option<u64> {
+    let released = update_index(link, op, key, |content| {
+        let Some(mut index) = content.map(|c| parse(key, c)).transpose()?.flatten() else {
+            return Ok((None, ()));
+        };
+        index.count = index.count.saturating_sub(slots.len() as u32);
+        while slots.contains(&index.head) {
+            index.head += 1;
+        }
+        Ok((Some(index.to_json()), ()))
+    })
+    .await;
+    match released {
+        Ok(updated) => updated.cas,
+        Err(e) => {
+            if link.logs(Level::WARN) {
+                warn!(
+                    actor_id = %link.actor_id,
+                    key = %link.log_key(key),
+                    "couchbase list index could not be updated: {}",
+                    e
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Read the elements of a list in `slots`, skipping empty slots
+async fn read(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    slots: Range<u64>,
+) -> RpcResult<Vec<Element>> {
+    let reads = slots.map(|slot| {
+        let element_key = element_key(key, slot);
+        let options = kv_options!(link, GetOptions::default());
+        let connection = link.connection();
+        async move {
+            let doc_key = element_key.clone();
+            let operation = async move { connection.collection.get(doc_key, options).await };
+            match link.execute(op, Some(&element_key), operation).await {
+                Ok(r) => {
+                    let content: serde_json::Value = r.content().map_err(to_rpc_err)?;
+                    Ok(Some(Element {
+                        slot,
+                        size: quota::doc_size(&element_key, &content),
+                        value: link.codec.decode(key, content)?,
+                        cas: r.cas(),
+                    }))
+                }
+                Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+                Err(e) => Err(to_rpc_err(e)),
+            }
+        }
+    });
+    Ok(futures::future::try_join_all(reads)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Read up to `count` elements of a list in slot order, from `slot` on
+async fn read_from(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: &Index,
+    mut slot: u64,
+    count: usize,
+) -> RpcResult<Vec<Element>> {
+    let mut elements = Vec::new();
+    while elements.len() < count && slot < index.next {
+        let end = (slot + BATCH).min(index.next);
+        elements.extend(read(link, op, key, slot..end).await?);
+        slot = end;
+    }
+    elements.truncate(count);
+    Ok(elements)
+}
+
+/// Returns the elements of a list from position `start` to `stop` included, negative positions
+/// counting from the end of the list
+pub(crate) async fn range(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: &Index,
+    start: i32,
+    stop: i32,
+) -> RpcResult<Vec<String>> {
+    let Some(positions) = containers::index_range(index.count as usize, start, stop) else {
+        return Ok(Vec::new());
+    };
+    let elements = read_from(link, op, key, index, index.head, positions.end).await?;
+    Ok(elements
+        .into_iter()
+        .skip(positions.start)
+        .map(|e| e.value)
+        .collect())
+}
+
+/// Returns the page at `cursor` of a list, or the first page of the elements from position
+/// `start` to `stop` included without a cursor, and the cursor of the next page
+pub(crate) async fn page(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: &Index,
+    cursor: Option<Cursor>,
+    (start, stop): (i32, i32),
+    limit: usize,
+) -> RpcResult<(Vec<String>, Option<Cursor>)> {
+    // the first page reads from the head and skips the elements before the range
+    let (cursor, skip) = match cursor {
+        Some(cursor) => (cursor, 0),
+        None => match Cursor::first(index.count as usize, start, stop, index.head) {
+            Some(cursor) => {
+                let skip = cursor.next;
+                (cursor, skip)
+            }
+            None => return Ok((Vec::new(), None)),
+        },
+    };
+    let count = skip + cursor.page_len(limit);
+    let from = cursor.slot.max(index.head);
+    let elements = read_from(link, op, key, index, from, count).await?;
+    let slot = elements.last().map_or(from, |e| e.slot + 1);
+    let values: Vec<String> = elements.into_iter().skip(skip).map(|e| e.value).collect();
+    let next = cursor.after(limit, values.len(), slot);
+    Ok((values, next))
+}
+
+/// Delete the first element of a list equal to `value`. Returns whether one was deleted.
+pub(crate) async fn del(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: Index,
+    value: &str,
+) -> RpcResult<Updated<bool>> {
+    let deleted = take(link, op, key, index, false, |e| e.value == value).await?;
+    Ok(Updated {
+        result: deleted.result.is_some(),
+        cas: deleted.cas,
+    })
+}
+
+/// Remove the first element of a list, or the last one with `back`, and return its value,
+/// or None if the list is empty
+pub(crate) async fn pop(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: Index,
+    back: bool,
+) -> RpcResult<Updated<Option<String>>> {
+    take(link, op, key, index, back, |_| true).await
+}
+
+/// Remove the first `count` elements of a list and return their values. The elements are
+/// claimed by moving the head of the index past them in one cas-protected write, so concurrent
+/// consumers never get the same elements.
+pub(crate) async fn pop_n(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: Index,
+    count: usize,
+) -> RpcResult<Updated<Vec<String>>> {
+    let (mut seen, mut retry) = (index, CasRetry::new());
+    loop {
+        let elements = read_from(link, op, key, &seen, seen.head, count).await?;
+        let Some(head) = elements.last().map(|e| e.slot + 1) else {
+            return Ok(Updated {
+                result: Vec::new(),
+                cas: None,
+            });
+        };
+        let taken = elements.len() as u32;
+        let claimed = update_index(link, op, key, |content| {
+            let Some(mut index) = content.map(|c| parse(key, c)).transpose()?.flatten() else {
+                return Ok((None, false));
+            };
+            // elements may have been added since they were read, but none removed
+            let added = index.next - seen.next;
+            if index.head != seen.head || index.count as u64 != seen.count as u64 + added {
+                return Ok((None, false));
+            }
+            index.head = head;
+            index.count -= taken;
+            Ok((Some(index.to_json()), true))
+        })
+        .await?;
+        if claimed.result {
+            let removals = elements.iter().map(|e| remove(link, op, key, e));
+            let removed = futures::future::try_join_all(removals).await?;
+            let values: Vec<String> = elements
+                .into_iter()
+                .zip(removed)
+                .filter_map(|(element, removed)| removed.then_some(element.value))
+                .collect();
+            // elements removed meanwhile by a writer that also took them off the count
+            let lost = taken - values.len() as u32;
+            if lost > 0 {
+                recount(link, op, key, lost).await?;
+            }
+            return Ok(Updated {
+                result: values,
+                cas: claimed.cas,
+            });
+        }
+        // changed by another consumer meanwhile: read the new head
+        retry.conflicted(key).await?;
+        seen = match storage(link, op, key).await? {
+            Storage::Elements(Some(index)) => index,
+            _ => {
+                return Ok(Updated {
+                    result: Vec::new(),
+                    cas: None,
+                })
+            }
+        };
+    }
+}
+
+/// Add back to the count of a list `lost` elements taken off it twice
+async fn recount(link: &CouchbaseLink, op: &str, key: &str, lost: u32) -> RpcResult<()> {
+    update_index(link, op, key, |content| {
+        let Some(mut index) = content.map(|c| parse(key, c)).transpose()?.flatten() else {
+            return Ok((None, ()));
+        };
+        index.count += lost;
+        Ok((Some(index.to_json()), ()))
+    })
+    .await?;
+    Ok(())
+}
+
+/// Remove the first element of a list that `wanted` accepts, or the last one with `back`,
+/// and return its value
+async fn take(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: Index,
+    back: bool,
+    wanted: impl Fn(&Element) -> bool,
+) -> RpcResult<Updated<Option<String>>> {
+    let (mut index, mut retry) = (index, CasRetry::new());
+    loop {
+        let Some(element) = find(link, op, key, &index, back, &wanted).await? else {
+            return Ok(Updated {
+                result: None,
+                cas: None,
+            });
+        };
+        if remove(link, op, key, &element).await? {
+            let cas = release(link, op, key, &[element.slot]).await;
+            return Ok(Updated {
+                result: Some(element.value),
+                cas,
+            });
+        }
+        // removed by another writer meanwhile: look again
+        retry.conflicted(key).await?;
+        index = match storage(link, op, key).await? {
+            Storage::Elements(Some(index)) => index,
+            _ => {
+                return Ok(Updated {
+                    result: None,
+                    cas: None,
+                })
+            }
+        };
+    }
+}
+
+/// Returns the first element `wanted` accepts, or the last one with `back`
+async fn find(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: &Index,
+    back: bool,
+    wanted: impl Fn(&Element) -> bool,
+) -> RpcResult<Option<Element>> {
+    let (mut low, mut high) = (index.head, index.next);
+    while low < high {
+        let slots = match back {
+            true => high.saturating_sub(BATCH).max(low)..high,
+            false => low..(low + BATCH).min(high),
+        };
+        let mut elements = read(link, op, key, slots.clone()).await?;
+        if back {
+            elements.reverse();
+        }
+        let found = elements.into_iter().find(|e| wanted(e));
+        if found.is_some() {
+            return Ok(found);
+        }
+        match back {
+            true => high = slots.start,
+            false => low = slots.end,
+        }
+    }
+    Ok(None)
+}
+
+/// Set the expiry of the element documents of a list
+pub(crate) async fn touch(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: &Index,
+    expiry: Option<Duration>,
+) -> RpcResult<()> {
+    let element_keys = (index.head..index.next).map(|slot| element_key(key, slot));
+    let mut touches = futures::stream::iter(element_keys)
+        .map(|element_key| async move { containers::touch(link, op, &element_key, expiry).await })
+        .buffer_unordered(BATCH as usize);
+    while let Some(touched) = touches.next().await {
+        touched?;
+    }
+    Ok(())
+}
+
+/// Delete a list and its elements. Returns the cas of the index removal.
+pub(crate) async fn clear(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    index: Index,
+) -> RpcResult<Option<u64>> {
+    let removed = containers::remove(link, op, key).await?;
+    let failures: Vec<_> = futures::stream::iter(index.head..index.next)
+        .map(|slot| async move {
+            containers::remove(link, op, &element_key(key, slot))
+                .await
+                .err()
+        })
+        .buffer_unordered(BATCH as usize)
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+    if let Some(e) = failures.first() {
+        if link.logs(Level::WARN) {
+            warn!(
+                actor_id = %link.actor_id,
+                key = %link.log_key(key),
+                failed = failures.len(),
+                "couchbase list elements could not be removed: {}",
+                e
+            );
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_list_indexes() {
+        let index = Index {
+            head: 2,
+            next: 5,
+            count: 3,
+        };
+        assert_eq!(parse("l", index.to_json()).unwrap(), Some(index));
+        assert_eq!(parse("l", json!(["a", "b"])).unwrap(), None);
+        assert!(parse("l", json!("a")).is_err());
+        assert_eq!(element_key("l", 7), "l::element::7");
+    }
+}
@@ -0,0 +1,118 @@
+//! Ranges of lists returned a page at a time, for ranges too large for one response
+//!
+//! `ListRangePage` returns the values of a range `limit` at a time, with a cursor for the next
+//! page. The range is resolved against the list's length on the first page and kept in the
+//! cursor, so later pages neither move with values added meanwhile nor read the values of the
+//! pages before them: lists stored by elements resume at the slot after the last value returned.
+use std::fmt;
+
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::containers;
+
+/// Values returned by a page when the request sets no limit
+pub(crate) const DEFAULT_PAGE_LIMIT: u32 = 100;
+
+/// Where the next page of a range starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    /// position of the next value
+    pub(crate) next: usize,
+    /// position of the last value of the range
+    pub(crate) last: usize,
+    /// slot to resume reading at, for lists stored by elements
+    pub(crate) slot: u64,
+}
+
+impl Cursor {
+    /// The cursor of the first page of the values from `start` to `stop` included of a list of
+    /// `len` values, or None if the range is empty. Negative positions count from the end.
+    pub(crate) fn first(len: usize, start: i32, stop: i32, slot: u64) -> Option<Self> {
+        let positions = containers::index_range(len, start, stop)?;
+        Some(Cursor {
+            next: positions.start,
+            last: positions.end - 1,
+            slot,
+        })
+    }
+
+    /// Parse a cursor returned by a previous page
+    pub(crate) fn parse(cursor: &str) -> RpcResult<Self> {
+        let invalid = || RpcError::InvalidParameter(format!("invalid list cursor '{}'", cursor));
+        let mut parts = cursor.split(':').map(|part| part.parse::<u64>());
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(next)), Some(Ok(last)), Some(Ok(slot)), None) if next <= last => Ok(Cursor {
+                next: next as usize,
+                last: last as usize,
+                slot,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Number of values in the page starting at this cursor
+    pub(crate) fn page_len(&self, limit: usize) -> usize {
+        (self.last + 1 - self.next).min(limit)
+    }
+
+    /// The cursor after a page of `returned` values, or None if it was the last page: the range
+    /// is complete or the list ended before it
+    pub(crate) fn after(&self, limit: usize, returned: usize, slot: u64) -> Option<Self> {
+        let next = self.next + returned;
+        (returned == self.page_len(limit) && next <= self.last).then_some(Cursor {
+            next,
+            last: self.last,
+            slot,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.next, self.last, self.slot)
+    }
+}
+
+/// Returns the page at `cursor` of a list stored as one document, and the cursor of the next
+pub(crate) fn page(
+    mut list: Vec<String>,
+    cursor: &Cursor,
+    limit: usize,
+) -> (Vec<String>, Option<Cursor>) {
+    let end = (cursor.next + cursor.page_len(limit)).min(list.len());
+    let values: Vec<String> = match cursor.next < end {
+        true => list.drain(cursor.next..end).collect(),
+        false => Vec::new(),
+    };
+    let next = cursor.after(limit, values.len(), 0);
+    (values, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_through_a_range() {
+        let list: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        let cursor = Cursor::first(list.len(), 2, -2, 0).unwrap();
+        let (values, cursor) = page(list.clone(), &cursor, 4);
+        assert_eq!(values, ["2", "3", "4", "5"]);
+        let cursor = Cursor::parse(&cursor.unwrap().to_string()).unwrap();
+        let (values, cursor) = page(list.clone(), &cursor, 4);
+        assert_eq!(values, ["6", "7", "8"]);
+        assert_eq!(cursor, None);
+
+        // the list shrank between pages
+        let cursor = Cursor::first(list.len(), 0, -1, 0).unwrap();
+        let (_, cursor) = page(list.clone(), &cursor, 5);
+        let (values, cursor) = page(list[..7].to_vec(), &cursor.unwrap(), 5);
+        assert_eq!(values, ["5", "6"]);
+        assert_eq!(cursor, None);
+
+        assert_eq!(Cursor::first(list.len(), 5, 2, 0), None);
+        assert!(Cursor::parse("3:1:0").is_err());
+        assert!(Cursor::parse("1:3").is_err());
+        assert!(Cursor::parse("a:b:c").is_err());
+    }
+}
@@ -1,29 +1,172 @@
 //! Couchbase implementation for wasmcloud:keyvalue.
 //!
+/// Apply the link's key-value timeout, if any, to a key-value options builder
+macro_rules! kv_options {
+    ($link:expr, $options:expr) => {
+        match $link.config.sdk_kv_timeout() {
+            Some(timeout) => $options.timeout(timeout),
+            None => $options,
+        }
+    };
+}
+
+#[cfg(test)]
+mod arbitrary;
+mod admin;
+mod batch;
+mod blobs;
+mod blobstore;
+mod bloom;
+mod change_feed;
+mod check_config;
+mod cli;
+mod cas;
+mod chunks;
+mod codec;
 mod config;
+mod contracts;
+mod container_gc;
+mod containers;
+mod counters;
+mod dead_letter;
+mod debug_http;
+mod diagnostics;
+mod errors;
+mod export;
+mod fault;
+mod hashes;
+mod health;
+mod list_elements;
+mod list_pages;
+mod history;
+mod hyperloglog;
+mod import;
+mod interface;
+mod lattice;
+mod management;
+mod metadata;
+mod metrics;
+mod monitor;
+mod mutation_tokens;
+mod orphan;
+mod query;
+mod queues;
+mod quota;
+mod rate_limit;
+mod redis_migration;
+mod replicas;
+mod resp;
+mod sampling;
+mod scan;
+mod set_algebra;
+mod set_members;
+mod set_pages;
+mod sorted_sets;
+mod sqldb;
+mod stats;
+mod subdoc;
+mod tombstones;
+mod transactions;
+mod wasi_buckets;
+mod wasi_keyvalue;
+mod watches;
+mod write_behind;
 
-use std::{collections::HashMap, convert::Infallible, ops::DerefMut, sync::Arc};
-use std::borrow::Borrow;
-use std::env::args;
-use couchbase::{Collection, CouchbaseError, ExistsOptions, GetOptions, GetResult, RemoveOptions};
-use couchbase::CouchbaseError::DocumentNotFound;
-use futures::executor::block_on;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use couchbase::{
+    Bucket, Cluster, Collection, CouchbaseError, CouchbaseResult, ErrorContext, ExistsOptions,
+    GetAndLockOptions, GetAndTouchOptions, GetOptions, GetResult, InsertOptions, LookupInOptions,
+    LookupInSpec, MutateInOptions, MutateInSpec, MutationResult, QueryStringQuery, RemoveOptions,
+    ReplaceOptions, SearchOptions, SearchRow, UpsertOptions,
+};
 
-use serde::Deserialize;
+use futures::TryStreamExt;
 use tokio::sync::RwLock;
-use tracing::{info, instrument, warn};
+use tracing::{debug, info, instrument, level_filters::LevelFilter, warn, Instrument, Level};
+use wasmbus_rpc::core::{HealthCheckRequest, HealthCheckResponse};
 use wasmbus_rpc::provider::prelude::*;
 use wasmcloud_interface_keyvalue::{
     GetResponse, IncrementRequest, KeyValue, KeyValueReceiver, ListAddRequest, ListDelRequest,
     ListRangeRequest, SetAddRequest, SetDelRequest, SetRequest, StringList,
 };
-use crate::config::Config;
+use crate::codec::Codec;
+use crate::admin::{CollectionName, CouchbaseAdmin, CouchbaseAdminReceiver};
+use crate::config::{Config, ListStorage};
+use crate::errors::to_rpc_err;
+use crate::fault::FaultInjector;
+use crate::interface::{
+    AppendRequest, ApproxAddRequest, CouchbaseKeyValue, CouchbaseKeyValueReceiver, DequePopRequest,
+    DequePushRequest, ExpireContainerRequest, GetAllReplicasResponse, GetExpiryResponse,
+    GetHistoryRequest, GetManyResult, GetMetadataResponse, GetWithCasResponse, GetWithMetaResponse,
+    HFieldRequest, HSetRequest, HashField, HistoryEntry, Increment64Request, KeyResult,
+    ListAddCappedRequest, ListPopNRequest, ListRangePageRequest, ListRangePageResponse, LockRequest,
+    LockResponse, LookupPathRequest, LookupPathResponse, MutatePathRequest, ScanKeysRequest,
+    ScanKeysResponse, ScoredMember, SearchHit, SearchRequest, SetAddManyRequest, SetContainsRequest,
+    SetContentTypeRequest, SetIfCasRequest, SetIfCasResponse, SetQueryPageRequest,
+    SetQueryPageResponse, SetRequests, SetWithModeRequest, SetWithModeResponse,
+    SetWithOptionsRequest, TouchRequest, TransactRequest, UnlockRequest, WatchRequest, ZAddRequest,
+    ZIncrByRequest, ZRangeByScoreRequest, ZRangeRequest, ZRankRequest, ZRankResponse, ZRemRequest,
+    ZScoreRequest, ZScoreResponse, ZTopRequest,
+};
+use crate::blobs::Uploads;
+use crate::blobstore::{
+    Blobstore, BlobstoreReceiver, ContainerIds, ContainerMetadata, ContainerObject,
+    ContainersInfo, GetObjectRequest, GetObjectResponse, ListObjectsRequest, ListObjectsResponse,
+    MultiResult, ObjectMetadata, PutChunkRequest, PutObjectRequest, PutObjectResponse,
+    RemoveObjectsRequest,
+};
+use crate::bloom::SetFilters;
+use crate::list_elements::Storage;
+use crate::list_pages::{Cursor, DEFAULT_PAGE_LIMIT};
+use crate::mutation_tokens::MutationTokens;
+use crate::orphan::OrphanReport;
+use crate::query::N1ql;
+use crate::quota::StorageQuota;
+use crate::rate_limit::RateLimiter;
+use crate::sampling::LogSampler;
+use crate::sqldb::{ExecuteResult, SqlDb, SqlDbError, SqlDbReceiver, Statement};
+use crate::stats::LinkStats;
+use crate::wasi_keyvalue::{
+    Atomics, AtomicsReceiver, Batch, BatchReceiver, BucketKey, BucketKeys, CompareAndSwapRequest,
+    KeyResponse, KeyValuePair, ListKeysRequest, MaybeValue, SetManyRequest, Store, StoreReceiver,
+};
+use crate::watches::{Watch, Watches};
+use crate::write_behind::{Pending, WriteBehind};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(passed) = cli::run(&args) {
+        std::process::exit(if passed { 0 } else { 1 });
+    }
     let hd = load_host_data()?;
+    let provider_config = config::load_provider_config(&hd)?;
+    if let Some(port) = provider_config.metrics_port {
+        metrics::start_server(([0, 0, 0, 0], port).into())?;
+    }
+    if let Some(subject) = provider_config.metrics_subject.clone() {
+        let interval = provider_config.metrics_publish_interval();
+        metrics::start_publisher(hd.clone(), subject, interval)?;
+    }
 
+    let provider = KvCouchbaseProvider {
+        shutdown_grace_period: provider_config.shutdown_grace_period(),
+        default_log_level: provider_config.log_level,
+        contracts: Arc::new(provider_config.contracts()),
+        admin_actors: Arc::new(provider_config.admin_actors.into_iter().collect()),
+        ..Default::default()
+    };
     provider_start(
-        KvCouchbaseProvider::default(),
+        provider,
         hd,
         Some("KeyValue Couchbase Provider".to_string()),
     )?;
@@ -32,12 +175,400 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// How often shutdown checks whether in-flight calls have completed
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Longest lock the server grants, in seconds
+const MAX_LOCK_TTL: u32 = 30;
+
 /// Couchbase keyValue provider implementation.
 #[derive(Default, Clone, Provider)]
-#[services(KeyValue)]
+#[services(KeyValue, CouchbaseKeyValue, CouchbaseAdmin, SqlDb, Blobstore, Store, Atomics, Batch)]
 struct KvCouchbaseProvider {
     // store couchbase connections per actor
-    actors: Arc<RwLock<HashMap<String, Collection>>>,
+    actors: Arc<RwLock<HashMap<String, Arc<CouchbaseLink>>>>,
+    /// set once shutdown starts, after which requests are refused
+    closing: Arc<AtomicBool>,
+    /// how long shutdown waits for in-flight Couchbase calls
+    shutdown_grace_period: Duration,
+    /// log_level of links that do not set one
+    default_log_level: Option<LevelFilter>,
+    /// actors allowed to call CouchbaseAdmin
+    admin_actors: Arc<HashSet<String>>,
+    /// contracts on which links are accepted
+    contracts: Arc<Vec<String>>,
+}
+
+/// Couchbase handles for a link
+struct Connection {
+    cluster: Cluster,
+    bucket: Bucket,
+    collection: Collection,
+    /// collection receiving audit records of mutations, if enabled
+    audit: Option<Collection>,
+    /// collection receiving dead-letter records of failed writes, if enabled
+    dead_letter: Option<Collection>,
+    /// collection holding the metadata of the link's keys, if enabled
+    metadata: Option<Collection>,
+}
+
+/// Couchbase connection and link settings for a linked actor
+struct CouchbaseLink {
+    actor_id: String,
+    connection: std::sync::RwLock<Arc<Connection>>,
+    /// set by the connection monitor while the cluster is unreachable
+    degraded: AtomicBool,
+    config: Config,
+    rate_limiter: Option<RateLimiter>,
+    quota: Option<StorageQuota>,
+    /// requests timed out by the provider, if the link reports orphans
+    orphans: Option<Arc<OrphanReport>>,
+    stats: LinkStats,
+    /// Couchbase calls currently running for this link
+    in_flight: AtomicUsize,
+    /// verbosity of the provider's logs about this link, None to leave it to RUST_LOG
+    log_level: Option<LevelFilter>,
+    /// picks the successful operations that are logged
+    log_sampler: LogSampler,
+    /// failed writes waiting to be retried, if the link uses write-behind
+    write_behind: Option<WriteBehind>,
+    /// faults injected into the actor's operations, if the link is used for testing
+    faults: Option<FaultInjector>,
+    /// mutation tokens of the link's recent writes, if queries read them at_plus
+    tokens: Option<MutationTokens>,
+    /// conversion of the actor's values to and from stored JSON
+    codec: Codec,
+    /// bloom filters of the sets set_contains checked last, if the link keeps any
+    set_filters: Option<SetFilters>,
+    /// objects the actor is uploading in chunks
+    uploads: Uploads,
+    /// keys and prefixes whose changes are reported to the actor
+    watches: Watches,
+}
+
+/// Counts a Couchbase call as in flight until dropped
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn start(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CouchbaseLink {
+    /// Connect to the cluster with the link's settings and set up the link's resources,
+    /// without starting its background tasks
+    async fn connect(
+        actor_id: &str,
+        config: Config,
+        default_log_level: Option<LevelFilter>,
+    ) -> RpcResult<Self> {
+        let connection = config::create_collection_conection(&config)
+            .await
+            .inspect_err(|_| metrics::record_bootstrap_failure(actor_id))?;
+
+        let rate_limiter = match config.max_ops_per_sec {
+            0 => None,
+            rate => Some(RateLimiter::new(rate, config.burst.max(rate))),
+        };
+        let quota = match config.storage_quota {
+            0 => None,
+            limit => Some(StorageQuota::new(limit)),
+        };
+
+        let orphans = config
+            .reports_orphans()
+            .then(|| Arc::new(OrphanReport::default()));
+
+        let log_level = config.log_level.or(default_log_level);
+        let log_sampler = LogSampler::new(
+            config.log_sample_rate.default,
+            config.log_sample_rate.operations.clone(),
+        );
+
+        let write_behind = config.write_behind.then(|| {
+            WriteBehind::new(
+                actor_id,
+                config.write_behind_queue_size,
+                config.write_behind_max_attempts,
+                config.write_behind_spill_file.as_ref().map(PathBuf::from),
+            )
+        });
+
+        let faults = config.fault_injection.is_enabled().then(|| {
+            FaultInjector::new(
+                config.fault_injection.clone(),
+                config.fault_injection_latency(),
+            )
+        });
+
+        let tokens = config.uses_at_plus().then(MutationTokens::default);
+        let codec = Codec::new(&config)?;
+        let set_filters = (config.set_filters > 0).then(|| {
+            SetFilters::new(config.set_filters as usize, config.set_filter_refresh())
+        });
+
+        Ok(CouchbaseLink {
+            actor_id: actor_id.to_string(),
+            connection: std::sync::RwLock::new(Arc::new(connection)),
+            degraded: AtomicBool::new(false),
+            config,
+            rate_limiter,
+            quota,
+            orphans,
+            stats: LinkStats::default(),
+            in_flight: AtomicUsize::new(0),
+            log_level,
+            log_sampler,
+            write_behind,
+            faults,
+            tokens,
+            codec,
+            set_filters,
+            uploads: Uploads::default(),
+            watches: Watches::default(),
+        })
+    }
+
+    /// Returns true if the link's log_level allows logging at `level`
+    fn logs(&self, level: Level) -> bool {
+        self.log_level.is_none_or(|max| level <= max)
+    }
+
+    /// Returns the current connection, which the monitor may replace after a reconnect
+    fn connection(&self) -> Arc<Connection> {
+        self.connection.read().unwrap().clone()
+    }
+
+    fn replace_connection(&self, connection: Connection) {
+        *self.connection.write().unwrap() = Arc::new(connection);
+    }
+
+    /// Run a Couchbase operation for this link in a client span, recording its metrics
+    /// and logging it if it exceeds the link's slow operation threshold
+    async fn execute<T: Send + 'static>(
+        &self,
+        op: &str,
+        key: Option<&str>,
+        operation: impl Future<Output = CouchbaseResult<T>> + Send + 'static,
+    ) -> CouchbaseResult<T> {
+        let span = tracing::info_span!(
+            "couchbase",
+            otel.name = %format!("couchbase {}", op),
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            otel.status_message = tracing::field::Empty,
+            db.system = "couchbase",
+            db.operation = %op,
+            db.name = %self.config.bucket,
+            db.couchbase.scope = %self.config.scope,
+            db.couchbase.collection = %self.config.collection,
+            actor_id = %self.actor_id,
+        );
+        let _in_flight = InFlight::start(&self.in_flight);
+        let start = Instant::now();
+        let fault = self.faults.as_ref().and_then(|faults| faults.pick(op));
+        if let Some(fault) = fault {
+            if self.logs(Level::DEBUG) {
+                debug!(actor_id = %self.actor_id, operation = %op, ?fault, "injecting couchbase fault");
+            }
+        }
+        let operation = fault::inject(fault, operation).instrument(span.clone());
+        let result = match (&self.orphans, self.config.kv_timeout) {
+            (Some(orphans), Some(timeout)) if Config::is_kv_operation(op) => {
+                self.execute_with_deadline(op, timeout, orphans.clone(), operation)
+                    .await
+            }
+            _ => operation.await,
+        };
+        let elapsed = start.elapsed();
+        metrics::record(&self.actor_id, op, elapsed, result.as_ref().err());
+        self.stats.record(&self.actor_id, op, result.as_ref().err());
+        // operations are logged at INFO, so RUST_LOG does not filter out a link being debugged
+        // successful operations are sampled, errors are always logged
+        if matches!(self.log_level, Some(level) if level >= LevelFilter::DEBUG)
+            && (result.is_err() || self.log_sampler.sample(op))
+        {
+            info!(
+                actor_id = %self.actor_id,
+                operation = %op,
+                key = %key.map(|k| self.log_key(k)).unwrap_or_default(),
+                duration_ms = elapsed.as_millis() as u64,
+                error = %result.as_ref().err().map(|e| e.to_string()).unwrap_or_default(),
+                "couchbase operation"
+            );
+        }
+        match self.config.slow_op_threshold(op) {
+            Some(threshold) if elapsed > threshold && self.logs(Level::WARN) => warn!(
+                actor_id = %self.actor_id,
+                operation = %op,
+                key = %key.map(|k| self.log_key(k)).unwrap_or_default(),
+                duration_ms = elapsed.as_millis() as u64,
+                threshold_ms = threshold.as_millis() as u64,
+                "slow couchbase operation"
+            ),
+            _ => {}
+        }
+        if let Err(e) = &result {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", tracing::field::display(e));
+        }
+        result
+    }
+
+    /// Time the operation out after `timeout` while letting the SDK request run on,
+    /// so a late response is recorded in the link's orphan report
+    async fn execute_with_deadline<T: Send + 'static>(
+        &self,
+        op: &str,
+        timeout: Duration,
+        orphans: Arc<OrphanReport>,
+        operation: impl Future<Output = CouchbaseResult<T>> + Send + 'static,
+    ) -> CouchbaseResult<T> {
+        let start = Instant::now();
+        let mut handle = tokio::spawn(operation);
+        match tokio::time::timeout(timeout, &mut handle).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Ok(Err(_)) => Err(CouchbaseError::RequestCanceled {
+                ctx: ErrorContext::default(),
+            }),
+            Err(_) => {
+                let op = op.to_string();
+                tokio::spawn(async move {
+                    match handle.await {
+                        Ok(Err(CouchbaseError::Timeout { .. })) | Err(_) => orphans.record_lost(),
+                        Ok(_) => orphans.record_orphan(&op, start.elapsed()),
+                    }
+                });
+                Err(CouchbaseError::Timeout {
+                    ambiguous: true,
+                    ctx: ErrorContext::default(),
+                })
+            }
+        }
+    }
+
+    /// Keep the mutation token of a write, for the queries that read the link's writes at_plus
+    fn written(&self, result: &MutationResult) {
+        if let (Some(tokens), Some(token)) = (&self.tokens, result.mutation_token()) {
+            tokens.record(token);
+        }
+    }
+
+    /// Fails if writing `bytes` more would exceed the link's storage quota
+    fn check_quota(&self, bytes: u64) -> RpcResult<()> {
+        match &self.quota {
+            Some(quota) if !quota.has_room(bytes) => Err(RpcError::Other(format!(
+                "storage quota of {} bytes exceeded",
+                self.config.storage_quota
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record the sizes of a document the link read or wrote, warning about values
+    /// approaching Couchbase's document size limit
+    fn record_sizes(&self, op: &str, key: &str, value_len: usize) {
+        metrics::record_sizes(&self.actor_id, op, key.len(), value_len);
+        if value_len > metrics::LARGE_VALUE_SIZE && self.logs(Level::WARN) {
+            warn!(
+                actor_id = %self.actor_id,
+                operation = %op,
+                key = %self.log_key(key),
+                value_bytes = value_len,
+                limit_bytes = metrics::MAX_DOCUMENT_SIZE,
+                "couchbase value approaching the document size limit"
+            );
+        }
+    }
+
+    /// Write an audit record of a mutation to the link's audit collection, if any,
+    /// and update the key's metadata, if the link keeps them.
+    /// The record is written in the background so the caller does not wait for it.
+    fn audit(&self, op: &str, key: &str, cas: Option<u64>) {
+        metadata::record(self, op, key);
+        let connection = self.connection();
+        if connection.audit.is_none() {
+            return;
+        }
+        let record = serde_json::json!({
+            "actor": self.actor_id,
+            "op": op,
+            "key": key,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "cas": cas,
+        });
+        let id = format!("{}::{}", self.actor_id, wasmbus_rpc::provider::make_uuid());
+        let actor_id = self.actor_id.clone();
+        let log_failure = self.logs(Level::WARN);
+        tokio::spawn(async move {
+            if let Some(audit) = &connection.audit {
+                if let Err(e) = audit.insert(id, record, InsertOptions::default()).await {
+                    if log_failure {
+                        warn!(%actor_id, "couchbase audit record could not be written: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns the document key for an actor's key, refusing keys that contain the separator
+    /// of the documents derived from a key
+    fn doc_key(&self, key: &str) -> RpcResult<String> {
+        config::check_key(key)?;
+        Ok(self.config.doc_key(&self.actor_id, key))
+    }
+
+    /// Returns the namespace prefix of the link's document keys
+    fn namespace(&self) -> String {
+        self.config.doc_key(&self.actor_id, "")
+    }
+
+    /// Returns the document key as it may appear in logs: a stable hash if the link
+    /// redacts keys, the key itself otherwise
+    fn log_key(&self, key: &str) -> String {
+        self.config.log_key(key)
+    }
+
+    /// Returns the expiry to use for a `set`, falling back to the link's default_ttl
+    /// and clamping to the link's max_ttl, as Couchbase reads it: a unix time beyond 30 days
+    fn expiry(&self, key: &str, expires: u32) -> Option<Duration> {
+        let secs = match expires {
+            0 => self.config.default_ttl,
+            secs => secs,
+        };
+        let max = self.config.max_ttl;
+        if max > 0 && (secs == 0 || secs > max) {
+            if !self.logs(Level::WARN) {
+                // clamped silently
+            } else if secs == 0 {
+                warn!(
+                    actor_id = %self.actor_id,
+                    key = %self.log_key(key),
+                    "no expiry, clamping to max_ttl {}s", max
+                );
+            } else {
+                warn!(
+                    actor_id = %self.actor_id,
+                    key = %self.log_key(key),
+                    "expiry {}s exceeds max_ttl, clamping to {}s", secs, max
+                );
+            }
+            return Some(server_expiry(max));
+        }
+        match secs {
+            0 => None,
+            secs => Some(server_expiry(secs)),
+        }
+    }
 }
 
 
@@ -53,37 +584,720 @@ impl ProviderHandler for KvCouchbaseProvider {
     /// If the link is allowed, return true, otherwise return false to deny the link.
     #[instrument(level = "debug", skip(self, ld), fields(actor_id = %ld.actor_id))]
     async fn put_link(&self, ld: &LinkDefinition) -> RpcResult<bool> {
+        if !self.contracts.contains(&ld.contract_id) {
+            warn!(
+                contract_id = %ld.contract_id,
+                "couchbase link denied: contract not served, expected one of {:?}",
+                self.contracts
+            );
+            return Ok(false);
+        }
         let config = config::load_config(ld)?;
-        let collection = config::create_collection_conection(config).await.unwrap();
+        let link = CouchbaseLink::connect(&ld.actor_id, config, self.default_log_level).await?;
+        let link = Arc::new(link);
+        if link.quota.is_some() {
+            quota::spawn_reconciliation(
+                Arc::downgrade(&link),
+                link.config.quota_reconcile_interval(),
+            );
+        }
+        if link.orphans.is_some() {
+            orphan::spawn_reporter(
+                Arc::downgrade(&link),
+                link.config.orphan_report_interval(),
+            );
+        }
+        if let Some(interval) = link.config.stats_interval() {
+            stats::spawn_logger(Arc::downgrade(&link), interval);
+        }
+        if let Some(interval) = link.config.monitor_interval() {
+            monitor::spawn_monitor(Arc::downgrade(&link), interval);
+        }
+        if link.write_behind.is_some() {
+            write_behind::spawn_worker(
+                Arc::downgrade(&link),
+                link.config.write_behind_retry_interval(),
+            );
+        }
+        if link.config.change_feed_subject.is_some() {
+            change_feed::spawn_feed(
+                Arc::downgrade(&link),
+                ld.clone(),
+                link.config.change_feed_interval(),
+            );
+        }
+        watches::spawn_watcher(
+            Arc::downgrade(&link),
+            ld.clone(),
+            link.config.change_feed_interval(),
+        );
+
+        if let Some(interval) = link.config.container_gc_interval() {
+            container_gc::spawn_collector(Arc::downgrade(&link), interval);
+        }
+
+        let diagnosed = link.clone();
+        tokio::spawn(async move { diagnostics::report(&diagnosed).await });
 
         let mut update_map = self.actors.write().await;
-        update_map.insert(ld.actor_id.to_string(), collection);
+        update_map.insert(ld.actor_id.to_string(), link);
+        metrics::set_connections(update_map.len());
         Ok(true)
     }
 
-    /// Handle notification that a link is dropped - close the connection
+    /// Handle notification that a link is dropped - flush its write-behind queue
+    /// and close the connection, then relink the actor if it has a link on another contract
     #[instrument(level = "info", skip(self))]
     async fn delete_link(&self, actor_id: &str) {
-        let mut aw = self.actors.write().await;
-        if let Some(conn) = aw.remove(actor_id) {
+        let removed = {
+            let mut aw = self.actors.write().await;
+            let removed = aw.remove(actor_id);
+            metrics::set_connections(aw.len());
+            removed
+        };
+        if let Some(conn) = removed {
             info!("couchbase closing connection for actor {}", actor_id);
+            write_behind::flush(&conn, self.shutdown_grace_period).await;
             drop(conn)
         }
+        self.relink(actor_id).await;
+    }
+
+    /// Report the provider healthy when every linked cluster answers a key-value ping.
+    /// The message lists the status and latency of each link.
+    async fn health_request(&self, _arg: &HealthCheckRequest) -> RpcResult<HealthCheckResponse> {
+        let links: Vec<Arc<CouchbaseLink>> = self.actors.read().await.values().cloned().collect();
+        let reports =
+            futures::future::join_all(links.iter().map(|link| health::ping_link(link))).await;
+        let healthy = reports.iter().all(|r| r.healthy);
+        if !healthy {
+            for report in reports.iter().filter(|r| !r.healthy) {
+                warn!(
+                    "couchbase health check failed for actor {}: {}",
+                    report.actor_id,
+                    report.error.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        let message = serde_json::to_string(&serde_json::json!({ "links": reports }))
+            .map_err(|e| RpcError::Ser(e.to_string()))?;
+        Ok(HealthCheckResponse {
+            healthy,
+            message: Some(message),
+        })
     }
 
-    /// Handle shutdown request by closing all connections
+    /// Handle shutdown request: refuse new requests, wait up to the grace period
+    /// for in-flight Couchbase calls to complete and write-behind queues to flush,
+    /// then close all connections
     async fn shutdown(&self) -> Result<(), Infallible> {
+        self.closing.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + self.shutdown_grace_period;
+        let links: Vec<Arc<CouchbaseLink>> = self.actors.read().await.values().cloned().collect();
+        let in_flight = || -> usize {
+            links
+                .iter()
+                .map(|link| link.in_flight.load(Ordering::SeqCst))
+                .sum()
+        };
+        let drained = tokio::time::timeout(self.shutdown_grace_period, async {
+            while in_flight() > 0 {
+                tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "closing couchbase connections with {} calls still in flight after {:?}",
+                in_flight(),
+                self.shutdown_grace_period
+            );
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        futures::future::join_all(
+            links
+                .iter()
+                .map(|link| write_behind::flush(link, remaining)),
+        )
+        .await;
+
         let mut aw = self.actors.write().await;
         // empty the actor link data and stop all servers
         for (_, conn) in aw.drain() {
             drop(conn)
         }
+        metrics::set_connections(0);
         Ok(())
     }
 }
 
-fn to_rpc_err(e: CouchbaseError) -> RpcError {
-    RpcError::Other(format!("Couchbase error: {}", e))
+impl KvCouchbaseProvider {
+    /// Returns the link of the actor in the request, if the link's policy allows the operation
+    /// and the actor is within its rate limit
+    async fn link(&self, ctx: &Context, op: &str) -> RpcResult<Arc<CouchbaseLink>> {
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(RpcError::Other("provider is shutting down".to_string()));
+        }
+        let actor_id = actor_id(ctx)?;
+        let link = self
+            .actors
+            .read()
+            .await
+            .get(actor_id)
+            .cloned()
+            .ok_or_else(|| RpcError::InvalidParameter(format!("actor not linked:{}", actor_id)))?;
+        if !link.config.is_op_allowed(op) {
+            return Err(RpcError::Other(format!(
+                "operation denied by link policy: {}",
+                op
+            )));
+        }
+        if let Some(limiter) = &link.rate_limiter {
+            if let Err(retry_after) = limiter.try_acquire() {
+                return Err(RpcError::Other(format!(
+                    "rate limit exceeded, retry after {}ms",
+                    retry_after.as_millis().max(1)
+                )));
+            }
+        }
+        Ok(link)
+    }
+
+    /// Link an actor again with the link it still has on another contract, if any, since
+    /// wasmbus-rpc forgets the actor when any of its links is deleted
+    async fn relink(&self, actor_id: &str) {
+        if self.contracts.len() < 2 || self.closing.load(Ordering::SeqCst) {
+            return;
+        }
+        let host_data = match load_host_data() {
+            Ok(host_data) => host_data,
+            Err(e) => {
+                warn!("couchbase could not read the host data to relink {}: {}", actor_id, e);
+                return;
+            }
+        };
+        let links = match lattice::links().await {
+            Ok(links) => links,
+            Err(e) => {
+                warn!("couchbase could not list the remaining links of {}: {}", actor_id, e);
+                return;
+            }
+        };
+        let ld = match contracts::remaining_link(
+            links,
+            actor_id,
+            &host_data.provider_key,
+            &host_data.link_name,
+            &self.contracts,
+        ) {
+            Some(ld) => ld,
+            None => return,
+        };
+        info!(contract_id = %ld.contract_id, "couchbase relinking actor {}", actor_id);
+        match self.put_link(&ld).await {
+            Ok(true) => get_host_bridge().put_link(ld).await,
+            Ok(false) => {}
+            Err(e) => warn!("couchbase could not relink actor {}: {}", actor_id, e),
+        }
+    }
+
+    /// Returns the link of the actor making a CouchbaseAdmin request, like [Self::link],
+    /// if the actor is one of the provider's admin_actors
+    async fn admin_link(&self, ctx: &Context, op: &str) -> RpcResult<Arc<CouchbaseLink>> {
+        let actor_id = actor_id(ctx)?;
+        if !self.admin_actors.contains(actor_id) {
+            return Err(RpcError::Other(format!(
+                "operation denied: {} is not an admin actor",
+                actor_id
+            )));
+        }
+        self.link(ctx, op).await
+    }
+}
+
+/// Returns the expiry for a document to expire `secs` seconds from now
+fn server_expiry(secs: u32) -> Duration {
+    config::server_expiry(secs as u64, change_feed::unix_time().as_secs())
+}
+
+/// Returns the duration to pass to the SDK for `secs` seconds of expiry or lock time:
+/// for get-and-touch, get-and-lock and sub-document mutations, the SDK passes it on in
+/// microseconds where the server expects seconds
+fn sdk_seconds(secs: u64) -> Duration {
+    Duration::from_micros(secs)
+}
+
+/// A document read with N1QL with its cas and expiration
+#[derive(serde::Deserialize)]
+struct DocumentWithMeta {
+    cas: u64,
+    /// unix time in seconds at which the document expires, 0 if it does not
+    #[serde(default)]
+    expiration: u64,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+/// Returns the seconds left before the unix time `expiration`, 0 if it is 0 for no expiration
+fn seconds_left(expiration: u64) -> u32 {
+    if expiration == 0 {
+        return 0;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    // a key expiring now may not be purged yet
+    expiration.saturating_sub(now).clamp(1, u32::MAX as u64) as u32
+}
+
+/// Deletes a key of the link, returning true if it was deleted
+async fn del_value(link: &CouchbaseLink, op: &str, name: &str) -> RpcResult<bool> {
+    let key = link.doc_key(name)?;
+    if link.write_behind.as_ref().is_some_and(|w| w.is_pending(&key)) {
+        // queue behind the pending write so the writes apply in order
+        let pending = Pending::del(&key, name);
+        return write_behind::queue(link, pending).await.map(|_| true);
+    }
+    let removed = match remove_value(link, op, &key).await {
+        Ok(removed) => removed,
+        Err(e) => {
+            dead_letter::record(link, op, &key, &e);
+            return Err(e);
+        }
+    };
+    match removed {
+        Ok(cas) => {
+            link.audit(op, name, Some(cas));
+            Ok(true)
+        }
+        Err(e @ CouchbaseError::DocumentNotFound { .. }) => Err(to_rpc_err(e)),
+        Err(e) => {
+            if write_behind::should_queue(link, &e) {
+                let pending = Pending::del(&key, name);
+                if write_behind::queue(link, pending).await.is_ok() {
+                    return Ok(true);
+                }
+            }
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Removes a document: buries it on soft-delete links, otherwise removes it with its chunks
+/// and releases its size from the quota. Shared by `del` and its write-behind retries.
+/// The outer error is a failed chunk lookup or burial, the inner one the removal's.
+pub(crate) async fn remove_value(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<CouchbaseResult<u64>> {
+    let chunked = match link.config.chunk_size() {
+        Some(_) => chunks::lookup(link, op, key).await?,
+        None => None,
+    };
+    // chunked values are removed for good, their chunks would outlive the tombstone
+    if link.config.soft_delete().is_some() && chunked.is_none() {
+        return Ok(match tombstones::bury(link, op, key).await? {
+            Some(cas) => Ok(cas),
+            None => Err(CouchbaseError::DocumentNotFound {
+                ctx: ErrorContext::default(),
+            }),
+        });
+    }
+    // look up the stored size so it can be released from the quota
+    let size = match &link.quota {
+        Some(_) => {
+            let options = kv_options!(link, GetOptions::default());
+            let connection = link.connection();
+            let doc_key = key.to_string();
+            let operation = async move { connection.collection.get(doc_key, options).await };
+            match link.execute("quota_lookup", Some(key), operation).await {
+                Ok(r) => r
+                    .content::<serde_json::Value>()
+                    .map(|v| quota::doc_size(key, &v))
+                    .unwrap_or_default(),
+                Err(_) => 0,
+            }
+        }
+        None => 0,
+    };
+    let options = kv_options!(link, RemoveOptions::default());
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move { connection.collection.remove(doc_key, options).await };
+    let r = match link.execute(op, Some(key), operation).await {
+        Ok(r) => r,
+        Err(e) => return Ok(Err(e)),
+    };
+    link.written(&r);
+    if let Some(manifest) = chunked {
+        chunks::remove(link, op, key, &manifest).await;
+        if let Some(quota) = &link.quota {
+            quota.sub(manifest.size as u64);
+        }
+    }
+    if let Some(quota) = &link.quota {
+        quota.sub(size);
+    }
+    Ok(Ok(r.cas()))
+}
+
+/// Gets the value of a key of the link
+async fn get_value(link: &CouchbaseLink, op: &str, name: &str) -> RpcResult<GetResponse> {
+    let key = link.doc_key(name)?;
+    let options = kv_options!(link, GetOptions::default());
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let operation = async move { connection.collection.get(doc_key, options).await };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) => {
+            let content = r.content::<serde_json::Value>().map_err(to_rpc_err)?;
+            if tombstones::is_tombstone(&content) {
+                return Ok(GetResponse::default());
+            }
+            let content = match chunks::manifest(&content) {
+                Some(manifest) => chunks::read(link, op, &key, &manifest).await?,
+                None => content,
+            };
+            let value = link.codec.decode(&key, content)?;
+            link.record_sizes(op, &key, value.len());
+            Ok(GetResponse {
+                exists: true,
+                value,
+            })
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(GetResponse {
+            exists: false,
+            ..Default::default()
+        }),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Sets the value of a key of the link
+async fn set_value(link: &CouchbaseLink, op: &str, arg: &SetRequest) -> RpcResult<()> {
+    let key = link.doc_key(&arg.key)?;
+    let value = link.codec.encode(&key, &arg.value)?;
+    let size = quota::doc_size(&key, &value);
+    link.check_quota(size)?;
+    link.record_sizes(op, &key, size as usize - key.len());
+    let expiry = link.expiry(&arg.key, arg.expires);
+    if link.write_behind.as_ref().is_some_and(|w| w.is_pending(&key)) {
+        // queue behind the pending write so the writes apply in order
+        let pending = Pending::set(&key, &arg.key, &arg.value, expiry);
+        return write_behind::queue(link, pending).await;
+    }
+    let stored = match store_value(link, op, &key, value, expiry).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            dead_letter::record(link, op, &key, &e);
+            return Err(e);
+        }
+    };
+    match stored {
+        Ok(cas) => {
+            link.audit(op, &arg.key, Some(cas));
+            Ok(())
+        }
+        Err(e) => {
+            if write_behind::should_queue(link, &e) {
+                let pending = Pending::set(&key, &arg.key, &arg.value, expiry);
+                if write_behind::queue(link, pending).await.is_ok() {
+                    return Ok(());
+                }
+            }
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Upserts an encoded value, split into chunks and with the value it replaces kept in its
+/// history as the link is configured. Shared by `set` and its write-behind retries.
+/// The outer error is a failed chunk or history step, the inner one the upsert's.
+pub(crate) async fn store_value(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    mut value: serde_json::Value,
+    expiry: Option<Duration>,
+) -> RpcResult<CouchbaseResult<u64>> {
+    let size = quota::doc_size(key, &value);
+    // the chunks of the value being replaced, removed once it is
+    let replaced = match link.config.chunk_size() {
+        Some(_) => chunks::lookup(link, op, key).await?,
+        None => None,
+    };
+    let pieces = link
+        .config
+        .chunk_size()
+        .and_then(|chunk_size| chunks::split(&value, chunk_size));
+    let chunked = match pieces {
+        Some(pieces) => Some(chunks::write(link, op, key, pieces, expiry).await?),
+        None => None,
+    };
+    if let Some(manifest) = &chunked {
+        value = manifest.to_json();
+    }
+    let previous = match link.config.history_length {
+        0 => None,
+        _ => history::previous(link, op, key).await?,
+    };
+    let mut options = kv_options!(link, UpsertOptions::default());
+    if let Some(expiry) = expiry {
+        options = options.expiry(expiry);
+    }
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move { connection.collection.upsert(doc_key, value, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(manifest) = replaced {
+                chunks::remove(link, op, key, &manifest).await;
+            }
+            if let Some(previous) = previous {
+                history::append(link, op, key, previous).await;
+            }
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            Ok(Ok(r.cas()))
+        }
+        Err(e) => {
+            if let Some(manifest) = chunked {
+                chunks::remove(link, op, key, &manifest).await;
+            }
+            Ok(Err(e))
+        }
+    }
+}
+
+/// Set the expiry of a document and read it, clamping the expiry like `set`.
+/// A missing document is Ok(None).
+async fn touch(link: &CouchbaseLink, op: &str, arg: &TouchRequest) -> RpcResult<Option<GetResult>> {
+    let key = link.doc_key(&arg.key)?;
+    let expiry = link
+        .expiry(&arg.key, arg.expires)
+        .map_or(Duration::ZERO, |expiry| sdk_seconds(expiry.as_secs()));
+    let options = kv_options!(link, GetAndTouchOptions::default());
+    let connection = link.connection();
+    let doc_key = key.clone();
+    let operation =
+        async move { connection.collection.get_and_touch(doc_key, expiry, options).await };
+    match link.execute(op, Some(&key), operation).await {
+        Ok(r) => {
+            link.audit(op, &arg.key, Some(r.cas()));
+            Ok(Some(r))
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Append a value onto the end of a list, removing its oldest values beyond `max_length`
+/// unless it is 0. Returns the new list size.
+async fn list_push(
+    link: &CouchbaseLink,
+    op: &str,
+    list_name: &str,
+    value: &str,
+    max_length: u32,
+) -> RpcResult<u32> {
+    let key = link.doc_key(list_name)?;
+    if link.config.list_storage == ListStorage::Elements {
+        if let Some(updated) = list_elements::add(link, op, &key, value, max_length).await? {
+            link.audit(op, list_name, updated.cas);
+            return Ok(updated.result);
+        }
+    }
+    let updated = containers::update_strings(link, op, &key, |list| {
+        let mut list = list.unwrap_or_default();
+        list.push(value.to_string());
+        if max_length > 0 && list.len() > max_length as usize {
+            list.drain(..list.len() - max_length as usize);
+        }
+        let len = list.len() as u32;
+        Ok((Some(list), len))
+    })
+    .await?;
+    link.audit(op, list_name, updated.cas);
+    Ok(updated.result)
+}
+
+/// Delete a list and its contents. Returns the cas of the removal, or None if the list
+/// did not exist.
+async fn list_clear(link: &CouchbaseLink, op: &str, list_name: &str) -> RpcResult<Option<u64>> {
+    let key = link.doc_key(list_name)?;
+    let removed = match list_elements::storage(link, op, &key).await? {
+        Storage::Elements(Some(index)) => list_elements::clear(link, op, &key, index).await?,
+        Storage::Elements(None) => None,
+        Storage::Document => containers::remove(link, op, &key).await?,
+    };
+    if removed.is_some() {
+        link.audit(op, list_name, removed);
+    }
+    Ok(removed)
+}
+
+/// Set the TTL of a list or set, or remove it with 0, and apply it to the container.
+/// Returns the cas of the container, or None if it does not exist.
+async fn expire_container(
+    link: &CouchbaseLink,
+    op: &str,
+    name: &str,
+    secs: u32,
+) -> RpcResult<Option<u64>> {
+    let key = link.doc_key(name)?;
+    let ttl_key = containers::ttl_key(&key);
+    match secs {
+        0 => {
+            containers::remove(link, op, &ttl_key).await?;
+        }
+        secs => {
+            let keep = |_: Option<u32>| Ok((Some(secs), ()));
+            containers::update(link, op, &ttl_key, keep).await?;
+        }
+    }
+    let expiry = containers::expiry(link, op, &key).await?;
+    let touched = containers::touch(link, op, &key, expiry).await?;
+    if let Storage::Elements(Some(index)) = list_elements::storage(link, op, &key).await? {
+        list_elements::touch(link, op, &key, &index, expiry).await?;
+    }
+    if touched.is_some() {
+        link.audit(op, name, touched);
+    }
+    Ok(touched)
+}
+
+/// Remove the first value of a list, or the last one with `back`, and return it
+async fn list_pop(
+    link: &CouchbaseLink,
+    op: &str,
+    list_name: &str,
+    back: bool,
+) -> RpcResult<GetResponse> {
+    let key = link.doc_key(list_name)?;
+    let popped = match list_elements::storage(link, op, &key).await? {
+        Storage::Elements(Some(index)) => list_elements::pop(link, op, &key, index, back).await?,
+        Storage::Elements(None) => return Ok(GetResponse::default()),
+        Storage::Document => queues::pop(link, op, &key, back).await?,
+    };
+    match popped.result {
+        Some(value) => {
+            link.audit(op, list_name, popped.cas);
+            Ok(GetResponse {
+                value,
+                exists: true,
+            })
+        }
+        None => Ok(GetResponse::default()),
+    }
+}
+
+/// Add text at the end of a value, or at its start for `prepend`. The SDK has no binary
+/// append, so the value is read and written back with its cas. A missing key is Ok(false).
+async fn concat(link: &CouchbaseLink, op: &str, arg: &AppendRequest) -> RpcResult<bool> {
+    let key = link.doc_key(&arg.key)?;
+    let updated = containers::update(link, op, &key, |current| {
+        let Some(current) = current else {
+            return Ok((None, false));
+        };
+        let current = link.codec.decode(&key, current)?;
+        let value = match op {
+            "prepend" => arg.value.clone() + &current,
+            _ => current + &arg.value,
+        };
+        Ok((Some(link.codec.encode(&key, &value)?), true))
+    })
+    .await?;
+    if updated.result {
+        link.audit(op, &arg.key, updated.cas);
+    }
+    Ok(updated.result)
+}
+
+/// Insert, replace or upsert a document. On links with soft_delete, an insert replaces a
+/// tombstone and a replace fails on one, like on a missing key.
+async fn write_with_mode(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+    mode: &str,
+    value: serde_json::Value,
+    expiry: Option<Duration>,
+) -> CouchbaseResult<MutationResult> {
+    let soft_delete = link.config.soft_delete().is_some();
+    let connection = link.connection();
+    let key = doc_key.to_string();
+    // the cas of the document to replace, 0 for any
+    let cas = match mode {
+        "insert" => {
+            let mut options = kv_options!(link, InsertOptions::default());
+            if let Some(expiry) = expiry {
+                options = options.expiry(expiry);
+            }
+            let (connection, key, content) = (connection.clone(), key.clone(), value.clone());
+            let operation =
+                async move { connection.collection.insert(key, content, options).await };
+            match link.execute(op, Some(doc_key), operation).await {
+                Err(CouchbaseError::DocumentExists { ctx }) if soft_delete => {
+                    match tombstones::lookup(link, op, doc_key).await? {
+                        Some((true, cas)) => cas,
+                        _ => return Err(CouchbaseError::DocumentExists { ctx }),
+                    }
+                }
+                written => return written,
+            }
+        }
+        "replace" if soft_delete => match tombstones::lookup(link, op, doc_key).await? {
+            Some((false, cas)) => cas,
+            _ => {
+                return Err(CouchbaseError::DocumentNotFound {
+                    ctx: ErrorContext::default(),
+                })
+            }
+        },
+        "replace" => 0,
+        _ => {
+            let mut options = kv_options!(link, UpsertOptions::default());
+            if let Some(expiry) = expiry {
+                options = options.expiry(expiry);
+            }
+            let operation = async move { connection.collection.upsert(key, value, options).await };
+            return link.execute(op, Some(doc_key), operation).await;
+        }
+    };
+    let mut options = kv_options!(link, ReplaceOptions::default());
+    if cas != 0 {
+        options = options.cas(cas);
+    }
+    if let Some(expiry) = expiry {
+        options = options.expiry(expiry);
+    }
+    let operation = async move { connection.collection.replace(key, value, options).await };
+    link.execute(op, Some(doc_key), operation).await
+}
+
+/// Refuse to read or write a field of the documents of a link that encrypts or compresses its
+/// values, or stores them as raw_base64 or msgpack, whose fields are hidden in an envelope
+fn check_fields(link: &CouchbaseLink) -> RpcResult<()> {
+    if link.codec.wraps() {
+        return Err(RpcError::InvalidParameter(
+            "fields of encrypted, compressed, raw_base64 or msgpack values cannot be read or \
+             written"
+                .to_string(),
+        ));
+    }
+    Ok(())
 }
 
 fn actor_id(ctx: &Context) -> Result<&String, RpcError> {
@@ -99,7 +1313,19 @@ impl KeyValue for KvCouchbaseProvider {
     /// Increments a numeric value, returning the new value
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
     async fn increment(&self, ctx: &Context, arg: &IncrementRequest) -> RpcResult<i32> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "increment").await?;
+        let key = link.doc_key(&arg.key)?;
+        let range = (i32::MIN as i64, i32::MAX as i64);
+        let overflow = link.config.counter_overflow;
+        let updated = containers::update(&link, "increment", &key, |value| {
+            let total =
+                containers::add_to_counter(&arg.key, value, arg.value as i64, range, overflow)?;
+            Ok((Some(serde_json::Value::from(total)), total))
+        })
+        .await?;
+        link.audit("increment", &arg.key, updated.cas);
+        // within the i32 range
+        Ok(updated.result as i32)
     }
 
     /// Returns true if the store contains the key
@@ -109,12 +1335,20 @@ impl KeyValue for KvCouchbaseProvider {
         ctx: &Context,
         arg: &TS,
     ) -> RpcResult<bool> {
-        let actor_id = actor_id(ctx)?;
-        let rd = self.actors.read().await;
-        let collection = rd
-            .get(actor_id)
-            .ok_or_else(|| RpcError::InvalidParameter(format!("actor not linked:{}", actor_id)))?;
-        match block_on(collection.exists(arg.to_string(), ExistsOptions::default())) {
+        let link = self.link(ctx, "contains").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        if link.config.soft_delete().is_some() {
+            // a tombstone is a document too
+            return match tombstones::lookup(&link, "contains", &key).await {
+                Ok(found) => Ok(matches!(found, Some((false, _)))),
+                Err(e) => Err(to_rpc_err(e)),
+            };
+        }
+        let options = kv_options!(link, ExistsOptions::default());
+        let connection = link.connection();
+        let doc_key = key.clone();
+        let operation = async move { connection.collection.exists(doc_key, options).await };
+        match link.execute("contains", Some(&key), operation).await {
             Ok(r) => Ok( r.exists()),
             Err(e) => Err(to_rpc_err(e)),
         }
@@ -123,16 +1357,8 @@ impl KeyValue for KvCouchbaseProvider {
     /// Deletes a key, returning true if the key was deleted
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
     async fn del<TS: ToString + ?Sized + Sync>(&self, ctx: &Context, arg: &TS) -> RpcResult<bool> {
-
-        let actor_id = actor_id(ctx)?;
-        let rd = self.actors.read().await;
-        let collection = rd
-            .get(actor_id)
-            .ok_or_else(|| RpcError::InvalidParameter(format!("actor not linked:{}", actor_id)))?;
-        match block_on(collection.remove(arg.to_string(), RemoveOptions::default())) {
-            Ok(r) => Ok( 1 > 0),
-            Err(e) => Err(to_rpc_err(e)),
-        }
+        let link = self.link(ctx, "del").await?;
+        del_value(&link, "del", &arg.to_string()).await
     }
 
     /// Gets a value for a specified key. If the key exists,
@@ -144,35 +1370,16 @@ impl KeyValue for KvCouchbaseProvider {
         ctx: &Context,
         arg: &TS,
     ) -> RpcResult<GetResponse> {
-
-        let actor_id = actor_id(ctx)?;
-        let rd = self.actors.read().await;
-        let collection = rd
-            .get(actor_id)
-            .ok_or_else(|| RpcError::InvalidParameter(format!("actor not linked:{}", actor_id)))?;
-        let res = block_on(collection.get(arg.to_string(), GetOptions::default()));
-        if res.is_ok() {
-            Ok(GetResponse {
-                exists: true,
-                value: res.unwrap().content().unwrap(),
-            })
-        } else {
-            let e = res.err().unwrap();
-            match e {
-                DocumentNotFound  => Ok(GetResponse {
-                    exists: false,
-                    ..Default::default()
-                })
-                ,
-                _ => Err(to_rpc_err(e))
-            }
-        }
+        let link = self.link(ctx, "get").await?;
+        get_value(&link, "get", &arg.to_string()).await
     }
 
     /// Append a value onto the end of a list. Returns the new list size
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
     async fn list_add(&self, ctx: &Context, arg: &ListAddRequest) -> RpcResult<u32> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "list_add").await?;
+        let max_length = link.config.list_max_length;
+        list_push(&link, "list_add", &arg.list_name, &arg.value, max_length).await
     }
 
     /// Deletes a list and its contents
@@ -184,23 +1391,69 @@ impl KeyValue for KvCouchbaseProvider {
         ctx: &Context,
         arg: &TS,
     ) -> RpcResult<bool> {
-        // self.del(ctx, arg).await
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "list_clear").await?;
+        let removed = list_clear(&link, "list_clear", &arg.to_string()).await?;
+        Ok(removed.is_some())
     }
 
     /// Deletes an item from a list. Returns true if the item was removed.
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
     async fn list_del(&self, ctx: &Context, arg: &ListDelRequest) -> RpcResult<bool> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "list_del").await?;
+        let key = link.doc_key(&arg.list_name)?;
+        if let Storage::Elements(index) = list_elements::storage(&link, "list_del", &key).await? {
+            let Some(index) = index else {
+                return Ok(false);
+            };
+            let updated = list_elements::del(&link, "list_del", &key, index, &arg.value).await?;
+            if updated.result {
+                link.audit("list_del", &arg.list_name, updated.cas);
+            }
+            return Ok(updated.result);
+        }
+        let updated = containers::update_strings(&link, "list_del", &key, |list| {
+            let mut list = list.unwrap_or_default();
+            match list.iter().position(|v| *v == arg.value) {
+                Some(pos) => {
+                    list.remove(pos);
+                    Ok((Some(list), true))
+                }
+                None => Ok((None, false)),
+            }
+        })
+        .await?;
+        if updated.result {
+            link.audit("list_del", &arg.list_name, updated.cas);
+        }
+        Ok(updated.result)
     }
 
     /// Retrieves a range of values from a list using 0-based indices.
     /// Start and end values are inclusive, for example, (0,10) returns
-    /// 11 items if the list contains at least 11 items. If the stop value
-    /// is beyond the end of the list, it is treated as the end of the list.
+    /// 11 items if the list contains at least 11 items. Negative indices count
+    /// from the end of the list, -1 being the last element, so (0,-1) returns
+    /// the whole list. Indices past either end are clamped to the list.
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
     async fn list_range(&self, ctx: &Context, arg: &ListRangeRequest) -> RpcResult<StringList> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "list_range").await?;
+        let key = link.doc_key(&arg.list_name)?;
+        if let Storage::Elements(index) = list_elements::storage(&link, "list_range", &key).await? {
+            return match index {
+                Some(index) => {
+                    let (start, stop) = (arg.start, arg.stop);
+                    list_elements::range(&link, "list_range", &key, &index, start, stop).await
+                }
+                None => Ok(StringList::new()),
+            };
+        }
+        let mut list = match containers::read_strings(&link, "list_range", &key).await? {
+            Some((list, _)) => list,
+            None => return Ok(StringList::new()),
+        };
+        match containers::index_range(list.len(), arg.start, arg.stop) {
+            Some(range) => Ok(list.drain(range).collect()),
+            None => Ok(StringList::new()),
+        }
     }
 
     /// Sets the value of a key.
@@ -208,19 +1461,54 @@ impl KeyValue for KvCouchbaseProvider {
     /// or 0 for no expiration.
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
     async fn set(&self, ctx: &Context, arg: &SetRequest) -> RpcResult<()> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set").await?;
+        set_value(&link, "set", arg).await
     }
 
     /// Add an item into a set. Returns number of items added
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.set_name))]
     async fn set_add(&self, ctx: &Context, arg: &SetAddRequest) -> RpcResult<u32> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_add").await?;
+        let key = link.doc_key(&arg.set_name)?;
+        if let Some(filters) = &link.set_filters {
+            filters.insert(&key, &arg.value);
+        }
+        let updated = containers::update_strings(&link, "set_add", &key, |set| {
+            let mut set = set.unwrap_or_default();
+            if set.contains(&arg.value) {
+                return Ok((None, 0));
+            }
+            containers::make_room(&link.config, &arg.set_name, &mut set, 1)?;
+            set.push(arg.value.clone());
+            Ok((Some(set), 1))
+        })
+        .await?;
+        if updated.result > 0 {
+            link.audit("set_add", &arg.set_name, updated.cas);
+        }
+        Ok(updated.result)
     }
 
-    /// Remove a item from the set. Returns
+    /// Remove a item from the set. Returns the number of items removed
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.set_name))]
     async fn set_del(&self, ctx: &Context, arg: &SetDelRequest) -> RpcResult<u32> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_del").await?;
+        let key = link.doc_key(&arg.set_name)?;
+        let updated = containers::update_strings(&link, "set_del", &key, |set| {
+            let mut set = set.unwrap_or_default();
+            match set.iter().position(|v| *v == arg.value) {
+                Some(pos) => {
+                    set.remove(pos);
+                    Ok((Some(set), 1))
+                }
+                None => Ok((None, 0)),
+            }
+        })
+        .await?;
+        if updated.result > 0 {
+            link.audit("set_del", &arg.set_name, updated.cas);
+        }
+        Ok(updated.result)
     }
 
     /// Deletes a set and its contents
@@ -232,32 +1520,1559 @@ impl KeyValue for KvCouchbaseProvider {
         ctx: &Context,
         arg: &TS,
     ) -> RpcResult<bool> {
-        // self.del(ctx, arg).await
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_clear").await?;
+        let name = arg.to_string();
+        let removed = containers::remove(&link, "set_clear", &link.doc_key(&name)?).await?;
+        if removed.is_some() {
+            link.audit("set_clear", &name, removed);
+        }
+        Ok(removed.is_some())
     }
 
+    /// Returns the members found in every one of the sets
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = ?arg))]
     async fn set_intersection(
         &self,
         ctx: &Context,
         arg: &StringList,
     ) -> Result<StringList, RpcError> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_intersection").await?;
+        set_algebra::intersection(&link, "set_intersection", arg).await
     }
 
+    /// Returns the members of the set
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
     async fn set_query<TS: ToString + ?Sized + Sync>(
         &self,
         ctx: &Context,
         arg: &TS,
     ) -> RpcResult<StringList> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_query").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        match containers::read_strings(&link, "set_query", &key).await? {
+            Some((set, _)) => Ok(set),
+            None => Ok(StringList::new()),
+        }
     }
 
+    /// Returns the members found in any of the sets
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = ?arg))]
     async fn set_union(&self, ctx: &Context, arg: &StringList) -> RpcResult<StringList> {
-        Err(RpcError::NotImplemented)
+        let link = self.link(ctx, "set_union").await?;
+        set_algebra::union(&link, "set_union", arg).await
     }
 
 }
 
+/// Handle CouchbaseKeyValue methods
+#[async_trait]
+impl CouchbaseKeyValue for KvCouchbaseProvider {
+    /// Gets a value and the cas to pass to set_if_cas
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get_with_cas<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetWithCasResponse> {
+        let link = self.link(ctx, "get_with_cas").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        let options = kv_options!(link, GetOptions::default());
+        let connection = link.connection();
+        let doc_key = key.clone();
+        let operation = async move { connection.collection.get(doc_key, options).await };
+        match link.execute("get_with_cas", Some(&key), operation).await {
+            Ok(r) => {
+                let content = r.content::<serde_json::Value>().map_err(to_rpc_err)?;
+                // the cas replaces the tombstone with SetIfCas
+                if tombstones::is_tombstone(&content) {
+                    return Ok(GetWithCasResponse {
+                        cas: r.cas(),
+                        ..Default::default()
+                    });
+                }
+                let value = link.codec.decode(&key, content)?;
+                link.record_sizes("get_with_cas", &key, value.len());
+                Ok(GetWithCasResponse {
+                    exists: true,
+                    value,
+                    cas: r.cas(),
+                })
+            }
+            Err(CouchbaseError::DocumentNotFound { .. }) => Ok(GetWithCasResponse::default()),
+            Err(e) => Err(to_rpc_err(e)),
+        }
+    }
+
+    /// Replaces a value if its cas still matches, or with cas 0 creates it if it does not exist.
+    /// A value that changed meanwhile is reported with updated: false rather than an error.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn set_if_cas(
+        &self,
+        ctx: &Context,
+        arg: &SetIfCasRequest,
+    ) -> RpcResult<SetIfCasResponse> {
+        let link = self.link(ctx, "set_if_cas").await?;
+        let key = link.doc_key(&arg.key)?;
+        let value = link.codec.encode(&key, &arg.value)?;
+        let size = quota::doc_size(&key, &value);
+        link.check_quota(size)?;
+        link.record_sizes("set_if_cas", &key, size as usize - key.len());
+        let expiry = link.expiry(&arg.key, arg.expires);
+        let connection = link.connection();
+        let doc_key = key.clone();
+        let written = if arg.cas == 0 {
+            let mut options = kv_options!(link, InsertOptions::default());
+            if let Some(expiry) = expiry {
+                options = options.expiry(expiry);
+            }
+            let operation =
+                async move { connection.collection.insert(doc_key, value, options).await };
+            link.execute("set_if_cas", Some(&key), operation).await
+        } else {
+            let mut options = kv_options!(link, ReplaceOptions::default()).cas(arg.cas);
+            if let Some(expiry) = expiry {
+                options = options.expiry(expiry);
+            }
+            let operation =
+                async move { connection.collection.replace(doc_key, value, options).await };
+            link.execute("set_if_cas", Some(&key), operation).await
+        };
+        match written {
+            Ok(r) => {
+                link.written(&r);
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                link.audit("set_if_cas", &arg.key, Some(r.cas()));
+                Ok(SetIfCasResponse {
+                    updated: true,
+                    cas: r.cas(),
+                })
+            }
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. })
+            | Err(CouchbaseError::DocumentExists { .. }) => Ok(SetIfCasResponse::default()),
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(&link, "set_if_cas", &key, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Gets one field of a JSON document, without transferring the rest of it
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn lookup_path(
+        &self,
+        ctx: &Context,
+        arg: &LookupPathRequest,
+    ) -> RpcResult<LookupPathResponse> {
+        let link = self.link(ctx, "lookup_path").await?;
+        check_fields(&link)?;
+        let key = link.doc_key(&arg.key)?;
+        let options = kv_options!(link, LookupInOptions::default());
+        let connection = link.connection();
+        let (doc_key, specs) = (key.clone(), vec![LookupInSpec::get(arg.path.as_str())]);
+        let operation =
+            async move { connection.collection.lookup_in(doc_key, specs, options).await };
+        match link.execute("lookup_path", Some(&key), operation).await {
+            Ok(r) if r.exists(0) => {
+                let field: serde_json::Value = r.content(0).map_err(to_rpc_err)?;
+                let value = field.to_string();
+                link.record_sizes("lookup_path", &key, value.len());
+                Ok(LookupPathResponse {
+                    exists: true,
+                    value,
+                })
+            }
+            Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => {
+                Ok(LookupPathResponse::default())
+            }
+            Err(e) => Err(to_rpc_err(e)),
+        }
+    }
+
+    /// Sets one field of a JSON document in a single atomic server-side mutation.
+    /// With create_parents, the document is updated with CAS by the provider instead,
+    /// because the SDK cannot ask the server to create missing parents.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn mutate_path(&self, ctx: &Context, arg: &MutatePathRequest) -> RpcResult<u64> {
+        let link = self.link(ctx, "mutate_path").await?;
+        check_fields(&link)?;
+        let key = link.doc_key(&arg.key)?;
+        let value: serde_json::Value = serde_json::from_str(&arg.value).map_err(|e| {
+            RpcError::InvalidParameter(format!("value of {} is not JSON: {}", arg.path, e))
+        })?;
+        if arg.create_parents {
+            let apply = |doc: Option<serde_json::Value>| {
+                let mut doc = doc.unwrap_or_default();
+                subdoc::set_path(&mut doc, &arg.path, value.clone())
+                    .map_err(RpcError::InvalidParameter)?;
+                Ok((Some(doc), ()))
+            };
+            let updated = containers::update(&link, "mutate_path", &key, apply).await?;
+            link.audit("mutate_path", &arg.key, updated.cas);
+            return Ok(updated.cas.unwrap_or_default());
+        }
+
+        let size = arg.value.len() as u64;
+        link.check_quota(size)?;
+        let options = kv_options!(link, MutateInOptions::default());
+        let connection = link.connection();
+        let specs = vec![MutateInSpec::upsert(arg.path.as_str(), value)];
+        let doc_key = key.clone();
+        let operation =
+            async move { connection.collection.mutate_in(doc_key, specs, options).await };
+        match link.execute("mutate_path", Some(&key), operation).await {
+            Ok(r) => {
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                link.audit("mutate_path", &arg.key, Some(r.cas()));
+                Ok(r.cas())
+            }
+            Err(e) => {
+                let e = to_rpc_err(e);
+                if !matches!(e, RpcError::InvalidParameter(_)) {
+                    dead_letter::record(&link, "mutate_path", &key, &e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Sets the expiration time of a key, clamped to the link's max_ttl like `set`.
+    /// An expiration of 0 falls back to the link's default_ttl, or removes the expiry.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn touch(&self, ctx: &Context, arg: &TouchRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "touch").await?;
+        Ok(touch(&link, "touch", arg).await?.is_some())
+    }
+
+    /// Gets a value and sets its expiration time in a single operation,
+    /// for actors implementing sliding expiration
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn get_and_touch(&self, ctx: &Context, arg: &TouchRequest) -> RpcResult<GetResponse> {
+        let link = self.link(ctx, "get_and_touch").await?;
+        match touch(&link, "get_and_touch", arg).await? {
+            Some(r) => {
+                let content = r.content::<serde_json::Value>().map_err(to_rpc_err)?;
+                if tombstones::is_tombstone(&content) {
+                    return Ok(GetResponse::default());
+                }
+                let key = link.doc_key(&arg.key)?;
+                let value = link.codec.decode(&key, content)?;
+                link.record_sizes("get_and_touch", &key, value.len());
+                Ok(GetResponse {
+                    exists: true,
+                    value,
+                })
+            }
+            None => Ok(GetResponse::default()),
+        }
+    }
+
+    /// Gets the seconds left before a key expires.
+    /// The SDK cannot read the `$document.exptime` extended attribute,
+    /// so the expiration time is read with N1QL instead.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get_expiry<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetExpiryResponse> {
+        let link = self.link(ctx, "get_expiry").await?;
+        let statement = N1ql::on(
+            "SELECT RAW META(d).expiration FROM {keyspace} AS d USE KEYS $key",
+            &link.config,
+        );
+        let options = query::options(&link, "get_expiry")
+            .named_parameters(serde_json::json!({ "key": link.doc_key(&arg.to_string())? }));
+        let rows = query::query::<u64>(&link, "get_expiry", statement, options)
+            .await
+            .map_err(to_rpc_err)?;
+        match rows.first() {
+            Some(expiration) => Ok(GetExpiryResponse {
+                expires: seconds_left(*expiration),
+                exists: true,
+            }),
+            None => Ok(GetExpiryResponse::default()),
+        }
+    }
+
+    /// Locks a key, creating it with an empty value, stored by the link's codec, if it does not
+    /// exist. A key locked by someone else is reported with locked: false rather than an error.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn lock(&self, ctx: &Context, arg: &LockRequest) -> RpcResult<LockResponse> {
+        if arg.ttl > MAX_LOCK_TTL {
+            return Err(RpcError::InvalidParameter(format!(
+                "lock ttl {}s exceeds the maximum of {}s",
+                arg.ttl, MAX_LOCK_TTL
+            )));
+        }
+        let link = self.link(ctx, "lock").await?;
+        let key = link.doc_key(&arg.key)?;
+        let mut created = false;
+        loop {
+            let options = kv_options!(link, GetAndLockOptions::default());
+            let connection = link.connection();
+            let (doc_key, lock_time) = (key.clone(), sdk_seconds(arg.ttl as u64));
+            let operation =
+                async move { connection.collection.get_and_lock(doc_key, lock_time, options).await };
+            match link.execute("lock", Some(&key), operation).await {
+                Ok(r) => {
+                    return Ok(LockResponse {
+                        locked: true,
+                        token: r.cas(),
+                    })
+                }
+                Err(CouchbaseError::DocumentLocked { .. })
+                | Err(CouchbaseError::TemporaryFailure { .. }) => {
+                    return Ok(LockResponse::default())
+                }
+                Err(CouchbaseError::DocumentNotFound { .. }) if !created => {}
+                // removed again as soon as it was created
+                Err(CouchbaseError::DocumentNotFound { .. }) => {
+                    return Ok(LockResponse::default())
+                }
+                Err(e) => return Err(to_rpc_err(e)),
+            }
+            created = true;
+            // create the key, unless someone else just did, and lock it
+            let empty = link.codec.encode(&key, "").map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "missing key {} cannot be locked: the link's value_format cannot store the \
+                     empty value it would be created with",
+                    link.log_key(&arg.key)
+                ))
+            })?;
+            let mut options = kv_options!(link, InsertOptions::default());
+            if let Some(expiry) = link.expiry(&arg.key, 0) {
+                options = options.expiry(expiry);
+            }
+            let connection = link.connection();
+            let doc_key = key.clone();
+            let operation =
+                async move { connection.collection.insert(doc_key, empty, options).await };
+            match link.execute("lock", Some(&key), operation).await {
+                Ok(r) => link.written(&r),
+                Err(CouchbaseError::DocumentExists { .. }) => {}
+                Err(e) => return Err(to_rpc_err(e)),
+            }
+        }
+    }
+
+    /// Releases a lock by writing back the key's value and expiry with the lock's cas, as the
+    /// SDK has no unlock.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn unlock(&self, ctx: &Context, arg: &UnlockRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "unlock").await?;
+        let key = link.doc_key(&arg.key)?;
+        let options = kv_options!(link, GetOptions::default());
+        let connection = link.connection();
+        let doc_key = key.clone();
+        let operation = async move { connection.collection.get(doc_key, options).await };
+        let value: serde_json::Value = match link.execute("unlock", Some(&key), operation).await {
+            Ok(r) => r.content().map_err(to_rpc_err)?,
+            Err(CouchbaseError::DocumentNotFound { .. }) => return Ok(false),
+            Err(e) => return Err(to_rpc_err(e)),
+        };
+        let mut options = kv_options!(link, ReplaceOptions::default()).cas(arg.token);
+        // a replace without an expiry would remove it
+        if let Some(expiry) = containers::current_expiry(&link, "unlock", &key).await? {
+            options = options.expiry(expiry);
+        }
+        let connection = link.connection();
+        let doc_key = key.clone();
+        let operation =
+            async move { connection.collection.replace(doc_key, value, options).await };
+        match link.execute("unlock", Some(&key), operation).await {
+            Ok(r) => {
+                link.written(&r);
+                Ok(true)
+            }
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentLocked { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+            Err(e) => Err(to_rpc_err(e)),
+        }
+    }
+
+    /// Applies several mutations with all-or-nothing semantics, as a N1QL transaction
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, mutations = arg.mutations.len()))]
+    async fn transact(&self, ctx: &Context, arg: &TransactRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "transact").await?;
+        transactions::transact(&link, arg).await
+    }
+
+    /// Searches the link's documents with a full-text search index.
+    /// Hits outside the link's key prefix are left out.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, index = %arg.index))]
+    async fn search(&self, ctx: &Context, arg: &SearchRequest) -> RpcResult<Vec<SearchHit>> {
+        let link = self.link(ctx, "search").await?;
+        let mut options = SearchOptions::default();
+        if arg.limit > 0 {
+            options = options.limit(arg.limit);
+        }
+        let connection = link.connection();
+        let (index, query) = (arg.index.clone(), QueryStringQuery::new(arg.query.clone()));
+        let operation = async move {
+            let mut result = connection.cluster.search_query(index, query, options).await?;
+            result.rows().try_collect::<Vec<SearchRow>>().await
+        };
+        let rows = link
+            .execute("search", None, operation)
+            .await
+            .map_err(to_rpc_err)?;
+        let prefix = link.namespace();
+        let mut hits: Vec<SearchHit> = rows
+            .iter()
+            .filter_map(|row| {
+                Some(SearchHit {
+                    key: row.id().strip_prefix(&prefix)?.to_string(),
+                    score: row.score() as f64,
+                    value: None,
+                })
+            })
+            .collect();
+        if arg.include_values {
+            let ids: Vec<_> = rows
+                .iter()
+                .map(|row| row.id())
+                .filter(|id| id.starts_with(&prefix))
+                .collect();
+            let reads = ids
+                .iter()
+                .map(|id| containers::read::<serde_json::Value>(&link, "search", id));
+            let values = futures::future::try_join_all(reads).await?;
+            for ((hit, id), value) in hits.iter_mut().zip(&ids).zip(values) {
+                hit.value = value.map(|(value, _)| link.codec.decode(id, value)).transpose()?;
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Lists the link's keys starting with a prefix, in order, a page at a time
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, prefix = %arg.prefix))]
+    async fn scan_keys(
+        &self,
+        ctx: &Context,
+        arg: &ScanKeysRequest,
+    ) -> RpcResult<ScanKeysResponse> {
+        let link = self.link(ctx, "scan_keys").await?;
+        let limit = match arg.limit {
+            0 => scan::DEFAULT_SCAN_LIMIT,
+            limit => limit,
+        };
+        let keys = scan::scan_keys(&link, "scan_keys", &arg.prefix, &arg.cursor, limit).await?;
+        // a full page may be followed by more keys
+        let cursor = match keys.last() {
+            Some(last) if keys.len() == limit as usize => last.clone(),
+            _ => String::new(),
+        };
+        Ok(ScanKeysResponse { keys, cursor })
+    }
+
+    /// Removes the link's keys starting with a prefix with a single N1QL DELETE
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, prefix = %arg.to_string()))]
+    async fn del_by_prefix<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64> {
+        let link = self.link(ctx, "del_by_prefix").await?;
+        scan::delete_by_prefix(&link, "del_by_prefix", &arg.to_string()).await
+    }
+
+    /// Compares the cas of the active and replica copies of a key on every node
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get_all_replicas<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetAllReplicasResponse> {
+        let link = self.link(ctx, "get_all_replicas").await?;
+        replicas::check(&link, "get_all_replicas", &link.doc_key(&arg.to_string())?).await
+    }
+
+    /// Gets the metadata the link keeps of a key in its metadata collection
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get_metadata<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetMetadataResponse> {
+        let link = self.link(ctx, "get_metadata").await?;
+        metadata::get(&link, "get_metadata", &arg.to_string()).await
+    }
+
+    /// Declares the content type of a key in the link's metadata collection
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn set_content_type(
+        &self,
+        ctx: &Context,
+        arg: &SetContentTypeRequest,
+    ) -> RpcResult<bool> {
+        let link = self.link(ctx, "set_content_type").await?;
+        metadata::set_content_type(&link, "set_content_type", &arg.key, &arg.content_type).await
+    }
+
+    /// Restores a key deleted by a link with soft_delete, before its tombstone expires
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn undelete<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool> {
+        let link = self.link(ctx, "undelete").await?;
+        let key = arg.to_string();
+        let doc_key = link.doc_key(&key)?;
+        let restored = tombstones::restore(&link, "undelete", &key)
+            .await
+            .inspect_err(|e| dead_letter::record(&link, "undelete", &doc_key, e))?;
+        if restored.is_some() {
+            link.audit("undelete", &key, restored);
+        }
+        Ok(restored.is_some())
+    }
+
+    /// Gets the values a key held before it was set, on links with a history_length
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn get_history(
+        &self,
+        ctx: &Context,
+        arg: &GetHistoryRequest,
+    ) -> RpcResult<Vec<HistoryEntry>> {
+        let link = self.link(ctx, "get_history").await?;
+        history::get(&link, "get_history", &link.doc_key(&arg.key)?, arg.count).await
+    }
+
+    /// Sets the value of a key only if it does not exist (insert), only if it does (replace),
+    /// or either way (upsert)
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn set_with_mode(
+        &self,
+        ctx: &Context,
+        arg: &SetWithModeRequest,
+    ) -> RpcResult<SetWithModeResponse> {
+        let link = self.link(ctx, "set_with_mode").await?;
+        let mode = match arg.mode.as_str() {
+            "" => "upsert",
+            mode @ ("insert" | "replace" | "upsert") => mode,
+            mode => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "invalid mode {}: expected insert, replace or upsert",
+                    mode
+                )))
+            }
+        };
+        let key = link.doc_key(&arg.key)?;
+        let value = link.codec.encode(&key, &arg.value)?;
+        let size = quota::doc_size(&key, &value);
+        link.check_quota(size)?;
+        link.record_sizes("set_with_mode", &key, size as usize - key.len());
+        let expiry = link.expiry(&arg.key, arg.expires);
+        match write_with_mode(&link, "set_with_mode", &key, mode, value, expiry).await {
+            Ok(r) => {
+                link.written(&r);
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                link.audit("set_with_mode", &arg.key, Some(r.cas()));
+                Ok(SetWithModeResponse {
+                    written: true,
+                    cas: r.cas(),
+                })
+            }
+            // the key existed for an insert, or was missing or changed for a replace
+            Err(CouchbaseError::DocumentExists { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. })
+            | Err(CouchbaseError::CasMismatch { .. }) => Ok(SetWithModeResponse::default()),
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(&link, "set_with_mode", &key, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Gets a value with its cas and the seconds left before it expires.
+    /// The SDK cannot read the `$document.exptime` extended attribute with the document,
+    /// so both are read with one N1QL query.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get_with_meta<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetWithMetaResponse> {
+        let link = self.link(ctx, "get_with_meta").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        let statement = N1ql::on(
+            "SELECT META(d).cas AS cas, META(d).expiration AS expiration, d AS `value` \
+             FROM {keyspace} AS d USE KEYS $key",
+            &link.config,
+        );
+        let options = query::options(&link, "get_with_meta")
+            .named_parameters(serde_json::json!({ "key": key }));
+        let rows = query::query::<DocumentWithMeta>(&link, "get_with_meta", statement, options)
+            .await
+            .map_err(to_rpc_err)?;
+        let row = match rows.into_iter().next() {
+            Some(row) => row,
+            None => return Ok(GetWithMetaResponse::default()),
+        };
+        // the cas replaces the tombstone with SetWithOptions
+        if tombstones::is_tombstone(&row.value) {
+            return Ok(GetWithMetaResponse {
+                cas: row.cas,
+                ..Default::default()
+            });
+        }
+        let content = match chunks::manifest(&row.value) {
+            Some(manifest) => chunks::read(&link, "get_with_meta", &key, &manifest).await?,
+            None => row.value,
+        };
+        let value = link.codec.decode(&key, content)?;
+        link.record_sizes("get_with_meta", &key, value.len());
+        Ok(GetWithMetaResponse {
+            exists: true,
+            value,
+            cas: row.cas,
+            expires: seconds_left(row.expiration),
+        })
+    }
+
+    /// Sets the value of a key, only if its cas still matches when one is given.
+    /// A value that changed or was removed meanwhile is reported with written: false.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn set_with_options(
+        &self,
+        ctx: &Context,
+        arg: &SetWithOptionsRequest,
+    ) -> RpcResult<SetWithModeResponse> {
+        let link = self.link(ctx, "set_with_options").await?;
+        let key = link.doc_key(&arg.key)?;
+        let value = link.codec.encode(&key, &arg.value)?;
+        let size = quota::doc_size(&key, &value);
+        link.check_quota(size)?;
+        link.record_sizes("set_with_options", &key, size as usize - key.len());
+        let expiry = link.expiry(&arg.key, arg.expires);
+        let written = if arg.cas == 0 {
+            write_with_mode(&link, "set_with_options", &key, "upsert", value, expiry).await
+        } else {
+            let mut options = kv_options!(link, ReplaceOptions::default()).cas(arg.cas);
+            if let Some(expiry) = expiry {
+                options = options.expiry(expiry);
+            }
+            let connection = link.connection();
+            let doc_key = key.clone();
+            let operation =
+                async move { connection.collection.replace(doc_key, value, options).await };
+            link.execute("set_with_options", Some(&key), operation).await
+        };
+        match written {
+            Ok(r) => {
+                link.written(&r);
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                link.audit("set_with_options", &arg.key, Some(r.cas()));
+                Ok(SetWithModeResponse {
+                    written: true,
+                    cas: r.cas(),
+                })
+            }
+            // the key changed or was removed since its cas was read
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(SetWithModeResponse::default()),
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(&link, "set_with_options", &key, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sets the value of a key and returns the value it replaced, atomically
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn get_set(&self, ctx: &Context, arg: &SetRequest) -> RpcResult<GetResponse> {
+        let link = self.link(ctx, "get_set").await?;
+        let key = link.doc_key(&arg.key)?;
+        let value = link.codec.encode(&key, &arg.value)?;
+        let expiry = link.expiry(&arg.key, arg.expires);
+        let updated = containers::update_with_expiry(&link, "get_set", &key, expiry, |previous| {
+            // decoded before writing, so a value that cannot be returned is not replaced
+            let previous = previous.map(|p| link.codec.decode(&key, p)).transpose()?;
+            Ok((Some(value.clone()), previous))
+        })
+        .await?;
+        link.audit("get_set", &arg.key, updated.cas);
+        Ok(match updated.result {
+            Some(value) => GetResponse {
+                value,
+                exists: true,
+            },
+            None => GetResponse::default(),
+        })
+    }
+
+    /// Increments a 64-bit counter, creating it at 0 if it does not exist
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn increment64(&self, ctx: &Context, arg: &Increment64Request) -> RpcResult<i64> {
+        let link = self.link(ctx, "increment64").await?;
+        let key = link.doc_key(&arg.key)?;
+        let (range, overflow) = ((i64::MIN, i64::MAX), link.config.counter_overflow);
+        let updated = containers::update(&link, "increment64", &key, |value| {
+            let total = containers::add_to_counter(&arg.key, value, arg.value, range, overflow)?;
+            Ok((Some(serde_json::Value::from(total)), total))
+        })
+        .await?;
+        link.audit("increment64", &arg.key, updated.cas);
+        Ok(updated.result)
+    }
+
+    /// Returns the value of a 64-bit counter, 0 if it does not exist
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn get64<TS: ToString + ?Sized + Sync>(&self, ctx: &Context, arg: &TS) -> RpcResult<i64> {
+        let link = self.link(ctx, "get64").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        let value = containers::read::<serde_json::Value>(&link, "get64", &key).await?;
+        containers::counter_value(&arg.to_string(), value.map(|(value, _)| value))
+    }
+
+    /// Converts the counters starting with a prefix that are stored as numeric strings or
+    /// doubles into JSON integers
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, prefix = %arg.to_string()))]
+    async fn migrate_counters<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64> {
+        let link = self.link(ctx, "migrate_counters").await?;
+        counters::migrate(&link, "migrate_counters", &arg.to_string()).await
+    }
+
+    /// Adds members to a sorted set, or changes their scores
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zadd(&self, ctx: &Context, arg: &ZAddRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "zadd").await?;
+        let key = link.doc_key(&arg.key)?;
+        let updated = sorted_sets::add(&link, "zadd", &key, &arg.members).await?;
+        if updated.cas.is_some() {
+            link.audit("zadd", &arg.key, updated.cas);
+        }
+        Ok(updated.result)
+    }
+
+    /// Adds to the score of a member of a sorted set
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zincr_by(&self, ctx: &Context, arg: &ZIncrByRequest) -> RpcResult<f64> {
+        let link = self.link(ctx, "zincr_by").await?;
+        let key = link.doc_key(&arg.key)?;
+        let updated =
+            sorted_sets::incr_by(&link, "zincr_by", &key, &arg.member, arg.increment).await?;
+        link.audit("zincr_by", &arg.key, updated.cas);
+        Ok(updated.result)
+    }
+
+    /// Removes members from a sorted set
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zrem(&self, ctx: &Context, arg: &ZRemRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "zrem").await?;
+        let key = link.doc_key(&arg.key)?;
+        let updated = sorted_sets::remove(&link, "zrem", &key, &arg.members).await?;
+        if updated.result > 0 {
+            link.audit("zrem", &arg.key, updated.cas);
+        }
+        Ok(updated.result)
+    }
+
+    /// Gets the score of a member of a sorted set
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zscore(&self, ctx: &Context, arg: &ZScoreRequest) -> RpcResult<ZScoreResponse> {
+        let link = self.link(ctx, "zscore").await?;
+        let key = link.doc_key(&arg.key)?;
+        let members = sorted_sets::read(&link, "zscore", &key).await?.unwrap_or_default();
+        Ok(match members.into_iter().find(|m| m.member == arg.member) {
+            Some(m) => ZScoreResponse {
+                score: m.score,
+                exists: true,
+            },
+            None => ZScoreResponse::default(),
+        })
+    }
+
+    /// Gets the rank of a member of a sorted set
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zrank(&self, ctx: &Context, arg: &ZRankRequest) -> RpcResult<ZRankResponse> {
+        let link = self.link(ctx, "zrank").await?;
+        let key = link.doc_key(&arg.key)?;
+        let members = sorted_sets::read(&link, "zrank", &key).await?.unwrap_or_default();
+        Ok(match sorted_sets::rank(&members, &arg.member, arg.reverse) {
+            Some(rank) => ZRankResponse { rank, exists: true },
+            None => ZRankResponse::default(),
+        })
+    }
+
+    /// Gets the members of a sorted set between two ranks
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zrange(&self, ctx: &Context, arg: &ZRangeRequest) -> RpcResult<Vec<ScoredMember>> {
+        let link = self.link(ctx, "zrange").await?;
+        let key = link.doc_key(&arg.key)?;
+        let members = sorted_sets::read(&link, "zrange", &key).await?.unwrap_or_default();
+        Ok(sorted_sets::range(members, arg.start, arg.stop, arg.reverse))
+    }
+
+    /// Adds text at the end of the value of a key
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn append(&self, ctx: &Context, arg: &AppendRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "append").await?;
+        concat(&link, "append", arg).await
+    }
+
+    /// Adds text at the start of the value of a key
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn prepend(&self, ctx: &Context, arg: &AppendRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "prepend").await?;
+        concat(&link, "prepend", arg).await
+    }
+
+    /// Append a value onto the end of a list, removing its oldest values beyond a maximum length
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn list_add_capped(&self, ctx: &Context, arg: &ListAddCappedRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "list_add_capped").await?;
+        let max_length = match arg.max_length {
+            0 => link.config.list_max_length,
+            max_length => max_length,
+        };
+        list_push(&link, "list_add_capped", &arg.list_name, &arg.value, max_length).await
+    }
+
+    /// Retrieves a range of values from a list a page at a time
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn list_range_page(
+        &self,
+        ctx: &Context,
+        arg: &ListRangePageRequest,
+    ) -> RpcResult<ListRangePageResponse> {
+        let link = self.link(ctx, "list_range_page").await?;
+        let key = link.doc_key(&arg.list_name)?;
+        let limit = match arg.limit {
+            0 => DEFAULT_PAGE_LIMIT,
+            limit => limit,
+        } as usize;
+        let cursor = match arg.cursor.as_str() {
+            "" => None,
+            cursor => Some(Cursor::parse(cursor)?),
+        };
+        let op = "list_range_page";
+        let (values, next) = match list_elements::storage(&link, op, &key).await? {
+            Storage::Elements(Some(index)) => {
+                let range = (arg.start, arg.stop);
+                list_elements::page(&link, op, &key, &index, cursor, range, limit).await?
+            }
+            Storage::Elements(None) => (Vec::new(), None),
+            Storage::Document => {
+                let list = containers::read_strings(&link, op, &key).await?;
+                let len = list.as_ref().map_or(0, |(list, _)| list.len());
+                let cursor = cursor.or_else(|| Cursor::first(len, arg.start, arg.stop, 0));
+                match (list, cursor) {
+                    (Some((list, _)), Some(cursor)) => list_pages::page(list, &cursor, limit),
+                    _ => (Vec::new(), None),
+                }
+            }
+        };
+        Ok(ListRangePageResponse {
+            values,
+            cursor: next.map(|c| c.to_string()).unwrap_or_default(),
+        })
+    }
+
+    /// Appends a value to the end of a queue
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn queue_push(&self, ctx: &Context, arg: &ListAddRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "queue_push").await?;
+        let max_length = link.config.list_max_length;
+        list_push(&link, "queue_push", &arg.list_name, &arg.value, max_length).await
+    }
+
+    /// Removes the value at the front of a queue and returns it
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn queue_pop<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<GetResponse> {
+        let link = self.link(ctx, "queue_pop").await?;
+        list_pop(&link, "queue_pop", &arg.to_string(), false).await
+    }
+
+    /// Adds a value at the front or the back of a list
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn deque_push(&self, ctx: &Context, arg: &DequePushRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "deque_push").await?;
+        let key = link.doc_key(&arg.list_name)?;
+        let max_length = link.config.list_max_length;
+        match list_elements::storage(&link, "deque_push", &key).await? {
+            Storage::Elements(_) if arg.front => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "values cannot be pushed at the front of {}, a list stored by elements",
+                    arg.list_name
+                )))
+            }
+            Storage::Elements(_) => {
+                return list_push(&link, "deque_push", &arg.list_name, &arg.value, max_length)
+                    .await
+            }
+            Storage::Document if max_length == 0 => {
+                let pushed = queues::push(&link, "deque_push", &key, &arg.value, arg.front).await?;
+                if let Some(updated) = pushed {
+                    link.audit("deque_push", &arg.list_name, updated.cas);
+                    return Ok(updated.result);
+                }
+            }
+            Storage::Document => {}
+        }
+        // a new or deleted list, or one to trim: read and write back the whole list
+        let updated = containers::update_strings(&link, "deque_push", &key, |list| {
+            let mut list = list.unwrap_or_default();
+            match arg.front {
+                true => list.insert(0, arg.value.clone()),
+                false => list.push(arg.value.clone()),
+            }
+            // trim the other end
+            let max_length = max_length as usize;
+            if max_length > 0 && list.len() > max_length {
+                match arg.front {
+                    true => list.truncate(max_length),
+                    false => drop(list.drain(..list.len() - max_length)),
+                }
+            }
+            let len = list.len() as u32;
+            Ok((Some(list), len))
+        })
+        .await?;
+        link.audit("deque_push", &arg.list_name, updated.cas);
+        Ok(updated.result)
+    }
+
+    /// Removes the value at the front or the back of a list and returns it
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn deque_pop(&self, ctx: &Context, arg: &DequePopRequest) -> RpcResult<GetResponse> {
+        let link = self.link(ctx, "deque_pop").await?;
+        list_pop(&link, "deque_pop", &arg.list_name, arg.back).await
+    }
+
+    /// Removes up to count values at the front of a list in one mutation and returns them
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.list_name))]
+    async fn list_pop_n(&self, ctx: &Context, arg: &ListPopNRequest) -> RpcResult<StringList> {
+        let link = self.link(ctx, "list_pop_n").await?;
+        let key = link.doc_key(&arg.list_name)?;
+        let count = arg.count as usize;
+        if count == 0 {
+            return Ok(StringList::new());
+        }
+        let popped = match list_elements::storage(&link, "list_pop_n", &key).await? {
+            Storage::Elements(Some(index)) => {
+                list_elements::pop_n(&link, "list_pop_n", &key, index, count).await?
+            }
+            Storage::Elements(None) => return Ok(StringList::new()),
+            Storage::Document => {
+                containers::update_strings(&link, "list_pop_n", &key, |list| {
+                    let mut list = list.unwrap_or_default();
+                    let popped: StringList = list.drain(..count.min(list.len())).collect();
+                    match popped.is_empty() {
+                        true => Ok((None, popped)),
+                        false => Ok((Some(list), popped)),
+                    }
+                })
+                .await?
+            }
+        };
+        if !popped.result.is_empty() {
+            link.audit("list_pop_n", &arg.list_name, popped.cas);
+        }
+        Ok(popped.result)
+    }
+
+    /// Sets the time to live of a list or set, renewed by each of its mutations
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.name))]
+    async fn expire_container(
+        &self,
+        ctx: &Context,
+        arg: &ExpireContainerRequest,
+    ) -> RpcResult<bool> {
+        let link = self.link(ctx, "expire_container").await?;
+        let touched = expire_container(&link, "expire_container", &arg.name, arg.expires).await?;
+        Ok(touched.is_some())
+    }
+
+    /// Returns whether the set holds the value
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.set_name))]
+    async fn set_contains(&self, ctx: &Context, arg: &SetContainsRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "set_contains").await?;
+        let key = link.doc_key(&arg.set_name)?;
+        let Some(filters) = &link.set_filters else {
+            return set_algebra::contains(&link, "set_contains", &key, &arg.value).await;
+        };
+        match filters.may_contain(&key, &arg.value) {
+            Some(false) => Ok(false),
+            Some(true) => set_algebra::contains(&link, "set_contains", &key, &arg.value).await,
+            None => {
+                let epoch = filters.epoch();
+                let set = containers::read_strings(&link, "set_contains", &key)
+                    .await?
+                    .map(|(set, _)| set)
+                    .unwrap_or_default();
+                filters.build(&key, &set, epoch);
+                Ok(set.contains(&arg.value))
+            }
+        }
+    }
+
+    /// Returns a page of the members of a set
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.set_name))]
+    async fn set_query_page(
+        &self,
+        ctx: &Context,
+        arg: &SetQueryPageRequest,
+    ) -> RpcResult<SetQueryPageResponse> {
+        let link = self.link(ctx, "set_query_page").await?;
+        let key = link.doc_key(&arg.set_name)?;
+        let limit = match arg.limit {
+            0 => DEFAULT_PAGE_LIMIT,
+            limit => limit,
+        };
+        let (members, next_offset) =
+            set_pages::page(&link, "set_query_page", &key, arg.offset, limit).await?;
+        Ok(SetQueryPageResponse {
+            members,
+            next_offset,
+        })
+    }
+
+    /// Adds values to a set. Returns the number of values the set did not hold.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.set_name))]
+    async fn set_add_many(&self, ctx: &Context, arg: &SetAddManyRequest) -> RpcResult<u32> {
+        let link = self.link(ctx, "set_add_many").await?;
+        let key = link.doc_key(&arg.set_name)?;
+        let updated =
+            set_members::add(&link, "set_add_many", &key, &arg.set_name, &arg.values).await?;
+        if updated.result > 0 {
+            link.audit("set_add_many", &arg.set_name, updated.cas);
+        }
+        Ok(updated.result)
+    }
+
+    /// Returns the members of the first set found in none of the others
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = ?arg))]
+    async fn set_diff(&self, ctx: &Context, arg: &StringList) -> RpcResult<StringList> {
+        let link = self.link(ctx, "set_diff").await?;
+        set_algebra::difference(&link, "set_diff", arg).await
+    }
+
+    /// Returns the members of a sorted set within a range of scores
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn zrange_by_score(
+        &self,
+        ctx: &Context,
+        arg: &ZRangeByScoreRequest,
+    ) -> RpcResult<Vec<ScoredMember>> {
+        let link = self.link(ctx, "zrange_by_score").await?;
+        let key = link.doc_key(&arg.key)?;
+        sorted_sets::range_by_score(&link, "zrange_by_score", &key, arg).await
+    }
+
+    /// Returns the members of a sorted set with the highest scores, highest first
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn ztop(&self, ctx: &Context, arg: &ZTopRequest) -> RpcResult<Vec<ScoredMember>> {
+        let link = self.link(ctx, "ztop").await?;
+        let key = link.doc_key(&arg.key)?;
+        let range = ZRangeByScoreRequest {
+            min: f64::MIN,
+            max: f64::MAX,
+            reverse: true,
+            limit: match arg.count {
+                0 => sorted_sets::DEFAULT_TOP_COUNT,
+                count => count,
+            },
+            ..Default::default()
+        };
+        sorted_sets::range_by_score(&link, "ztop", &key, &range).await
+    }
+
+    /// Adds a member to an approximate distinct count. Returns whether the estimate changed.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.name))]
+    async fn approx_add(&self, ctx: &Context, arg: &ApproxAddRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "approx_add").await?;
+        let key = link.doc_key(&arg.name)?;
+        let updated = hyperloglog::add(&link, "approx_add", &key, &arg.member).await?;
+        if updated.result {
+            link.audit("approx_add", &arg.name, updated.cas);
+        }
+        Ok(updated.result)
+    }
+
+    /// Returns the estimated number of distinct members of an approximate distinct count
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn approx_count<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<u64> {
+        let link = self.link(ctx, "approx_count").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        hyperloglog::count(&link, "approx_count", &key).await
+    }
+
+    /// Sets a field of a hash. Returns whether the field is new.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn hset(&self, ctx: &Context, arg: &HSetRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "hset").await?;
+        let key = link.doc_key(&arg.key)?;
+        let updated = hashes::set(&link, "hset", &key, &arg.field, &arg.value).await?;
+        link.audit("hset", &arg.key, updated.cas);
+        Ok(updated.result)
+    }
+
+    /// Gets the value of a field of a hash
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn hget(&self, ctx: &Context, arg: &HFieldRequest) -> RpcResult<GetResponse> {
+        let link = self.link(ctx, "hget").await?;
+        let key = link.doc_key(&arg.key)?;
+        Ok(match hashes::get(&link, "hget", &key, &arg.field).await? {
+            Some(value) => GetResponse {
+                value,
+                exists: true,
+            },
+            None => GetResponse::default(),
+        })
+    }
+
+    /// Removes a field of a hash. Returns whether the field existed.
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn hdel(&self, ctx: &Context, arg: &HFieldRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "hdel").await?;
+        let key = link.doc_key(&arg.key)?;
+        let removed = hashes::remove(&link, "hdel", &key, &arg.field).await?;
+        if removed.is_some() {
+            link.audit("hdel", &arg.key, removed);
+        }
+        Ok(removed.is_some())
+    }
+
+    /// Returns the fields of a hash and their values
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.to_string()))]
+    async fn hgetall<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<Vec<HashField>> {
+        let link = self.link(ctx, "hgetall").await?;
+        let key = link.doc_key(&arg.to_string())?;
+        hashes::get_all(&link, "hgetall", &key).await
+    }
+
+    /// Gets the values of keys, batch_concurrency keys at a time
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = arg.len()))]
+    async fn get_many(&self, ctx: &Context, arg: &StringList) -> RpcResult<Vec<GetManyResult>> {
+        let link = self.link(ctx, "get_many").await?;
+        Ok(batch::get_many(&link, "get_many", arg).await)
+    }
+
+    /// Sets the values of keys, batch_concurrency keys at a time
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = arg.len()))]
+    async fn set_many(&self, ctx: &Context, arg: &SetRequests) -> RpcResult<Vec<KeyResult>> {
+        let link = self.link(ctx, "set_many").await?;
+        Ok(batch::set_many(&link, "set_many", arg).await)
+    }
+
+    /// Deletes keys, batch_concurrency keys at a time
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, keys = arg.len()))]
+    async fn del_many(&self, ctx: &Context, arg: &StringList) -> RpcResult<Vec<KeyResult>> {
+        let link = self.link(ctx, "del_many").await?;
+        Ok(batch::del_many(&link, "del_many", arg).await)
+    }
+
+    /// Reports changes to a key or prefix to the actor
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn watch(&self, ctx: &Context, arg: &WatchRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "watch").await?;
+        link.watches.add(Watch {
+            key: arg.key.clone(),
+            prefix: arg.prefix,
+        })
+    }
+
+    /// Stops reporting changes to a key or prefix
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, key = %arg.key))]
+    async fn unwatch(&self, ctx: &Context, arg: &WatchRequest) -> RpcResult<bool> {
+        let link = self.link(ctx, "unwatch").await?;
+        Ok(link.watches.remove(&Watch {
+            key: arg.key.clone(),
+            prefix: arg.prefix,
+        }))
+    }
+}
+
+/// Handle CouchbaseAdmin methods with the cluster's management API, for admin actors only
+#[async_trait]
+impl CouchbaseAdmin for KvCouchbaseProvider {
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, scope = %arg.to_string()))]
+    async fn create_scope<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool> {
+        let link = self.admin_link(ctx, "create_scope").await?;
+        let scope = arg.to_string();
+        admin::check_name("scope", &scope)?;
+        let cluster = &link.connection().cluster;
+        management::create_scope(cluster, &link.config.bucket, &scope).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, scope = %arg.to_string()))]
+    async fn drop_scope<TS: ToString + ?Sized + Sync>(
+        &self,
+        ctx: &Context,
+        arg: &TS,
+    ) -> RpcResult<bool> {
+        let link = self.admin_link(ctx, "drop_scope").await?;
+        let scope = arg.to_string();
+        admin::check_name("scope", &scope)?;
+        if link.config.collections().iter().any(|(s, _)| *s == scope) {
+            return Err(RpcError::InvalidParameter(format!(
+                "scope {} holds a collection of the link",
+                scope
+            )));
+        }
+        let cluster = &link.connection().cluster;
+        management::drop_scope(cluster, &link.config.bucket, &scope).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, scope = %arg.scope, collection = %arg.collection))]
+    async fn create_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<bool> {
+        let link = self.admin_link(ctx, "create_collection").await?;
+        check_collection_name(arg)?;
+        let cluster = &link.connection().cluster;
+        let bucket = &link.config.bucket;
+        management::create_collection(cluster, bucket, &arg.scope, &arg.collection).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, scope = %arg.scope, collection = %arg.collection))]
+    async fn drop_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<bool> {
+        let link = self.admin_link(ctx, "drop_collection").await?;
+        check_collection_name(arg)?;
+        let named = (arg.scope.as_str(), arg.collection.as_str());
+        if link.config.collections().contains(&named) {
+            return Err(RpcError::InvalidParameter(format!(
+                "{}.{} is a collection of the link and cannot be dropped",
+                arg.scope, arg.collection
+            )));
+        }
+        let cluster = &link.connection().cluster;
+        let bucket = &link.config.bucket;
+        management::drop_collection(cluster, bucket, &arg.scope, &arg.collection).await
+    }
+
+    /// Removes the documents with a N1QL DELETE, which needs a primary index on the collection:
+    /// Couchbase can only flush whole buckets
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, scope = %arg.scope, collection = %arg.collection))]
+    async fn flush_collection(&self, ctx: &Context, arg: &CollectionName) -> RpcResult<u64> {
+        let link = self.admin_link(ctx, "flush_collection").await?;
+        check_collection_name(arg)?;
+        let keyspace = format!(
+            "`{}`.`{}`.`{}`",
+            link.config.bucket, arg.scope, arg.collection
+        );
+        let statement = N1ql::on_keyspace("DELETE FROM {keyspace}", &keyspace).adhoc();
+        let options = query::options(&link, "flush_collection");
+        let (_, removed) =
+            query::execute::<serde_json::Value>(&link, "flush_collection", statement, options)
+                .await
+                .map_err(to_rpc_err)?;
+        info!(
+            "flushed {} documents from {}.{}.{}",
+            removed, link.config.bucket, arg.scope, arg.collection
+        );
+        Ok(removed)
+    }
+}
+
+fn check_collection_name(arg: &CollectionName) -> RpcResult<()> {
+    admin::check_name("scope", &arg.scope)?;
+    admin::check_name("collection", &arg.collection)
+}
+
+/// Handle SqlDb methods with N1QL
+#[async_trait]
+impl SqlDb for KvCouchbaseProvider {
+    /// Executes a N1QL statement, returning the number of documents it changed
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor))]
+    async fn execute(&self, ctx: &Context, arg: &Statement) -> RpcResult<ExecuteResult> {
+        let link = self.link(ctx, "sql_execute").await?;
+        sqldb::check_enabled(&link)?;
+        if let Some(error) = check_database(&link, arg) {
+            return Ok(ExecuteResult {
+                error: Some(error),
+                ..Default::default()
+            });
+        }
+        let options = sqldb::options(&link, "sql_execute", arg)?;
+        let statement = N1ql::actor(arg.sql.clone());
+        let executed =
+            query::execute::<serde_json::Value>(&link, "sql_execute", statement, options).await;
+        Ok(match executed {
+            Ok((_, rows_affected)) => ExecuteResult {
+                rows_affected,
+                error: None,
+            },
+            Err(e) => ExecuteResult {
+                error: Some(sqldb::sql_error(e)),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Runs a N1QL query, returning its rows
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor))]
+    async fn query(&self, ctx: &Context, arg: &Statement) -> RpcResult<sqldb::QueryResult> {
+        let link = self.link(ctx, "sql_query").await?;
+        sqldb::check_enabled(&link)?;
+        if let Some(error) = check_database(&link, arg) {
+            return Ok(sqldb::QueryResult {
+                error: Some(error),
+                ..Default::default()
+            });
+        }
+        let options = sqldb::options(&link, "sql_query", arg)?;
+        let statement = N1ql::actor(arg.sql.clone());
+        match query::query::<serde_json::Value>(&link, "sql_query", statement, options).await {
+            Ok(rows) => {
+                let (columns, encoded) = sqldb::encode_rows(&rows)?;
+                Ok(sqldb::QueryResult {
+                    num_rows: rows.len() as u64,
+                    columns,
+                    rows: encoded,
+                    error: None,
+                })
+            }
+            Err(e) => Ok(sqldb::QueryResult {
+                error: Some(sqldb::sql_error(e)),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Handle Blobstore methods with documents of the link
+#[async_trait]
+impl Blobstore for KvCouchbaseProvider {
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg))]
+    async fn container_exists(&self, ctx: &Context, arg: &String) -> RpcResult<bool> {
+        let link = self.link(ctx, "blob_container_exists").await?;
+        blobs::container_exists(&link, "blob_container_exists", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg))]
+    async fn create_container(&self, ctx: &Context, arg: &String) -> RpcResult<()> {
+        let link = self.link(ctx, "blob_create_container").await?;
+        blobs::create_container(&link, "blob_create_container", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg))]
+    async fn get_container_info(
+        &self,
+        ctx: &Context,
+        arg: &String,
+    ) -> RpcResult<ContainerMetadata> {
+        let link = self.link(ctx, "blob_get_container_info").await?;
+        blobs::container_info(&link, "blob_get_container_info", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx), fields(actor_id = ?ctx.actor))]
+    async fn list_containers(&self, ctx: &Context) -> RpcResult<ContainersInfo> {
+        let link = self.link(ctx, "blob_list_containers").await?;
+        blobs::list_containers(&link, "blob_list_containers").await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor))]
+    async fn remove_containers(&self, ctx: &Context, arg: &ContainerIds) -> RpcResult<MultiResult> {
+        let link = self.link(ctx, "blob_remove_containers").await?;
+        Ok(blobs::remove_containers(&link, "blob_remove_containers", arg).await)
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.container_id, object = %arg.object_id))]
+    async fn object_exists(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<bool> {
+        let link = self.link(ctx, "blob_object_exists").await?;
+        blobs::object_exists(&link, "blob_object_exists", &arg.container_id, &arg.object_id).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.container_id, object = %arg.object_id))]
+    async fn get_object_info(
+        &self,
+        ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectMetadata> {
+        let link = self.link(ctx, "blob_get_object_info").await?;
+        blobs::object_info(&link, "blob_get_object_info", &arg.container_id, &arg.object_id)
+            .await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.container_id))]
+    async fn list_objects(
+        &self,
+        ctx: &Context,
+        arg: &ListObjectsRequest,
+    ) -> RpcResult<ListObjectsResponse> {
+        let link = self.link(ctx, "blob_list_objects").await?;
+        blobs::list_objects(&link, "blob_list_objects", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.container_id))]
+    async fn remove_objects(
+        &self,
+        ctx: &Context,
+        arg: &RemoveObjectsRequest,
+    ) -> RpcResult<MultiResult> {
+        let link = self.link(ctx, "blob_remove_objects").await?;
+        let op = "blob_remove_objects";
+        Ok(blobs::remove_objects(&link, op, &arg.container_id, &arg.objects).await)
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.chunk.container_id, object = %arg.chunk.object_id))]
+    async fn put_object(
+        &self,
+        ctx: &Context,
+        arg: &PutObjectRequest,
+    ) -> RpcResult<PutObjectResponse> {
+        let link = self.link(ctx, "blob_put_object").await?;
+        blobs::put_object(&link, "blob_put_object", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.container_id, object = %arg.object_id))]
+    async fn get_object(
+        &self,
+        ctx: &Context,
+        arg: &GetObjectRequest,
+    ) -> RpcResult<GetObjectResponse> {
+        let link = self.link(ctx, "blob_get_object").await?;
+        blobs::get_object(&link, "blob_get_object", arg).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, container = %arg.chunk.container_id, object = %arg.chunk.object_id))]
+    async fn put_chunk(&self, ctx: &Context, arg: &PutChunkRequest) -> RpcResult<()> {
+        let link = self.link(ctx, "blob_put_chunk").await?;
+        blobs::put_chunk(&link, "blob_put_chunk", arg).await
+    }
+}
+
+/// Handle the Store service with collections of the link's bucket
+#[async_trait]
+impl Store for KvCouchbaseProvider {
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn get(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<MaybeValue> {
+        let link = self.link(ctx, "wasi_get").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        let value = wasi_buckets::get(&link, "wasi_get", &bucket, &arg.key).await?;
+        Ok(MaybeValue { value })
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn set(&self, ctx: &Context, arg: &wasi_keyvalue::SetRequest) -> RpcResult<()> {
+        let link = self.link(ctx, "wasi_set").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::set(&link, "wasi_set", &bucket, &arg.key, &arg.value).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn delete(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<()> {
+        let link = self.link(ctx, "wasi_delete").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::delete(&link, "wasi_delete", &bucket, &arg.key).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn exists(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<bool> {
+        let link = self.link(ctx, "wasi_exists").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::exists(&link, "wasi_exists", &bucket, &arg.key).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket))]
+    async fn list_keys(&self, ctx: &Context, arg: &ListKeysRequest) -> RpcResult<KeyResponse> {
+        let link = self.link(ctx, "wasi_list_keys").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::list_keys(&link, "wasi_list_keys", &bucket, arg.cursor).await
+    }
+}
+
+/// Handle the Atomics service with cas-guarded counters
+#[async_trait]
+impl Atomics for KvCouchbaseProvider {
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn increment(
+        &self,
+        ctx: &Context,
+        arg: &wasi_keyvalue::IncrementRequest,
+    ) -> RpcResult<u64> {
+        let link = self.link(ctx, "wasi_increment").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::increment(&link, "wasi_increment", &bucket, &arg.key, arg.delta).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, key = %arg.key))]
+    async fn compare_and_swap(
+        &self,
+        ctx: &Context,
+        arg: &CompareAndSwapRequest,
+    ) -> RpcResult<bool> {
+        let link = self.link(ctx, "wasi_compare_and_swap").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        let op = "wasi_compare_and_swap";
+        wasi_buckets::compare_and_swap(&link, op, &bucket, &arg.key, arg.old, arg.new).await
+    }
+}
+
+/// Handle the Batch service, running the operations on each key concurrently
+#[async_trait]
+impl Batch for KvCouchbaseProvider {
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, keys = arg.keys.len()))]
+    async fn get_many(
+        &self,
+        ctx: &Context,
+        arg: &BucketKeys,
+    ) -> RpcResult<Vec<Option<KeyValuePair>>> {
+        let link = self.link(ctx, "wasi_get_many").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::get_many(&link, "wasi_get_many", &bucket, &arg.keys).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, keys = arg.key_values.len()))]
+    async fn set_many(&self, ctx: &Context, arg: &SetManyRequest) -> RpcResult<()> {
+        let link = self.link(ctx, "wasi_set_many").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::set_many(&link, "wasi_set_many", &bucket, &arg.key_values).await
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket = %arg.bucket, keys = arg.keys.len()))]
+    async fn delete_many(&self, ctx: &Context, arg: &BucketKeys) -> RpcResult<()> {
+        let link = self.link(ctx, "wasi_delete_many").await?;
+        let bucket = wasi_buckets::open(&link, &arg.bucket)?;
+        wasi_buckets::delete_many(&link, "wasi_delete_many", &bucket, &arg.keys).await
+    }
+}
+
+/// Returns an error if the statement names a database other than the link's bucket
+fn check_database(link: &CouchbaseLink, arg: &Statement) -> Option<SqlDbError> {
+    match &arg.database {
+        Some(database) if *database != link.config.bucket => Some(SqlDbError {
+            code: "invalid_argument".to_string(),
+            message: format!(
+                "database {} is not the link's bucket {}",
+                database, link.config.bucket
+            ),
+        }),
+        _ => None,
+    }
+}
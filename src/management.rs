@@ -0,0 +1,298 @@
+//! Cluster management operations, sent through the SDK's generic management request
+//!
+use std::time::{Duration, Instant};
+
+use couchbase::{
+    Cluster, Collection, CouchbaseError, CouchbaseResult, ExistsOptions, GenericManagementRequest,
+    GenericManagementResult, QueryOptions, Request,
+};
+use futures::{channel::oneshot, TryStreamExt};
+use tracing::info;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::config::Config;
+use crate::errors::to_rpc_err;
+use crate::query::N1ql;
+
+/// how long to wait for a newly created bucket or collection to become usable
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Send a management REST request to the cluster
+async fn request(
+    cluster: &Cluster,
+    method: &str,
+    path: String,
+    payload: Option<String>,
+) -> CouchbaseResult<GenericManagementResult> {
+    let (sender, receiver) = oneshot::channel();
+    cluster
+        .core()
+        .send(Request::GenericManagementRequest(GenericManagementRequest::new(
+            sender,
+            path,
+            method.to_string(),
+            payload,
+        )));
+    receiver.await.map_err(|_| CouchbaseError::RequestCanceled {
+        ctx: Default::default(),
+    })?
+}
+
+/// GET a management REST path and parse its JSON response
+pub(crate) async fn get_json(cluster: &Cluster, path: String) -> Result<serde_json::Value, String> {
+    let result = request(cluster, "get", path, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    if result.http_status() != 200 {
+        return Err(format!("status {}", result.http_status()));
+    }
+    result
+        .payload()
+        .ok_or_else(|| "empty response".to_string())
+        .and_then(|p| serde_json::from_slice(p).map_err(|e| e.to_string()))
+}
+
+fn mgmt_err(what: &str, result: &GenericManagementResult) -> RpcError {
+    RpcError::ProviderInit(failure(what, result))
+}
+
+fn failure(what: &str, result: &GenericManagementResult) -> String {
+    format!(
+        "{} failed with status {}: {}",
+        what,
+        result.http_status(),
+        payload_text(result)
+    )
+}
+
+fn payload_text(result: &GenericManagementResult) -> String {
+    result
+        .payload()
+        .map(|p| String::from_utf8_lossy(p).to_string())
+        .unwrap_or_default()
+}
+
+fn to_init_err(e: CouchbaseError) -> RpcError {
+    RpcError::ProviderInit(format!("Couchbase management error: {}", e))
+}
+
+/// Create the configured bucket if it does not exist, and wait until it is ready
+pub(crate) async fn ensure_bucket(cluster: &Cluster, config: &Config) -> Result<(), RpcError> {
+    let path = format!("/pools/default/buckets/{}", config.bucket);
+    let result = request(cluster, "get", path.clone(), None)
+        .await
+        .map_err(to_init_err)?;
+    match result.http_status() {
+        200 => return Ok(()),
+        404 => {}
+        _ => return Err(mgmt_err("bucket lookup", &result)),
+    }
+
+    info!(
+        "creating bucket {} with {}MB RAM quota",
+        config.bucket, config.bucket_ram_quota_mb
+    );
+    let payload = format!(
+        "name={}&bucketType=couchbase&ramQuotaMB={}",
+        config.bucket, config.bucket_ram_quota_mb
+    );
+    let result = request(cluster, "post", "/pools/default/buckets".into(), Some(payload))
+        .await
+        .map_err(to_init_err)?;
+    if !(200..300).contains(&result.http_status()) {
+        return Err(mgmt_err("bucket creation", &result));
+    }
+
+    // wait until every node reports the bucket healthy
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        let result = request(cluster, "get", path.clone(), None)
+            .await
+            .map_err(to_init_err)?;
+        if result.http_status() == 200 && bucket_healthy(result.payload()) {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            return Err(RpcError::ProviderInit(format!(
+                "bucket {} was created but did not become ready",
+                config.bucket
+            )));
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+fn bucket_healthy(payload: Option<&Vec<u8>>) -> bool {
+    let bucket: serde_json::Value = match payload.and_then(|p| serde_json::from_slice(p).ok()) {
+        Some(bucket) => bucket,
+        None => return false,
+    };
+    match bucket["nodes"].as_array() {
+        Some(nodes) => !nodes.is_empty() && nodes.iter().all(|n| n["status"] == "healthy"),
+        None => false,
+    }
+}
+
+/// Create the scope and collection `bucket.scope_name.name` if they do not exist,
+/// and wait until the collection accepts key-value operations
+pub(crate) async fn ensure_collection(
+    cluster: &Cluster,
+    collection: &Collection,
+    bucket: &str,
+    scope_name: &str,
+    name: &str,
+) -> Result<(), RpcError> {
+    let scopes_path = format!("/pools/default/buckets/{}/scopes", bucket);
+    let result = request(cluster, "get", scopes_path.clone(), None)
+        .await
+        .map_err(to_init_err)?;
+    if result.http_status() != 200 {
+        return Err(mgmt_err("collection manifest lookup", &result));
+    }
+    let manifest: serde_json::Value = result
+        .payload()
+        .and_then(|p| serde_json::from_slice(p).ok())
+        .unwrap_or_default();
+    let scope = manifest["scopes"]
+        .as_array()
+        .and_then(|scopes| scopes.iter().find(|s| s["name"] == scope_name));
+
+    if scope.is_none() {
+        info!("creating scope {}.{}", bucket, scope_name);
+        let payload = format!("name={}", scope_name);
+        let result = request(cluster, "post", scopes_path.clone(), Some(payload))
+            .await
+            .map_err(to_init_err)?;
+        if !(200..300).contains(&result.http_status()) {
+            return Err(mgmt_err("scope creation", &result));
+        }
+    }
+    let collection_exists = scope
+        .and_then(|s| s["collections"].as_array())
+        .map(|c| c.iter().any(|c| c["name"] == name))
+        .unwrap_or(false);
+    if collection_exists {
+        return Ok(());
+    }
+
+    info!("creating collection {}.{}.{}", bucket, scope_name, name);
+    let payload = format!("name={}", name);
+    let path = format!("{}/{}/collections", scopes_path, scope_name);
+    let result = request(cluster, "post", path, Some(payload))
+        .await
+        .map_err(to_init_err)?;
+    if !(200..300).contains(&result.http_status()) {
+        return Err(mgmt_err("collection creation", &result));
+    }
+
+    // the manifest propagates to the data nodes asynchronously
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        match collection
+            .exists("_kvcouchbase_probe", ExistsOptions::default())
+            .await
+        {
+            Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => return Ok(()),
+            Err(e) if Instant::now() > deadline => {
+                return Err(RpcError::ProviderInit(format!(
+                    "collection {}.{} was created but did not become usable: {}",
+                    scope_name, name, e
+                )))
+            }
+            Err(_) => tokio::time::sleep(READY_POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Create a scope of `bucket`. Returns false if it exists already.
+pub(crate) async fn create_scope(cluster: &Cluster, bucket: &str, scope: &str) -> RpcResult<bool> {
+    info!("creating scope {}.{}", bucket, scope);
+    let path = format!("/pools/default/buckets/{}/scopes", bucket);
+    let payload = format!("name={}", scope);
+    change_manifest(cluster, "post", path, Some(payload), "scope creation").await
+}
+
+/// Drop a scope of `bucket` and its collections. Returns false if it does not exist.
+pub(crate) async fn drop_scope(cluster: &Cluster, bucket: &str, scope: &str) -> RpcResult<bool> {
+    info!("dropping scope {}.{}", bucket, scope);
+    let path = format!("/pools/default/buckets/{}/scopes/{}", bucket, scope);
+    change_manifest(cluster, "delete", path, None, "scope removal").await
+}
+
+/// Create a collection in an existing scope of `bucket`. Returns false if it exists already.
+pub(crate) async fn create_collection(
+    cluster: &Cluster,
+    bucket: &str,
+    scope: &str,
+    name: &str,
+) -> RpcResult<bool> {
+    info!("creating collection {}.{}.{}", bucket, scope, name);
+    let path = format!(
+        "/pools/default/buckets/{}/scopes/{}/collections",
+        bucket, scope
+    );
+    let payload = format!("name={}", name);
+    change_manifest(cluster, "post", path, Some(payload), "collection creation").await
+}
+
+/// Drop a collection of `bucket` and its documents. Returns false if it does not exist.
+pub(crate) async fn drop_collection(
+    cluster: &Cluster,
+    bucket: &str,
+    scope: &str,
+    name: &str,
+) -> RpcResult<bool> {
+    info!("dropping collection {}.{}.{}", bucket, scope, name);
+    let path = format!(
+        "/pools/default/buckets/{}/scopes/{}/collections/{}",
+        bucket, scope, name
+    );
+    change_manifest(cluster, "delete", path, None, "collection removal").await
+}
+
+/// Send a request changing the collection manifest of a bucket. Returns false if there was
+/// nothing to change: the scope or collection to create exists, or the one to drop does not.
+async fn change_manifest(
+    cluster: &Cluster,
+    method: &str,
+    path: String,
+    payload: Option<String>,
+    what: &str,
+) -> RpcResult<bool> {
+    let result = request(cluster, method, path, payload)
+        .await
+        .map_err(to_rpc_err)?;
+    match result.http_status() {
+        200..=299 => Ok(true),
+        404 if method == "delete" => Ok(false),
+        400 if payload_text(&result).contains("already exists") => Ok(false),
+        _ => Err(RpcError::Other(failure(what, &result))),
+    }
+}
+
+/// Create the indexes needed by the link's N1QL-backed features if they do not exist:
+/// a primary index, and an index on CAS for the change feed
+pub(crate) async fn ensure_indexes(cluster: &Cluster, config: &Config) -> Result<(), RpcError> {
+    let mut statements = vec![N1ql::on("CREATE PRIMARY INDEX IF NOT EXISTS ON {keyspace}", config)];
+    if config.change_feed_subject.is_some() {
+        statements.push(N1ql::on(
+            "CREATE INDEX `kvcouchbase_cas` IF NOT EXISTS ON {keyspace}(META().cas)",
+            config,
+        ));
+    }
+    for statement in statements {
+        let statement = statement.text();
+        info!("{}", statement);
+        let mut result = cluster
+            .query(statement, QueryOptions::default())
+            .await
+            .map_err(to_init_err)?;
+        result
+            .rows::<serde_json::Value>()
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| RpcError::ProviderInit(format!("{} failed: {}", statement, e)))?;
+    }
+    Ok(())
+}
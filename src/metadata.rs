@@ -0,0 +1,184 @@
+//! Creation time, update time and writer of the link's keys, kept beside the documents
+//!
+//! The Couchbase SDK cannot write extended attributes, so the metadata of a key is a document
+//! of the link's metadata collection, at the same document key, and the stored values keep
+//! their shape. It is updated in the background after each mutation the link audits.
+//! The content type of a key is the link's content_type when it last wrote the key, or the one
+//! the actor declared since.
+use couchbase::{
+    Collection, CouchbaseError, CouchbaseResult, GetOptions, InsertOptions, MutateInOptions,
+    MutateInSpec, RemoveOptions,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{warn, Level};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::config::parse_content_type;
+use crate::interface::GetMetadataResponse;
+use crate::{errors::to_rpc_err, CouchbaseLink};
+
+/// Operations that delete the document, and its metadata with it
+const DELETIONS: &[&str] = &["del", "list_clear", "set_clear"];
+/// Audited operations whose key is not one the metadata follow: del_by_prefix audits its
+/// prefix, and transactions write the metadata of each of their mutations themselves
+const UNTRACKED: &[&str] = &["del_by_prefix", "transact"];
+
+/// The metadata document of a key
+#[derive(Debug, Deserialize, Serialize)]
+struct Metadata {
+    created_at: String,
+    updated_at: String,
+    writer_actor: String,
+    #[serde(default)]
+    content_type: String,
+}
+
+/// Update the metadata of a key the link mutated with an audited operation
+pub(crate) fn record(link: &CouchbaseLink, op: &str, key: &str) {
+    if !UNTRACKED.contains(&op) {
+        write(link, key, DELETIONS.contains(&op));
+    }
+}
+
+/// Update the metadata of a key the link changed or deleted, if the link keeps metadata.
+/// The metadata is written in the background so the caller does not wait for it.
+pub(crate) fn write(link: &CouchbaseLink, key: &str, deleted: bool) {
+    let connection = link.connection();
+    if connection.metadata.is_none() {
+        return;
+    }
+    // the key was checked by the write it follows
+    let doc_key = link.config.doc_key(&link.actor_id, key);
+    let now = chrono::Utc::now().to_rfc3339();
+    let created = Metadata {
+        created_at: now.clone(),
+        updated_at: now,
+        writer_actor: link.actor_id.clone(),
+        content_type: link.config.content_type.clone().unwrap_or_default(),
+    };
+    let actor_id = link.actor_id.clone();
+    let log_failure = link.logs(Level::WARN);
+    tokio::spawn(async move {
+        if let Some(metadata) = &connection.metadata {
+            let written = if deleted {
+                remove(metadata, doc_key).await
+            } else {
+                stamp(metadata, doc_key, created).await
+            };
+            if let Err(e) = written {
+                if log_failure {
+                    warn!(%actor_id, "couchbase key metadata could not be written: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Write the metadata of a key that has none, or else its update time and writer,
+/// and its content type if the link has one
+async fn stamp(metadata: &Collection, doc_key: String, created: Metadata) -> CouchbaseResult<()> {
+    let (now, writer) = (created.updated_at.clone(), created.writer_actor.clone());
+    let content_type = Some(created.content_type.clone()).filter(|c| !c.is_empty());
+    match metadata
+        .insert(doc_key.clone(), created, InsertOptions::default())
+        .await
+    {
+        Err(CouchbaseError::DocumentExists { .. }) => {
+            let mut specs = vec![
+                MutateInSpec::upsert("updated_at", now),
+                MutateInSpec::upsert("writer_actor", writer),
+            ];
+            if let Some(content_type) = content_type {
+                specs.push(MutateInSpec::upsert("content_type", content_type));
+            }
+            metadata
+                .mutate_in(doc_key, specs, MutateInOptions::default())
+                .await
+                .map(|_| ())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+async fn remove(metadata: &Collection, doc_key: String) -> CouchbaseResult<()> {
+    match metadata.remove(doc_key, RemoveOptions::default()).await {
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Declare the content type of a key. Returns false if the key has no metadata.
+pub(crate) async fn set_content_type(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    content_type: &str,
+) -> RpcResult<bool> {
+    parse_content_type(content_type).map_err(RpcError::InvalidParameter)?;
+    let connection = link.connection();
+    if connection.metadata.is_none() {
+        return Err(no_metadata());
+    }
+    let doc_key = link.doc_key(key)?;
+    let options = kv_options!(link, MutateInOptions::default());
+    let (id, specs) = (
+        doc_key.clone(),
+        vec![MutateInSpec::upsert("content_type", content_type)],
+    );
+    let operation = async move {
+        let Some(metadata) = &connection.metadata else {
+            return Err(CouchbaseError::DocumentNotFound {
+                ctx: Default::default(),
+            });
+        };
+        metadata.mutate_in(id, specs, options).await
+    };
+    match link.execute(op, Some(&doc_key), operation).await {
+        Ok(_) => Ok(true),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+fn no_metadata() -> RpcError {
+    RpcError::InvalidParameter(
+        "the link keeps no key metadata, set its metadata_collection".to_string(),
+    )
+}
+
+/// Read the metadata of a key
+pub(crate) async fn get(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<GetMetadataResponse> {
+    let connection = link.connection();
+    if connection.metadata.is_none() {
+        return Err(no_metadata());
+    }
+    let doc_key = link.doc_key(key)?;
+    let options = kv_options!(link, GetOptions::default());
+    let id = doc_key.clone();
+    let operation = async move {
+        let Some(metadata) = &connection.metadata else {
+            return Err(CouchbaseError::DocumentNotFound {
+                ctx: Default::default(),
+            });
+        };
+        metadata.get(id, options).await
+    };
+    match link.execute(op, Some(&doc_key), operation).await {
+        Ok(r) => {
+            let metadata: Metadata = r.content().map_err(to_rpc_err)?;
+            Ok(GetMetadataResponse {
+                exists: true,
+                created_at: metadata.created_at,
+                updated_at: metadata.updated_at,
+                writer_actor: metadata.writer_actor,
+                content_type: metadata.content_type,
+            })
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(GetMetadataResponse::default()),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
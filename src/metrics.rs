@@ -0,0 +1,312 @@
+//! Prometheus metrics and the HTTP endpoint serving them
+//!
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use couchbase::CouchbaseError;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, proto::MetricType, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use serde_json::{json, Map, Value};
+use tracing::{error, info, warn};
+use wasmbus_rpc::core::HostData;
+
+static OPERATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_operations_total",
+        "Couchbase operations performed",
+        &["actor_id", "operation"]
+    )
+    .unwrap()
+});
+
+static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_errors_total",
+        "Couchbase operations that returned an error, by error category",
+        &["actor_id", "operation", "category"]
+    )
+    .unwrap()
+});
+
+static LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "kvcouchbase_operation_duration_seconds",
+        "Latency of Couchbase operations",
+        &["actor_id", "operation"]
+    )
+    .unwrap()
+});
+
+static LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_lookups_total",
+        "Key lookups by result (hit or miss)",
+        &["actor_id", "result"]
+    )
+    .unwrap()
+});
+
+/// Couchbase rejects documents larger than 20 MiB
+pub(crate) const MAX_DOCUMENT_SIZE: usize = 20 * 1024 * 1024;
+
+/// Values above this size are logged as approaching the document size limit
+pub(crate) const LARGE_VALUE_SIZE: usize = MAX_DOCUMENT_SIZE / 10 * 8;
+
+/// 64 bytes to 16 MiB in powers of 4, then the document size limit
+fn size_buckets() -> Vec<f64> {
+    let mut buckets = exponential_buckets(64.0, 4.0, 10).unwrap();
+    buckets.push(MAX_DOCUMENT_SIZE as f64);
+    buckets
+}
+
+static KEY_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "kvcouchbase_key_size_bytes",
+        "Length of the document keys read and written",
+        &["actor_id"],
+        exponential_buckets(8.0, 2.0, 6).unwrap()
+    )
+    .unwrap()
+});
+
+static VALUE_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "kvcouchbase_value_size_bytes",
+        "Size of the values read and written",
+        &["actor_id", "operation"],
+        size_buckets()
+    )
+    .unwrap()
+});
+
+static TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_timeouts_total",
+        "Couchbase operations that timed out",
+        &["actor_id", "operation"]
+    )
+    .unwrap()
+});
+
+static RECONNECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_reconnects_total",
+        "Connection rebuilds attempted by the connection monitor, by result",
+        &["actor_id", "result"]
+    )
+    .unwrap()
+});
+
+static BOOTSTRAP_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kvcouchbase_bootstrap_failures_total",
+        "Links that could not connect to their cluster",
+        &["actor_id"]
+    )
+    .unwrap()
+});
+
+static WRITE_BEHIND_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "kvcouchbase_write_behind_depth",
+        "Writes waiting to be retried, in memory or spilled to disk",
+        &["actor_id"]
+    )
+    .unwrap()
+});
+
+static CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("kvcouchbase_connections", "Linked Couchbase connections").unwrap()
+});
+
+/// Record the outcome of a Couchbase operation
+pub(crate) fn record(
+    actor_id: &str,
+    operation: &str,
+    elapsed: Duration,
+    error: Option<&CouchbaseError>,
+) {
+    OPERATIONS.with_label_values(&[actor_id, operation]).inc();
+    LATENCY
+        .with_label_values(&[actor_id, operation])
+        .observe(elapsed.as_secs_f64());
+    if let Some(e) = error {
+        ERRORS
+            .with_label_values(&[actor_id, operation, crate::errors::category(e)])
+            .inc();
+        if let CouchbaseError::Timeout { .. } = e {
+            TIMEOUTS.with_label_values(&[actor_id, operation]).inc();
+        }
+    }
+}
+
+/// Record the key length and value size of a document read or written by an operation
+pub(crate) fn record_sizes(actor_id: &str, operation: &str, key_len: usize, value_len: usize) {
+    KEY_SIZE
+        .with_label_values(&[actor_id])
+        .observe(key_len as f64);
+    VALUE_SIZE
+        .with_label_values(&[actor_id, operation])
+        .observe(value_len as f64);
+}
+
+/// Count a connection rebuild by the connection monitor
+pub(crate) fn record_reconnect(actor_id: &str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    RECONNECTS.with_label_values(&[actor_id, result]).inc();
+}
+
+pub(crate) fn record_bootstrap_failure(actor_id: &str) {
+    BOOTSTRAP_FAILURES.with_label_values(&[actor_id]).inc();
+}
+
+/// Count a `get` that found (hit) or did not find (miss) its key
+pub(crate) fn record_lookup(actor_id: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    LOOKUPS.with_label_values(&[actor_id, result]).inc();
+}
+
+pub(crate) fn set_write_behind_depth(actor_id: &str, depth: usize) {
+    WRITE_BEHIND_DEPTH
+        .with_label_values(&[actor_id])
+        .set(depth as i64);
+}
+
+pub(crate) fn set_connections(count: usize) {
+    CONNECTIONS.set(count as i64);
+}
+
+/// Serve metrics in the Prometheus text format on `addr`.
+/// The server runs on its own thread so it is independent of the provider's runtime.
+pub(crate) fn start_server(addr: SocketAddr) -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let make_svc = make_service_fn(|_| async {
+                    Ok::<_, Infallible>(service_fn(|_req| async { Ok::<_, Infallible>(encode()) }))
+                });
+                let server = match Server::try_bind(&addr) {
+                    Ok(builder) => builder.serve(make_svc),
+                    Err(e) => {
+                        error!("metrics server could not bind {}: {}", addr, e);
+                        return;
+                    }
+                };
+                info!("serving metrics on http://{}/metrics", addr);
+                if let Err(e) = server.await {
+                    error!("metrics server failed: {}", e);
+                }
+            })
+        })?;
+    Ok(())
+}
+
+fn encode() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        return Response::builder()
+            .status(500)
+            .body(Body::from(e.to_string()))
+            .unwrap();
+    }
+    Response::builder()
+        .header("Content-Type", encoder.format_type())
+        .body(Body::from(buf))
+        .unwrap()
+}
+
+/// Publish a JSON snapshot of the metrics on the NATS `subject` every `interval`,
+/// using the host's lattice connection settings. The publisher runs on its own thread.
+pub(crate) fn start_publisher(
+    host_data: HostData,
+    subject: String,
+    interval: Duration,
+) -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("metrics-publisher".to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let client = match host_data.nats_connect().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("metrics publisher could not connect to nats: {}", e);
+                        return;
+                    }
+                };
+                info!(%subject, "publishing metrics every {:?}", interval);
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let payload = snapshot(&host_data).to_string();
+                    if let Err(e) = client.publish(subject.clone(), payload.into()).await {
+                        warn!(%subject, "metrics publish failed: {}", e);
+                    }
+                }
+            })
+        })?;
+    Ok(())
+}
+
+/// JSON snapshot of every metric: counters and gauges with their value,
+/// histograms with their sample count and sum
+fn snapshot(host_data: &HostData) -> Value {
+    let metrics: Vec<Value> = prometheus::gather()
+        .iter()
+        .map(|family| {
+            let samples: Vec<Value> = family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    let labels: Map<String, Value> = metric
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().into()))
+                        .collect();
+                    let mut sample = json!({ "labels": labels });
+                    match family.get_field_type() {
+                        MetricType::COUNTER => {
+                            sample["value"] = metric.get_counter().get_value().into()
+                        }
+                        MetricType::GAUGE => {
+                            sample["value"] = metric.get_gauge().get_value().into()
+                        }
+                        MetricType::HISTOGRAM => {
+                            let histogram = metric.get_histogram();
+                            sample["count"] = histogram.get_sample_count().into();
+                            sample["sum"] = histogram.get_sample_sum().into();
+                        }
+                        _ => {}
+                    }
+                    sample
+                })
+                .collect();
+            json!({
+                "name": family.get_name(),
+                "help": family.get_help(),
+                "samples": samples,
+            })
+        })
+        .collect();
+    json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "host_id": host_data.host_id,
+        "provider_key": host_data.provider_key,
+        "link_name": host_data.link_name,
+        "metrics": metrics,
+    })
+}
@@ -0,0 +1,66 @@
+//! Background connection monitor that rebuilds dead connections
+//!
+use std::{
+    sync::{atomic::Ordering, Weak},
+    time::Duration,
+};
+
+use tracing::{info, warn, Level};
+
+use crate::{config, health, metrics, CouchbaseLink};
+
+/// Periodically ping the link's cluster. After `reconnect_after_failures`
+/// consecutive failures the link is marked degraded and its connection rebuilt.
+/// Stops when the link is dropped.
+pub(crate) fn spawn_monitor(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // the link was just connected, skip the immediate first tick
+        ticker.tick().await;
+        let mut failures = 0u32;
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let health = health::ping_link(&link).await;
+            if health.healthy {
+                if link.degraded.swap(false, Ordering::Relaxed) && link.logs(Level::INFO) {
+                    info!(actor_id = %link.actor_id, "couchbase connection recovered");
+                }
+                failures = 0;
+                continue;
+            }
+
+            failures += 1;
+            if link.logs(Level::WARN) {
+                warn!(
+                    actor_id = %link.actor_id,
+                    failures,
+                    "couchbase ping failed: {}",
+                    health.error.as_deref().unwrap_or_default()
+                );
+            }
+            if failures < link.config.reconnect_after_failures.max(1) {
+                continue;
+            }
+            link.degraded.store(true, Ordering::Relaxed);
+            let reconnected = config::create_collection_conection(&link.config).await;
+            metrics::record_reconnect(&link.actor_id, reconnected.is_ok());
+            match reconnected {
+                Ok(connection) => {
+                    if link.logs(Level::INFO) {
+                        info!(actor_id = %link.actor_id, "couchbase connection rebuilt");
+                    }
+                    link.replace_connection(connection);
+                    failures = 0;
+                }
+                Err(e) if link.logs(Level::WARN) => {
+                    warn!(actor_id = %link.actor_id, "couchbase reconnect failed: {}", e)
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,94 @@
+//! Mutation tokens of a link's recent writes, for read-your-writes N1QL queries
+//!
+//! A mutation token names the sequence number a write got in its vBucket. Queries run with
+//! `at_plus` scan consistency and a scan vector of these tokens wait for the indexes to include
+//! the link's own writes only, which is cheaper than waiting for every write with `request_plus`.
+//! The SDK cannot send its `MutationState` with a query, so the scan vector is sent as a raw
+//! query parameter.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use couchbase::MutationToken;
+use serde_json::json;
+
+/// How long a token is kept; indexes catch up with a write well within this
+const TOKEN_RETENTION: Duration = Duration::from_secs(60);
+
+/// The latest write of the link in a vBucket
+#[derive(Debug)]
+struct Written {
+    partition_uuid: u64,
+    sequence_number: u64,
+    at: Instant,
+}
+
+/// Latest token of the link's writes in each vBucket, at most one per vBucket
+#[derive(Debug, Default)]
+pub(crate) struct MutationTokens {
+    by_partition: Mutex<HashMap<u16, Written>>,
+}
+
+impl MutationTokens {
+    pub(crate) fn record(&self, token: &MutationToken) {
+        let mut by_partition = self.by_partition.lock().unwrap();
+        let written = Written {
+            partition_uuid: token.partition_uuid(),
+            sequence_number: token.sequence_number(),
+            at: Instant::now(),
+        };
+        match by_partition.get(&token.partition_id()) {
+            // a write with a later sequence number of the same vBucket history was recorded
+            Some(w)
+                if w.partition_uuid == written.partition_uuid
+                    && w.sequence_number > written.sequence_number => {}
+            _ => {
+                by_partition.insert(token.partition_id(), written);
+            }
+        }
+    }
+
+    /// Returns the sparse scan vector of the writes of the last TOKEN_RETENTION,
+    /// as `{"<vbucket>": [<sequence number>, "<vbucket uuid>"]}`, or None if there are none
+    pub(crate) fn scan_vector(&self) -> Option<serde_json::Value> {
+        let mut by_partition = self.by_partition.lock().unwrap();
+        by_partition.retain(|_, written| written.at.elapsed() < TOKEN_RETENTION);
+        if by_partition.is_empty() {
+            return None;
+        }
+        let vector: serde_json::Map<String, serde_json::Value> = by_partition
+            .iter()
+            .map(|(partition, written)| {
+                (
+                    partition.to_string(),
+                    json!([written.sequence_number, written.partition_uuid.to_string()]),
+                )
+            })
+            .collect();
+        Some(serde_json::Value::Object(vector))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_tokens() {
+        let tokens = MutationTokens::default();
+        assert_eq!(tokens.scan_vector(), None);
+        tokens.record(&MutationToken::new(7, 10, 3, "b".to_string()));
+        tokens.record(&MutationToken::new(7, 12, 3, "b".to_string()));
+        tokens.record(&MutationToken::new(7, 11, 3, "b".to_string()));
+        tokens.record(&MutationToken::new(9, 2, 1000, "b".to_string()));
+        assert_eq!(
+            tokens.scan_vector(),
+            Some(json!({"3": [12, "7"], "1000": [2, "9"]}))
+        );
+        // after a failover the vBucket has a new uuid and its sequence numbers may go back
+        tokens.record(&MutationToken::new(8, 5, 3, "b".to_string()));
+        assert_eq!(tokens.scan_vector().unwrap()["3"], json!([5, "8"]));
+    }
+}
@@ -0,0 +1,117 @@
+//! Reporting of responses that arrive after the provider timed out the request
+//!
+use std::{
+    collections::HashMap,
+    sync::{Mutex, Weak},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{warn, Level};
+
+use crate::CouchbaseLink;
+
+/// Number of slowest responses kept in a report
+const WORST_LATENCIES: usize = 10;
+
+/// Requests the provider timed out since the last report
+#[derive(Default, Serialize)]
+pub(crate) struct OrphanSummary {
+    /// responses received after the timeout, a sign of server slowness
+    orphaned: u64,
+    /// requests still unanswered at the end of the orphan window, a sign of network issues
+    lost: u64,
+    /// orphaned responses by operation
+    operations: HashMap<String, u64>,
+    /// slowest orphaned responses as (operation, latency in ms), slowest first
+    worst_latencies_ms: Vec<(String, u64)>,
+}
+
+/// Collects the timed-out requests of a link until the next report
+#[derive(Default)]
+pub(crate) struct OrphanReport {
+    summary: Mutex<OrphanSummary>,
+}
+
+impl OrphanReport {
+    /// Record a response that arrived `latency` after the request was sent
+    pub(crate) fn record_orphan(&self, op: &str, latency: Duration) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.orphaned += 1;
+        *summary.operations.entry(op.to_string()).or_default() += 1;
+        let latency_ms = latency.as_millis() as u64;
+        let worst = &mut summary.worst_latencies_ms;
+        let pos = worst.partition_point(|(_, ms)| *ms >= latency_ms);
+        if pos < WORST_LATENCIES {
+            worst.insert(pos, (op.to_string(), latency_ms));
+            worst.truncate(WORST_LATENCIES);
+        }
+    }
+
+    /// Record a request that got no response within the orphan window
+    pub(crate) fn record_lost(&self) {
+        self.summary.lock().unwrap().lost += 1;
+    }
+
+    /// Returns the summary since the last call, or None if no request timed out
+    fn take(&self) -> Option<OrphanSummary> {
+        let mut summary = self.summary.lock().unwrap();
+        if summary.orphaned == 0 && summary.lost == 0 {
+            return None;
+        }
+        Some(std::mem::take(&mut *summary))
+    }
+}
+
+/// Periodically log the link's orphan report. Stops when the link is dropped.
+pub(crate) fn spawn_reporter(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let orphans = match &link.orphans {
+                Some(orphans) => orphans,
+                None => break,
+            };
+            let summary = match orphans.take() {
+                Some(summary) => summary,
+                None => continue,
+            };
+            if link.logs(Level::WARN) {
+                warn!(
+                    actor_id = %link.actor_id,
+                    report = %serde_json::to_string(&summary).unwrap_or_default(),
+                    "couchbase requests timed out by the provider"
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_worst_latencies() {
+        let report = OrphanReport::default();
+        for ms in 0..20 {
+            report.record_orphan(if ms % 2 == 0 { "get" } else { "set" }, Duration::from_millis(ms));
+        }
+        report.record_lost();
+        let summary = report.take().unwrap();
+        assert_eq!(summary.orphaned, 20);
+        assert_eq!(summary.lost, 1);
+        assert_eq!(summary.operations["get"], 10);
+        assert_eq!(summary.worst_latencies_ms.len(), WORST_LATENCIES);
+        assert_eq!(summary.worst_latencies_ms[0], ("set".to_string(), 19));
+        assert_eq!(summary.worst_latencies_ms[9].1, 10);
+        assert!(report.take().is_none());
+    }
+}
@@ -0,0 +1,304 @@
+//! N1QL queries run on behalf of a link
+//!
+//! Every statement the provider runs is a [N1ql]: a fixed template into which only the link's
+//! keyspace, validated when the link is put, is interpolated. Keys and values are always passed
+//! as named or positional parameters, so they can never change the meaning of a statement.
+//! The provider's statements are prepared once per cluster and executed by name afterwards.
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::Instant,
+};
+
+use couchbase::{CouchbaseError, CouchbaseResult, QueryOptions, QueryResult};
+use futures::TryStreamExt;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tracing::{warn, Level};
+
+use crate::config::{self, Config, ScanConsistency};
+use crate::CouchbaseLink;
+
+/// Placeholder of the link's keyspace in statement templates
+const KEYSPACE: &str = "{keyspace}";
+
+/// Statements prepared by this process, by cluster URL and statement name
+static PREPARED: Lazy<Mutex<HashSet<(String, String)>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// A N1QL statement the provider may run
+#[derive(Clone, Debug)]
+pub(crate) struct N1ql {
+    text: String,
+    prepared: bool,
+}
+
+impl N1ql {
+    /// A statement on the link's keyspace, which replaces `{keyspace}` in the template
+    pub(crate) fn on(template: &'static str, config: &Config) -> Self {
+        N1ql {
+            text: template.replace(KEYSPACE, &config.keyspace()),
+            prepared: true,
+        }
+    }
+
+    /// A statement on another keyspace, which replaces `{keyspace}` in the template
+    pub(crate) fn on_keyspace(template: &'static str, keyspace: &str) -> Self {
+        N1ql {
+            text: template.replace(KEYSPACE, keyspace),
+            prepared: true,
+        }
+    }
+
+    /// A statement that names no keyspace
+    pub(crate) fn fixed(template: &'static str) -> Self {
+        N1ql {
+            text: template.to_string(),
+            prepared: true,
+        }
+    }
+
+    /// A statement written by the actor, run as is and never prepared
+    pub(crate) fn actor(sql: String) -> Self {
+        N1ql {
+            text: sql,
+            prepared: false,
+        }
+    }
+
+    /// Run the statement without preparing it, for transaction control and index management
+    pub(crate) fn adhoc(self) -> Self {
+        N1ql {
+            prepared: false,
+            ..self
+        }
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Name under which the statement is prepared, the same for every provider instance
+    /// running the same statement
+    fn name(&self) -> String {
+        format!("kvcouchbase_{:016x}", config::stable_hash(&self.text))
+    }
+}
+
+/// Returns the options of a query run for the operation, reading at the link's scan consistency
+pub(crate) fn options(link: &CouchbaseLink, op: &str) -> QueryOptions {
+    options_with(link, op, serde_json::Map::new())
+}
+
+/// Returns the options of a query, like [options], with raw query parameters
+pub(crate) fn options_with(
+    link: &CouchbaseLink,
+    op: &str,
+    mut raw: serde_json::Map<String, serde_json::Value>,
+) -> QueryOptions {
+    let scan_vector = || link.tokens.as_ref().and_then(|t| t.scan_vector());
+    add_consistency(&mut raw, link.config.scan_consistency(op), scan_vector);
+    if raw.is_empty() {
+        QueryOptions::default()
+    } else {
+        QueryOptions::default().raw(raw)
+    }
+}
+
+/// Add the raw query parameters of a scan consistency, with the link's scan vector for at_plus
+fn add_consistency(
+    raw: &mut serde_json::Map<String, serde_json::Value>,
+    consistency: ScanConsistency,
+    scan_vector: impl FnOnce() -> Option<serde_json::Value>,
+) {
+    match consistency {
+        ScanConsistency::NotBounded => {}
+        ScanConsistency::RequestPlus => {
+            raw.insert("scan_consistency".to_string(), json!("request_plus"));
+        }
+        // without recent writes there is nothing to wait for
+        ScanConsistency::AtPlus => {
+            if let Some(vector) = scan_vector() {
+                raw.insert("scan_consistency".to_string(), json!("at_plus"));
+                raw.insert("scan_vector".to_string(), vector);
+            }
+        }
+    }
+}
+
+/// Run a N1QL statement for the link and collect its rows.
+/// Queries slower than the link's slow_query_threshold, rows included, are logged
+/// with their statement, which holds placeholders rather than parameter values.
+pub(crate) async fn query<T: DeserializeOwned>(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: N1ql,
+    options: QueryOptions,
+) -> CouchbaseResult<Vec<T>> {
+    let (rows, _) = run(link, op, &statement, options).await?;
+    Ok(rows)
+}
+
+/// Run a N1QL statement for the link, like [query], and return its rows
+/// and the number of documents it changed
+pub(crate) async fn execute<T: DeserializeOwned>(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: N1ql,
+    options: QueryOptions,
+) -> CouchbaseResult<(Vec<T>, u64)> {
+    let (rows, mut result) = run(link, op, &statement, options).await?;
+    let mutations = result.meta_data().await.metrics().mutation_count();
+    Ok((rows, mutations as u64))
+}
+
+async fn run<T: DeserializeOwned>(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: &N1ql,
+    options: QueryOptions,
+) -> CouchbaseResult<(Vec<T>, QueryResult)> {
+    let start = Instant::now();
+    let mut result = if statement.prepared {
+        run_prepared(link, op, statement, options).await?
+    } else {
+        send(link, op, statement.text.clone(), options).await?
+    };
+    let rows: Vec<T> = result.rows::<T>().try_collect().await?;
+    let elapsed = start.elapsed();
+    match link.config.slow_query_threshold() {
+        Some(threshold) if elapsed > threshold && link.logs(Level::WARN) => warn!(
+            actor_id = %link.actor_id,
+            operation = %op,
+            statement = %statement.text,
+            duration_ms = elapsed.as_millis() as u64,
+            result_count = rows.len(),
+            "slow couchbase query"
+        ),
+        _ => {}
+    }
+    Ok((rows, result))
+}
+
+/// Execute the prepared statement, preparing it first if this process has not on the link's
+/// cluster. A statement the query service no longer knows, after a restart of its nodes, is
+/// prepared again and executed once more.
+async fn run_prepared(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: &N1ql,
+    options: QueryOptions,
+) -> CouchbaseResult<QueryResult> {
+    let prepared = (link.config.url().to_string(), statement.name());
+    if !PREPARED.lock().unwrap().contains(&prepared) {
+        prepare(link, op, statement, prepared.clone()).await?;
+    }
+    let execute = format!("EXECUTE `{}`", prepared.1);
+    let retry = replay(&options);
+    match send(link, op, execute.clone(), options).await {
+        Err(CouchbaseError::PreparedStatementFailure { .. }) if retry.is_some() => {
+            PREPARED.lock().unwrap().remove(&prepared);
+            prepare(link, op, statement, prepared).await?;
+            send(link, op, execute, retry.unwrap_or_default()).await
+        }
+        Err(e @ CouchbaseError::PreparedStatementFailure { .. }) => {
+            PREPARED.lock().unwrap().remove(&prepared);
+            Err(e)
+        }
+        result => result,
+    }
+}
+
+/// Prepare the statement on the link's cluster under its name
+async fn prepare(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: &N1ql,
+    prepared: (String, String),
+) -> CouchbaseResult<()> {
+    let prepare = format!("PREPARE `{}` FROM {}", prepared.1, statement.text);
+    let mut result = send(link, op, prepare, QueryOptions::default()).await?;
+    result
+        .rows::<serde_json::Value>()
+        .try_collect::<Vec<_>>()
+        .await?;
+    PREPARED.lock().unwrap().insert(prepared);
+    Ok(())
+}
+
+/// Returns options sending the same parameters as `options`, which cannot be cloned, to execute
+/// a statement again, or None if they cannot be serialized
+fn replay(options: &QueryOptions) -> Option<QueryOptions> {
+    match serde_json::to_value(options) {
+        Ok(serde_json::Value::Object(mut raw)) => {
+            // set by the SDK for each request
+            raw.remove("statement");
+            raw.remove("client_context_id");
+            Some(QueryOptions::default().raw(raw))
+        }
+        _ => None,
+    }
+}
+
+async fn send(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: String,
+    options: QueryOptions,
+) -> CouchbaseResult<QueryResult> {
+    let connection = link.connection();
+    let operation = async move { connection.cluster.query(statement, options).await };
+    link.execute(op, None, operation).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_statements() {
+        let mut config = Config::default();
+        config.bucket = "b".to_string();
+        config.scope = "s".to_string();
+        config.collection = "c".to_string();
+        let statement = N1ql::on("SELECT RAW META(d).id FROM {keyspace} AS d", &config);
+        assert_eq!(statement.text(), "SELECT RAW META(d).id FROM `b`.`s`.`c` AS d");
+        assert!(statement.prepared);
+        let same = N1ql::on("SELECT RAW META(d).id FROM {keyspace} AS d", &config);
+        assert_eq!(statement.name(), same.name());
+        assert_ne!(statement.name(), N1ql::fixed("SELECT 1").name());
+        // other provider instances find statements prepared under this name
+        assert_eq!(N1ql::fixed("SELECT 1").name(), "kvcouchbase_199e7bca63ea84f2");
+        assert!(!N1ql::actor("SELECT 1".to_string()).prepared);
+        assert!(!N1ql::fixed("BEGIN WORK").adhoc().prepared);
+    }
+
+    #[test]
+    fn replays_options() {
+        let options = QueryOptions::default()
+            .named_parameters(json!({ "prefix": "a%" }))
+            .raw(json!({ "scan_consistency": "request_plus" }));
+        let replayed = replay(&options).unwrap();
+        let sent = |options: &QueryOptions| {
+            let mut sent = serde_json::to_value(options).unwrap();
+            sent.as_object_mut().unwrap().remove("client_context_id");
+            sent
+        };
+        assert_eq!(sent(&replayed), sent(&options));
+        assert_eq!(sent(&replayed)["$prefix"], "a%");
+    }
+
+    #[test]
+    fn feeds_read_at_request_plus() {
+        // not_bounded by default
+        let config = Config::default();
+        for op in ["change_feed", "watch"] {
+            let mut raw = serde_json::Map::new();
+            add_consistency(&mut raw, config.scan_consistency(op), || None);
+            assert_eq!(raw["scan_consistency"], "request_plus", "{}", op);
+        }
+        let mut raw = serde_json::Map::new();
+        add_consistency(&mut raw, config.scan_consistency("scan_keys"), || None);
+        assert!(raw.is_empty());
+    }
+}
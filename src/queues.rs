@@ -0,0 +1,161 @@
+//! Queues and deques on lists, for actors using Couchbase as a work queue or a stack
+//!
+//! `QueuePush` appends a value to a list like `list_add`, and `QueuePop` removes and returns
+//! its first value; `DequePush` and `DequePop` do the same at either end. On lists stored as one
+//! document, pushes are sub-document array mutations the server applies atomically, and pops
+//! read element `[0]` or `[-1]` with the document's cas and remove it with that cas, retried
+//! when another writer changed the list meanwhile, so each value is popped exactly once and
+//! only the pushed or popped value is transferred.
+use couchbase::{CouchbaseError, LookupInOptions, LookupInSpec, MutateInOptions, MutateInSpec};
+use wasmbus_rpc::error::RpcResult;
+
+use crate::cas::CasRetry;
+use crate::containers::{self, Updated};
+use crate::{dead_letter, errors::to_rpc_err, tombstones, CouchbaseLink};
+
+/// Add a value at the front of a list stored as one document, or at its back, and return the
+/// new list size, or None without writing if the list does not exist or is deleted
+pub(crate) async fn push(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    value: &str,
+    front: bool,
+) -> RpcResult<Option<Updated<u32>>> {
+    let element = link.codec.encode(key, value)?;
+    let size = element.to_string().len() as u64;
+    link.check_quota(size)?;
+    let spec = match front {
+        true => MutateInSpec::array_prepend("", vec![element]),
+        false => MutateInSpec::array_append("", vec![element]),
+    };
+    let expiry = containers::expiry(link, op, key).await?;
+    let options = kv_options!(link, MutateInOptions::default());
+    let options = containers::mutate_in_expiry(options, expiry);
+    let connection = link.connection();
+    let doc_key = key.to_string();
+    let operation = async move {
+        connection
+            .collection
+            .mutate_in(doc_key, vec![spec], options)
+            .await
+    };
+    let cas = match link.execute(op, Some(key), operation).await {
+        Ok(r) => r.cas(),
+        // not an array: a deleted list is a tombstone object
+        Err(CouchbaseError::DocumentNotFound { .. }) | Err(CouchbaseError::PathMismatch { .. }) => {
+            return Ok(None)
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, key, &e);
+            return Err(e);
+        }
+    };
+    if let Some(quota) = &link.quota {
+        quota.add(size);
+    }
+    // the mutation returns no content: count the values separately, concurrent pushes and pops
+    // included
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![LookupInSpec::count("")]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    let len = match link.execute(op, Some(key), operation).await {
+        Ok(r) if r.exists(0) => r.content(0).map_err(to_rpc_err)?,
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => 0,
+        Err(e) => return Err(to_rpc_err(e)),
+    };
+    Ok(Some(Updated {
+        result: len,
+        cas: Some(cas),
+    }))
+}
+
+/// Remove the first value of a list stored as one document, or the last one with `back`, and
+/// return it, or None if the list is empty or does not exist
+pub(crate) async fn pop(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    back: bool,
+) -> RpcResult<Updated<Option<String>>> {
+    let path = match back {
+        true => "[-1]",
+        false => "[0]",
+    };
+    let expiry = containers::expiry(link, op, key).await?;
+    let mut retry = CasRetry::new();
+    loop {
+        let Some((stored, cas)) = lookup(link, op, key, path).await? else {
+            return Ok(Updated {
+                result: None,
+                cas: None,
+            });
+        };
+        let options = kv_options!(link, MutateInOptions::default()).cas(cas);
+        let options = containers::mutate_in_expiry(options, expiry);
+        let connection = link.connection();
+        let (doc_key, specs) = (key.to_string(), vec![MutateInSpec::remove(path)]);
+        let operation = async move {
+            connection
+                .collection
+                .mutate_in(doc_key, specs, options)
+                .await
+        };
+        match link.execute(op, Some(key), operation).await {
+            Ok(r) => {
+                if let Some(quota) = &link.quota {
+                    quota.sub(stored.to_string().len() as u64);
+                }
+                return Ok(Updated {
+                    result: Some(link.codec.decode(key, stored)?),
+                    cas: Some(r.cas()),
+                });
+            }
+            // popped or pushed by another writer meanwhile: read the new end value
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => retry.conflicted(key).await?,
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(link, op, key, &e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Read the value at `path` of a list, as stored, with the cas of the list
+async fn lookup(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    path: &str,
+) -> RpcResult<Option<(serde_json::Value, u64)>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (doc_key, specs) = (key.to_string(), vec![LookupInSpec::get(path)]);
+    let operation = async move {
+        connection
+            .collection
+            .lookup_in(doc_key, specs, options)
+            .await
+    };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) if r.exists(0) => Ok(Some((r.content(0).map_err(to_rpc_err)?, r.cas()))),
+        Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        // not an array: a deleted list is a tombstone object
+        Err(e @ CouchbaseError::PathMismatch { .. }) => {
+            match tombstones::lookup(link, op, key).await {
+                Ok(Some((true, _))) => Ok(None),
+                _ => Err(to_rpc_err(e)),
+            }
+        }
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
@@ -0,0 +1,100 @@
+//! Approximate per-link storage quota
+//!
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use couchbase::CouchbaseError;
+use tracing::{debug, warn, Level};
+
+use crate::query::{self, N1ql};
+use crate::CouchbaseLink;
+
+/// Tracks the approximate number of bytes stored by a link
+pub(crate) struct StorageQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl StorageQuota {
+    pub(crate) fn new(limit: u64) -> Self {
+        StorageQuota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if `bytes` more can be written without exceeding the quota
+    pub(crate) fn has_room(&self, bytes: u64) -> bool {
+        self.used.load(Ordering::Relaxed).saturating_add(bytes) <= self.limit
+    }
+
+    pub(crate) fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub(&self, bytes: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+
+    fn reset(&self, bytes: u64) {
+        self.used.store(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Approximate stored size of a document
+pub(crate) fn doc_size(key: &str, value: &serde_json::Value) -> u64 {
+    (key.len() + value.to_string().len()) as u64
+}
+
+/// Periodically recompute the link's usage with N1QL to correct drift
+/// from overwrites and expiries. Stops when the link is dropped.
+pub(crate) fn spawn_reconciliation(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let quota = match &link.quota {
+                Some(quota) => quota,
+                None => break,
+            };
+            match stored_bytes(&link).await {
+                Ok(bytes) => {
+                    if link.logs(Level::DEBUG) {
+                        debug!(actor_id = %link.actor_id, bytes, "storage quota reconciled");
+                    }
+                    quota.reset(bytes);
+                }
+                Err(e) if link.logs(Level::WARN) => {
+                    warn!(actor_id = %link.actor_id, "storage quota reconciliation failed: {}", e)
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+/// Sum the size of all documents in the link's key namespace
+async fn stored_bytes(link: &Arc<CouchbaseLink>) -> Result<u64, CouchbaseError> {
+    let statement = N1ql::on(
+        "SELECT RAW SUM(LENGTH(META(d).id) + LENGTH(ENCODE_JSON(d))) FROM {keyspace} AS d \
+         WHERE SUBSTR(META(d).id, 0, LENGTH($prefix)) = $prefix",
+        &link.config,
+    );
+    let options = query::options(link, "quota_reconcile")
+        .named_parameters(serde_json::json!({ "prefix": link.namespace() }));
+    let rows = query::query::<Option<u64>>(link, "quota_reconcile", statement, options).await?;
+    Ok(rows.into_iter().flatten().next().unwrap_or_default())
+}
@@ -0,0 +1,219 @@
+//! `migrate --from-redis <url>`: copy the strings, lists and sets of a Redis database into a
+//! link's collection, for actors moving from the Redis keyvalue provider
+//!
+//! Keys are found with SCAN, `--match`ing a pattern if given, and read with GET, LRANGE and
+//! SMEMBERS; keys of other types are skipped and counted. Each key is written with the provider's
+//! own `set`, list and set writes, so its documents are keyed, encoded and laid out as if the
+//! actor had written them, replacing what the key held. The remaining TTL of a key is kept, as the
+//! expiry of a string or the `ExpireContainer` TTL of a list or set. Keys are read one at a time
+//! and written batch_concurrency at a time.
+use futures::StreamExt;
+use wasmcloud_interface_keyvalue::SetRequest;
+
+use crate::config::ListStorage;
+use crate::resp::{Client, Reply};
+use crate::{
+    cli, containers, expire_container, list_clear, list_push, set_members, set_value, CouchbaseLink,
+};
+
+/// Operation name of the migration's writes, in the audit log
+const OP: &str = "migrate";
+
+/// Keys asked of each SCAN
+const SCAN_COUNT: &str = "1000";
+
+/// Value of a Redis key
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    List(Vec<String>),
+    Set(Vec<String>),
+}
+
+/// A key read from Redis
+struct Entry {
+    key: String,
+    value: Value,
+    /// remaining time to live in seconds, 0 if the key does not expire
+    ttl: u32,
+}
+
+/// Keys migrated, skipped and failed
+#[derive(Default)]
+struct Summary {
+    strings: u64,
+    lists: u64,
+    sets: u64,
+    skipped: u64,
+    failed: u64,
+}
+
+/// Run the migration. Returns whether every key of a supported type was migrated.
+pub(crate) async fn run(args: &[&str]) -> bool {
+    match migrate(args).await {
+        Ok(summary) => {
+            println!(
+                "migrated {} strings, {} lists and {} sets; skipped {} keys of other types; \
+                 {} failed",
+                summary.strings, summary.lists, summary.sets, summary.skipped, summary.failed
+            );
+            summary.failed == 0
+        }
+        Err(e) => {
+            eprintln!("migration failed: {}", e);
+            false
+        }
+    }
+}
+
+async fn migrate(args: &[&str]) -> Result<Summary, String> {
+    let url = cli::option(args, "--from-redis")?
+        .ok_or_else(|| "missing --from-redis <url>".to_string())?;
+    let pattern = cli::option(args, "--match")?.unwrap_or("*");
+    let mut redis = Client::connect(url).await?;
+    let link = cli::open_link(args).await?;
+    let mut summary = Summary::default();
+    let mut cursor = "0".to_string();
+    loop {
+        let scan = ["SCAN", &cursor, "MATCH", pattern, "COUNT", SCAN_COUNT];
+        let (next, keys) = match redis.command(&scan).await? {
+            Reply::Array(Some(mut page)) if page.len() == 2 => {
+                let keys = page.pop().unwrap().into_strings()?;
+                let next = page.pop().unwrap().into_string()?.unwrap_or_default();
+                (next, keys)
+            }
+            reply => return Err(format!("unexpected SCAN reply {:?}", reply)),
+        };
+        let mut entries = Vec::new();
+        for key in keys {
+            match read(&mut redis, &key).await {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => summary.skipped += 1,
+                Err(e) => {
+                    eprintln!("{}: {}", key, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+        let mut writes = futures::stream::iter(entries)
+            .map(|entry| {
+                let link = &link;
+                async move {
+                    let result = write(link, &entry).await;
+                    (entry, result)
+                }
+            })
+            .buffer_unordered(link.config.batch_concurrency);
+        while let Some((entry, result)) = writes.next().await {
+            match (result, entry.value) {
+                (Err(e), _) => {
+                    eprintln!("{}: {}", entry.key, e);
+                    summary.failed += 1;
+                }
+                (Ok(()), Value::String(_)) => summary.strings += 1,
+                (Ok(()), Value::List(_)) => summary.lists += 1,
+                (Ok(()), Value::Set(_)) => summary.sets += 1,
+            }
+        }
+        if next == "0" {
+            return Ok(summary);
+        }
+        cursor = next;
+    }
+}
+
+/// Read a key from Redis. Returns None for keys of unsupported types and keys gone since the
+/// scan.
+async fn read(redis: &mut Client, key: &str) -> Result<Option<Entry>, String> {
+    let kind = redis.command(&["TYPE", key]).await?.into_string()?;
+    let value = match kind.as_deref() {
+        Some("string") => match redis.command(&["GET", key]).await?.into_string()? {
+            Some(value) => Value::String(value),
+            None => return Ok(None),
+        },
+        Some("list") => Value::List(
+            redis
+                .command(&["LRANGE", key, "0", "-1"])
+                .await?
+                .into_strings()?,
+        ),
+        Some("set") => Value::Set(redis.command(&["SMEMBERS", key]).await?.into_strings()?),
+        _ => return Ok(None),
+    };
+    let ttl = ttl_secs(redis.command(&["PTTL", key]).await?.into_integer()?);
+    Ok(Some(Entry {
+        key: key.to_string(),
+        value,
+        ttl,
+    }))
+}
+
+/// Seconds to live of a PTTL in milliseconds, rounded up, 0 for keys without a TTL
+fn ttl_secs(pttl: i64) -> u32 {
+    match pttl {
+        ms if ms > 0 => ((ms + 999) / 1000).min(u32::MAX as i64) as u32,
+        _ => 0,
+    }
+}
+
+/// Write a key as the actor would have, replacing what it holds
+async fn write(link: &CouchbaseLink, entry: &Entry) -> Result<(), String> {
+    let key = link.doc_key(&entry.key).map_err(|e| e.to_string())?;
+    match &entry.value {
+        Value::String(value) => {
+            let request = SetRequest {
+                key: entry.key.clone(),
+                value: value.clone(),
+                expires: entry.ttl,
+            };
+            return set_value(link, OP, &request)
+                .await
+                .map_err(|e| e.to_string());
+        }
+        Value::List(values) => {
+            list_clear(link, OP, &entry.key)
+                .await
+                .map_err(|e| e.to_string())?;
+            if link.config.list_storage == ListStorage::Elements {
+                for value in values {
+                    list_push(link, OP, &entry.key, value, link.config.list_max_length)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+            } else {
+                let replace = |_| Ok((Some(values.clone()), ()));
+                let updated = containers::update_strings(link, OP, &key, replace)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                link.audit(OP, &entry.key, updated.cas);
+            }
+        }
+        Value::Set(members) => {
+            containers::remove(link, OP, &key)
+                .await
+                .map_err(|e| e.to_string())?;
+            let updated = set_members::add(link, OP, &key, &entry.key, members)
+                .await
+                .map_err(|e| e.to_string())?;
+            link.audit(OP, &entry.key, updated.cas);
+        }
+    }
+    expire_container(link, OP, &entry.key, entry.ttl)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_ttls() {
+        assert_eq!(ttl_secs(-1), 0);
+        assert_eq!(ttl_secs(-2), 0);
+        assert_eq!(ttl_secs(1), 1);
+        assert_eq!(ttl_secs(1000), 1);
+        assert_eq!(ttl_secs(1001), 2);
+    }
+}
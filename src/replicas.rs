@@ -0,0 +1,182 @@
+//! Replication check of a key across its active and replica copies
+//!
+//! The Couchbase SDK cannot read replicas, so each node is asked for the `vkey` statistics of
+//! the key, which hold the cas of its copy. Copies with the same cas hold the same value.
+//! The nodes expected to hold a copy come from the bucket's vBucket map.
+use std::collections::HashMap;
+
+use couchbase::{CouchbaseError, KvStatsRequest, Request};
+use futures::{channel::oneshot, StreamExt};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::interface::{GetAllReplicasResponse, ReplicaCopy};
+use crate::{errors::to_rpc_err, management, CouchbaseLink};
+
+/// Report the copies of a document, keyed with the link's prefix, and whether they agree
+pub(crate) async fn check(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+) -> RpcResult<GetAllReplicasResponse> {
+    // statistics keys are space separated
+    if doc_key.contains(char::is_whitespace) {
+        return Err(RpcError::InvalidParameter(
+            "keys with whitespace cannot be checked".to_string(),
+        ));
+    }
+    let connection = link.connection();
+    let path = format!("/pools/default/buckets/{}", link.config.bucket);
+    let bucket = management::get_json(&connection.cluster, path)
+        .await
+        .map_err(|e| RpcError::Other(format!("couchbase bucket details unavailable: {}", e)))?;
+    let (vbucket, nodes) = copy_nodes(&bucket, doc_key)?;
+
+    let stat = format!(
+        "vkey {} {} {}.{}",
+        doc_key, vbucket, link.config.scope, link.config.collection
+    );
+    let operation = async move {
+        let (sender, receiver) = oneshot::channel();
+        connection
+            .cluster
+            .core()
+            .send(Request::KvStatsRequest(KvStatsRequest::new(
+                sender,
+                Some(stat),
+            )));
+        let mut result = receiver
+            .await
+            .map_err(|_| CouchbaseError::RequestCanceled {
+                ctx: Default::default(),
+            })??;
+        Ok(result.stats().collect::<Vec<_>>().await)
+    };
+    let stats = link
+        .execute(op, Some(doc_key), operation)
+        .await
+        .map_err(to_rpc_err)?;
+
+    // only the nodes that have the document answer
+    let mut cas_by_node: HashMap<String, u64> = HashMap::new();
+    for stat in &stats {
+        if stat.key() == "key_cas" {
+            let cas = stat.value().parse().unwrap_or_default();
+            cas_by_node.insert(stat.server().to_string(), cas);
+        }
+    }
+    let copies: Vec<ReplicaCopy> = nodes
+        .into_iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let cas = node_cas(&cas_by_node, &node);
+            ReplicaCopy {
+                node,
+                active: i == 0,
+                exists: cas.is_some(),
+                cas: cas.unwrap_or_default(),
+            }
+        })
+        .collect();
+    let consistent = copies
+        .windows(2)
+        .all(|c| c[0].exists == c[1].exists && c[0].cas == c[1].cas);
+    Ok(GetAllReplicasResponse { copies, consistent })
+}
+
+/// Returns the vBucket of a key and the nodes expected to hold its copies, active first
+fn copy_nodes(bucket: &serde_json::Value, doc_key: &str) -> RpcResult<(usize, Vec<String>)> {
+    let map = &bucket["vBucketServerMap"];
+    let servers: Vec<&str> = map["serverList"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s.as_str())
+        .collect();
+    let vbuckets = map["vBucketMap"]
+        .as_array()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| {
+            RpcError::Other("couchbase bucket has no vBucket map, as memcached buckets".to_string())
+        })?;
+    let vbucket = vbucket(doc_key, vbuckets.len());
+    // -1 marks a replica with no node assigned, reported as a node without the document
+    let nodes = vbuckets[vbucket]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|index| match index.as_i64() {
+            Some(i) if i >= 0 => servers
+                .get(i as usize)
+                .copied()
+                .unwrap_or_default()
+                .to_string(),
+            _ => String::new(),
+        })
+        .collect();
+    Ok((vbucket, nodes))
+}
+
+/// Returns the cas a node reported, matching the node by host alone
+/// when the statistics name it with another port
+fn node_cas(cas_by_node: &HashMap<String, u64>, node: &str) -> Option<u64> {
+    if node.is_empty() {
+        return None;
+    }
+    if let Some(cas) = cas_by_node.get(node) {
+        return Some(*cas);
+    }
+    let host = |n: &str| n.rsplit_once(':').map_or(n, |(host, _)| host).to_string();
+    let mut matching = cas_by_node.iter().filter(|(n, _)| host(n) == host(node));
+    match (matching.next(), matching.next()) {
+        (Some((_, cas)), None) => Some(*cas),
+        _ => None,
+    }
+}
+
+/// The vBucket of a key, as Couchbase clients compute it
+fn vbucket(key: &str, vbuckets: usize) -> usize {
+    (((crc32(key.as_bytes()) >> 16) & 0x7fff) as usize) % vbuckets
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn maps_keys_to_nodes() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert!(vbucket("greeting", 1024) < 1024);
+        let bucket = json!({"vBucketServerMap": {
+            "serverList": ["a:11210", "b:11210"],
+            "vBucketMap": [[0, 1], [1, -1]],
+        }});
+        let (vb, nodes) = copy_nodes(&bucket, "greeting").unwrap();
+        assert_eq!(vb, vbucket("greeting", 2));
+        let expected: Vec<String> = match vb {
+            0 => vec!["a:11210".into(), "b:11210".into()],
+            _ => vec!["b:11210".into(), String::new()],
+        };
+        assert_eq!(nodes, expected);
+
+        let cas_by_node = HashMap::from([("a:11207".to_string(), 5)]);
+        assert_eq!(node_cas(&cas_by_node, "a:11210"), Some(5));
+        assert_eq!(node_cas(&cas_by_node, "b:11210"), None);
+        assert_eq!(node_cas(&cas_by_node, ""), None);
+    }
+}
@@ -0,0 +1,53 @@
+//! Sampling of per-operation logs, so verbose logging can stay enabled on busy links
+//!
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Logs one in N successful operations, with N set per operation or by default
+pub(crate) struct LogSampler {
+    default_rate: u64,
+    rates: HashMap<String, u64>,
+    counters: HashMap<String, AtomicU64>,
+    default_counter: AtomicU64,
+}
+
+impl LogSampler {
+    /// `rates` maps operation names to N; operations not listed use `default_rate`.
+    /// A rate of 0 or 1 logs every operation.
+    pub(crate) fn new(default_rate: u64, rates: HashMap<String, u64>) -> Self {
+        let counters = rates
+            .keys()
+            .map(|op| (op.clone(), AtomicU64::new(0)))
+            .collect();
+        LogSampler {
+            default_rate,
+            rates,
+            counters,
+            default_counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if this successful operation should be logged
+    pub(crate) fn sample(&self, op: &str) -> bool {
+        let (rate, counter) = match (self.rates.get(op), self.counters.get(op)) {
+            (Some(rate), Some(counter)) => (*rate, counter),
+            _ => (self.default_rate, &self.default_counter),
+        };
+        rate <= 1 || counter.fetch_add(1, Ordering::Relaxed) % rate == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_one_in_n() {
+        let sampler = LogSampler::new(1, HashMap::from([("get".to_string(), 3)]));
+        let gets: Vec<bool> = (0..6).map(|_| sampler.sample("get")).collect();
+        assert_eq!(gets, [true, false, false, true, false, false]);
+        assert!((0..4).all(|_| sampler.sample("set")));
+    }
+}
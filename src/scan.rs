@@ -0,0 +1,113 @@
+//! Key enumeration and removal by prefix with N1QL
+//!
+//! The Couchbase SDK has no KV range scan, so keys are read from the collection's
+//! primary index, in key order.
+use serde_json::json;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::config::KEY_SEPARATOR;
+use crate::query::{self, N1ql};
+use crate::{dead_letter, errors::to_rpc_err, CouchbaseLink};
+
+/// Keys returned by a scan when the request sets no limit
+pub(crate) const DEFAULT_SCAN_LIMIT: u32 = 100;
+
+/// Returns up to `limit` of the link's keys starting with `prefix`, in order,
+/// after the key `after` if it is not empty
+pub(crate) async fn scan_keys(
+    link: &CouchbaseLink,
+    op: &str,
+    prefix: &str,
+    after: &str,
+    limit: u32,
+) -> RpcResult<Vec<String>> {
+    let doc_prefix = link.doc_key(prefix)?;
+    let statement = N1ql::on(
+        "SELECT RAW META(d).id FROM {keyspace} AS d \
+         WHERE META(d).id LIKE $pattern AND META(d).id NOT LIKE $derived \
+         AND META(d).id > $after ORDER BY META(d).id LIMIT $limit",
+        &link.config,
+    );
+    let after = if after.is_empty() {
+        String::new()
+    } else {
+        link.doc_key(after)?
+    };
+    let options = query::options(link, op).named_parameters(json!({
+        "pattern": like_prefix(&doc_prefix),
+        "derived": like_derived(&link.namespace()),
+        "after": after,
+        "limit": limit,
+    }));
+    let ids: Vec<String> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    // the namespace prefix is the same for every key of the link
+    let namespace = link.namespace().len();
+    Ok(ids.into_iter().map(|id| id[namespace..].to_string()).collect())
+}
+
+/// Remove the link's documents whose key starts with `prefix` in a single N1QL DELETE,
+/// returning the number removed. A failed removal is recorded as a dead letter.
+pub(crate) async fn delete_by_prefix(
+    link: &CouchbaseLink,
+    op: &str,
+    prefix: &str,
+) -> RpcResult<u64> {
+    let doc_prefix = link.doc_key(prefix)?;
+    if doc_prefix.is_empty() {
+        return Err(RpcError::InvalidParameter(
+            "an empty prefix would remove every document of the collection".to_string(),
+        ));
+    }
+    let statement = N1ql::on(
+        "DELETE FROM {keyspace} AS d WHERE META(d).id LIKE $pattern \
+         RETURNING RAW LENGTH(META(d).id) + LENGTH(ENCODE_JSON(d))",
+        &link.config,
+    );
+    let options =
+        query::options(link, op).named_parameters(json!({ "pattern": like_prefix(&doc_prefix) }));
+    let (sizes, removed) = query::execute::<u64>(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)
+        .inspect_err(|e| dead_letter::record(link, op, &doc_prefix, e))?;
+    if let Some(quota) = &link.quota {
+        quota.sub(sizes.iter().sum());
+    }
+    if removed > 0 {
+        link.audit(op, prefix, None);
+    }
+    Ok(removed)
+}
+
+/// Returns a LIKE pattern matching the strings that start with `prefix`
+pub(crate) fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Returns a LIKE pattern matching the documents derived from the keys of a namespace,
+/// such as their TTL, history and chunk documents
+pub(crate) fn like_derived(namespace: &str) -> String {
+    format!("{}{}%", like_prefix(namespace), KEY_SEPARATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_like_patterns() {
+        assert_eq!(like_prefix("user:"), "user:%");
+        assert_eq!(like_prefix("50%_off\\"), "50\\%\\_off\\\\%");
+        assert_eq!(like_prefix(""), "%");
+        assert_eq!(like_derived("app_1:"), "app\\_1:%::%");
+    }
+}
@@ -0,0 +1,302 @@
+//! Operations over several sets, for sets too large to hold in the provider side by side
+//!
+//! `set_intersection` and `set_union` of more than SERVER_SIDE_SETS sets run as one N1QL
+//! statement that reads the sets by key and unnests their members, so the cluster combines them
+//! and only the result reaches the provider. Fewer sets are combined by the provider, as are
+//! sets of links whose codec wraps members in envelopes, which differ for equal members.
+//!
+//! There, `set_intersection` sizes the sets with sub-document counts and reads only the smallest
+//! one whole. Its members are then checked against the other sets, smallest first: sets of up to
+//! SEGMENT members are read whole, larger ones are probed with N1QL, SEGMENT members at a time,
+//! so the provider holds the smallest set and one segment of results rather than every set.
+//! Members are compared as stored, unless the link's codec wraps them in envelopes, which differ
+//! for equal members: then every set is read whole and compared decoded.
+//!
+//! `SetDiff` runs as one N1QL statement whatever the number of sets, unless the codec wraps
+//! members.
+//!
+//! `SetContains` likewise asks N1QL whether a set holds a member rather than reading the set.
+use std::collections::HashSet;
+
+use couchbase::{CouchbaseError, LookupInOptions, LookupInSpec};
+use serde_json::{json, Value};
+use wasmbus_rpc::error::RpcResult;
+
+use crate::query::{self, N1ql};
+use crate::{containers, errors::to_rpc_err, CouchbaseLink};
+
+/// Members probed by one N1QL query, and size up to which a set is read whole
+const SEGMENT: usize = 1000;
+/// Number of sets beyond which they are combined by the cluster
+const SERVER_SIDE_SETS: usize = 4;
+
+/// Returns the members found in every one of the sets, in the order of the smallest set when the
+/// provider combines them
+pub(crate) async fn intersection(
+    link: &CouchbaseLink,
+    op: &str,
+    names: &[String],
+) -> RpcResult<Vec<String>> {
+    if names.len() > SERVER_SIDE_SETS && !link.codec.wraps() {
+        let statement = N1ql::on(
+            "SELECT RAW m FROM {keyspace} AS d USE KEYS $keys UNNEST d AS m \
+             GROUP BY m HAVING COUNT(DISTINCT META(d).id) = $sets",
+            &link.config,
+        );
+        return combine(link, op, names, statement).await;
+    }
+    let keys: Vec<String> = names
+        .iter()
+        .map(|name| link.doc_key(name))
+        .collect::<RpcResult<_>>()?;
+    let mut sets: Vec<(u32, &String)> = sizes(link, op, &keys)
+        .await?
+        .into_iter()
+        .zip(&keys)
+        .collect();
+    sets.sort_by_key(|(size, _)| *size);
+    let mut sets = sets.into_iter();
+    let Some((_, smallest)) = sets.next() else {
+        return Ok(Vec::new());
+    };
+    let mut members = read(link, op, smallest).await?;
+    for (size, key) in sets {
+        if members.is_empty() {
+            break;
+        }
+        members = match size as usize <= SEGMENT || link.codec.wraps() {
+            true => keep(members, &read(link, op, key).await?),
+            false => probe(link, op, key, members).await?,
+        };
+    }
+    decode(link, members)
+}
+
+/// Returns the members found in any of the sets, in the order they are first found when the
+/// provider combines them
+pub(crate) async fn union(
+    link: &CouchbaseLink,
+    op: &str,
+    names: &[String],
+) -> RpcResult<Vec<String>> {
+    if names.len() > SERVER_SIDE_SETS && !link.codec.wraps() {
+        let statement = N1ql::on(
+            "SELECT DISTINCT RAW m FROM {keyspace} AS d USE KEYS $keys UNNEST d AS m",
+            &link.config,
+        );
+        return combine(link, op, names, statement).await;
+    }
+    let reads = names
+        .iter()
+        .map(|name| async move { read(link, op, &link.doc_key(name)?).await });
+    let sets = futures::future::try_join_all(reads).await?;
+    let mut members = decode(link, sets.into_iter().flatten().collect())?;
+    let mut seen = HashSet::new();
+    members.retain(|member| seen.insert(member.clone()));
+    Ok(members)
+}
+
+/// Returns the members of the first set found in none of the others, in the order of the first
+pub(crate) async fn difference(
+    link: &CouchbaseLink,
+    op: &str,
+    names: &[String],
+) -> RpcResult<Vec<String>> {
+    let Some((first, others)) = names.split_first() else {
+        return Ok(Vec::new());
+    };
+    let first = link.doc_key(first)?;
+    let others: Vec<String> = others
+        .iter()
+        .map(|name| link.doc_key(name))
+        .collect::<RpcResult<_>>()?;
+    if !link.codec.wraps() {
+        let statement = N1ql::on(
+            "SELECT RAW m FROM {keyspace} AS d USE KEYS $first UNNEST d AS m \
+             WHERE m NOT IN (SELECT RAW o FROM {keyspace} AS x USE KEYS $others UNNEST x AS o)",
+            &link.config,
+        );
+        let options = query::options(link, op).named_parameters(json!({
+            "first": first,
+            "others": others,
+        }));
+        let members: Vec<Value> = query::query(link, op, statement, options)
+            .await
+            .map_err(to_rpc_err)?;
+        return link.codec.decode_all(&first, members);
+    }
+    let mut members = read(link, op, &first).await?;
+    for key in others {
+        if members.is_empty() {
+            break;
+        }
+        members = without(members, &read(link, op, &key).await?);
+    }
+    decode(link, members)
+}
+
+/// Returns the members combined by a N1QL statement from the sets at `$keys`, given the number
+/// of distinct sets as `$sets`; a missing set has no members
+async fn combine(
+    link: &CouchbaseLink,
+    op: &str,
+    names: &[String],
+    statement: N1ql,
+) -> RpcResult<Vec<String>> {
+    let keys: HashSet<String> = names
+        .iter()
+        .map(|name| link.doc_key(name))
+        .collect::<RpcResult<_>>()?;
+    let options = query::options(link, op).named_parameters(json!({
+        "keys": keys,
+        "sets": keys.len(),
+    }));
+    let members: Vec<Value> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    // sets are only combined by N1QL if the codec does not wrap members, which are then not
+    // bound to the set they were read from
+    link.codec.decode_all("", members)
+}
+
+/// Returns whether the set at `key` holds `member`, asking N1QL rather than reading the set
+/// unless the link's codec wraps members
+pub(crate) async fn contains(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    member: &str,
+) -> RpcResult<bool> {
+    if link.codec.wraps() {
+        let set = containers::read_strings(link, op, key).await?;
+        return Ok(set.is_some_and(|(set, _)| set.iter().any(|m| m == member)));
+    }
+    // a value the codec cannot store, not JSON for the json format, is in no set
+    let Ok(member) = link.codec.encode(key, member) else {
+        return Ok(false);
+    };
+    let statement = N1ql::on(
+        "SELECT RAW 1 FROM {keyspace} AS d USE KEYS $key WHERE ARRAY_CONTAINS(d, $member)",
+        &link.config,
+    );
+    let options = query::options(link, op).named_parameters(json!({
+        "key": key,
+        "member": member,
+    }));
+    let found: Vec<Value> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    Ok(!found.is_empty())
+}
+
+/// Returns the number of members of each set, 0 if it does not exist
+async fn sizes(link: &CouchbaseLink, op: &str, keys: &[String]) -> RpcResult<Vec<u32>> {
+    let counts = keys.iter().map(|key| async move {
+        let options = kv_options!(link, LookupInOptions::default());
+        let connection = link.connection();
+        let (doc_key, specs) = (key.clone(), vec![LookupInSpec::count("")]);
+        let operation = async move {
+            connection
+                .collection
+                .lookup_in(doc_key, specs, options)
+                .await
+        };
+        match link.execute(op, Some(key), operation).await {
+            Ok(r) if r.exists(0) => r.content(0).map_err(to_rpc_err),
+            Ok(_) | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(0),
+            Err(e) => Err(to_rpc_err(e)),
+        }
+    });
+    futures::future::try_join_all(counts).await
+}
+
+/// Read the members of a set as they are compared: as stored, or decoded if the link's codec
+/// wraps them
+async fn read(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Vec<Value>> {
+    let stored = match containers::read::<Vec<Value>>(link, op, key).await? {
+        Some((stored, _)) => stored,
+        None => return Ok(Vec::new()),
+    };
+    match link.codec.wraps() {
+        true => Ok(link
+            .codec
+            .decode_all(key, stored)?
+            .into_iter()
+            .map(Value::String)
+            .collect()),
+        false => Ok(stored),
+    }
+}
+
+/// Returns the values of members read by [read], from any of the sets: members the codec wraps
+/// are decoded by [read], and the others are not bound to their set
+fn decode(link: &CouchbaseLink, members: Vec<Value>) -> RpcResult<Vec<String>> {
+    match link.codec.wraps() {
+        true => Ok(members
+            .into_iter()
+            .map(|member| match member {
+                Value::String(member) => member,
+                member => member.to_string(),
+            })
+            .collect()),
+        false => link.codec.decode_all("", members),
+    }
+}
+
+/// Returns the members also in `set`
+fn keep(mut members: Vec<Value>, set: &[Value]) -> Vec<Value> {
+    let set: HashSet<String> = set.iter().map(Value::to_string).collect();
+    members.retain(|member| set.contains(&member.to_string()));
+    members
+}
+
+/// Returns the members not in `set`
+fn without(mut members: Vec<Value>, set: &[Value]) -> Vec<Value> {
+    let set: HashSet<String> = set.iter().map(Value::to_string).collect();
+    members.retain(|member| !set.contains(&member.to_string()));
+    members
+}
+
+/// Returns the members also in the set at `key`, asking N1QL SEGMENT members at a time
+async fn probe(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    members: Vec<Value>,
+) -> RpcResult<Vec<Value>> {
+    let statement = N1ql::on(
+        "SELECT DISTINCT RAW m FROM {keyspace} AS d USE KEYS $key UNNEST d AS m \
+         WHERE m IN $members",
+        &link.config,
+    );
+    let mut kept = Vec::new();
+    for segment in members.chunks(SEGMENT) {
+        let options = query::options(link, op).named_parameters(json!({
+            "key": key,
+            "members": segment,
+        }));
+        let found: Vec<Value> = query::query(link, op, statement.clone(), options)
+            .await
+            .map_err(to_rpc_err)?;
+        kept.extend(keep(segment.to_vec(), &found));
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_members() {
+        let members = vec![json!("a"), json!({"b": 1}), json!("c"), json!(4)];
+        let set = vec![json!(4), json!("a"), json!({"b": 1}), json!("d")];
+        assert_eq!(keep(members, &set), [json!("a"), json!({"b": 1}), json!(4)]);
+        assert!(keep(vec![json!("4")], &[json!(4)]).is_empty());
+        let members = vec![json!("a"), json!("c"), json!(4)];
+        assert_eq!(
+            without(members, &[json!("a"), json!("4")]),
+            [json!("c"), json!(4)]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Members added to a set in batches, for actors adding many members at once
+//!
+//! `SetAddMany` reads the set with its cas, keeps the values it does not hold yet, and appends
+//! them in one sub-document mutation guarded by that cas, retried when another writer changed the
+//! set meanwhile. Only the new members are written, and the count returned is exactly the number
+//! of members the set gained. A set that does not exist yet, or that has to evict members to stay
+//! within `set_max_members`, is written whole instead.
+use std::collections::HashSet;
+
+use couchbase::{CouchbaseError, MutateInOptions, MutateInSpec};
+use wasmbus_rpc::error::RpcResult;
+
+use crate::cas::CasRetry;
+use crate::containers::{self, Updated};
+use crate::{dead_letter, errors::to_rpc_err, CouchbaseLink};
+
+/// Add `values` to the set at `key` and return how many of them it did not hold
+pub(crate) async fn add(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    set_name: &str,
+    values: &[String],
+) -> RpcResult<Updated<u32>> {
+    if let Some(filters) = &link.set_filters {
+        for value in values {
+            filters.insert(key, value);
+        }
+    }
+    let expiry = containers::expiry(link, op, key).await?;
+    let max = link.config.set_max_members as usize;
+    let mut retry = CasRetry::new();
+    loop {
+        let Some((set, cas)) = containers::read_strings(link, op, key).await? else {
+            return rewrite(link, op, key, set_name, values).await;
+        };
+        let added = new_members(&set, values);
+        if added.is_empty() {
+            return Ok(Updated {
+                result: 0,
+                cas: None,
+            });
+        }
+        if max > 0 && set.len() + added.len() > max {
+            return rewrite(link, op, key, set_name, values).await;
+        }
+        let elements = link.codec.encode_all(key, &added)?;
+        let size: u64 = elements.iter().map(|e| e.to_string().len() as u64).sum();
+        link.check_quota(size)?;
+        let options = kv_options!(link, MutateInOptions::default()).cas(cas);
+        let options = containers::mutate_in_expiry(options, expiry);
+        let connection = link.connection();
+        let doc_key = key.to_string();
+        let specs = vec![MutateInSpec::array_append("", elements)];
+        let operation = async move {
+            connection
+                .collection
+                .mutate_in(doc_key, specs, options)
+                .await
+        };
+        match link.execute(op, Some(key), operation).await {
+            Ok(r) => {
+                if let Some(quota) = &link.quota {
+                    quota.add(size);
+                }
+                return Ok(Updated {
+                    result: added.len() as u32,
+                    cas: Some(r.cas()),
+                });
+            }
+            // changed, deleted or removed by another writer meanwhile: read the set again
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => retry.conflicted(key).await?,
+            Err(e) => {
+                let e = to_rpc_err(e);
+                dead_letter::record(link, op, key, &e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Add `values` to the set at `key` by writing the whole set, making room for them
+async fn rewrite(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    set_name: &str,
+    values: &[String],
+) -> RpcResult<Updated<u32>> {
+    containers::update_strings(link, op, key, |set| {
+        let mut set = set.unwrap_or_default();
+        let added = new_members(&set, values);
+        if added.is_empty() {
+            return Ok((None, 0));
+        }
+        containers::make_room(&link.config, set_name, &mut set, added.len())?;
+        let count = added.len() as u32;
+        set.extend(added);
+        Ok((Some(set), count))
+    })
+    .await
+}
+
+/// Returns the values not in `set`, each once, in the order they are given
+fn new_members(set: &[String], values: &[String]) -> Vec<String> {
+    let mut seen: HashSet<&String> = set.iter().collect();
+    values
+        .iter()
+        .filter(|value| seen.insert(value))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_new_members() {
+        let set = vec!["a".to_string(), "b".to_string()];
+        let values: Vec<String> = ["b", "c", "d", "c", "a"]
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(new_members(&set, &values), ["c", "d"]);
+        assert!(new_members(&set, &[]).is_empty());
+    }
+}
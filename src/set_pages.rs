@@ -0,0 +1,79 @@
+//! Members of sets returned a page at a time, for sets too large for one response
+//!
+//! `SetQueryPage` returns `limit` members of a set from an offset, with the offset of the next
+//! page. The page is sliced from the set by N1QL, so only its members are read from the cluster
+//! and held in the provider. Members are kept in the order they were added, so members added
+//! between pages are returned by the last pages, but removing members shifts those after them,
+//! which the next page then skips.
+use serde::Deserialize;
+use serde_json::{json, Value};
+use wasmbus_rpc::error::RpcResult;
+
+use crate::query::{self, N1ql};
+use crate::{errors::to_rpc_err, CouchbaseLink};
+
+/// A page of a set and the set's size, both null if the key holds no set
+#[derive(Deserialize)]
+struct Page {
+    #[serde(default)]
+    members: Option<Vec<Value>>,
+    #[serde(default)]
+    size: Option<u32>,
+}
+
+/// Returns the `limit` members of the set at `key` from `offset`, and the offset of the next
+/// page, or 0 if it was the last one
+pub(crate) async fn page(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    offset: u32,
+    limit: u32,
+) -> RpcResult<(Vec<String>, u32)> {
+    let statement = N1ql::on(
+        "SELECT d[$offset:LEAST($end, ARRAY_LENGTH(d))] AS members, ARRAY_LENGTH(d) AS size \
+         FROM {keyspace} AS d USE KEYS $key",
+        &link.config,
+    );
+    let options = query::options(link, op).named_parameters(json!({
+        "key": key,
+        "offset": offset,
+        "end": offset as u64 + limit as u64,
+    }));
+    let pages: Vec<Page> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    let Some(Page {
+        members,
+        size: Some(size),
+    }) = pages.into_iter().next()
+    else {
+        return Ok((Vec::new(), 0));
+    };
+    let members = link.codec.decode_all(key, members.unwrap_or_default())?;
+    let next = next_offset(offset, members.len(), size);
+    Ok((members, next))
+}
+
+/// Offset of the page after `returned` members from `offset` of a set of `size` members, or 0
+/// if there are no more
+fn next_offset(offset: u32, returned: usize, size: u32) -> u32 {
+    match offset.saturating_add(returned as u32) {
+        next if returned > 0 && next < size => next,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_through_a_set() {
+        assert_eq!(next_offset(0, 100, 250), 100);
+        assert_eq!(next_offset(200, 50, 250), 0);
+        assert_eq!(next_offset(100, 100, 200), 0);
+        // the set shrank below the offset
+        assert_eq!(next_offset(300, 0, 250), 0);
+    }
+}
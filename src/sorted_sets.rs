@@ -0,0 +1,285 @@
+//! Sorted sets, members ordered by a score, for leaderboards and actors ported from Redis
+//!
+//! A sorted set is a JSON array of `{"member", "score"}` objects in ascending score order,
+//! ties ordered by member, stored under the set name. Members are stored like list and set
+//! elements by the link's codec, and mutations read the document and write it back with its
+//! CAS like the other containers.
+//!
+//! Ranges by score and top members are selected by N1QL from the set read by key, ordered by
+//! score and then by position in the array, so only the members returned leave the cluster.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::containers::{self, Updated};
+use crate::interface::{ScoredMember, ZRangeByScoreRequest};
+use crate::query::{self, N1ql};
+use crate::{errors::to_rpc_err, CouchbaseLink};
+
+/// Members returned by `ZTop` when the request sets no count
+pub(crate) const DEFAULT_TOP_COUNT: u32 = 10;
+
+/// A member as stored
+#[derive(Debug, Deserialize, Serialize)]
+struct Stored {
+    member: serde_json::Value,
+    score: f64,
+}
+
+/// Read a sorted set in ascending order, or None if it does not exist
+pub(crate) async fn read(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+) -> RpcResult<Option<Vec<ScoredMember>>> {
+    match containers::read::<Vec<Stored>>(link, op, key).await? {
+        Some((stored, _)) => Ok(Some(decode(link, key, stored)?)),
+        None => Ok(None),
+    }
+}
+
+/// Apply `apply` to the members of a sorted set, empty if it does not exist, and write them
+/// back in order like [containers::update], with the set's expiry
+async fn update<R>(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    mut apply: impl FnMut(Vec<ScoredMember>) -> RpcResult<(Option<Vec<ScoredMember>>, R)>,
+) -> RpcResult<Updated<R>> {
+    let expiry = containers::expiry(link, op, key).await?;
+    containers::update_with_expiry(link, op, key, expiry, |stored: Option<Vec<Stored>>| {
+        let members = decode(link, key, stored.unwrap_or_default())?;
+        match apply(members)? {
+            (Some(mut members), result) => {
+                order(&mut members);
+                Ok((Some(encode(link, key, members)?), result))
+            }
+            (None, result) => Ok((None, result)),
+        }
+    })
+    .await
+}
+
+/// Add members with their scores, or change the scores of existing ones.
+/// Returns the number of members added.
+pub(crate) async fn add(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    added: &[ScoredMember],
+) -> RpcResult<Updated<u32>> {
+    for scored in added {
+        check_score(scored.score)?;
+    }
+    update(link, op, key, |mut members| {
+        let (mut new, mut changed) = (0, false);
+        for scored in added {
+            match members.iter_mut().find(|m| m.member == scored.member) {
+                Some(m) if m.score == scored.score => {}
+                Some(m) => {
+                    m.score = scored.score;
+                    changed = true;
+                }
+                None => {
+                    members.push(scored.clone());
+                    new += 1;
+                    changed = true;
+                }
+            }
+        }
+        Ok((changed.then_some(members), new))
+    })
+    .await
+}
+
+/// Add `increment` to the score of a member, adding it with that score if it is not a member.
+/// Returns the new score.
+pub(crate) async fn incr_by(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    member: &str,
+    increment: f64,
+) -> RpcResult<Updated<f64>> {
+    update(link, op, key, |mut members| {
+        let score = match members.iter_mut().find(|m| m.member == member) {
+            Some(m) => {
+                m.score += increment;
+                m.score
+            }
+            None => {
+                members.push(ScoredMember {
+                    member: member.to_string(),
+                    score: increment,
+                });
+                increment
+            }
+        };
+        check_score(score)?;
+        Ok((Some(members), score))
+    })
+    .await
+}
+
+/// Remove members. Returns the number of members removed.
+pub(crate) async fn remove(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    removed: &[String],
+) -> RpcResult<Updated<u32>> {
+    update(link, op, key, |mut members| {
+        let before = members.len();
+        members.retain(|m| !removed.contains(&m.member));
+        match (before - members.len()) as u32 {
+            0 => Ok((None, 0)),
+            count => Ok((Some(members), count)),
+        }
+    })
+    .await
+}
+
+/// Returns the 0-based position of a member in ascending score order, or in descending order
+/// with `reverse`, or None if it is not a member
+pub(crate) fn rank(members: &[ScoredMember], member: &str, reverse: bool) -> Option<u32> {
+    let position = members.iter().position(|m| m.member == member)?;
+    match reverse {
+        true => Some((members.len() - 1 - position) as u32),
+        false => Some(position as u32),
+    }
+}
+
+/// Returns the members from rank `start` to rank `stop` included, in descending order with
+/// `reverse`. Negative ranks count from the end, -1 being the last member.
+pub(crate) fn range(
+    mut members: Vec<ScoredMember>,
+    start: i32,
+    stop: i32,
+    reverse: bool,
+) -> Vec<ScoredMember> {
+    if reverse {
+        members.reverse();
+    }
+    match containers::index_range(members.len(), start, stop) {
+        Some(range) => members.drain(range).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the members scored from `min` to `max` included of the sorted set at `key`, in
+/// ascending score order or descending with `reverse`, skipping `offset` members and returning
+/// at most `limit`, 0 for all
+pub(crate) async fn range_by_score(
+    link: &CouchbaseLink,
+    op: &str,
+    key: &str,
+    arg: &ZRangeByScoreRequest,
+) -> RpcResult<Vec<ScoredMember>> {
+    check_score(arg.min)?;
+    check_score(arg.max)?;
+    let template = match arg.reverse {
+        true => {
+            "SELECT e.member, e.score FROM {keyspace} AS d USE KEYS $key UNNEST d AS e \
+             WHERE e.score BETWEEN $min AND $max ORDER BY e.score DESC, UNNEST_POS(e) DESC \
+             LIMIT $limit OFFSET $offset"
+        }
+        false => {
+            "SELECT e.member, e.score FROM {keyspace} AS d USE KEYS $key UNNEST d AS e \
+             WHERE e.score BETWEEN $min AND $max ORDER BY e.score, UNNEST_POS(e) \
+             LIMIT $limit OFFSET $offset"
+        }
+    };
+    let statement = N1ql::on(template, &link.config);
+    let limit = match arg.limit {
+        0 => u32::MAX,
+        limit => limit,
+    };
+    let options = query::options(link, op).named_parameters(json!({
+        "key": key,
+        "min": arg.min,
+        "max": arg.max,
+        "limit": limit,
+        "offset": arg.offset,
+    }));
+    let stored: Vec<Stored> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    decode(link, key, stored)
+}
+
+fn order(members: &mut [ScoredMember]) {
+    members.sort_by(|a, b| {
+        a.score
+            .total_cmp(&b.score)
+            .then_with(|| a.member.cmp(&b.member))
+    });
+}
+
+fn check_score(score: f64) -> RpcResult<()> {
+    match score.is_finite() {
+        true => Ok(()),
+        false => Err(RpcError::InvalidParameter(format!(
+            "score {} is not a finite number",
+            score
+        ))),
+    }
+}
+
+fn decode(link: &CouchbaseLink, key: &str, stored: Vec<Stored>) -> RpcResult<Vec<ScoredMember>> {
+    stored
+        .into_iter()
+        .map(|s| {
+            Ok(ScoredMember {
+                member: link.codec.decode(key, s.member)?,
+                score: s.score,
+            })
+        })
+        .collect()
+}
+
+fn encode(link: &CouchbaseLink, key: &str, members: Vec<ScoredMember>) -> RpcResult<Vec<Stored>> {
+    members
+        .into_iter()
+        .map(|m| {
+            Ok(Stored {
+                member: link.codec.encode(key, &m.member)?,
+                score: m.score,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(scores: &[(&str, f64)]) -> Vec<ScoredMember> {
+        scores
+            .iter()
+            .map(|(member, score)| ScoredMember {
+                member: member.to_string(),
+                score: *score,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn orders_and_ranks_members() {
+        let mut leaderboard = members(&[("carol", 7.0), ("bob", 3.5), ("alice", 7.0)]);
+        order(&mut leaderboard);
+        let names: Vec<&str> = leaderboard.iter().map(|m| m.member.as_str()).collect();
+        assert_eq!(names, ["bob", "alice", "carol"]);
+        assert_eq!(rank(&leaderboard, "alice", false), Some(1));
+        assert_eq!(rank(&leaderboard, "carol", true), Some(0));
+        assert_eq!(rank(&leaderboard, "dave", false), None);
+
+        let top = range(leaderboard.clone(), 0, 1, true);
+        assert_eq!(top, members(&[("carol", 7.0), ("alice", 7.0)]));
+        assert_eq!(
+            range(leaderboard.clone(), -1, -1, false),
+            members(&[("carol", 7.0)])
+        );
+        assert!(range(leaderboard, 2, 1, false).is_empty());
+        assert!(check_score(f64::NAN).is_err());
+    }
+}
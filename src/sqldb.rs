@@ -0,0 +1,266 @@
+//! wasmcloud:sqldb served with N1QL on the same links as keyvalue
+//!
+//! Actors call `SqlDb.Execute` and `SqlDb.Query` with the messages of the sqldb interface.
+//! Parameters and result rows are CBOR-encoded, as the interface specifies.
+use async_trait::async_trait;
+use couchbase::{CouchbaseError, QueryOptions};
+use serde::{Deserialize, Serialize};
+use wasmbus_rpc::{
+    common::{Context, Message, MessageDispatch},
+    error::{RpcError, RpcResult},
+};
+
+use crate::{errors, query, CouchbaseLink};
+
+/// Refuse a statement on a link that does not serve SqlDb
+pub(crate) fn check_enabled(link: &CouchbaseLink) -> RpcResult<()> {
+    match link.config.sqldb {
+        true => Ok(()),
+        false => Err(RpcError::Other(
+            "SqlDb is not enabled on this link: set sqldb to true".to_string(),
+        )),
+    }
+}
+
+/// Name of the column of rows that are not objects, as N1QL names unnamed expressions
+const UNNAMED_COLUMN: &str = "$1";
+
+/// Metadata of a column
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Column {
+    /// column ordinal
+    pub ordinal: u32,
+    /// column name in the result
+    #[serde(default)]
+    pub name: String,
+    /// column data type as reported by the database
+    #[serde(rename = "dbType")]
+    #[serde(default)]
+    pub db_type: String,
+}
+
+/// List of columns in the result set returned by a Query operation
+pub type Columns = Vec<Column>;
+
+/// Result of an Execute operation
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ExecuteResult {
+    /// the number of rows affected by the query
+    #[serde(rename = "rowsAffected")]
+    #[serde(default)]
+    pub rows_affected: u64,
+    /// optional error information.
+    /// If error is included in the QueryResult, other values should be ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<SqlDbError>,
+}
+
+/// An optional list of arguments to be used in the SQL statement.
+/// Each parameter is a CBOR-encoded value
+pub type ParameterList = Vec<Vec<u8>>;
+
+/// Result of a query
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct QueryResult {
+    /// number of rows returned
+    #[serde(rename = "numRows")]
+    #[serde(default)]
+    pub num_rows: u64,
+    /// description of columns returned
+    pub columns: Columns,
+    /// result rows, encoded in CBOR as
+    /// an array (rows) of arrays (fields per row)
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub rows: Vec<u8>,
+    /// optional error information.
+    /// If error is included in the QueryResult, other values should be ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<SqlDbError>,
+}
+
+/// Detailed error information from the previous operation
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SqlDbError {
+    /// Type of error.
+    /// The list of enum variants for this field may be expanded in the future
+    /// to provide finer-granularity failure information
+    #[serde(default)]
+    pub code: String,
+    /// error message
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Statement {
+    /// Optional database in which the statement must be executed.
+    /// The value in this field is case-sensitive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+    /// A list of arguments to be used in the SQL statement.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<ParameterList>,
+    /// A sql query or statement that is a non-empty string containing
+    /// in the syntax of the back-end database.
+    #[serde(default)]
+    pub sql: String,
+}
+
+/// wasmbus.contractId: wasmcloud:sqldb
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait SqlDb {
+    /// Execute an sql statement
+    async fn execute(&self, ctx: &Context, arg: &Statement) -> RpcResult<ExecuteResult>;
+    /// Perform select query on database, returning all result rows
+    async fn query(&self, ctx: &Context, arg: &Statement) -> RpcResult<QueryResult>;
+}
+
+/// SqlDbReceiver receives messages defined in the SqlDb service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait SqlDbReceiver: MessageDispatch + SqlDb {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "Execute" => {
+                let value: Statement = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'Statement': {}", e)))?;
+
+                let resp = SqlDb::execute(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Query" => {
+                let value: Statement = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'Statement': {}", e)))?;
+
+                let resp = SqlDb::query(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "SqlDb::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the query options of a statement: its parameters, decoded from CBOR,
+/// and the link's scope as query context, so statements may name collections alone
+pub(crate) fn options(
+    link: &CouchbaseLink,
+    op: &str,
+    statement: &Statement,
+) -> RpcResult<QueryOptions> {
+    let context = format!("default:`{}`.`{}`", link.config.bucket, link.config.scope);
+    let mut raw = serde_json::Map::new();
+    raw.insert("query_context".to_string(), serde_json::Value::String(context));
+    let mut options = query::options_with(link, op, raw);
+    if let Some(parameters) = &statement.parameters {
+        let parameters = parameters
+            .iter()
+            .enumerate()
+            .map(|(i, parameter)| {
+                minicbor_ser::from_slice::<serde_json::Value>(parameter).map_err(|e| {
+                    RpcError::InvalidParameter(format!("parameter {} is not CBOR: {}", i + 1, e))
+                })
+            })
+            .collect::<RpcResult<Vec<_>>>()?;
+        options = options.positional_parameters(parameters);
+    }
+    Ok(options)
+}
+
+/// Describe the columns of N1QL rows and encode the rows as the interface specifies.
+/// Columns are the fields of the row objects, in order of appearance; rows that are not
+/// objects, as returned by `SELECT RAW`, have a single column.
+pub(crate) fn encode_rows(rows: &[serde_json::Value]) -> RpcResult<(Columns, Vec<u8>)> {
+    let mut columns: Columns = Vec::new();
+    for row in rows {
+        let fields: Vec<(&str, &serde_json::Value)> = match row {
+            serde_json::Value::Object(fields) => {
+                fields.iter().map(|(name, value)| (name.as_str(), value)).collect()
+            }
+            value => vec![(UNNAMED_COLUMN, value)],
+        };
+        for (name, value) in fields {
+            match columns.iter_mut().find(|column| column.name == name) {
+                Some(column) if column.db_type == "null" => column.db_type = db_type(value),
+                Some(_) => {}
+                None => columns.push(Column {
+                    ordinal: columns.len() as u32,
+                    name: name.to_string(),
+                    db_type: db_type(value),
+                }),
+            }
+        }
+    }
+    let table: Vec<Vec<&serde_json::Value>> = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| match row {
+                    serde_json::Value::Object(fields) => {
+                        fields.get(&column.name).unwrap_or(&serde_json::Value::Null)
+                    }
+                    value => value,
+                })
+                .collect()
+        })
+        .collect();
+    let encoded = minicbor_ser::to_vec(&table)
+        .map_err(|e| RpcError::Ser(format!("query rows could not be encoded: {}", e)))?;
+    Ok((columns, encoded))
+}
+
+/// Returns the JSON type of a value, reported as the column's type
+fn db_type(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+    .to_string()
+}
+
+/// Report a Couchbase error in the result, as the interface specifies
+pub(crate) fn sql_error(e: CouchbaseError) -> SqlDbError {
+    SqlDbError {
+        code: errors::category(&e).to_string(),
+        message: errors::to_rpc_err(e).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn encodes_rows() {
+        let rows = vec![
+            json!({"name": "a", "age": null}),
+            json!({"name": "b", "age": 3, "tags": ["x"]}),
+        ];
+        let (columns, encoded) = encode_rows(&rows).unwrap();
+        let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+        let types: Vec<&str> = columns.iter().map(|c| c.db_type.as_str()).collect();
+        assert_eq!(names, ["age", "name", "tags"]);
+        assert_eq!(types, ["number", "string", "array"]);
+        let decoded: serde_json::Value = minicbor_ser::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, json!([[null, "a", null], [3, "b", ["x"]]]));
+
+        let (columns, encoded) = encode_rows(&[json!(1), json!(2)]).unwrap();
+        assert_eq!(columns[0].name, UNNAMED_COLUMN);
+        let decoded: serde_json::Value = minicbor_ser::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, json!([[1], [2]]));
+    }
+}
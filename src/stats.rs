@@ -0,0 +1,164 @@
+//! Per-link operation statistics, so application teams can see their own hit rates
+//!
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Weak,
+    },
+    time::Duration,
+};
+
+use couchbase::CouchbaseError;
+use serde::Serialize;
+use tracing::{info, Level};
+
+use crate::{metrics, CouchbaseLink};
+
+/// Operation counters of a link
+#[derive(Default)]
+pub(crate) struct LinkStats {
+    gets: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Values of a link's counters at one point in time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct StatsSnapshot {
+    pub(crate) gets: u64,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) sets: u64,
+    pub(crate) deletes: u64,
+    pub(crate) errors: u64,
+}
+
+impl StatsSnapshot {
+    /// Fraction of gets that found their key, or None before the first get
+    pub(crate) fn hit_rate(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            lookups => Some(self.hits as f64 / lookups as f64),
+        }
+    }
+}
+
+impl LinkStats {
+    /// Count the outcome of an operation. A `get` of a missing key is a miss, not an error.
+    pub(crate) fn record(&self, actor_id: &str, op: &str, error: Option<&CouchbaseError>) {
+        let counter = match op {
+            "get" => Some(&self.gets),
+            "set" => Some(&self.sets),
+            "del" => Some(&self.deletes),
+            _ => None,
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        match (op, error) {
+            ("get", None) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::record_lookup(actor_id, true);
+            }
+            ("get", Some(CouchbaseError::DocumentNotFound { .. })) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                metrics::record_lookup(actor_id, false);
+            }
+            // reading a list, set or counter that does not exist yet is not an error
+            (op, Some(CouchbaseError::DocumentNotFound { .. })) if op != "del" => {}
+            (_, Some(_)) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            (_, None) => {}
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            gets: self.gets.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Periodically log the link's statistics when they changed since the last log.
+/// Stops when the link is dropped.
+pub(crate) fn spawn_logger(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        let mut last = StatsSnapshot::default();
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let stats = link.stats.snapshot();
+            if stats == last || !link.logs(Level::INFO) {
+                continue;
+            }
+            info!(
+                actor_id = %link.actor_id,
+                gets = stats.gets,
+                hits = stats.hits,
+                misses = stats.misses,
+                hit_rate = stats.hit_rate().unwrap_or_default(),
+                sets = stats.sets,
+                deletes = stats.deletes,
+                errors = stats.errors,
+                "couchbase link statistics"
+            );
+            last = stats;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use couchbase::ErrorContext;
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let stats = LinkStats::default();
+        stats.record("actor", "get", None);
+        stats.record("actor", "get", None);
+        stats.record(
+            "actor",
+            "get",
+            Some(&CouchbaseError::DocumentNotFound {
+                ctx: ErrorContext::default(),
+            }),
+        );
+        stats.record(
+            "actor",
+            "del",
+            Some(&CouchbaseError::DocumentNotFound {
+                ctx: ErrorContext::default(),
+            }),
+        );
+        stats.record("actor", "set", None);
+        stats.record("actor", "ping", None);
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot,
+            StatsSnapshot {
+                gets: 3,
+                hits: 2,
+                misses: 1,
+                sets: 1,
+                deletes: 1,
+                errors: 1,
+            }
+        );
+        assert_eq!(snapshot.hit_rate(), Some(2.0 / 3.0));
+    }
+}
@@ -0,0 +1,111 @@
+//! Sub-document paths applied by the provider, for what the Couchbase SDK cannot do server-side
+//!
+use serde_json::{Map, Value};
+
+/// One step of a sub-document path
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+/// Parse a path such as `address.city`, `items[0].name` or `` `dotted.name`.value ``
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = || format!("invalid path '{}'", path);
+    let mut segments = Vec::new();
+    let mut chars = path.chars().peekable();
+    // a field name is expected at the start and after each dot
+    let mut expect_field = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if !expect_field || segments.is_empty() => {
+                let index: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                segments.push(Segment::Index(index.trim().parse().map_err(|_| invalid())?));
+                expect_field = false;
+            }
+            '.' if !expect_field => expect_field = true,
+            '`' if expect_field => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        // a doubled backtick is a literal backtick
+                        Some('`') if chars.peek() == Some(&'`') => {
+                            chars.next();
+                            name.push('`');
+                        }
+                        Some('`') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(invalid()),
+                    }
+                }
+                segments.push(Segment::Field(name));
+                expect_field = false;
+            }
+            c if expect_field && c != '.' && c != ']' => {
+                let mut name = c.to_string();
+                while let Some(c) = chars.next_if(|c| !matches!(c, '.' | '[' | ']' | '`')) {
+                    name.push(c);
+                }
+                segments.push(Segment::Field(name));
+                expect_field = false;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if segments.is_empty() || expect_field {
+        return Err(invalid());
+    }
+    Ok(segments)
+}
+
+/// Set the value at `path` in `doc`, creating missing objects along the way.
+/// Array elements must already exist.
+pub(crate) fn set_path(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let segments = parse_path(path)?;
+    let mut target = doc;
+    for segment in segments {
+        target = match segment {
+            Segment::Field(name) => {
+                if target.is_null() {
+                    *target = Value::Object(Map::new());
+                }
+                target
+                    .as_object_mut()
+                    .ok_or_else(|| format!("'{}' is not inside an object", name))?
+                    .entry(name)
+                    .or_insert(Value::Null)
+            }
+            Segment::Index(index) => target
+                .as_array_mut()
+                .ok_or_else(|| format!("[{}] is not inside an array", index))?
+                .get_mut(index)
+                .ok_or_else(|| format!("[{}] is out of bounds", index))?,
+        };
+    }
+    *target = value;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sets_paths() {
+        let mut doc = json!({"items": [{"name": "a"}]});
+        set_path(&mut doc, "address.city", json!("Paris")).unwrap();
+        set_path(&mut doc, "items[0].name", json!("b")).unwrap();
+        set_path(&mut doc, "`a.b`.c", json!(1)).unwrap();
+        assert_eq!(
+            doc,
+            json!({"items": [{"name": "b"}], "address": {"city": "Paris"}, "a.b": {"c": 1}})
+        );
+        assert!(set_path(&mut doc, "items[1]", json!(0)).is_err());
+        assert!(set_path(&mut doc, "address.city.zip", json!(0)).is_err());
+        assert!(set_path(&mut doc, "a..b", json!(0)).is_err());
+        assert!(set_path(&mut doc, "a.", json!(0)).is_err());
+        assert!(set_path(&mut doc, "items.[0]", json!(0)).is_err());
+        assert!(set_path(&mut doc, "", json!(0)).is_err());
+    }
+}
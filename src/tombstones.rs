@@ -0,0 +1,148 @@
+//! Soft deletion of keys, which `del` replaces with a tombstone rather than removing
+//!
+//! A tombstone, `{"kvcouchbase_tombstone": {"deleted_at", "deleted_by", "content"}}`, keeps the
+//! deleted value until it expires after the link's soft_delete period, so `undelete` can restore
+//! it. Reads treat tombstoned keys as absent, and writes replace tombstones like missing keys.
+use couchbase::{
+    CouchbaseError, CouchbaseResult, GetOptions, LookupInOptions, LookupInSpec, ReplaceOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::{cas::CasRetry, errors::to_rpc_err, CouchbaseLink};
+
+/// Field of a tombstone document
+pub(crate) const TOMBSTONE: &str = "kvcouchbase_tombstone";
+
+/// What a tombstone keeps of the deleted value
+#[derive(Debug, Deserialize, Serialize)]
+struct Tombstone {
+    deleted_at: String,
+    deleted_by: String,
+    /// the deleted document
+    content: serde_json::Value,
+}
+
+/// Whether a document is a tombstone
+pub(crate) fn is_tombstone(content: &serde_json::Value) -> bool {
+    match content.as_object() {
+        Some(object) => object.len() == 1 && object.contains_key(TOMBSTONE),
+        None => false,
+    }
+}
+
+/// Returns whether the document at the key is a tombstone, with its cas,
+/// or None if the key does not exist
+pub(crate) async fn lookup(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+) -> CouchbaseResult<Option<(bool, u64)>> {
+    let options = kv_options!(link, LookupInOptions::default());
+    let connection = link.connection();
+    let (key, specs) = (doc_key.to_string(), vec![LookupInSpec::exists(TOMBSTONE)]);
+    let operation = async move { connection.collection.lookup_in(key, specs, options).await };
+    match link.execute(op, Some(doc_key), operation).await {
+        Ok(r) => Ok(Some((r.exists(0), r.cas()))),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace a document with its tombstone, which expires after the link's soft_delete period.
+/// Returns the cas of the tombstone, or None if the key does not exist or is already deleted.
+pub(crate) async fn bury(link: &CouchbaseLink, op: &str, doc_key: &str) -> RpcResult<Option<u64>> {
+    let period = link.config.soft_delete().unwrap_or_default();
+    let mut retry = CasRetry::new();
+    loop {
+        let (content, cas) = match read(link, op, doc_key).await? {
+            Some((content, _)) if is_tombstone(&content) => return Ok(None),
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        let tombstone = Tombstone {
+            deleted_at: chrono::Utc::now().to_rfc3339(),
+            deleted_by: link.actor_id.clone(),
+            content,
+        };
+        let options = kv_options!(link, ReplaceOptions::default())
+            .cas(cas)
+            .expiry(period);
+        match replace(link, op, doc_key, json!({ TOMBSTONE: tombstone }), options).await {
+            Ok(cas) => return Ok(Some(cas)),
+            // changed or removed since it was read: start over
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => retry.conflicted(doc_key).await?,
+            Err(e) => return Err(to_rpc_err(e)),
+        }
+    }
+}
+
+/// Replace a tombstone with the value it keeps, with the link's default_ttl.
+/// Returns the cas of the restored document, or None if the key is not deleted.
+pub(crate) async fn restore(link: &CouchbaseLink, op: &str, key: &str) -> RpcResult<Option<u64>> {
+    let doc_key = link.doc_key(key)?;
+    let mut retry = CasRetry::new();
+    loop {
+        let (tombstone, cas) = match read(link, op, &doc_key).await? {
+            Some((content, cas)) if is_tombstone(&content) => (content, cas),
+            _ => return Ok(None),
+        };
+        let tombstone: Tombstone = serde_json::from_value(tombstone[TOMBSTONE].clone())
+            .map_err(|e| RpcError::Other(format!("tombstone of {} is corrupt: {}", key, e)))?;
+        let mut options = kv_options!(link, ReplaceOptions::default()).cas(cas);
+        if let Some(expiry) = link.expiry(key, 0) {
+            options = options.expiry(expiry);
+        }
+        match replace(link, op, &doc_key, tombstone.content, options).await {
+            Ok(cas) => return Ok(Some(cas)),
+            Err(CouchbaseError::CasMismatch { .. })
+            | Err(CouchbaseError::DocumentNotFound { .. }) => retry.conflicted(&doc_key).await?,
+            Err(e) => return Err(to_rpc_err(e)),
+        }
+    }
+}
+
+async fn read(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+) -> RpcResult<Option<(serde_json::Value, u64)>> {
+    let options = kv_options!(link, GetOptions::default());
+    let connection = link.connection();
+    let key = doc_key.to_string();
+    let operation = async move { connection.collection.get(key, options).await };
+    match link.execute(op, Some(doc_key), operation).await {
+        Ok(r) => Ok(Some((r.content().map_err(to_rpc_err)?, r.cas()))),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+async fn replace(
+    link: &CouchbaseLink,
+    op: &str,
+    doc_key: &str,
+    content: serde_json::Value,
+    options: ReplaceOptions,
+) -> Result<u64, CouchbaseError> {
+    let connection = link.connection();
+    let key = doc_key.to_string();
+    let operation = async move { connection.collection.replace(key, content, options).await };
+    let r = link.execute(op, Some(doc_key), operation).await?;
+    link.written(&r);
+    Ok(r.cas())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tombstones() {
+        assert!(is_tombstone(&json!({ TOMBSTONE: { "content": "v" } })));
+        assert!(!is_tombstone(&json!({ TOMBSTONE: {}, "other": 1 })));
+        assert!(!is_tombstone(&json!("kvcouchbase_tombstone")));
+    }
+}
@@ -0,0 +1,274 @@
+//! Multi-document transactions, run as N1QL transactions
+//!
+//! The Couchbase SDK has no distributed transactions API, so the mutations are sent to the
+//! query service between `BEGIN WORK` and `COMMIT WORK`, each statement carrying the
+//! transaction id. The SDK load-balances each statement across the query nodes and cannot
+//! pin them to the node that began the transaction, so clusters with several query nodes are
+//! refused.
+use std::collections::HashSet;
+
+use couchbase::{CouchbaseResult, PingOptions, QueryOptions, ServiceType};
+use serde_json::json;
+use tracing::{warn, Level};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::interface::{TransactMutation, TransactRequest};
+use crate::query::{self, N1ql};
+use crate::codec::Codec;
+use crate::config::Config;
+use crate::{dead_letter, errors::to_rpc_err, metadata, CouchbaseLink};
+
+/// Operation name of transactions, in policies and metrics
+const OP: &str = "transact";
+
+/// Run the mutations atomically. Returns false, with nothing written, if an insert's key
+/// already exists or a replace's or remove's key does not.
+/// Transactions that fail in Couchbase are recorded as dead letters, one per key.
+pub(crate) async fn transact(link: &CouchbaseLink, arg: &TransactRequest) -> RpcResult<bool> {
+    check_link(&link.config)?;
+    if arg.mutations.is_empty() {
+        return Ok(true);
+    }
+    let keys: Vec<String> = arg
+        .mutations
+        .iter()
+        .map(|m| link.doc_key(&m.key))
+        .collect::<RpcResult<_>>()?;
+    validate(&link.codec, &arg.mutations, &keys)?;
+    check_query_nodes(link).await?;
+    let result = run_transaction(link, arg, &keys).await;
+    match &result {
+        Ok(true) => {
+            for mutation in &arg.mutations {
+                link.audit(OP, &mutation.key, None);
+                metadata::write(link, &mutation.key, mutation.op == "remove");
+            }
+        }
+        Ok(false) => {}
+        Err(e) => {
+            for key in &keys {
+                dead_letter::record(link, OP, key, e);
+            }
+        }
+    }
+    result
+}
+
+/// Refuse transactions on links whose writes take steps a N1QL transaction cannot:
+/// tombstones, chunks, history and the storage quota
+fn check_link(config: &Config) -> RpcResult<()> {
+    let features = [
+        ("soft_delete", config.soft_delete().is_some()),
+        ("chunk_size", config.chunk_size().is_some()),
+        ("history_length", config.history_length > 0),
+        ("storage_quota", config.storage_quota > 0),
+    ];
+    let enabled: Vec<&str> = features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    if enabled.is_empty() {
+        return Ok(());
+    }
+    Err(RpcError::Other(format!(
+        "Transact is not supported on links with {}",
+        enabled.join(", ")
+    )))
+}
+
+/// Refuse transactions on clusters with more than one query node, where the statements
+/// after `BEGIN WORK` may reach a node that does not know the transaction
+async fn check_query_nodes(link: &CouchbaseLink) -> RpcResult<()> {
+    let connection = link.connection();
+    let ping = link.execute(OP, None, async move {
+        connection.bucket.ping(PingOptions::default()).await
+    });
+    let result = ping.await.map_err(to_rpc_err)?;
+    let nodes: HashSet<String> = result
+        .endpoints()
+        .get(&ServiceType::Query)
+        .into_iter()
+        .flatten()
+        .filter_map(|endpoint| endpoint.remote())
+        .collect();
+    if nodes.len() <= 1 {
+        return Ok(());
+    }
+    Err(RpcError::Other(format!(
+        "Transact is not supported on clusters with {} query nodes, only one",
+        nodes.len()
+    )))
+}
+
+/// Reject unknown operations, values the link's value format refuses
+/// and keys changed more than once
+fn validate(codec: &Codec, mutations: &[TransactMutation], keys: &[String]) -> RpcResult<()> {
+    for (i, (mutation, key)) in mutations.iter().zip(keys).enumerate() {
+        if keys[..i].contains(key) {
+            return Err(RpcError::InvalidParameter(format!(
+                "key {} is changed more than once in the transaction",
+                mutation.key
+            )));
+        }
+        match mutation.op.as_str() {
+            "insert" | "replace" | "upsert" => {
+                codec.encode(key, &mutation.value)?;
+            }
+            "remove" => {}
+            op => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "unknown transaction operation '{}', expected insert, replace, upsert or remove",
+                    op
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Begin a transaction, apply the mutations, then commit it, or roll it back if a
+/// precondition or a mutation failed
+async fn run_transaction(
+    link: &CouchbaseLink,
+    arg: &TransactRequest,
+    keys: &[String],
+) -> RpcResult<bool> {
+    // like the provider's other writes, no durability, which also lets transactions run on
+    // single-node clusters
+    let mut options = json!({ "durability_level": "none" });
+    if arg.timeout_ms > 0 {
+        options["txtimeout"] = json!(format!("{}ms", arg.timeout_ms));
+    }
+    let rows: Vec<serde_json::Value> = query::query(
+        link,
+        OP,
+        N1ql::fixed("BEGIN WORK").adhoc(),
+        QueryOptions::default().raw(options),
+    )
+    .await
+    .map_err(to_rpc_err)?;
+    let txid = rows
+        .first()
+        .and_then(|row| row["txid"].as_str())
+        .ok_or_else(|| RpcError::Other("couchbase BEGIN WORK returned no txid".to_string()))?;
+
+    let applied = apply(link, txid, &arg.mutations, keys).await;
+    if let Ok(true) = applied {
+        run(link, txid, N1ql::fixed("COMMIT WORK").adhoc(), None)
+            .await
+            .map_err(to_rpc_err)?;
+    } else if let Err(e) = run(link, txid, N1ql::fixed("ROLLBACK WORK").adhoc(), None).await {
+        // the transaction expires on its own
+        if link.logs(Level::WARN) {
+            warn!(actor_id = %link.actor_id, %txid, "couchbase transaction rollback failed: {}", e);
+        }
+    }
+    applied
+}
+
+/// Check the preconditions and run the mutations in the transaction.
+/// Returns false if a precondition failed.
+async fn apply(
+    link: &CouchbaseLink,
+    txid: &str,
+    mutations: &[TransactMutation],
+    keys: &[String],
+) -> RpcResult<bool> {
+    // reading the keys in the transaction also detects concurrent changes to them at commit
+    let statement = N1ql::on(
+        "SELECT RAW META(d).id FROM {keyspace} AS d USE KEYS $keys",
+        &link.config,
+    );
+    let options = QueryOptions::default()
+        .named_parameters(json!({ "keys": keys }))
+        .raw(json!({ "txid": txid }));
+    let existing: Vec<String> = query::query(link, OP, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    for (mutation, key) in mutations.iter().zip(keys) {
+        let exists = existing.contains(key);
+        match mutation.op.as_str() {
+            "insert" if exists => return Ok(false),
+            "replace" | "remove" if !exists => return Ok(false),
+            _ => {}
+        }
+    }
+    for (mutation, key) in mutations.iter().zip(keys) {
+        let (statement, parameters) = if mutation.op == "remove" {
+            (
+                N1ql::on("DELETE FROM {keyspace} USE KEYS $key", &link.config),
+                json!({ "key": key }),
+            )
+        } else {
+            // values are stored in the link's value format, with its default_ttl, like `set`
+            let value = link.codec.encode(key, &mutation.value)?;
+            let expiration = link
+                .expiry(&mutation.key, 0)
+                .map_or(0, |expiry| expiry.as_secs());
+            (
+                N1ql::on(
+                    "UPSERT INTO {keyspace} (KEY, VALUE, OPTIONS) \
+                     VALUES ($key, $value, {\"expiration\": $expiration})",
+                    &link.config,
+                ),
+                json!({ "key": key, "value": value, "expiration": expiration }),
+            )
+        };
+        run(link, txid, statement, Some(parameters))
+            .await
+            .map_err(to_rpc_err)?;
+    }
+    Ok(true)
+}
+
+/// Run a statement in the transaction
+async fn run(
+    link: &CouchbaseLink,
+    txid: &str,
+    statement: N1ql,
+    parameters: Option<serde_json::Value>,
+) -> CouchbaseResult<()> {
+    let mut options = QueryOptions::default().raw(json!({ "txid": txid }));
+    if let Some(parameters) = parameters {
+        options = options.named_parameters(parameters);
+    }
+    query::query::<serde_json::Value>(link, OP, statement, options).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mutation(op: &str, key: &str, value: &str) -> TransactMutation {
+        TransactMutation {
+            op: op.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn validates_mutations() {
+        let codec = Codec::new(&Config::default()).unwrap();
+        let keys = vec!["a".to_string(), "b".to_string()];
+        let mutations = vec![mutation("insert", "a", "x"), mutation("remove", "b", "")];
+        assert!(validate(&codec, &mutations, &keys).is_ok());
+        let mutations = vec![mutation("insert", "a", "x"), mutation("delete", "b", "")];
+        assert!(validate(&codec, &mutations, &keys).is_err());
+        let keys = vec!["a".to_string(), "a".to_string()];
+        let mutations = vec![mutation("upsert", "a", "x"), mutation("remove", "a", "")];
+        assert!(validate(&codec, &mutations, &keys).is_err());
+    }
+
+    #[test]
+    fn refuses_links_with_write_steps() {
+        assert!(check_link(&Config::default()).is_ok());
+        let mut config = Config::default();
+        config.history_length = 5;
+        config.storage_quota = 1024;
+        let e = check_link(&config).unwrap_err().to_string();
+        assert!(e.contains("history_length, storage_quota"), "{}", e);
+    }
+}
@@ -0,0 +1,403 @@
+//! Buckets of the Store, Atomics and Batch services mapped onto collections of the link's bucket
+//!
+//! The bucket an actor opens as `default`, or with an empty identifier, is the link's collection,
+//! so values set with `Store` are read with wasmcloud:keyvalue and the other way round.
+//! Other identifiers name the collections the link maps them to in `keyvalue_buckets`; a bucket
+//! the link does not map does not exist. Keys get the link's `key_prefix` in every bucket.
+//!
+//! `Store` values are bytes: valid UTF-8 is stored by the link's codec like
+//! wasmcloud:keyvalue values, other bytes, and text the codec refuses, as
+//! `{"kvcouchbase_bytes": ...}`, base64 stored by the codec. Links with the `raw_base64` value
+//! format store every value's bytes as they are. Counters are JSON numbers, like those of
+//! `Increment`. Writes are single upserts: values are never chunked, soft-deleted or queued by
+//! write-behind.
+use std::sync::Arc;
+
+use couchbase::{
+    Collection, CouchbaseError, GetOptions, InsertOptions, LookupInOptions, LookupInSpec,
+    RemoveOptions, ReplaceOptions, UpsertOptions,
+};
+use serde_json::{json, Value};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::cas::CasRetry;
+use crate::config::ValueFormat;
+use crate::query::{self, N1ql};
+use crate::tombstones::{self, TOMBSTONE};
+use crate::wasi_keyvalue::{KeyResponse, KeyValuePair};
+use crate::{dead_letter, errors::to_rpc_err, quota, scan, CouchbaseLink};
+
+/// Field of a document holding bytes that are not stored as text
+const BYTES: &str = "kvcouchbase_bytes";
+
+/// Keys returned by a ListKeys
+const LIST_KEYS_PAGE: u64 = 1000;
+
+/// A collection opened as a bucket of the Store, Atomics and Batch services
+pub(crate) struct Bucket {
+    collection: Arc<Collection>,
+    keyspace: String,
+}
+
+/// Open the bucket an identifier names, failing if the link maps no collection to it
+pub(crate) fn open(link: &CouchbaseLink, identifier: &str) -> RpcResult<Bucket> {
+    let (scope, collection) = link
+        .config
+        .keyvalue_bucket(identifier)
+        .ok_or_else(|| RpcError::InvalidParameter(format!("no such store: {}", identifier)))?;
+    let keyspace = format!("`{}`.`{}`.`{}`", link.config.bucket, scope, collection);
+    let collection = link.connection().bucket.scope(scope).collection(collection);
+    Ok(Bucket {
+        collection: Arc::new(collection),
+        keyspace,
+    })
+}
+
+/// Returns the JSON storing a value of the document `doc_key`
+fn encode(link: &CouchbaseLink, doc_key: &str, value: &[u8]) -> RpcResult<Value> {
+    if link.config.value_format == ValueFormat::RawBase64 {
+        return link.codec.encode(doc_key, &base64::encode(value));
+    }
+    if let Some(content) = std::str::from_utf8(value)
+        .ok()
+        .and_then(|text| link.codec.encode(doc_key, text).ok())
+    {
+        return Ok(content);
+    }
+    // the JSON of a string, which every value format stores
+    let text = Value::String(base64::encode(value)).to_string();
+    Ok(json!({ BYTES: link.codec.encode(doc_key, &text)? }))
+}
+
+/// Returns the value stored as JSON in the document `doc_key`
+fn decode(link: &CouchbaseLink, doc_key: &str, content: Value) -> RpcResult<Vec<u8>> {
+    let bytes = match content {
+        Value::Object(mut object) if object.len() == 1 && object.contains_key(BYTES) => {
+            object.remove(BYTES)
+        }
+        content => {
+            let text = link.codec.decode(doc_key, content)?;
+            return match link.config.value_format {
+                ValueFormat::RawBase64 => base64::decode(text)
+                    .map_err(|_| RpcError::Other("stored bytes are corrupt".to_string())),
+                _ => Ok(text.into_bytes()),
+            };
+        }
+    };
+    let text = link.codec.decode(doc_key, bytes.unwrap_or_default())?;
+    serde_json::from_str::<String>(&text)
+        .ok()
+        .and_then(|encoded| base64::decode(encoded).ok())
+        .ok_or_else(|| RpcError::Other("stored bytes are corrupt".to_string()))
+}
+
+/// Returns the value of a key and its cas, None if it does not exist or was deleted
+async fn read(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+) -> RpcResult<Option<(Value, u64)>> {
+    let options = kv_options!(link, GetOptions::default());
+    let collection = bucket.collection.clone();
+    let doc_key = key.to_string();
+    let operation = async move { collection.get(doc_key, options).await };
+    match link.execute(op, Some(key), operation).await {
+        Ok(r) => {
+            let content: Value = r.content().map_err(to_rpc_err)?;
+            match tombstones::is_tombstone(&content) {
+                true => Ok(None),
+                false => Ok(Some((content, r.cas()))),
+            }
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+pub(crate) async fn get(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+) -> RpcResult<Option<Vec<u8>>> {
+    let doc_key = link.doc_key(key)?;
+    match read(link, op, bucket, &doc_key).await? {
+        Some((content, _)) => Ok(Some(decode(link, &doc_key, content)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) async fn set(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+    value: &[u8],
+) -> RpcResult<()> {
+    let doc_key = link.doc_key(key)?;
+    let content = encode(link, &doc_key, value)?;
+    let size = quota::doc_size(&doc_key, &content);
+    link.check_quota(size)?;
+    let mut options = kv_options!(link, UpsertOptions::default());
+    if let Some(expiry) = link.expiry(key, 0) {
+        options = options.expiry(expiry);
+    }
+    let collection = bucket.collection.clone();
+    let id = doc_key.clone();
+    let operation = async move { collection.upsert(id, content, options).await };
+    match link.execute(op, Some(&doc_key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            if let Some(quota) = &link.quota {
+                quota.add(size);
+            }
+            link.audit(op, key, Some(r.cas()));
+            Ok(())
+        }
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &doc_key, &e);
+            Err(e)
+        }
+    }
+}
+
+pub(crate) async fn delete(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+) -> RpcResult<()> {
+    let doc_key = link.doc_key(key)?;
+    let options = kv_options!(link, RemoveOptions::default());
+    let collection = bucket.collection.clone();
+    let id = doc_key.clone();
+    let operation = async move { collection.remove(id, options).await };
+    match link.execute(op, Some(&doc_key), operation).await {
+        Ok(r) => {
+            link.written(&r);
+            link.audit(op, key, Some(r.cas()));
+            Ok(())
+        }
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(()),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &doc_key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Returns whether a key exists and was not deleted, without reading its value
+pub(crate) async fn exists(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+) -> RpcResult<bool> {
+    let doc_key = link.doc_key(key)?;
+    let options = kv_options!(link, LookupInOptions::default());
+    let collection = bucket.collection.clone();
+    let (id, specs) = (doc_key.clone(), vec![LookupInSpec::exists(TOMBSTONE)]);
+    let operation = async move { collection.lookup_in(id, specs, options).await };
+    match link.execute(op, Some(&doc_key), operation).await {
+        Ok(r) => Ok(!r.exists(0)),
+        Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+        Err(e) => Err(to_rpc_err(e)),
+    }
+}
+
+/// Returns up to LIST_KEYS_PAGE keys of the bucket in order, from the `cursor`th
+pub(crate) async fn list_keys(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    cursor: Option<u64>,
+) -> RpcResult<KeyResponse> {
+    let statement = N1ql::on_keyspace(
+        "SELECT RAW META(d).id FROM {keyspace} AS d \
+         WHERE META(d).id LIKE $pattern AND META(d).id NOT LIKE $derived \
+         AND d.kvcouchbase_tombstone IS MISSING ORDER BY META(d).id LIMIT $limit OFFSET $offset",
+        &bucket.keyspace,
+    );
+    let offset = cursor.unwrap_or(0);
+    // one more key than returned tells whether keys remain
+    let options = query::options(link, op).named_parameters(json!({
+        "pattern": scan::like_prefix(&link.namespace()),
+        "derived": scan::like_derived(&link.namespace()),
+        "limit": LIST_KEYS_PAGE + 1,
+        "offset": offset,
+    }));
+    let mut ids: Vec<String> = query::query(link, op, statement, options)
+        .await
+        .map_err(to_rpc_err)?;
+    let cursor = (ids.len() as u64 > LIST_KEYS_PAGE).then_some(offset + LIST_KEYS_PAGE);
+    ids.truncate(LIST_KEYS_PAGE as usize);
+    // the namespace prefix is the same for every key of the link
+    let namespace = link.namespace().len();
+    Ok(KeyResponse {
+        keys: ids
+            .into_iter()
+            .map(|id| id[namespace..].to_string())
+            .collect(),
+        cursor,
+    })
+}
+
+/// Returns the value of a counter, 0 if it does not exist
+fn counter(key: &str, content: Option<&Value>) -> RpcResult<u64> {
+    match content {
+        None => Ok(0),
+        Some(content) => content.as_u64().ok_or_else(|| {
+            RpcError::InvalidParameter(format!("value of {} is not a counter", key))
+        }),
+    }
+}
+
+/// Write a counter with the cas it was read with, or create it. Returns false on a conflict.
+async fn write_counter(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+    cas: Option<u64>,
+    value: u64,
+) -> RpcResult<bool> {
+    let doc_key = link.doc_key(key)?;
+    let content = json!(value);
+    let collection = bucket.collection.clone();
+    let id = doc_key.clone();
+    let written = match cas {
+        Some(cas) => {
+            let options = kv_options!(link, ReplaceOptions::default()).cas(cas);
+            let operation = async move { collection.replace(id, content, options).await };
+            link.execute(op, Some(&doc_key), operation).await
+        }
+        None => {
+            let mut options = kv_options!(link, InsertOptions::default());
+            if let Some(expiry) = link.expiry(key, 0) {
+                options = options.expiry(expiry);
+            }
+            let operation = async move { collection.insert(id, content, options).await };
+            link.execute(op, Some(&doc_key), operation).await
+        }
+    };
+    match written {
+        Ok(r) => {
+            link.written(&r);
+            link.audit(op, key, Some(r.cas()));
+            Ok(true)
+        }
+        // changed, created or removed by another writer meanwhile
+        Err(CouchbaseError::CasMismatch { .. })
+        | Err(CouchbaseError::DocumentExists { .. })
+        | Err(CouchbaseError::DocumentNotFound { .. }) => Ok(false),
+        Err(e) => {
+            let e = to_rpc_err(e);
+            dead_letter::record(link, op, &doc_key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Add `delta` to the counter at a key and return its new value
+pub(crate) async fn increment(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+    delta: u64,
+) -> RpcResult<u64> {
+    let mut retry = CasRetry::new();
+    loop {
+        let read = read(link, op, bucket, &link.doc_key(key)?).await?;
+        let total = counter(key, read.as_ref().map(|(content, _)| content))?
+            .checked_add(delta)
+            .ok_or_else(|| RpcError::InvalidParameter(format!("counter {} overflows", key)))?;
+        let cas = read.map(|(_, cas)| cas);
+        if write_counter(link, op, bucket, key, cas, total).await? {
+            return Ok(total);
+        }
+        retry.conflicted(key).await?;
+    }
+}
+
+/// Set the counter at a key to `new` if its value is `old`. Returns whether it did; a counter
+/// that does not exist has no value.
+pub(crate) async fn compare_and_swap(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key: &str,
+    old: u64,
+    new: u64,
+) -> RpcResult<bool> {
+    let mut retry = CasRetry::new();
+    loop {
+        let Some((content, cas)) = read(link, op, bucket, &link.doc_key(key)?).await? else {
+            return Ok(false);
+        };
+        if counter(key, Some(&content))? != old {
+            return Ok(false);
+        }
+        if write_counter(link, op, bucket, key, Some(cas), new).await? {
+            return Ok(true);
+        }
+        retry.conflicted(key).await?;
+    }
+}
+
+/// Returns the keys that exist with their values, None for the others, in order
+pub(crate) async fn get_many(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    keys: &[String],
+) -> RpcResult<Vec<Option<KeyValuePair>>> {
+    let reads = keys.iter().map(|key| async move {
+        let value = get(link, op, bucket, key).await?;
+        Ok::<_, RpcError>(value.map(|value| KeyValuePair {
+            key: key.clone(),
+            value,
+        }))
+    });
+    futures::future::try_join_all(reads).await
+}
+
+pub(crate) async fn set_many(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    key_values: &[KeyValuePair],
+) -> RpcResult<()> {
+    let writes = key_values
+        .iter()
+        .map(|pair| set(link, op, bucket, &pair.key, &pair.value));
+    futures::future::try_join_all(writes).await?;
+    Ok(())
+}
+
+pub(crate) async fn delete_many(
+    link: &CouchbaseLink,
+    op: &str,
+    bucket: &Bucket,
+    keys: &[String],
+) -> RpcResult<()> {
+    let removals = keys.iter().map(|key| delete(link, op, bucket, key));
+    futures::future::try_join_all(removals).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_counters() {
+        assert_eq!(counter("k", None).unwrap(), 0);
+        assert_eq!(counter("k", Some(&json!(7))).unwrap(), 7);
+        assert!(counter("k", Some(&json!(-1))).is_err());
+        assert!(counter("k", Some(&json!("7"))).is_err());
+    }
+}
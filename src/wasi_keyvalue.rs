@@ -0,0 +1,299 @@
+//! Bucket services modelled on wasi:keyvalue, served on the same links as wasmcloud:keyvalue
+//!
+//! The `Store`, `Atomics` and `Batch` services mirror the `store`, `atomics` and `batch`
+//! interfaces of wasi:keyvalue as wasmbus services. They are not those WIT interfaces, which are
+//! served over wRPC, so component actors cannot call them. The bucket resource of the WIT
+//! interfaces has no counterpart in wasmbus messages, so every request names the bucket it was
+//! opened with; how buckets map onto collections is described in [crate::wasi_buckets].
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use wasmbus_rpc::{
+    common::{Context, Message, MessageDispatch},
+    error::{RpcError, RpcResult},
+};
+
+/// A key of a bucket
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BucketKey {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub key: String,
+}
+
+/// Keys of a bucket
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BucketKeys {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// A key and its value
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyValuePair {
+    #[serde(default)]
+    pub key: String,
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub value: Vec<u8>,
+}
+
+/// Value of a key, if it exists
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MaybeValue {
+    #[serde(with = "serde_bytes")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Vec<u8>>,
+}
+
+/// Parameter to Store.Set
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetRequest {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub value: Vec<u8>,
+}
+
+/// Parameter to Store.ListKeys
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListKeysRequest {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    /// cursor returned by the previous ListKeys, none for the first keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u64>,
+}
+
+/// Response to Store.ListKeys
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyResponse {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// cursor to pass to the next ListKeys, none once every key was listed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u64>,
+}
+
+/// Parameter to Atomics.Increment
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IncrementRequest {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub delta: u64,
+}
+
+/// Parameter to Atomics.CompareAndSwap
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CompareAndSwapRequest {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub old: u64,
+    #[serde(default)]
+    pub new: u64,
+}
+
+/// Parameter to Batch.SetMany
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SetManyRequest {
+    /// identifier the bucket was opened with
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(rename = "keyValues")]
+    #[serde(default)]
+    pub key_values: Vec<KeyValuePair>,
+}
+
+/// Modelled on wasi:keyvalue/store
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait Store {
+    /// Returns the value of a key, none if it does not exist
+    async fn get(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<MaybeValue>;
+    /// Sets the value of a key, replacing its value if it exists
+    async fn set(&self, ctx: &Context, arg: &SetRequest) -> RpcResult<()>;
+    /// Deletes a key, doing nothing if it does not exist
+    async fn delete(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<()>;
+    /// Returns whether a key exists
+    async fn exists(&self, ctx: &Context, arg: &BucketKey) -> RpcResult<bool>;
+    /// Returns keys of the bucket, from the cursor returned by the previous call
+    async fn list_keys(&self, ctx: &Context, arg: &ListKeysRequest) -> RpcResult<KeyResponse>;
+}
+
+/// Modelled on wasi:keyvalue/atomics
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait Atomics {
+    /// Adds delta to the counter at a key, created at 0 if it does not exist, and returns its
+    /// new value
+    async fn increment(&self, ctx: &Context, arg: &IncrementRequest) -> RpcResult<u64>;
+    /// Sets the counter at a key to new if its value is old, and returns whether it did
+    async fn compare_and_swap(&self, ctx: &Context, arg: &CompareAndSwapRequest)
+        -> RpcResult<bool>;
+}
+
+/// Modelled on wasi:keyvalue/batch
+/// wasmbus.providerReceive
+#[async_trait]
+pub trait Batch {
+    /// Returns the keys that exist with their values, none for the others, in order
+    async fn get_many(
+        &self,
+        ctx: &Context,
+        arg: &BucketKeys,
+    ) -> RpcResult<Vec<Option<KeyValuePair>>>;
+    /// Sets the values of keys
+    async fn set_many(&self, ctx: &Context, arg: &SetManyRequest) -> RpcResult<()>;
+    /// Deletes keys, ignoring those that do not exist
+    async fn delete_many(&self, ctx: &Context, arg: &BucketKeys) -> RpcResult<()>;
+}
+
+/// StoreReceiver receives messages defined in the Store service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait StoreReceiver: MessageDispatch + Store {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "Get" => {
+                let value: BucketKey = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'BucketKey': {}", e)))?;
+
+                let resp = Store::get(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "Set" => {
+                let value: SetRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetRequest': {}", e)))?;
+
+                Store::set(self, ctx, &value).await?;
+                let buf = Vec::new();
+
+                Ok(buf)
+            }
+            "Delete" => {
+                let value: BucketKey = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'BucketKey': {}", e)))?;
+
+                Store::delete(self, ctx, &value).await?;
+                let buf = Vec::new();
+
+                Ok(buf)
+            }
+            "Exists" => {
+                let value: BucketKey = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'BucketKey': {}", e)))?;
+
+                let resp = Store::exists(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "ListKeys" => {
+                let value: ListKeysRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListKeysRequest': {}", e)))?;
+
+                let resp = Store::list_keys(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "Store::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// AtomicsReceiver receives messages defined in the Atomics service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait AtomicsReceiver: MessageDispatch + Atomics {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "Increment" => {
+                let value: IncrementRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'IncrementRequest': {}", e)))?;
+
+                let resp = Atomics::increment(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "CompareAndSwap" => {
+                let value: CompareAndSwapRequest =
+                    wasmbus_rpc::common::deserialize(&message.arg)
+                        .map_err(|e| RpcError::Deser(format!("'CompareAndSwapRequest': {}", e)))?;
+
+                let resp = Atomics::compare_and_swap(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "Atomics::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// BatchReceiver receives messages defined in the Batch service trait
+#[doc(hidden)]
+#[async_trait]
+pub trait BatchReceiver: MessageDispatch + Batch {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> Result<Vec<u8>, RpcError> {
+        match message.method {
+            "GetMany" => {
+                let value: BucketKeys = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'BucketKeys': {}", e)))?;
+
+                let resp = Batch::get_many(self, ctx, &value).await?;
+                let buf = wasmbus_rpc::common::serialize(&resp)?;
+
+                Ok(buf)
+            }
+            "SetMany" => {
+                let value: SetManyRequest = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetManyRequest': {}", e)))?;
+
+                Batch::set_many(self, ctx, &value).await?;
+                let buf = Vec::new();
+
+                Ok(buf)
+            }
+            "DeleteMany" => {
+                let value: BucketKeys = wasmbus_rpc::common::deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'BucketKeys': {}", e)))?;
+
+                Batch::delete_many(self, ctx, &value).await?;
+                let buf = Vec::new();
+
+                Ok(buf)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "Batch::{}",
+                message.method
+            ))),
+        }
+    }
+}
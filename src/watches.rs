@@ -0,0 +1,194 @@
+//! Watches: keys and prefixes the actor asked to be told about when their documents change
+//!
+//! Watched documents are found like those of the change feed: with no DCP client in the
+//! Couchbase SDK, the watcher polls N1QL every change_feed_interval for the documents whose CAS
+//! is newer than the last change seen, under the longest prefix shared by the watches, and
+//! reports those matching a watch to the actor with a `KeyWatcher.HandleChange` call.
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    sync::{Mutex, Weak},
+    time::Duration,
+};
+
+use tracing::{warn, Level};
+use wasmbus_rpc::{
+    common::{Context, Message, Transport},
+    core::LinkDefinition,
+    error::{RpcError, RpcResult},
+    provider::ProviderTransport,
+};
+
+use crate::change_feed::{self, ChangeEvent};
+use crate::CouchbaseLink;
+
+/// Operation name of the watcher's queries, in metrics
+const OP: &str = "watch";
+
+/// Watches a link may have at once
+const MAX_WATCHES: usize = 1000;
+
+/// The keys and prefixes a link watches
+#[derive(Default)]
+pub(crate) struct Watches(Mutex<BTreeSet<Watch>>);
+
+/// A watched key, or all the keys starting with a prefix
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) struct Watch {
+    pub(crate) key: String,
+    pub(crate) prefix: bool,
+}
+
+impl Watch {
+    fn matches(&self, key: &str) -> bool {
+        match self.prefix {
+            true => key.starts_with(&self.key),
+            false => key == self.key,
+        }
+    }
+}
+
+impl Watches {
+    /// Start watching. Returns false if the key or prefix was watched already.
+    pub(crate) fn add(&self, watch: Watch) -> RpcResult<bool> {
+        let mut watches = self.0.lock().unwrap();
+        if watches.len() >= MAX_WATCHES && !watches.contains(&watch) {
+            return Err(RpcError::InvalidParameter(format!(
+                "the link watches {} keys and prefixes already",
+                MAX_WATCHES
+            )));
+        }
+        Ok(watches.insert(watch))
+    }
+
+    /// Stop watching. Returns false if the key or prefix was not watched.
+    pub(crate) fn remove(&self, watch: &Watch) -> bool {
+        self.0.lock().unwrap().remove(watch)
+    }
+
+    /// Returns true if a watch matches the key
+    fn matches(&self, key: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|watch| watch.matches(key))
+    }
+
+    /// Returns the longest prefix of every watched key, or None if nothing is watched
+    fn common_prefix(&self) -> Option<String> {
+        let watches = self.0.lock().unwrap();
+        let mut keys = watches.iter().map(|watch| watch.key.as_str());
+        let first = keys.next()?;
+        let mut len = first.len();
+        for key in keys {
+            len = first
+                .char_indices()
+                .zip(key.chars())
+                .take_while(|((i, a), b)| *i < len && a == b)
+                .map(|((i, a), _)| i + a.len_utf8())
+                .last()
+                .unwrap_or(0);
+        }
+        Some(first[..len].to_string())
+    }
+}
+
+/// Periodically report the watched documents changed since the previous poll to the actor.
+/// Stops when the link is dropped.
+pub(crate) fn spawn_watcher(link: Weak<CouchbaseLink>, ld: LinkDefinition, interval: Duration) {
+    tokio::spawn(async move {
+        let mut since = change_feed::unix_time().as_nanos() as u64;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let prefix = match link.watches.common_prefix() {
+                Some(prefix) => prefix,
+                None => {
+                    // only changes made while a watch is set are reported
+                    since = change_feed::unix_time().as_nanos() as u64;
+                    continue;
+                }
+            };
+            let changes = match change_feed::poll(&link, OP, Some(since), &prefix).await {
+                Ok(changes) => changes,
+                Err(e) => {
+                    change_feed::log_failure(&link, "couchbase watch query failed", &e);
+                    continue;
+                }
+            };
+            for change in changes {
+                since = since.max(change.cas);
+                if !link.watches.matches(&change.id) {
+                    continue;
+                }
+                let event = ChangeEvent {
+                    event: "mutation",
+                    key: change.id,
+                    cas: change.cas,
+                };
+                if let Err(e) = notify(&ld, &event).await {
+                    if link.logs(Level::WARN) {
+                        warn!(
+                            actor_id = %link.actor_id,
+                            key = %link.log_key(&event.key),
+                            "couchbase watch notification could not be delivered: {}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Send a change of a watched document to the linked actor
+async fn notify(ld: &LinkDefinition, event: &ChangeEvent) -> RpcResult<()> {
+    let arg = wasmbus_rpc::common::serialize(event)?;
+    ProviderTransport::new(ld, None)
+        .send(
+            &Context::default(),
+            Message {
+                method: "KeyWatcher.HandleChange",
+                arg: Cow::Owned(arg),
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(key: &str, prefix: bool) -> Watch {
+        Watch {
+            key: key.to_string(),
+            prefix,
+        }
+    }
+
+    #[test]
+    fn matches_watches() {
+        let watches = Watches::default();
+        assert_eq!(watches.common_prefix(), None);
+        assert!(watches.add(watch("user:42", false)).unwrap());
+        assert!(!watches.add(watch("user:42", false)).unwrap());
+        assert_eq!(watches.common_prefix().unwrap(), "user:42");
+        assert!(watches.add(watch("user:1", true)).unwrap());
+        assert_eq!(watches.common_prefix().unwrap(), "user:");
+        assert!(watches.matches("user:42"));
+        assert!(watches.matches("user:17"));
+        assert!(!watches.matches("user:420"));
+        assert!(watches.remove(&watch("user:1", true)));
+        assert!(!watches.matches("user:17"));
+        assert!(watches.add(watch("session", true)).unwrap());
+        assert_eq!(watches.common_prefix().unwrap(), "");
+    }
+}
@@ -0,0 +1,489 @@
+//! Write-behind retries of `set` and `del` calls that failed with a transient error
+//!
+//! The actor is acknowledged as soon as the failed write is queued, and the queue is
+//! retried in the background. The queue holds the newest write of each key, and a write
+//! to a key that has a queued write is queued behind it, so writes to a key apply in order.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Mutex, Weak},
+    time::Duration,
+};
+
+use couchbase::CouchbaseError;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{warn, Level};
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::{dead_letter, errors, metrics, CouchbaseLink};
+
+/// A write waiting to be retried
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Write {
+    Set {
+        value: String,
+        /// expiry in seconds, counted from the successful retry, or past 30 days a unix time
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expiry: Option<u64>,
+    },
+    Del,
+}
+
+/// A queued write and its key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Pending {
+    /// document key
+    key: String,
+    /// key as given by the actor, for the audit log
+    name: String,
+    #[serde(flatten)]
+    write: Write,
+    #[serde(default)]
+    attempts: u32,
+}
+
+impl Pending {
+    pub(crate) fn set(key: &str, name: &str, value: &str, expiry: Option<Duration>) -> Self {
+        Pending {
+            key: key.to_string(),
+            name: name.to_string(),
+            write: Write::Set {
+                value: value.to_string(),
+                expiry: expiry.map(|expiry| expiry.as_secs()),
+            },
+            attempts: 0,
+        }
+    }
+
+    pub(crate) fn del(key: &str, name: &str) -> Self {
+        Pending {
+            key: key.to_string(),
+            name: name.to_string(),
+            write: Write::Del,
+            attempts: 0,
+        }
+    }
+
+    fn op(&self) -> &'static str {
+        match self.write {
+            Write::Set { .. } => "set",
+            Write::Del => "del",
+        }
+    }
+}
+
+/// Queued writes in the order they were queued, at most one per key
+#[derive(Default)]
+struct Queue {
+    next: u64,
+    writes: BTreeMap<u64, Pending>,
+    by_key: HashMap<String, u64>,
+    /// keys with writes in the spill file
+    spilled: HashSet<String>,
+}
+
+impl Queue {
+    /// Queue a write, replacing the queued write of the same key
+    fn insert(&mut self, pending: Pending) {
+        if let Some(seq) = self.by_key.remove(&pending.key) {
+            self.writes.remove(&seq);
+        }
+        let seq = self.next;
+        self.next += 1;
+        self.by_key.insert(pending.key.clone(), seq);
+        self.writes.insert(seq, pending);
+    }
+
+    fn remove(&mut self, seq: u64) -> Option<Pending> {
+        let pending = self.writes.remove(&seq)?;
+        self.by_key.remove(&pending.key);
+        Some(pending)
+    }
+
+    fn depth(&self) -> usize {
+        self.writes.len() + self.spilled.len()
+    }
+}
+
+/// Write-behind queue of a link
+pub(crate) struct WriteBehind {
+    actor_id: String,
+    capacity: usize,
+    max_attempts: u32,
+    spill_file: Option<PathBuf>,
+    queue: Mutex<Queue>,
+    /// serializes appends to and rewrites of the spill file
+    spill_lock: tokio::sync::Mutex<()>,
+}
+
+impl WriteBehind {
+    /// Create the link's queue. Writes spilled by a previous run are picked up from the
+    /// spill file by the first retry.
+    pub(crate) fn new(
+        actor_id: &str,
+        capacity: usize,
+        max_attempts: u32,
+        spill_file: Option<PathBuf>,
+    ) -> Self {
+        let mut queue = Queue::default();
+        if let Some(path) = &spill_file {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                queue.spilled = parse_lines(&content).map(|p| p.key).collect();
+            }
+        }
+        metrics::set_write_behind_depth(actor_id, queue.depth());
+        WriteBehind {
+            actor_id: actor_id.to_string(),
+            capacity,
+            max_attempts,
+            spill_file,
+            queue: Mutex::new(queue),
+            spill_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Returns true if a write of the key is waiting to be retried
+    pub(crate) fn is_pending(&self, key: &str) -> bool {
+        let queue = self.queue.lock().unwrap();
+        queue.by_key.contains_key(key) || queue.spilled.contains(key)
+    }
+
+    /// Queue a write in memory, or in the spill file when the queue is full or the key
+    /// already has a spilled write. Returns the write back if there is no room for it.
+    async fn push(&self, pending: Pending) -> Result<(), Pending> {
+        let _spill = self.spill_lock.lock().await;
+        {
+            let mut queue = self.queue.lock().unwrap();
+            let in_memory = queue.by_key.contains_key(&pending.key);
+            if in_memory
+                || (!queue.spilled.contains(&pending.key) && queue.writes.len() < self.capacity)
+            {
+                queue.insert(pending);
+                metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+                return Ok(());
+            }
+        }
+        let path = match &self.spill_file {
+            Some(path) => path,
+            None => return Err(pending),
+        };
+        if let Err(e) = append_lines(path, std::slice::from_ref(&pending)).await {
+            warn!(actor_id = %self.actor_id, path = %path.display(), "couchbase write-behind spill failed: {}", e);
+            return Err(pending);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        queue.spilled.insert(pending.key);
+        metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+        Ok(())
+    }
+
+    /// Move spilled writes into the queue as far as it has room, oldest first
+    async fn reload(&self) {
+        let path = match &self.spill_file {
+            Some(path) if !self.queue.lock().unwrap().spilled.is_empty() => path,
+            _ => return,
+        };
+        let _spill = self.spill_lock.lock().await;
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) => {
+                warn!(actor_id = %self.actor_id, path = %path.display(), "couchbase write-behind spill file unreadable: {}", e);
+                return;
+            }
+        };
+        let mut remaining = Vec::new();
+        {
+            let mut queue = self.queue.lock().unwrap();
+            for pending in parse_lines(&content) {
+                // a key is in memory here only if an earlier line of this file loaded it
+                if queue.by_key.contains_key(&pending.key) || queue.writes.len() < self.capacity {
+                    queue.insert(pending);
+                } else {
+                    remaining.push(pending);
+                }
+            }
+            queue.spilled = remaining.iter().map(|p| p.key.clone()).collect();
+            metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+        }
+        let rewritten = match remaining.is_empty() {
+            true => tokio::fs::remove_file(path).await,
+            false => write_lines(path, &remaining).await,
+        };
+        if let Err(e) = rewritten {
+            warn!(actor_id = %self.actor_id, path = %path.display(), "couchbase write-behind spill file not updated: {}", e);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<(u64, Pending)> {
+        let queue = self.queue.lock().unwrap();
+        queue
+            .writes
+            .iter()
+            .map(|(seq, pending)| (*seq, pending.clone()))
+            .collect()
+    }
+
+    /// Remove a retried write, unless a newer write of the key replaced it meanwhile
+    fn succeeded(&self, seq: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.remove(seq);
+        metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+    }
+
+    /// Count a failed retry. Returns the write if it ran out of attempts.
+    fn failed(&self, seq: u64) -> Option<Pending> {
+        let mut queue = self.queue.lock().unwrap();
+        let pending = queue.writes.get_mut(&seq)?;
+        pending.attempts += 1;
+        if self.max_attempts == 0 || pending.attempts < self.max_attempts {
+            return None;
+        }
+        let pending = queue.remove(seq);
+        metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+        pending
+    }
+
+    /// Empty the queue into the spill file. Returns the writes that could not be spilled.
+    async fn spill_all(&self) -> Vec<Pending> {
+        let _spill = self.spill_lock.lock().await;
+        let writes: Vec<Pending> = {
+            let mut queue = self.queue.lock().unwrap();
+            let writes = std::mem::take(&mut queue.writes).into_values().collect();
+            queue.by_key.clear();
+            writes
+        };
+        let spilled = match &self.spill_file {
+            Some(path) if !writes.is_empty() => {
+                append_lines(path, &writes).await.map_err(|e| {
+                    warn!(actor_id = %self.actor_id, path = %path.display(), "couchbase write-behind spill failed: {}", e);
+                })
+            }
+            _ => Err(()),
+        };
+        let mut queue = self.queue.lock().unwrap();
+        let lost = match spilled {
+            Ok(()) => {
+                queue.spilled.extend(writes.into_iter().map(|p| p.key));
+                Vec::new()
+            }
+            Err(()) => writes,
+        };
+        metrics::set_write_behind_depth(&self.actor_id, queue.depth());
+        lost
+    }
+}
+
+/// Returns true if the link queues writes that failed with this error.
+/// `set` and `del` are idempotent, so even ambiguous timeouts are safe to retry.
+pub(crate) fn should_queue(link: &CouchbaseLink, e: &CouchbaseError) -> bool {
+    link.write_behind.is_some()
+        && (errors::is_retryable(e)
+            || matches!(
+                e,
+                CouchbaseError::Timeout { .. } | CouchbaseError::RequestCanceled { .. }
+            ))
+}
+
+/// Queue a write of the link for retry
+pub(crate) async fn queue(link: &CouchbaseLink, pending: Pending) -> RpcResult<()> {
+    let write_behind = match &link.write_behind {
+        Some(write_behind) => write_behind,
+        None => return Err(RpcError::Other("write-behind is not enabled".to_string())),
+    };
+    write_behind.push(pending).await.map_err(|_| {
+        RpcError::Other(format!(
+            "write-behind queue of {} writes is full",
+            write_behind.capacity
+        ))
+    })
+}
+
+/// Periodically retry the link's queued writes. Stops when the link is dropped.
+pub(crate) fn spawn_worker(link: Weak<CouchbaseLink>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let link = match link.upgrade() {
+                Some(link) => link,
+                None => break,
+            };
+            let write_behind = match &link.write_behind {
+                Some(write_behind) => write_behind,
+                None => break,
+            };
+            write_behind.reload().await;
+            retry(&link).await;
+        }
+    });
+}
+
+/// Retry every queued write of the link once
+async fn retry(link: &CouchbaseLink) {
+    let write_behind = match &link.write_behind {
+        Some(write_behind) => write_behind,
+        None => return,
+    };
+    for (seq, pending) in write_behind.snapshot() {
+        match attempt(link, &pending).await {
+            Ok(cas) => {
+                write_behind.succeeded(seq);
+                link.audit(pending.op(), &pending.name, cas);
+            }
+            Err(e) => {
+                if let Some(pending) = write_behind.failed(seq) {
+                    if link.logs(Level::WARN) {
+                        warn!(
+                            actor_id = %link.actor_id,
+                            operation = %pending.op(),
+                            key = %link.log_key(&pending.key),
+                            attempts = pending.attempts,
+                            "couchbase write-behind retries exhausted: {}", e
+                        );
+                    }
+                    dead_letter::record(link, pending.op(), &pending.key, &e);
+                }
+            }
+        }
+    }
+}
+
+/// Write a queued write to Couchbase, through the same chunking, history and soft-delete
+/// steps as the call it stands for. Returns the cas of the write, or None for a `del`
+/// of a document that is already gone.
+async fn attempt(link: &CouchbaseLink, pending: &Pending) -> RpcResult<Option<u64>> {
+    match &pending.write {
+        Write::Set { value, expiry } => {
+            // values are checked against the link's value format when queued; one spilled by a
+            // link with another format is stored as a string
+            let value = link
+                .codec
+                .encode(&pending.key, value)
+                .unwrap_or_else(|_| serde_json::Value::from(value.as_str()));
+            let expiry = expiry.map(Duration::from_secs);
+            match crate::store_value(link, "set", &pending.key, value, expiry).await? {
+                Ok(cas) => Ok(Some(cas)),
+                Err(e) => Err(errors::to_rpc_err(e)),
+            }
+        }
+        Write::Del => match crate::remove_value(link, "del", &pending.key).await? {
+            Ok(cas) => Ok(Some(cas)),
+            Err(CouchbaseError::DocumentNotFound { .. }) => Ok(None),
+            Err(e) => Err(errors::to_rpc_err(e)),
+        },
+    }
+}
+
+/// Retry the link's queued writes once within `timeout`, then spill what is left,
+/// or dead-letter it if the link has no spill file
+pub(crate) async fn flush(link: &CouchbaseLink, timeout: Duration) {
+    let write_behind = match &link.write_behind {
+        Some(write_behind) => write_behind,
+        None => return,
+    };
+    let _ = tokio::time::timeout(timeout, retry(link)).await;
+    let lost = write_behind.spill_all().await;
+    if lost.is_empty() {
+        return;
+    }
+    if link.logs(Level::WARN) {
+        warn!(
+            actor_id = %link.actor_id,
+            "couchbase write-behind queue closed with {} writes not applied",
+            lost.len()
+        );
+    }
+    let e = RpcError::Other("write-behind queue closed before the write was applied".to_string());
+    for pending in lost {
+        dead_letter::record(link, pending.op(), &pending.key, &e);
+    }
+}
+
+/// Parse the writes of a spill file, skipping malformed lines
+fn parse_lines(content: &str) -> impl Iterator<Item = Pending> + '_ {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+}
+
+fn to_lines(writes: &[Pending]) -> std::io::Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for pending in writes {
+        serde_json::to_writer(&mut lines, pending)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+async fn append_lines(path: &Path, writes: &[Pending]) -> std::io::Result<()> {
+    let lines = to_lines(writes)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&lines).await?;
+    // tokio completes writes in the background until flushed
+    file.flush().await
+}
+
+async fn write_lines(path: &Path, writes: &[Pending]) -> std::io::Result<()> {
+    tokio::fs::write(path, to_lines(writes)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_behind(capacity: usize, spill_file: Option<PathBuf>) -> WriteBehind {
+        WriteBehind::new("actor", capacity, 2, spill_file)
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_write_per_key() {
+        let queue = write_behind(2, None);
+        queue.push(Pending::set("a", "a", "1", None)).await.unwrap();
+        queue.push(Pending::set("b", "b", "1", None)).await.unwrap();
+        // replacing a queued write needs no room
+        queue.push(Pending::del("a", "a")).await.unwrap();
+        assert!(queue.push(Pending::set("c", "c", "1", None)).await.is_err());
+        let writes: Vec<Pending> = queue.snapshot().into_iter().map(|(_, p)| p).collect();
+        assert_eq!(
+            writes,
+            [Pending::set("b", "b", "1", None), Pending::del("a", "a")]
+        );
+
+        let (seq, _) = queue.snapshot()[0].clone();
+        assert!(queue.failed(seq).is_none());
+        assert_eq!(queue.failed(seq).unwrap().attempts, 2);
+        assert!(!queue.is_pending("b"));
+    }
+
+    #[tokio::test]
+    async fn spills_and_reloads() {
+        let path = std::env::temp_dir().join(format!(
+            "kvcouchbase-write-behind-{}.jsonl",
+            std::process::id()
+        ));
+        let queue = write_behind(1, Some(path.clone()));
+        queue.push(Pending::set("a", "a", "1", None)).await.unwrap();
+        queue.push(Pending::set("b", "b", "1", None)).await.unwrap();
+        queue.push(Pending::set("b", "b", "2", None)).await.unwrap();
+        assert!(queue.is_pending("b"));
+
+        let (seq, _) = queue.snapshot()[0].clone();
+        queue.succeeded(seq);
+        queue.reload().await;
+        let writes: Vec<Pending> = queue.snapshot().into_iter().map(|(_, p)| p).collect();
+        assert_eq!(writes, [Pending::set("b", "b", "2", None)]);
+        assert!(!path.exists());
+
+        assert!(queue.spill_all().await.is_empty());
+        let reopened = write_behind(1, Some(path.clone()));
+        assert!(reopened.is_pending("b"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 use wasmbus_rpc::provider::prelude::*;
-use wasmcloud_interface_factorial::*;
+use wasmcloud_interface_keyvalue::*;
 use wasmcloud_test_util::{
     check,
     cli::print_test_results,
@@ -10,9 +10,16 @@ use wasmcloud_test_util::{
 use wasmcloud_test_util::{run_selected, run_selected_spawn};
 
 #[tokio::test]
+#[ignore = "requires NATS and a running Couchbase cluster"]
 async fn run_all() {
     let opts = TestOptions::default();
-    let res = run_selected_spawn!(&opts, health_check, factorial_0_1, factorial_more);
+    let res = run_selected_spawn!(
+        &opts,
+        health_check,
+        get_set,
+        set_expires,
+        increment_keeps_expiry
+    );
     print_test_results(&res);
 
     let passed = res.iter().filter(|tr| tr.passed).count();
@@ -34,39 +41,93 @@ async fn health_check(_opt: &TestOptions) -> RpcResult<()> {
     Ok(())
 }
 
-/// tests of the Factorial capability
-async fn factorial_0_1(_opt: &TestOptions) -> RpcResult<()> {
+/// tests of get, set, contains and del
+async fn get_set(_opt: &TestOptions) -> RpcResult<()> {
     let prov = test_provider().await;
 
     // create client and ctx
-    let client = FactorialSender::via(prov);
+    let client = KeyValueSender::via(prov);
     let ctx = Context::default();
 
-    let resp = client.calculate(&ctx, &0).await?;
-    assert_eq!(resp, 1, "0!");
+    let key = "kvcouchbase_get_set".to_string();
+    client
+        .set(
+            &ctx,
+            &SetRequest {
+                key: key.clone(),
+                value: "Alice".to_string(),
+                expires: 0,
+            },
+        )
+        .await?;
 
-    let resp = client.calculate(&ctx, &1).await?;
-    assert_eq!(resp, 1, "1!");
+    let resp = client.get(&ctx, &key).await?;
+    check!(resp.exists)?;
+    assert_eq!(resp.value, "Alice", "get after set");
+
+    check!(client.contains(&ctx, &key).await?)?;
+    check!(client.del(&ctx, &key).await?)?;
+
+    let resp = client.get(&ctx, &key).await?;
+    check!(!resp.exists)?;
 
     Ok(())
 }
 
-/// more tests of the Factorial interface
-async fn factorial_more(_opt: &TestOptions) -> RpcResult<()> {
+/// tests that a value set with an expiry disappears
+async fn set_expires(_opt: &TestOptions) -> RpcResult<()> {
     let prov = test_provider().await;
 
     // create client and ctx
-    let client = FactorialSender::via(prov);
+    let client = KeyValueSender::via(prov);
     let ctx = Context::default();
 
-    let resp = client.calculate(&ctx, &2).await?;
-    assert_eq!(resp, 2, "2!");
+    let key = "kvcouchbase_set_expires".to_string();
+    client
+        .set(
+            &ctx,
+            &SetRequest {
+                key: key.clone(),
+                value: "Bob".to_string(),
+                expires: 1,
+            },
+        )
+        .await?;
+    check!(client.contains(&ctx, &key).await?)?;
+
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    check!(!client.contains(&ctx, &key).await?)?;
+
+    Ok(())
+}
 
-    let resp = client.calculate(&ctx, &3).await?;
-    assert_eq!(resp, 6, "3!");
+/// tests that incrementing a value set with an expiry keeps the expiry
+async fn increment_keeps_expiry(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = KeyValueSender::via(prov);
+    let ctx = Context::default();
 
-    let resp = client.calculate(&ctx, &4).await?;
-    assert_eq!(resp, 24, "4!");
+    let key = "kvcouchbase_increment_keeps_expiry".to_string();
+    client
+        .set(
+            &ctx,
+            &SetRequest {
+                key: key.clone(),
+                value: "1".to_string(),
+                expires: 2,
+            },
+        )
+        .await?;
+    let request = IncrementRequest {
+        key: key.clone(),
+        value: 1,
+    };
+    check!(client.increment(&ctx, &request).await? == 2)?;
+
+    tokio::time::sleep(std::time::Duration::from_secs(4)).await;
+    check!(!client.contains(&ctx, &key).await?)?;
 
     Ok(())
 }